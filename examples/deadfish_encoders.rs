@@ -0,0 +1,74 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Compares the lengths of Deadfish programs generated by the BFS and
+//! square-root encoders.
+//!
+//! Usage: `cargo run --release --example deadfish_encoders [STRING]...`
+//!
+//! Each number in 0..=255 is encoded from an accumulator of 0, then each
+//! string is encoded as the sequence of its character codes.
+
+use std::env;
+use std::time::{Duration, Instant};
+
+use nebula2::deadfish::encode::{bfs_encode, sqrt_encode};
+use nebula2::deadfish::Inst;
+
+type Encoder = fn(&[u32]) -> Option<Vec<Inst>>;
+
+fn main() {
+    let mut strings = env::args().skip(1).collect::<Vec<_>>();
+    if strings.is_empty() {
+        strings.push("Hello, World!".to_owned());
+    }
+
+    println!("Numbers 0..=255:");
+    let mut totals = [(0, Duration::ZERO); 2];
+    let mut differ = Vec::new();
+    for n in 0..=255 {
+        let [bfs, sqrt] = compare(&[n], &mut totals);
+        if bfs != sqrt {
+            differ.push((n, bfs, sqrt));
+        }
+    }
+    print_totals(&totals);
+    println!("  {} numbers differ", differ.len());
+    for (n, bfs, sqrt) in differ {
+        println!("    {n:>3}: bfs {bfs:>3}, sqrt {sqrt:>3}");
+    }
+
+    for s in &strings {
+        println!("String {s:?}:");
+        let numbers = s.chars().map(u32::from).collect::<Vec<_>>();
+        let mut totals = [(0, Duration::ZERO); 2];
+        compare(&numbers, &mut totals);
+        print_totals(&totals);
+    }
+}
+
+/// Encodes the numbers with each encoder and returns the program lengths,
+/// accumulating the lengths and times into `totals`.
+fn compare(numbers: &[u32], totals: &mut [(usize, Duration); 2]) -> [usize; 2] {
+    let encoders: [Encoder; 2] = [bfs_encode, sqrt_encode];
+    let mut lens = [0; 2];
+    for (i, encode) in encoders.into_iter().enumerate() {
+        let start = Instant::now();
+        let insts = encode(numbers).expect("256 is not printable");
+        totals[i].0 += insts.len();
+        totals[i].1 += start.elapsed();
+        lens[i] = insts.len();
+    }
+    lens
+}
+
+fn print_totals(totals: &[(usize, Duration); 2]) {
+    for (name, (len, time)) in ["bfs", "sqrt"].iter().zip(totals) {
+        println!("  {name:<4} {len:>8} insts in {time:?}");
+    }
+}
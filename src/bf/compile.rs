@@ -0,0 +1,260 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Compiles Brainfuck to Whitespace.
+//!
+//! The tape is stored in the heap, starting at address 1, and the pointer is
+//! kept as the only value on the stack. Address 0 is used as a scratch cell for
+//! multiply loops, so that only Whitespace 0.2 instructions are needed. The
+//! first 30,000 cells are zeroed on entry, since some implementations do not
+//! allow retrieving from uninitialized heap addresses. Loops are compiled to
+//! labels with `jz` and `jmp`.
+//!
+//! The `#` debug extension is ignored.
+
+use bitvec::vec::BitVec;
+use rug::Integer;
+
+use crate::bf::ir::{Ir, IrError};
+use crate::bf::InstExt;
+use crate::ws::inst::{Inst, RawInst};
+use crate::ws::syntax::{convert, IntLiteral, LabelOrder, Program};
+
+/// Number of cells zeroed on entry.
+const TAPE_LEN: i64 = 30_000;
+/// Heap address of the first cell.
+const TAPE_START: i64 = 1;
+/// Heap address of the scratch cell.
+const SCRATCH: i64 = 0;
+
+/// Compiles Brainfuck instructions to a Whitespace program.
+///
+/// # Errors
+///
+/// Returns an error when the brackets are unmatched.
+pub fn compile_ws(insts: &[InstExt]) -> Result<Program, IrError> {
+    let ir = Ir::optimize(Ir::from_insts(insts)?);
+    let mut c = Compiler::default();
+    c.emit_prologue();
+    c.emit_block(&ir);
+    c.insts.push(Inst::End);
+    Ok(Program::new(c.insts, LabelOrder::Def))
+}
+
+#[derive(Debug, Default)]
+struct Compiler {
+    insts: Vec<RawInst>,
+    next_label: u32,
+}
+
+impl Compiler {
+    fn emit_prologue(&mut self) {
+        // Zero cells from the end of the tape downwards, so the heap is grown
+        // all at once in implementations that use an array. The loop exits
+        // with the address of the first cell on the stack, which is then used
+        // as the pointer.
+        let (head, tail) = (self.label(), self.label());
+        self.push(TAPE_START + TAPE_LEN - 1);
+        self.insts.push(Inst::Label(head.clone()));
+        self.insts.push(Inst::Dup);
+        self.push(0);
+        self.insts.push(Inst::Store);
+        self.insts.push(Inst::Dup);
+        self.push(TAPE_START);
+        self.insts.push(Inst::Sub);
+        self.insts.push(Inst::Jz(tail.clone()));
+        self.push(1);
+        self.insts.push(Inst::Sub);
+        self.insts.push(Inst::Jmp(head));
+        self.insts.push(Inst::Label(tail));
+    }
+
+    fn emit_block(&mut self, block: &[Ir]) {
+        for ir in block {
+            match *ir {
+                Ir::Add(n) => {
+                    self.insts.push(Inst::Dup);
+                    self.insts.push(Inst::Dup);
+                    self.insts.push(Inst::Retrieve);
+                    self.push(i64::from(n));
+                    self.insts.push(Inst::Add);
+                    self.emit_wrap();
+                    self.insts.push(Inst::Store);
+                }
+                Ir::Move { offset, .. } => {
+                    self.push(offset as i64);
+                    self.insts.push(Inst::Add);
+                }
+                Ir::SetZero => {
+                    self.insts.push(Inst::Dup);
+                    self.push(0);
+                    self.insts.push(Inst::Store);
+                }
+                Ir::MulAdd { offset, factor, .. } => {
+                    // Skip when the current cell is zero, so the cell at the
+                    // offset is not accessed, as in the interpreter.
+                    let skip = self.label();
+                    self.insts.push(Inst::Dup);
+                    self.insts.push(Inst::Retrieve);
+                    self.insts.push(Inst::Jz(skip.clone()));
+                    // scratch = cell[ptr+offset]
+                    self.insts.push(Inst::Dup);
+                    self.push(offset as i64);
+                    self.insts.push(Inst::Add);
+                    self.insts.push(Inst::Dup);
+                    self.insts.push(Inst::Retrieve);
+                    self.push(SCRATCH);
+                    self.insts.push(Inst::Swap);
+                    self.insts.push(Inst::Store);
+                    // scratch = (cell[ptr] * factor + scratch) mod 256
+                    self.insts.push(Inst::Swap);
+                    self.insts.push(Inst::Dup);
+                    self.insts.push(Inst::Retrieve);
+                    self.push(i64::from(factor));
+                    self.insts.push(Inst::Mul);
+                    self.push(SCRATCH);
+                    self.insts.push(Inst::Retrieve);
+                    self.insts.push(Inst::Add);
+                    self.emit_wrap();
+                    self.push(SCRATCH);
+                    self.insts.push(Inst::Swap);
+                    self.insts.push(Inst::Store);
+                    // cell[ptr+offset] = scratch
+                    self.insts.push(Inst::Swap);
+                    self.push(SCRATCH);
+                    self.insts.push(Inst::Retrieve);
+                    self.insts.push(Inst::Store);
+                    self.insts.push(Inst::Label(skip));
+                }
+                Ir::Scan { stride, .. } => {
+                    let (head, tail) = (self.label(), self.label());
+                    self.insts.push(Inst::Label(head.clone()));
+                    self.insts.push(Inst::Dup);
+                    self.insts.push(Inst::Retrieve);
+                    self.insts.push(Inst::Jz(tail.clone()));
+                    self.push(stride as i64);
+                    self.insts.push(Inst::Add);
+                    self.insts.push(Inst::Jmp(head));
+                    self.insts.push(Inst::Label(tail));
+                }
+                Ir::Loop(ref body) => {
+                    let (head, tail) = (self.label(), self.label());
+                    self.insts.push(Inst::Label(head.clone()));
+                    self.insts.push(Inst::Dup);
+                    self.insts.push(Inst::Retrieve);
+                    self.insts.push(Inst::Jz(tail.clone()));
+                    self.emit_block(body);
+                    self.insts.push(Inst::Jmp(head));
+                    self.insts.push(Inst::Label(tail));
+                }
+                Ir::Output => {
+                    self.insts.push(Inst::Dup);
+                    self.insts.push(Inst::Retrieve);
+                    self.insts.push(Inst::Printc);
+                }
+                Ir::Input => {
+                    self.insts.push(Inst::Dup);
+                    self.insts.push(Inst::Readc);
+                }
+                Ir::Debug => {}
+            }
+        }
+    }
+
+    /// Reduces the value on top of the stack modulo 256. Whitespace `mod`
+    /// rounds towards negative infinity, so the result is non-negative.
+    fn emit_wrap(&mut self) {
+        self.push(256);
+        self.insts.push(Inst::Mod);
+    }
+
+    fn push(&mut self, n: i64) {
+        let n = IntLiteral::from(Integer::from(n));
+        self.insts.push(Inst::Push(n.bits().clone()));
+    }
+
+    fn label(&mut self) -> BitVec {
+        self.next_label += 1;
+        convert::unsigned_bits_from_integer(&Integer::from(self.next_label))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::bf::Program as BfProgram;
+    use crate::ws::interp::Interpreter;
+
+    #[test]
+    fn structure() {
+        let program = compile_ws(BfProgram::parse(b"+[->++<]>.").insts()).unwrap();
+        let insts = program.insts();
+        assert_eq!(Some(&Inst::End), insts.last());
+        // Only the prologue loop and the guard on the multiply use labels,
+        // since the multiply loop is compiled without a loop.
+        assert_eq!(3, program.labels().len());
+        assert_eq!(
+            1,
+            insts
+                .iter()
+                .filter(|inst| matches!(inst, Inst::Mul))
+                .count(),
+        );
+        assert_eq!(
+            1,
+            insts
+                .iter()
+                .filter(|inst| matches!(inst, Inst::Printc))
+                .count(),
+        );
+    }
+
+    #[test]
+    fn mul_guard() {
+        // The multiply loop is skipped on a zero cell, so the cell left of the
+        // tape is never accessed.
+        let program = compile_ws(BfProgram::parse(b"[-<<+>>].").insts()).unwrap();
+        let mut interp = Interpreter::new(&program);
+        let mut stdout = Vec::new();
+        interp
+            .run(&mut io::empty(), &mut stdout, &mut io::sink())
+            .unwrap();
+        assert_eq!(b"\0", stdout.as_slice());
+        let min = interp.heap().keys().next().unwrap();
+        assert_eq!(&Integer::from(TAPE_START), min);
+    }
+
+    #[test]
+    fn run() {
+        let run = |src: &[u8]| {
+            let program = compile_ws(BfProgram::parse(src).insts()).unwrap();
+            let mut stdout = Vec::new();
+            Interpreter::new(&program)
+                .run(&mut io::empty(), &mut stdout, &mut io::sink())
+                .unwrap();
+            String::from_utf8(stdout).unwrap()
+        };
+        // From https://en.wikipedia.org/wiki/Brainfuck#Hello_World!
+        let hello = b"++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        assert_eq!("Hello World!\n", run(hello));
+        // 7 * 10 + 2 and 7 * 3 + 12, with a multiply loop that adds to two
+        // cells.
+        assert_eq!("H!", run(b"+++++++[->++++++++++>+++<<]>++.>++++++++++++."));
+    }
+
+    #[test]
+    fn unmatched() {
+        let insts = BfProgram::parse(b"+[[-]");
+        assert_eq!(
+            Err(IrError::UnmatchedHead(1)),
+            compile_ws(insts.insts()).map(|_| ())
+        );
+    }
+}
@@ -0,0 +1,327 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Brainfuck interpreter.
+//!
+//! Cells are 8-bit and wrap on overflow. The tape starts with 30,000 cells, as
+//! in the original implementation, and grows to the right as needed. Moving
+//! left of the first cell is an error. At EOF, `,` leaves the cell unchanged.
+
+use std::io::{self, ErrorKind, Read, Write};
+
+use crate::bf::{ir::Ir, Inst, InstExt, Program};
+
+/// Initial length of the tape.
+const TAPE_LEN: usize = 30_000;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Interpreter {
+    tape: Vec<u8>,
+    ptr: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum InterpError {
+    /// `[` at the given instruction index has no matching `]`.
+    UnmatchedHead(usize),
+    /// `]` at the given instruction index has no matching `[`.
+    UnmatchedTail(usize),
+    /// The pointer moved left of the first cell at the given instruction
+    /// index.
+    PointerUnderflow(usize),
+    IoError(ErrorKind),
+}
+
+impl Interpreter {
+    #[inline]
+    #[must_use]
+    pub fn new() -> Self {
+        Interpreter { tape: vec![0; TAPE_LEN], ptr: 0 }
+    }
+
+    /// Runs a parsed program. If the program has input following a `!`
+    /// separator, it is read instead of `stdin`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when the brackets are unmatched, the pointer moves left
+    /// of the first cell, or an I/O operation fails.
+    pub fn run_program<R: Read, W: Write, D: Write>(
+        &mut self,
+        program: &Program,
+        stdin: R,
+        stdout: W,
+        debug: D,
+    ) -> Result<(), InterpError> {
+        match program.input() {
+            Some(input) => self.run(program.insts(), input, stdout, debug),
+            None => self.run(program.insts(), stdin, stdout, debug),
+        }
+    }
+
+    /// Runs instructions on the current tape. `#` writes a dump of the tape to
+    /// `debug`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when the brackets are unmatched, the pointer moves left
+    /// of the first cell, or an I/O operation fails.
+    pub fn run<R: Read, W: Write, D: Write>(
+        &mut self,
+        insts: &[InstExt],
+        mut stdin: R,
+        mut stdout: W,
+        mut debug: D,
+    ) -> Result<(), InterpError> {
+        let jumps = match_brackets(insts)?;
+        let mut pc = 0;
+        while pc < insts.len() {
+            match insts[pc] {
+                InstExt::Bf(Inst::Right) => self.move_by(1, pc)?,
+                InstExt::Bf(Inst::Left) => self.move_by(-1, pc)?,
+                InstExt::Bf(Inst::Inc) => self.tape[self.ptr] = self.tape[self.ptr].wrapping_add(1),
+                InstExt::Bf(Inst::Dec) => self.tape[self.ptr] = self.tape[self.ptr].wrapping_sub(1),
+                InstExt::Bf(Inst::Output) => stdout.write_all(&[self.tape[self.ptr]])?,
+                InstExt::Bf(Inst::Input) => {
+                    stdout.flush()?;
+                    self.read_cell(&mut stdin)?;
+                }
+                InstExt::Bf(Inst::Head) => {
+                    if self.tape[self.ptr] == 0 {
+                        pc = jumps[pc];
+                    }
+                }
+                InstExt::Bf(Inst::Tail) => {
+                    if self.tape[self.ptr] != 0 {
+                        pc = jumps[pc];
+                    }
+                }
+                InstExt::Debug => {
+                    stdout.flush()?;
+                    self.dump_tape(&mut debug)?;
+                }
+            }
+            pc += 1;
+        }
+        stdout.flush()?;
+        Ok(())
+    }
+
+    /// Runs optimized IR on the current tape. It behaves the same as running
+    /// the instructions it was built from with [`Interpreter::run`], except
+    /// that a pointer underflow is reported at the first instruction of the
+    /// folded move or loop.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when the pointer moves left of the first cell or an I/O
+    /// operation fails.
+    pub fn run_ir<R: Read, W: Write, D: Write>(
+        &mut self,
+        ir: &[Ir],
+        mut stdin: R,
+        mut stdout: W,
+        mut debug: D,
+    ) -> Result<(), InterpError> {
+        self.exec_ir(ir, &mut stdin, &mut stdout, &mut debug)?;
+        stdout.flush()?;
+        Ok(())
+    }
+
+    fn exec_ir<R: Read, W: Write, D: Write>(
+        &mut self,
+        block: &[Ir],
+        stdin: &mut R,
+        stdout: &mut W,
+        debug: &mut D,
+    ) -> Result<(), InterpError> {
+        for ir in block {
+            match *ir {
+                Ir::Add(n) => self.tape[self.ptr] = self.tape[self.ptr].wrapping_add(n),
+                Ir::Move { offset, min, pos } => {
+                    self.offset_ptr(min, pos)?;
+                    self.move_by(offset, pos)?;
+                }
+                Ir::SetZero => self.tape[self.ptr] = 0,
+                Ir::MulAdd { offset, factor, pos } => {
+                    let cell = self.tape[self.ptr];
+                    if cell != 0 {
+                        let ptr = self.offset_ptr(offset, pos)?;
+                        self.tape[ptr] = self.tape[ptr].wrapping_add(cell.wrapping_mul(factor));
+                    }
+                }
+                Ir::Scan { stride, pos } => {
+                    while self.tape[self.ptr] != 0 {
+                        self.move_by(stride, pos)?;
+                    }
+                }
+                Ir::Loop(ref body) => {
+                    while self.tape[self.ptr] != 0 {
+                        self.exec_ir(body, stdin, stdout, debug)?;
+                    }
+                }
+                Ir::Output => stdout.write_all(&[self.tape[self.ptr]])?,
+                Ir::Input => {
+                    stdout.flush()?;
+                    self.read_cell(stdin)?;
+                }
+                Ir::Debug => {
+                    stdout.flush()?;
+                    self.dump_tape(&mut *debug)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn move_by(&mut self, offset: isize, pos: usize) -> Result<(), InterpError> {
+        self.ptr = self.offset_ptr(offset, pos)?;
+        Ok(())
+    }
+
+    /// Computes the pointer moved by `offset` and grows the tape to contain
+    /// it. `pos` is the instruction index reported on underflow.
+    #[inline]
+    fn offset_ptr(&mut self, offset: isize, pos: usize) -> Result<usize, InterpError> {
+        let ptr = if offset < 0 {
+            (self.ptr.checked_sub(offset.unsigned_abs()))
+                .ok_or(InterpError::PointerUnderflow(pos))?
+        } else {
+            self.ptr + offset.unsigned_abs()
+        };
+        if ptr >= self.tape.len() {
+            self.tape.resize((ptr + 1).max(self.tape.len() * 2), 0);
+        }
+        Ok(ptr)
+    }
+
+    fn read_cell<R: Read>(&mut self, stdin: &mut R) -> Result<(), InterpError> {
+        let mut buf = [0];
+        loop {
+            match stdin.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {
+                    self.tape[self.ptr] = buf[0];
+                    return Ok(());
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Writes the cells up to the last non-zero cell or the pointer, whichever
+    /// is further, with the current cell in brackets.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when writing fails.
+    pub fn dump_tape<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let last_nonzero = self.tape.iter().rposition(|&cell| cell != 0);
+        let end = last_nonzero.map_or(self.ptr, |i| i.max(self.ptr));
+        for (i, cell) in self.tape[..=end].iter().enumerate() {
+            if i != 0 {
+                w.write_all(b" ")?;
+            }
+            if i == self.ptr {
+                write!(w, "[{cell}]")?;
+            } else {
+                write!(w, "{cell}")?;
+            }
+        }
+        w.write_all(b"\n")
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn tape(&self) -> &[u8] {
+        &self.tape
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn ptr(&self) -> usize {
+        self.ptr
+    }
+}
+
+impl Default for Interpreter {
+    #[inline]
+    fn default() -> Self {
+        Interpreter::new()
+    }
+}
+
+/// Computes the index of the matching bracket for each `[` and `]`.
+fn match_brackets(insts: &[InstExt]) -> Result<Vec<usize>, InterpError> {
+    let mut jumps = vec![0; insts.len()];
+    let mut heads = Vec::new();
+    for (i, inst) in insts.iter().enumerate() {
+        match inst {
+            InstExt::Bf(Inst::Head) => heads.push(i),
+            InstExt::Bf(Inst::Tail) => {
+                let head = heads.pop().ok_or(InterpError::UnmatchedTail(i))?;
+                jumps[head] = i;
+                jumps[i] = head;
+            }
+            _ => {}
+        }
+    }
+    match heads.pop() {
+        Some(head) => Err(InterpError::UnmatchedHead(head)),
+        None => Ok(jumps),
+    }
+}
+
+impl From<io::Error> for InterpError {
+    #[inline]
+    fn from(err: io::Error) -> Self {
+        InterpError::IoError(err.kind())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn input_separator() {
+        // Reverses its input, which follows the `!`.
+        let program = Program::parse(b">,[>,]<[.<]!olleh");
+        assert_eq!(Some(&b"olleh"[..]), program.input());
+        let mut out = Vec::new();
+        Interpreter::new()
+            .run_program(&program, io::empty(), &mut out, io::sink())
+            .unwrap();
+        assert_eq!(b"hello", out.as_slice());
+    }
+
+    #[test]
+    fn debug_dump() {
+        let program = Program::parse(b"+++>++>+<#");
+        let mut debug = Vec::new();
+        Interpreter::new()
+            .run_program(&program, io::empty(), io::sink(), &mut debug)
+            .unwrap();
+        assert_eq!(b"3 [2] 1\n", debug.as_slice());
+    }
+
+    #[test]
+    fn errors() {
+        let mut interp = Interpreter::new();
+        let mut run = |src: &[u8]| {
+            interp.run_program(&Program::parse(src), io::empty(), io::sink(), io::sink())
+        };
+        assert_eq!(Err(InterpError::UnmatchedHead(1)), run(b"+[[-]"));
+        assert_eq!(Err(InterpError::UnmatchedTail(4)), run(b"+[-]]"));
+        assert_eq!(Err(InterpError::PointerUnderflow(2)), run(b"><<"));
+    }
+}
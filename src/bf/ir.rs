@@ -0,0 +1,314 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Optimizing IR for Brainfuck.
+//!
+//! Raw instructions are first folded into runs with [`Ir::from_insts`], then
+//! common loop idioms are recognized with [`Ir::optimize`]:
+//!
+//! - Clear loops: `[-]` and `[+]` set the cell to zero.
+//! - Multiply loops: a balanced loop of only additions and moves that
+//!   decrements or increments the current cell by 1, such as `[->+++>++<<]`,
+//!   adds multiples of the current cell to other cells, then clears it.
+//! - Scan loops: `[>]`, `[<<]`, etc. move until a zero cell.
+
+use std::collections::BTreeMap;
+use std::mem;
+
+use crate::bf::{Inst, InstExt};
+
+/// Brainfuck IR node. Cell arithmetic wraps modulo 256.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Ir {
+    /// Adds to the current cell (folded `+` and `-`).
+    Add(u8),
+    /// Moves the pointer (folded `>` and `<`). `min` is the lowest offset
+    /// reached within the run, which is at most 0, so that a run like `<>` is
+    /// kept and still underflows. `pos` is the index of the first instruction
+    /// in the run.
+    Move {
+        offset: isize,
+        min: isize,
+        pos: usize,
+    },
+    /// Sets the current cell to zero.
+    SetZero,
+    /// Adds the current cell times `factor` to the cell at `offset` from the
+    /// pointer. `pos` is the index of the first move that reached the cell.
+    MulAdd {
+        offset: isize,
+        factor: u8,
+        pos: usize,
+    },
+    /// Moves the pointer by the stride until the current cell is zero. `pos` is
+    /// the index of the first instruction in the move.
+    Scan { stride: isize, pos: usize },
+    /// Repeats the body while the current cell is non-zero.
+    Loop(Vec<Ir>),
+    /// `.`
+    Output,
+    /// `,`
+    Input,
+    /// `#`
+    Debug,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum IrError {
+    /// `[` at the given instruction index has no matching `]`.
+    UnmatchedHead(usize),
+    /// `]` at the given instruction index has no matching `[`.
+    UnmatchedTail(usize),
+}
+
+impl Ir {
+    /// Converts instructions to IR, folding runs of `+`/`-` and `>`/`<` and
+    /// nesting loops.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when the brackets are unmatched.
+    pub fn from_insts(insts: &[InstExt]) -> Result<Vec<Ir>, IrError> {
+        // Stack of the enclosing loops' instruction indices and bodies.
+        let mut loops = Vec::new();
+        let mut block = Vec::new();
+        for (i, &inst) in insts.iter().enumerate() {
+            match inst {
+                InstExt::Bf(Inst::Inc) => Ir::push_add(&mut block, 1),
+                InstExt::Bf(Inst::Dec) => Ir::push_add(&mut block, u8::MAX),
+                InstExt::Bf(Inst::Right) => Ir::push_move(&mut block, 1, i),
+                InstExt::Bf(Inst::Left) => Ir::push_move(&mut block, -1, i),
+                InstExt::Bf(Inst::Output) => block.push(Ir::Output),
+                InstExt::Bf(Inst::Input) => block.push(Ir::Input),
+                InstExt::Debug => block.push(Ir::Debug),
+                InstExt::Bf(Inst::Head) => loops.push((i, mem::take(&mut block))),
+                InstExt::Bf(Inst::Tail) => {
+                    let (_, outer) = loops.pop().ok_or(IrError::UnmatchedTail(i))?;
+                    let body = mem::replace(&mut block, outer);
+                    block.push(Ir::Loop(body));
+                }
+            }
+        }
+        match loops.pop() {
+            Some((head, _)) => Err(IrError::UnmatchedHead(head)),
+            None => Ok(block),
+        }
+    }
+
+    fn push_add(block: &mut Vec<Ir>, n: u8) {
+        if let Some(Ir::Add(m)) = block.last_mut() {
+            *m = m.wrapping_add(n);
+            if *m == 0 {
+                block.pop();
+            }
+        } else {
+            block.push(Ir::Add(n));
+        }
+    }
+
+    fn push_move(block: &mut Vec<Ir>, n: isize, pos: usize) {
+        if let Some(Ir::Move { offset, min, .. }) = block.last_mut() {
+            *offset += n;
+            *min = (*min).min(*offset);
+            if *offset == 0 && *min == 0 {
+                block.pop();
+            }
+        } else {
+            block.push(Ir::Move { offset: n, min: n.min(0), pos });
+        }
+    }
+
+    /// Recognizes clear, multiply, and scan loops, recursively.
+    #[must_use]
+    pub fn optimize(block: Vec<Ir>) -> Vec<Ir> {
+        let mut optimized = Vec::with_capacity(block.len());
+        for ir in block {
+            match ir {
+                Ir::Loop(body) => {
+                    let body = Ir::optimize(body);
+                    if let Some(scan) = Ir::recognize_scan(&body) {
+                        optimized.push(scan);
+                    } else if let Some(muls) = Ir::recognize_mul(&body) {
+                        optimized.extend(muls);
+                    } else {
+                        optimized.push(Ir::Loop(body));
+                    }
+                }
+                ir => optimized.push(ir),
+            }
+        }
+        optimized
+    }
+
+    fn recognize_scan(body: &[Ir]) -> Option<Ir> {
+        match body {
+            // A scan only checks where each step ends, so the move must not go
+            // further left than that.
+            &[Ir::Move { offset, min, pos }] if offset != 0 && min == offset.min(0) => {
+                Some(Ir::Scan { stride: offset, pos })
+            }
+            _ => None,
+        }
+    }
+
+    /// Recognizes a multiply loop, including clear loops as the case with no
+    /// other cells.
+    fn recognize_mul(body: &[Ir]) -> Option<Vec<Ir>> {
+        let (mut offset, mut low, mut move_pos) = (0, 0, 0);
+        // Delta for each offset and the position of the move that reached it.
+        let mut deltas = BTreeMap::new();
+        for ir in body {
+            match *ir {
+                Ir::Add(n) => {
+                    let (delta, _): &mut (u8, usize) =
+                        deltas.entry(offset).or_insert((0, move_pos));
+                    *delta = delta.wrapping_add(n);
+                }
+                Ir::Move { offset: n, min, pos } => {
+                    low = low.min(offset + min);
+                    offset += n;
+                    move_pos = pos;
+                }
+                _ => return None,
+            }
+        }
+        if offset != 0 {
+            return None;
+        }
+        // The multiplies only access the cells they change, so the loop must
+        // not go further left than the leftmost of them.
+        if low < 0 && deltas.get(&low).map_or(true, |&(delta, _)| delta == 0) {
+            return None;
+        }
+        // The loop runs `c` times when the current cell is decremented by 1 or
+        // `-c` times when it is incremented by 1.
+        let negate = match deltas.remove(&0).map(|(delta, _)| delta) {
+            Some(u8::MAX) => false,
+            Some(1) => true,
+            _ => return None,
+        };
+        let mut muls = Vec::with_capacity(deltas.len() + 1);
+        for (offset, (factor, pos)) in deltas {
+            if factor != 0 {
+                let factor = if negate {
+                    factor.wrapping_neg()
+                } else {
+                    factor
+                };
+                muls.push(Ir::MulAdd { offset, factor, pos });
+            }
+        }
+        muls.push(Ir::SetZero);
+        Some(muls)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::bf::{interp::Interpreter, Program};
+
+    fn parse(src: &[u8]) -> Vec<Ir> {
+        Ir::optimize(Ir::from_insts(Program::parse(src).insts()).unwrap())
+    }
+
+    #[test]
+    fn fold() {
+        let insts = Program::parse(b"+++--><<<.+-,");
+        let ir = Ir::from_insts(insts.insts()).unwrap();
+        assert_eq!(
+            vec![
+                Ir::Add(1),
+                Ir::Move { offset: -2, min: -2, pos: 7 },
+                Ir::Output,
+                Ir::Input
+            ],
+            ir,
+        );
+    }
+
+    #[test]
+    fn recognize_loops() {
+        assert_eq!(
+            vec![Ir::Move { offset: 1, min: 0, pos: 0 }, Ir::SetZero],
+            parse(b">[-]"),
+        );
+        assert_eq!(vec![Ir::SetZero], parse(b"[+]"));
+        assert_eq!(vec![Ir::Scan { stride: -2, pos: 1 }], parse(b"[<<]"));
+        assert_eq!(
+            vec![
+                Ir::MulAdd { offset: 1, factor: 3, pos: 2 },
+                Ir::MulAdd { offset: 2, factor: 254, pos: 6 },
+                Ir::SetZero,
+            ],
+            parse(b"[->+++>--<<]"),
+        );
+        assert_eq!(
+            vec![Ir::MulAdd { offset: -1, factor: 255, pos: 1 }, Ir::SetZero],
+            parse(b"[<+>+]"),
+        );
+        assert_eq!(
+            vec![Ir::Loop(vec![Ir::Add(255), Ir::Output])],
+            parse(b"[-.]"),
+        );
+    }
+
+    #[test]
+    fn run_equivalent() {
+        // From https://en.wikipedia.org/wiki/Brainfuck#Hello_World!
+        let src = b"++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        let program = Program::parse(src);
+        let ir = Ir::optimize(Ir::from_insts(program.insts()).unwrap());
+
+        let mut out1 = Vec::new();
+        let mut interp1 = Interpreter::new();
+        interp1
+            .run(program.insts(), io::empty(), &mut out1, io::sink())
+            .unwrap();
+        let mut out2 = Vec::new();
+        let mut interp2 = Interpreter::new();
+        interp2
+            .run_ir(&ir, io::empty(), &mut out2, io::sink())
+            .unwrap();
+        assert_eq!(b"Hello World!\n", out1.as_slice());
+        assert_eq!(out1, out2);
+        assert_eq!(interp1, interp2);
+    }
+
+    #[test]
+    fn underflow_position() {
+        for src in [&b"+[<+>-]"[..], b"+>+[<]"] {
+            let program = Program::parse(src);
+            let ir = Ir::optimize(Ir::from_insts(program.insts()).unwrap());
+            let res1 = Interpreter::new().run(program.insts(), io::empty(), io::sink(), io::sink());
+            let res2 = Interpreter::new().run_ir(&ir, io::empty(), io::sink(), io::sink());
+            assert!(res1.is_err());
+            assert_eq!(res1, res2);
+        }
+    }
+
+    #[test]
+    fn folded_underflow() {
+        // Moves that cancel out or end right of where they went below the
+        // first cell still underflow.
+        for src in [&b"<>"[..], b"<<>>>", b"+[-<>]", b"+[<>>]", b"+[-<<>+>]"] {
+            let program = Program::parse(src);
+            let ir = Ir::optimize(Ir::from_insts(program.insts()).unwrap());
+            let res1 = Interpreter::new().run(program.insts(), io::empty(), io::sink(), io::sink());
+            let res2 = Interpreter::new().run_ir(&ir, io::empty(), io::sink(), io::sink());
+            assert!(res1.is_err());
+            assert_eq!(res1, res2);
+        }
+        assert_eq!(
+            vec![Ir::Move { offset: 1, min: -2, pos: 0 }],
+            parse(b"<<>>>"),
+        );
+    }
+}
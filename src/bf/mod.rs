@@ -13,10 +13,14 @@
 //! - [Original distribution](http://main.aminet.net/dev/lang/brainfuck-2.lha)
 //! - [Esolang wiki](https://esolangs.org/wiki/Brainfuck)
 
+use std::fmt::{self, Display, Formatter};
 use std::mem;
 
 use crate::syntax::VariantIndex;
 
+pub mod compile;
+pub mod interp;
+pub mod ir;
 pub mod ook;
 pub mod spoon;
 
@@ -51,6 +55,124 @@ pub enum InstExt {
     Debug,
 }
 
+/// Brainfuck program with its input.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Program {
+    insts: Vec<InstExt>,
+    /// Input following the `!` separator, if any.
+    input: Option<Vec<u8>>,
+}
+
+impl Program {
+    /// Parses a Brainfuck program with the `#` debug extension. All other
+    /// characters are comments.
+    ///
+    /// Like many interpreters, the first `!` separates the code from the input
+    /// to the program, so that both can be given in one file. The remainder
+    /// after it is used as input verbatim.
+    #[must_use]
+    pub fn parse(src: &[u8]) -> Self {
+        let (code, input) = match src.iter().position(|&b| b == b'!') {
+            Some(i) => (&src[..i], Some(src[i + 1..].to_vec())),
+            None => (src, None),
+        };
+        let insts = code.iter().filter_map(|&b| InstExt::from_byte(b)).collect();
+        Program { insts, input }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn new(insts: Vec<InstExt>, input: Option<Vec<u8>>) -> Self {
+        Program { insts, input }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn insts(&self) -> &[InstExt] {
+        &self.insts
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn input(&self) -> Option<&[u8]> {
+        self.input.as_deref()
+    }
+}
+
+impl Inst {
+    #[inline]
+    #[must_use]
+    pub const fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            b'>' => Some(Inst::Right),
+            b'<' => Some(Inst::Left),
+            b'+' => Some(Inst::Inc),
+            b'-' => Some(Inst::Dec),
+            b'.' => Some(Inst::Output),
+            b',' => Some(Inst::Input),
+            b'[' => Some(Inst::Head),
+            b']' => Some(Inst::Tail),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn as_char(self) -> char {
+        match self {
+            Inst::Right => '>',
+            Inst::Left => '<',
+            Inst::Inc => '+',
+            Inst::Dec => '-',
+            Inst::Output => '.',
+            Inst::Input => ',',
+            Inst::Head => '[',
+            Inst::Tail => ']',
+        }
+    }
+}
+
+impl InstExt {
+    #[inline]
+    #[must_use]
+    pub const fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            b'#' => Some(InstExt::Debug),
+            _ => match Inst::from_byte(b) {
+                Some(inst) => Some(InstExt::Bf(inst)),
+                None => None,
+            },
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn as_char(self) -> char {
+        match self {
+            InstExt::Bf(inst) => inst.as_char(),
+            InstExt::Debug => '#',
+        }
+    }
+}
+
+impl Display for Inst {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_char())
+    }
+}
+
+impl Display for InstExt {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_char())
+    }
+}
+
+impl From<Inst> for InstExt {
+    fn from(inst: Inst) -> Self {
+        InstExt::Bf(inst)
+    }
+}
+
 impl VariantIndex for Inst {
     const COUNT: u32 = 8;
     #[inline]
@@ -67,10 +189,17 @@ impl VariantIndex for InstExt {
     const COUNT: u32 = 9;
     #[inline]
     fn variant(index: u32) -> Self {
-        unsafe { mem::transmute(index as u8) }
+        if index < Inst::COUNT {
+            InstExt::Bf(Inst::variant(index))
+        } else {
+            InstExt::Debug
+        }
     }
     #[inline]
     fn index(&self) -> u32 {
-        unsafe { mem::transmute::<_, u8>(*self) as u32 }
+        match self {
+            InstExt::Bf(inst) => inst.index(),
+            InstExt::Debug => Inst::COUNT,
+        }
     }
 }
@@ -13,6 +13,7 @@
 //! - [Language specification](https://www.dangermouse.net/esoteric/ook.html)
 //! - [Esolang wiki](https://esolangs.org/wiki/Ook!)
 
+use std::fmt::{self, Display, Formatter};
 use std::mem;
 use std::sync::LazyLock;
 
@@ -75,6 +76,54 @@ pub static TABLE: LazyLock<PrefixTable<Punct, Inst>> = LazyLock::new(|| {
     table
 });
 
+impl Inst {
+    /// Serializes instructions to Ook! punctuation tokens.
+    pub fn emit<I: IntoIterator<Item = Inst>>(insts: I) -> impl Iterator<Item = Punct> {
+        insts
+            .into_iter()
+            .flat_map(|inst| inst.tokens().iter().copied())
+    }
+
+    /// Serializes instructions to Ook! text, with tokens separated by spaces
+    /// and one instruction pair per line.
+    #[must_use]
+    pub fn emit_string<I: IntoIterator<Item = Inst>>(insts: I) -> String {
+        let mut s = String::new();
+        for inst in insts {
+            s.push_str(&inst.to_string());
+            s.push('\n');
+        }
+        s
+    }
+}
+
+impl Punct {
+    #[inline]
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Punct::Period => "Ook.",
+            Punct::Question => "Ook?",
+            Punct::Bang => "Ook!",
+        }
+    }
+}
+
+impl Display for Punct {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Display for Inst {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let [first, second] = self.tokens() else {
+            unreachable!("Ook! instructions have two tokens");
+        };
+        write!(f, "{first} {second}")
+    }
+}
+
 impl Tokens for Inst {
     type Token = Punct;
 
@@ -117,10 +166,57 @@ impl VariantIndex for Inst {
     const COUNT: u32 = 9;
     #[inline]
     fn variant(index: u32) -> Self {
-        unsafe { mem::transmute(index as u8) }
+        if index < bf::Inst::COUNT {
+            Inst::Bf(bf::Inst::variant(index))
+        } else {
+            Inst::Banana
+        }
     }
     #[inline]
     fn index(&self) -> u32 {
-        unsafe { mem::transmute::<_, u8>(*self) as u32 }
+        match self {
+            Inst::Bf(inst) => inst.index(),
+            Inst::Banana => bf::Inst::COUNT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use super::*;
+
+    #[test]
+    fn emit() {
+        use bf::Inst::*;
+        let insts = [
+            Inst::Bf(Inc),
+            Inst::Bf(Head),
+            Inst::Bf(Right),
+            Inst::Bf(Output),
+            Inst::Bf(Left),
+            Inst::Bf(Dec),
+            Inst::Bf(Tail),
+            Inst::Bf(Input),
+            Inst::Banana,
+        ];
+        let ook = "\
+            Ook. Ook.\n\
+            Ook! Ook?\n\
+            Ook. Ook?\n\
+            Ook! Ook.\n\
+            Ook? Ook.\n\
+            Ook! Ook!\n\
+            Ook? Ook!\n\
+            Ook. Ook!\n\
+            Ook? Ook?\n";
+        assert_eq!(ook, Inst::emit_string(insts));
+
+        let mut lex = Inst::emit(insts).map(Ok);
+        let parsed = iter::from_fn(|| TABLE.parse(&mut lex))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(insts.as_slice(), parsed);
     }
 }
@@ -14,6 +14,7 @@
 //! - [Reference interpreter mirror](http://marquisdegeek.com/pub/sources/spoon-v1.zip)
 //! - [Esolang wiki](https://esolangs.org/wiki/Talk:Spoon)
 
+use std::fmt::{self, Display, Formatter};
 use std::iter;
 use std::mem;
 use std::sync::LazyLock;
@@ -71,6 +72,15 @@ pub static TABLE: LazyLock<PrefixTable<Token, Inst>> = LazyLock::new(|| {
 });
 
 impl Token {
+    #[inline]
+    #[must_use]
+    pub const fn as_char(self) -> char {
+        match self {
+            Token::A => '0',
+            Token::B => '1',
+        }
+    }
+
     pub fn iter_bits<T: BitStore, O: BitOrder>(
         bits: &BitSlice<T, O>,
         swap: bool,
@@ -79,6 +89,17 @@ impl Token {
             .by_vals()
             .map(move |bit| if bit ^ swap { Token::B } else { Token::A })
     }
+
+    /// Packs tokens into bits. This is the inverse of [`Token::iter_bits`].
+    #[must_use]
+    pub fn collect_bits<T: BitStore, O: BitOrder, I: IntoIterator<Item = Token>>(
+        toks: I,
+        swap: bool,
+    ) -> BitVec<T, O> {
+        toks.into_iter()
+            .map(|tok| (tok == Token::B) ^ swap)
+            .collect()
+    }
 }
 
 impl Inst {
@@ -89,6 +110,35 @@ impl Inst {
         let table = &*TABLE;
         iter::from_fn(move || table.parse(&mut lex))
     }
+
+    /// Serializes instructions to Spoon tokens.
+    pub fn emit<I: IntoIterator<Item = Inst>>(insts: I) -> impl Iterator<Item = Token> {
+        insts
+            .into_iter()
+            .flat_map(|inst| inst.tokens().iter().copied())
+    }
+
+    /// Serializes instructions to packed Spoon bits. This is the inverse of
+    /// parsing with [`Token::iter_bits`].
+    #[must_use]
+    pub fn emit_bits<T: BitStore, O: BitOrder, I: IntoIterator<Item = Inst>>(
+        insts: I,
+        swap: bool,
+    ) -> BitVec<T, O> {
+        Token::collect_bits(Inst::emit(insts), swap)
+    }
+
+    /// Serializes instructions to a string of `0` and `1` characters.
+    #[must_use]
+    pub fn emit_string<I: IntoIterator<Item = Inst>>(insts: I) -> String {
+        Inst::emit(insts).map(Token::as_char).collect()
+    }
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_char())
+    }
 }
 
 impl Tokens for Inst {
@@ -134,11 +184,19 @@ impl VariantIndex for Inst {
     const COUNT: u32 = 10;
     #[inline]
     fn variant(index: u32) -> Self {
-        unsafe { mem::transmute(index as u8) }
+        match index.checked_sub(bf::Inst::COUNT) {
+            None => Inst::Bf(bf::Inst::variant(index)),
+            Some(0) => Inst::Debug,
+            Some(_) => Inst::Exit,
+        }
     }
     #[inline]
     fn index(&self) -> u32 {
-        unsafe { mem::transmute::<_, u8>(*self) as u32 }
+        match self {
+            Inst::Bf(inst) => inst.index(),
+            Inst::Debug => bf::Inst::COUNT,
+            Inst::Exit => bf::Inst::COUNT + 1,
+        }
     }
 }
 
@@ -192,5 +250,19 @@ mod tests {
             + + + + + '[' < - - - - - - > - ']' < - .
         ];
         assert_eq!(INSTS, insts);
+
+        let emitted: BitVec = Inst::emit_bits(insts.iter().copied(), false);
+        assert_eq!(bits, emitted);
+        let swapped: BitVec = Inst::emit_bits(insts.iter().copied(), true);
+        assert_eq!(!bits, swapped);
+    }
+
+    #[test]
+    fn emit_string() {
+        const INSTS: &[Inst] = insts![+ - > < '[' ']' . , DEBUG EXIT];
+        let bits = concat!(
+            "1", "000", "010", "011", "00100", "0011", "001010", "0010110", "00101110", "00101111",
+        );
+        assert_eq!(bits, Inst::emit_string(INSTS.iter().copied()));
     }
 }
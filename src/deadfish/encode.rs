@@ -0,0 +1,291 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Encoders that generate Deadfish programs to print numbers.
+//!
+//! 256 can never be printed, since the accumulator is reset when it reaches
+//! 256, so encoders return `None` for it.
+
+use std::collections::VecDeque;
+use std::iter;
+
+use crate::deadfish::Inst;
+
+/// Largest value the breadth-first search visits. Paths that would need a
+/// larger bound are found with [`sqrt_path`] instead.
+const BFS_LIMIT: u64 = 1 << 18;
+
+/// Generates a program that prints the numbers in order, using the sequence of
+/// instructions between each found by [`bfs_path`], which is the shortest
+/// except for large numbers.
+#[must_use]
+pub fn bfs_encode(numbers: &[u32]) -> Option<Vec<Inst>> {
+    encode_with(numbers, bfs_path)
+}
+
+/// Generates a program that prints the numbers in order, using the
+/// square-root heuristic between each, as found by [`sqrt_path`].
+#[must_use]
+pub fn sqrt_encode(numbers: &[u32]) -> Option<Vec<Inst>> {
+    encode_with(numbers, sqrt_path)
+}
+
+/// Generates a program that prints the numbers in order, using `path` to
+/// change the accumulator from one number to the next.
+pub fn encode_with<F: FnMut(u32, u32) -> Option<Vec<Inst>>>(
+    numbers: &[u32],
+    mut path: F,
+) -> Option<Vec<Inst>> {
+    let mut insts = Vec::new();
+    let mut acc = 0;
+    for &n in numbers {
+        insts.extend(path(acc, n)?);
+        insts.push(Inst::Output);
+        acc = n;
+    }
+    Some(insts)
+}
+
+/// Finds a shortest sequence of `i`, `d`, and `s` instructions that changes the
+/// accumulator from `from` to `to`, with a breadth-first search.
+///
+/// Values that exceed `max(to, 257) + c`, where `c` is the cost of a known
+/// path, cannot be on a shorter path, because only `d` decreases a value above
+/// 1 and it would need more than `c` steps to come back down to `to` or to 257,
+/// which resets to 0 on the way down. The search is bounded by a generous guess
+/// first, then repeated with the exact bound if the guess was too small.
+///
+/// Returns `None` when `to` is 256, which cannot be reached. Any other value
+/// can be reached by decrementing to 0, then squaring and incrementing, without
+/// exceeding `max(to, 289)`.
+///
+/// To keep memory bounded, the search does not visit values above
+/// [`BFS_LIMIT`]. When the guess would exceed it, the path is found with
+/// [`sqrt_path`], and when only the exact bound would, the path found within
+/// the guess is kept, so the path is not necessarily the shortest for large
+/// numbers.
+#[must_use]
+pub fn bfs_path(from: u32, to: u32) -> Option<Vec<Inst>> {
+    if to == 256 {
+        return None;
+    }
+    let guess = 2 * u64::from(from.max(to).max(257));
+    if guess > BFS_LIMIT {
+        return sqrt_path(from, to);
+    }
+    let (from, to) = (u64::from(from), u64::from(to));
+    let path = bfs_bounded(from, to, guess)?;
+    let bound = from.max(to.max(257) + path.len() as u64);
+    if bound <= guess || bound > BFS_LIMIT {
+        return Some(path);
+    }
+    bfs_bounded(from, to, bound)
+}
+
+/// Searches for a shortest path that stays at or below `limit`.
+fn bfs_bounded(from: u64, to: u64, limit: u64) -> Option<Vec<Inst>> {
+    if from == to {
+        return Some(Vec::new());
+    }
+    let mut prev: Vec<Option<(u64, Inst)>> = vec![None; usize::try_from(limit).ok()? + 1];
+    let mut queue = VecDeque::new();
+    queue.push_back(from);
+    'search: while let Some(v) = queue.pop_front() {
+        for inst in [Inst::Inc, Inst::Dec, Inst::Square] {
+            let Some(next) = step(v, inst) else { continue };
+            if next > limit || next == from || prev[next as usize].is_some() {
+                continue;
+            }
+            prev[next as usize] = Some((v, inst));
+            if next == to {
+                break 'search;
+            }
+            queue.push_back(next);
+        }
+    }
+    let mut path = Vec::new();
+    let mut v = to;
+    while v != from {
+        let (p, inst) = prev[v as usize]?;
+        path.push(inst);
+        v = p;
+    }
+    path.reverse();
+    Some(path)
+}
+
+/// Finds a short sequence of `i`, `d`, and `s` instructions that changes the
+/// accumulator from `from` to `to`, by recursively reaching a square root near
+/// `to`, squaring it, then adjusting with `i` or `d`. This is much faster than
+/// [`bfs_path`] for large numbers, but not always the shortest.
+#[must_use]
+pub fn sqrt_path(from: u32, to: u32) -> Option<Vec<Inst>> {
+    let mut best = direct_path(from, to);
+    if to >= 4 {
+        let root = isqrt(to);
+        for r in [root, root + 1] {
+            // 16 squared resets to 0.
+            if r < 2 || r >= to || r == 16 {
+                continue;
+            }
+            let Ok(square) = u32::try_from(u64::from(r) * u64::from(r)) else {
+                continue;
+            };
+            let (Some(mut path), Some(adjust)) = (sqrt_path(from, r), direct_path(square, to))
+            else {
+                continue;
+            };
+            path.push(Inst::Square);
+            path.extend(adjust);
+            if best.as_ref().map_or(true, |best| path.len() < best.len()) {
+                best = Some(path);
+            }
+        }
+    }
+    best
+}
+
+/// Changes the accumulator with only `i` and `d`, if possible.
+fn direct_path(from: u32, to: u32) -> Option<Vec<Inst>> {
+    let repeat = |inst, n| iter::repeat(inst).take(n as usize);
+    if to == 256 {
+        None
+    } else if from <= to {
+        // Incrementing from below 256 to above it resets to 0.
+        (from > 256 || to < 256).then(|| repeat(Inst::Inc, to - from).collect())
+    } else if from > 256 && to < 256 {
+        // Decrement to 256, which resets to 0, then increment.
+        Some(
+            repeat(Inst::Dec, from - 256)
+                .chain(repeat(Inst::Inc, to))
+                .collect(),
+        )
+    } else if from < 256 && 256 - from + to < from - to {
+        // Increment to 256, which resets to 0, then increment.
+        Some(repeat(Inst::Inc, 256 - from + to).collect())
+    } else {
+        Some(repeat(Inst::Dec, from - to).collect())
+    }
+}
+
+/// Computes the integer square root, rounded down.
+#[allow(clippy::cast_sign_loss)]
+fn isqrt(n: u32) -> u32 {
+    let mut r = f64::from(n).sqrt() as u32;
+    while u64::from(r) * u64::from(r) > u64::from(n) {
+        r -= 1;
+    }
+    while u64::from(r + 1) * u64::from(r + 1) <= u64::from(n) {
+        r += 1;
+    }
+    r
+}
+
+/// Applies an instruction to a non-negative accumulator, including the reset
+/// at 256 and -1. `s` on values too large to square returns `None`.
+#[inline]
+fn step(v: u64, inst: Inst) -> Option<u64> {
+    let next = match inst {
+        Inst::Inc => v + 1,
+        Inst::Dec if v == 0 => 0,
+        Inst::Dec => v - 1,
+        Inst::Square => v.checked_mul(v)?,
+        Inst::Output => v,
+    };
+    Some(if next == 256 { 0 } else { next })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use rug::Integer;
+
+    use super::*;
+    use crate::deadfish::{interp::Interpreter, Mapping};
+
+    fn path(from: u32, to: u32) -> String {
+        Inst::emit_string(&bfs_path(from, to).unwrap(), &Mapping::default())
+    }
+
+    fn run(insts: &[Inst]) -> String {
+        let mut out = Vec::new();
+        Interpreter::new().run(insts, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn shortest() {
+        assert_eq!("", path(5, 5));
+        assert_eq!("iii", path(0, 3));
+        assert_eq!("iis", path(0, 4));
+        assert_eq!("iiss", path(0, 16));
+        assert_eq!("iissd", path(0, 15));
+        assert_eq!("iissis", path(0, 289));
+        assert_eq!("ii", path(255, 1));
+        assert_eq!("d", path(257, 0));
+        assert_eq!(None, bfs_path(0, 256));
+        assert_eq!(None, bfs_path(300, 256));
+    }
+
+    #[test]
+    fn encode_prints() {
+        let numbers = [72, 101, 108, 108, 111, 0, 300, 65535, 255, 257];
+        let expected = numbers.map(|n| n.to_string() + "\n").concat();
+        assert_eq!(expected, run(&bfs_encode(&numbers).unwrap()));
+        assert_eq!(expected, run(&sqrt_encode(&numbers).unwrap()));
+        assert_eq!(None, bfs_encode(&[1, 256]));
+        assert_eq!(None, sqrt_encode(&[1, 256]));
+    }
+
+    /// Runs the path from `from` and returns the final accumulator.
+    fn apply(from: u32, path: &[Inst]) -> Integer {
+        let mut interp = Interpreter::new();
+        interp
+            .run(&sqrt_path(0, from).unwrap(), io::sink())
+            .unwrap();
+        interp.run(path, io::sink()).unwrap();
+        interp.acc().clone()
+    }
+
+    #[test]
+    fn large() {
+        let pairs = [
+            (0, 60_000),
+            (65_000, 3),
+            (1000, 65_535),
+            (0, u32::MAX),
+            (300, 1_000_000),
+            (1 << 20, (1 << 20) + 3),
+        ];
+        for (from, to) in pairs {
+            let bfs = bfs_path(from, to).unwrap();
+            assert_eq!(to, apply(from, &bfs), "{from} -> {to}");
+            let sqrt = sqrt_path(from, to).unwrap();
+            if 2 * u64::from(from.max(to).max(257)) <= BFS_LIMIT {
+                assert!(bfs.len() <= sqrt.len(), "{from} -> {to}");
+            }
+        }
+    }
+
+    #[test]
+    fn sqrt_not_shorter() {
+        for from in [0, 17, 100, 255, 300] {
+            for to in (0..=300).filter(|&n| n != 256) {
+                let bfs = bfs_path(from, to).unwrap();
+                let sqrt = sqrt_path(from, to).unwrap();
+                assert!(bfs.len() <= sqrt.len(), "{from} -> {to}");
+                let mut interp = Interpreter::new();
+                let setup = bfs_path(0, from).unwrap();
+                interp.run(&setup, io::sink()).unwrap();
+                interp.run(&sqrt, io::sink()).unwrap();
+                assert_eq!(&to, interp.acc(), "{from} -> {to}");
+            }
+        }
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Deadfish interpreter.
+//!
+//! The accumulator is arbitrary-precision, so repeated squaring does not
+//! overflow. Each output is written in decimal, followed by a line feed.
+
+use std::io::{self, Write};
+
+use rug::Integer;
+
+use crate::deadfish::{ir::Ir, Inst};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Interpreter {
+    acc: Integer,
+}
+
+impl Interpreter {
+    #[inline]
+    #[must_use]
+    pub fn new() -> Self {
+        Interpreter { acc: Integer::new() }
+    }
+
+    /// Runs instructions with the current accumulator.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when writing to `stdout` fails.
+    pub fn run<W: Write>(&mut self, insts: &[Inst], mut stdout: W) -> io::Result<()> {
+        for &inst in insts {
+            self.step(inst, &mut stdout)?;
+        }
+        stdout.flush()
+    }
+
+    /// Runs IR with the current accumulator. It behaves the same as running
+    /// the instructions it was built from with [`Interpreter::run`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when writing to `stdout` fails.
+    pub fn run_ir<W: Write>(&mut self, ir: &[Ir], mut stdout: W) -> io::Result<()> {
+        for &ir in ir {
+            let (inst, n) = ir.run();
+            for _ in 0..n {
+                self.step(inst, &mut stdout)?;
+            }
+        }
+        stdout.flush()
+    }
+
+    #[inline]
+    fn step<W: Write>(&mut self, inst: Inst, stdout: &mut W) -> io::Result<()> {
+        match inst {
+            Inst::Inc => self.acc += 1,
+            Inst::Dec => self.acc -= 1,
+            Inst::Square => self.acc.square_mut(),
+            Inst::Output => writeln!(stdout, "{}", self.acc)?,
+        }
+        if self.acc == 256 || self.acc == -1 {
+            self.acc = Integer::new();
+        }
+        Ok(())
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn acc(&self) -> &Integer {
+        &self.acc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deadfish::Mapping;
+
+    fn run(src: &str) -> String {
+        let mut out = Vec::new();
+        let insts = Inst::parse(src, &Mapping::default());
+        Interpreter::new().run(&insts, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn reset() {
+        assert_eq!("0\n", run("do"));
+        assert_eq!("0\n", run("iiiisso"));
+        assert_eq!("289\n", run("iiiiiiiiiiiiiiiiiso"));
+        assert_eq!("0\n", run(&format!("{}o", "i".repeat(256))));
+    }
+}
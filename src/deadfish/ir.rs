@@ -0,0 +1,113 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Run-length IR for Deadfish.
+//!
+//! Runs are not folded arithmetically, because the reset at 256 and -1 makes,
+//! for example, `i` repeated 300 times differ from adding 300.
+
+use crate::deadfish::Inst;
+
+/// Deadfish IR node, which repeats an instruction a non-zero number of times.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Ir {
+    Inc(u32),
+    Dec(u32),
+    Square(u32),
+    Output(u32),
+}
+
+impl Ir {
+    /// Folds runs of identical instructions.
+    #[must_use]
+    pub fn from_insts(insts: &[Inst]) -> Vec<Ir> {
+        let mut ir: Vec<Ir> = Vec::new();
+        for &inst in insts {
+            match ir.last_mut() {
+                Some(last) if last.inst() == inst => last.set_count(last.count() + 1),
+                _ => ir.push(Ir::new(inst, 1)),
+            }
+        }
+        ir
+    }
+
+    /// Expands runs to instructions. This is the inverse of
+    /// [`Ir::from_insts`].
+    #[must_use]
+    pub fn to_insts(ir: &[Ir]) -> Vec<Inst> {
+        ir.iter()
+            .flat_map(|ir| {
+                let (inst, n) = ir.run();
+                (0..n).map(move |_| inst)
+            })
+            .collect()
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn new(inst: Inst, n: u32) -> Self {
+        match inst {
+            Inst::Inc => Ir::Inc(n),
+            Inst::Dec => Ir::Dec(n),
+            Inst::Square => Ir::Square(n),
+            Inst::Output => Ir::Output(n),
+        }
+    }
+
+    /// Returns the repeated instruction and the number of repetitions.
+    #[inline]
+    #[must_use]
+    pub const fn run(self) -> (Inst, u32) {
+        match self {
+            Ir::Inc(n) => (Inst::Inc, n),
+            Ir::Dec(n) => (Inst::Dec, n),
+            Ir::Square(n) => (Inst::Square, n),
+            Ir::Output(n) => (Inst::Output, n),
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn inst(self) -> Inst {
+        self.run().0
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn count(self) -> u32 {
+        self.run().1
+    }
+
+    #[inline]
+    fn set_count(&mut self, n: u32) {
+        *self = Ir::new(self.inst(), n);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deadfish::Mapping;
+
+    #[test]
+    fn fold() {
+        let insts = Inst::parse("iiisoodi", &Mapping::default());
+        let ir = Ir::from_insts(&insts);
+        assert_eq!(
+            vec![
+                Ir::Inc(3),
+                Ir::Square(1),
+                Ir::Output(2),
+                Ir::Dec(1),
+                Ir::Inc(1)
+            ],
+            ir,
+        );
+        assert_eq!(insts, Ir::to_insts(&ir));
+    }
+}
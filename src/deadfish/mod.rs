@@ -0,0 +1,154 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Deadfish language.
+//!
+//! Deadfish has a single accumulator, which starts at 0 and is reset to 0
+//! whenever it becomes 256 or -1.
+//!
+//! # Resources
+//!
+//! - [Esolang wiki](https://esolangs.org/wiki/Deadfish)
+
+use std::fmt::{self, Display, Formatter};
+
+pub mod encode;
+pub mod interp;
+pub mod ir;
+
+/// Deadfish instructions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Inst {
+    /// `i`
+    Inc,
+    /// `d`
+    Dec,
+    /// `s`
+    Square,
+    /// `o`
+    Output,
+}
+
+/// Mapping from characters to Deadfish instructions.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Mapping<T> {
+    inc: T,
+    dec: T,
+    square: T,
+    output: T,
+}
+
+impl<T: Eq> Mapping<T> {
+    /// Constructs a mapping, if all of the values are distinct.
+    #[inline]
+    #[must_use]
+    pub fn new(inc: T, dec: T, square: T, output: T) -> Option<Self> {
+        if inc == dec
+            || inc == square
+            || inc == output
+            || dec == square
+            || dec == output
+            || square == output
+        {
+            return None;
+        }
+        Some(Mapping { inc, dec, square, output })
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn map(&self, v: &T) -> Option<Inst> {
+        match v {
+            _ if v == &self.inc => Some(Inst::Inc),
+            _ if v == &self.dec => Some(Inst::Dec),
+            _ if v == &self.square => Some(Inst::Square),
+            _ if v == &self.output => Some(Inst::Output),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn map_inst(&self, inst: Inst) -> &T {
+        match inst {
+            Inst::Inc => &self.inc,
+            Inst::Dec => &self.dec,
+            Inst::Square => &self.square,
+            Inst::Output => &self.output,
+        }
+    }
+}
+
+impl Mapping<char> {
+    /// The XKCD variant, which uses `x k c d` for `i s o d`.
+    pub const XKCD: Self = Mapping {
+        inc: 'x',
+        dec: 'd',
+        square: 'k',
+        output: 'c',
+    };
+}
+
+impl Default for Mapping<char> {
+    #[inline]
+    fn default() -> Self {
+        Mapping {
+            inc: 'i',
+            dec: 'd',
+            square: 's',
+            output: 'o',
+        }
+    }
+}
+
+impl Inst {
+    /// Parses instructions with the given mapping. All other characters are
+    /// comments.
+    #[must_use]
+    pub fn parse(src: &str, mapping: &Mapping<char>) -> Vec<Inst> {
+        src.chars().filter_map(|ch| mapping.map(&ch)).collect()
+    }
+
+    /// Serializes instructions with the given mapping.
+    #[must_use]
+    pub fn emit_string(insts: &[Inst], mapping: &Mapping<char>) -> String {
+        insts.iter().map(|&inst| *mapping.map_inst(inst)).collect()
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn as_char(self) -> char {
+        match self {
+            Inst::Inc => 'i',
+            Inst::Dec => 'd',
+            Inst::Square => 's',
+            Inst::Output => 'o',
+        }
+    }
+}
+
+impl Display for Inst {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_char())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mappings() {
+        use Inst::*;
+        let insts = vec![Inc, Inc, Square, Dec, Output];
+        assert_eq!(insts, Inst::parse("ii s\nd o", &Mapping::default()));
+        assert_eq!(insts, Inst::parse("xxkdc", &Mapping::XKCD));
+        assert_eq!("xxkdc", Inst::emit_string(&insts, &Mapping::XKCD));
+        assert_eq!(None, Mapping::new('a', 'b', 'a', 'c'));
+    }
+}
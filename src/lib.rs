@@ -27,6 +27,7 @@
 )]
 
 pub mod bf;
+pub mod deadfish;
 pub mod syntax;
 pub mod text;
 pub mod ws;
@@ -8,13 +8,25 @@
 
 use std::ffi::OsStr;
 use std::fs;
+use std::io;
 use std::path::PathBuf;
+use std::process;
 
-use clap::{Args, Parser as CliParser, Subcommand};
+use clap::{Args, Parser as CliParser, Subcommand, ValueEnum};
+use nebula2::bf::{self, compile::compile_ws};
 use nebula2::ws::{
+    cfg::Cfg,
+    codegen::{c::emit_c, llvm::emit_llvm, rust::emit_rust, wasm::emit_wat, x86_64::emit_x86_64},
+    debug::Debugger,
+    decompile::decompile,
     inst::{Feature, Features, Inst, InstArg, InstError},
+    interp::{Interpreter, Limits},
+    ir::Ir,
     parse::Parser,
-    syntax::{IntLiteral, LabelLiteral},
+    profile::Profiler,
+    ssa::Ssa,
+    structured,
+    syntax::{IntLiteral, LabelLiteral, LabelOrder, Program},
     token::{bit_unpack_dynamic, lex_mapping, BitOrderDynamic, Lexer, Mapping, MappingLexer},
 };
 
@@ -28,10 +40,24 @@ struct Cli {
 #[derive(Debug, Subcommand)]
 enum Command {
     /// Disassemble the program to Whitespace assembly syntax.
-    Disasm(ProgramOptions),
+    Disasm(DisasmOptions),
     /// Detect the spec version (0.2 or 0.3) for a program and any non-standard
     /// instructions
     Features(ProgramOptions),
+    /// Compile a Brainfuck program to Whitespace
+    Bf2ws(Bf2wsOptions),
+    /// Compile the program and emit an intermediate representation
+    Compile(CompileOptions),
+    /// Decompile the program to structured pseudocode
+    Decompile(ProgramOptions),
+    /// Lower a program in the structured dialect to Whitespace
+    Lower(LowerOptions),
+    /// Run the program with the interpreter
+    Run(RunOptions),
+    /// Debug the program interactively
+    Debug(DebugOptions),
+    /// Run the program and report where it spends its instructions
+    Profile(ProfileOptions),
 }
 
 #[derive(Debug, Args)]
@@ -56,11 +82,135 @@ struct ProgramOptions {
     mapping_l: Option<String>,
 }
 
+#[derive(Debug, Args)]
+struct DisasmOptions {
+    #[command(flatten)]
+    program: ProgramOptions,
+    /// Group instructions by inferred subroutine
+    #[arg(long, default_value_t = false)]
+    structured: bool,
+}
+
+#[derive(Debug, Args)]
+struct Bf2wsOptions {
+    /// Path to Brainfuck program
+    #[arg(required = true)]
+    filename: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct LowerOptions {
+    /// Path to structured program
+    #[arg(required = true)]
+    filename: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct RunOptions {
+    #[command(flatten)]
+    program: ProgramOptions,
+    #[command(flatten)]
+    limits: LimitOptions,
+    /// Log each executed instruction with the stack to stderr
+    #[arg(long, default_value_t = false)]
+    trace: bool,
+}
+
+#[derive(Debug, Args)]
+struct DebugOptions {
+    #[command(flatten)]
+    program: ProgramOptions,
+    /// Log each executed instruction with the stack to stdout
+    #[arg(long, default_value_t = false)]
+    trace: bool,
+}
+
+#[derive(Debug, Args)]
+struct LimitOptions {
+    /// Stop after executing this many instructions
+    #[arg(long)]
+    max_insts: Option<u64>,
+    /// Limit the number of values on the stack
+    #[arg(long)]
+    max_stack: Option<usize>,
+    /// Limit the number of active calls
+    #[arg(long)]
+    max_calls: Option<usize>,
+    /// Limit the number of heap cells that are set
+    #[arg(long)]
+    max_heap: Option<usize>,
+    /// Limit the width of values in bits
+    #[arg(long)]
+    max_bits: Option<u32>,
+}
+
+impl From<LimitOptions> for Limits {
+    fn from(options: LimitOptions) -> Self {
+        Limits {
+            insts: options.max_insts,
+            stack: options.max_stack,
+            calls: options.max_calls,
+            heap: options.max_heap,
+            bits: options.max_bits,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct ProfileOptions {
+    #[command(flatten)]
+    program: ProgramOptions,
+    #[command(flatten)]
+    limits: LimitOptions,
+    /// Log each executed instruction with the stack to stderr
+    #[arg(long, default_value_t = false)]
+    trace: bool,
+    /// Write the call stacks in folded format for flame graphs
+    #[arg(long)]
+    folded: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct CompileOptions {
+    #[command(flatten)]
+    program: ProgramOptions,
+    /// Set the output format
+    #[arg(long, value_enum)]
+    emit: Emit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    /// Stack IR in basic blocks
+    Ir,
+    /// SSA IR with block parameters
+    Ssa,
+    /// Control-flow graph in Graphviz DOT format
+    CfgDot,
+    /// C source, to be linked with GMP
+    C,
+    /// LLVM IR text
+    Llvm,
+    /// WebAssembly text, importing `read_char` and `write_char`
+    Wat,
+    /// Rust source, depending on `rug`
+    Rust,
+    /// x86-64 GNU assembly for Linux
+    X86_64,
+}
+
 fn main() {
     let args = Cli::parse();
     match args.command {
-        Command::Disasm(program) => disassemble(program),
+        Command::Disasm(options) => disassemble(options),
         Command::Features(program) => detect_features(program),
+        Command::Bf2ws(options) => bf_to_ws(options),
+        Command::Compile(options) => compile(options),
+        Command::Decompile(program) => decompile_program(program),
+        Command::Lower(options) => lower(options),
+        Command::Run(options) => run(options),
+        Command::Debug(options) => debug(options),
+        Command::Profile(options) => profile(options),
     }
 }
 
@@ -95,8 +245,13 @@ fn parse(program: ProgramOptions) -> Parser<'static, Box<dyn Lexer>> {
     Parser::new(lex)
 }
 
-fn disassemble(program: ProgramOptions) {
-    for inst in parse(program) {
+fn disassemble(options: DisasmOptions) {
+    if options.structured {
+        let program = Program::new(parse(options.program).collect(), LabelOrder::Def);
+        print!("{}", program.call_graph());
+        return;
+    }
+    for inst in parse(options.program) {
         if let Inst::Error(err) = inst {
             println!("error: {err:?}");
         } else {
@@ -128,3 +283,116 @@ fn detect_features(program: ProgramOptions) {
         println!("- {feature}");
     }
 }
+
+fn bf_to_ws(options: Bf2wsOptions) {
+    let src = fs::read(&options.filename).unwrap();
+    let program = bf::Program::parse(&src);
+    match compile_ws(program.insts()) {
+        Ok(program) => print_ws(&program),
+        Err(err) => println!("error: {err:?}"),
+    }
+}
+
+fn lower(options: LowerOptions) {
+    let src = fs::read_to_string(&options.filename).unwrap();
+    let program = match structured::Program::parse(&src) {
+        Ok(program) => program,
+        Err(err) => return println!("error: {err:?}"),
+    };
+    match program.lower() {
+        Ok(program) => print_ws(&program),
+        Err(err) => println!("error: {err:?}"),
+    }
+}
+
+fn print_ws(program: &Program) {
+    let mapping = Mapping::<char>::default();
+    let ws = program
+        .to_tokens()
+        .into_iter()
+        .map(|tok| *mapping.map_token(tok))
+        .collect::<String>();
+    print!("{ws}");
+}
+
+fn compile(options: CompileOptions) {
+    let program = Program::new(parse(options.program).collect(), LabelOrder::Def);
+    let ir = Ir::new(&program);
+    match options.emit {
+        Emit::Ir => print!("{ir}"),
+        Emit::Ssa => match Ssa::new(&ir) {
+            Ok(ssa) => print!("{ssa}"),
+            Err(err) => println!("error: {err:?}"),
+        },
+        Emit::CfgDot => print!("{}", Cfg::new(&program, &ir).to_dot(&ir)),
+        Emit::C => print!("{}", emit_c(&ir)),
+        Emit::Llvm => match Ssa::new(&ir) {
+            Ok(ssa) => print!("{}", emit_llvm(&ssa)),
+            Err(err) => println!("error: {err:?}"),
+        },
+        Emit::Wat => print!("{}", emit_wat(&ir)),
+        Emit::Rust => match Ssa::new(&ir) {
+            Ok(ssa) => print!("{}", emit_rust(&ssa)),
+            Err(err) => println!("error: {err:?}"),
+        },
+        Emit::X86_64 => match Ssa::new(&ir) {
+            Ok(ssa) => print!("{}", emit_x86_64(&ssa)),
+            Err(err) => println!("error: {err:?}"),
+        },
+    }
+}
+
+fn run(options: RunOptions) {
+    let program = Program::new(parse(options.program).collect(), LabelOrder::Def);
+    let mut interp = Interpreter::new(&program);
+    interp.set_trace(options.trace);
+    interp.set_limits(options.limits.into());
+    let res = interp.run(
+        &mut io::stdin().lock(),
+        &mut io::stdout().lock(),
+        &mut io::stderr().lock(),
+    );
+    if let Err(err) = res {
+        eprintln!("\nerror: {err:?}");
+        process::exit(1);
+    }
+}
+
+fn debug(options: DebugOptions) {
+    let program = Program::new(parse(options.program).collect(), LabelOrder::Def);
+    let mut debugger = Debugger::new(&program);
+    debugger.interp_mut().set_trace(options.trace);
+    debugger
+        .repl(&mut io::stdin().lock(), &mut io::stdout().lock())
+        .unwrap();
+}
+
+fn profile(options: ProfileOptions) {
+    let program = Program::new(parse(options.program).collect(), LabelOrder::Def);
+    let mut profiler = Profiler::new(&program);
+    profiler.interp_mut().set_trace(options.trace);
+    profiler.interp_mut().set_limits(options.limits.into());
+    let res = profiler.run(
+        &mut io::stdin().lock(),
+        &mut io::stdout().lock(),
+        &mut io::stderr().lock(),
+    );
+    if let Err(err) = &res {
+        eprintln!("\nerror: {err:?}");
+    }
+    eprint!("{}", profiler.report());
+    if let Some(path) = options.folded {
+        fs::write(path, profiler.folded()).unwrap();
+    }
+    if res.is_err() {
+        process::exit(1);
+    }
+}
+
+fn decompile_program(program: ProgramOptions) {
+    let program = Program::new(parse(program).collect(), LabelOrder::Def);
+    match decompile(&program.call_graph()) {
+        Ok(pseudocode) => print!("{pseudocode}"),
+        Err(err) => println!("error: {err:?}"),
+    }
+}
@@ -0,0 +1,498 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Control-flow graph over the basic blocks of a program.
+//!
+//! Jump edges are constructed from the uses of each resolved label and
+//! fallthrough edges from the block exits. Calls and returns are modeled as an
+//! interprocedural edge set: `call` has an edge to the callee, and `ret` has
+//! an edge to every return site, since which one it returns to depends on the
+//! call stack.
+//!
+//! The graph of a single subroutine is instead intraprocedural: `call` has an
+//! edge to its return site when the callee returns, and `ret` has none.
+
+use std::fmt::Write;
+
+use crate::ws::inst::Inst;
+use crate::ws::ir::{BlockId, Exit, Ir};
+use crate::ws::subroutine::{CallGraph, SubId};
+use crate::ws::syntax::Program;
+
+/// Control-flow graph with dominators and loops.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Cfg {
+    entry: BlockId,
+    edges: Vec<Edge>,
+    succs: Vec<Vec<usize>>,
+    preds: Vec<Vec<usize>>,
+    reachable: Vec<bool>,
+    /// Immediate dominator of each reachable block, except the entry.
+    idoms: Vec<Option<BlockId>>,
+    /// Reachable blocks in reverse postorder.
+    rpo: Vec<BlockId>,
+    loops: Vec<Loop>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Edge {
+    pub from: BlockId,
+    pub to: BlockId,
+    pub kind: EdgeKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EdgeKind {
+    /// Continues to the next block, including when `jz` or `jn` is not taken.
+    Fallthrough,
+    /// `jmp`
+    Jmp,
+    /// `jz` when taken.
+    Jz,
+    /// `jn` when taken.
+    Jn,
+    /// `call` to the callee.
+    Call,
+    /// `ret` to a return site.
+    Ret,
+    /// `call` to its return site, when the callee returns. It is only in the
+    /// graph of a subroutine.
+    CallReturn,
+}
+
+/// Natural loop, which is the union of the loops of the back edges to its
+/// header.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Loop {
+    pub header: BlockId,
+    /// Sources of the back edges to the header.
+    pub latches: Vec<BlockId>,
+    /// Blocks in the loop, including the header, in ascending order.
+    pub body: Vec<BlockId>,
+}
+
+impl Cfg {
+    /// Constructs the control-flow graph for the blocks of a program.
+    #[must_use]
+    pub fn new(program: &Program, ir: &Ir) -> Self {
+        let len = ir.blocks().len();
+        let insts = program.insts();
+        let mut edges = Vec::new();
+
+        for label in program.labels() {
+            let to = ir.label_block(label.id());
+            for &use_ in label.uses() {
+                let kind = match insts[use_] {
+                    Inst::Jmp(_) => EdgeKind::Jmp,
+                    Inst::Jz(_) => EdgeKind::Jz,
+                    Inst::Jn(_) => EdgeKind::Jn,
+                    Inst::Call(_) => EdgeKind::Call,
+                    _ => unreachable!("label use is not a jump"),
+                };
+                edges.push(Edge {
+                    from: ir.inst_block(use_),
+                    to,
+                    kind,
+                });
+            }
+        }
+        let ret_sites = ir.ret_sites();
+        for block in ir.blocks() {
+            let from = block.id();
+            match *block.exit() {
+                Exit::Fallthrough(to) | Exit::Jz { other: to, .. } | Exit::Jn { other: to, .. } => {
+                    edges.push(Edge {
+                        from,
+                        to,
+                        kind: EdgeKind::Fallthrough,
+                    });
+                }
+                Exit::Ret => {
+                    for &to in &ret_sites {
+                        edges.push(Edge { from, to, kind: EdgeKind::Ret });
+                    }
+                }
+                _ => {}
+            }
+        }
+        Cfg::from_edges(len, BlockId(0), edges)
+    }
+
+    /// Constructs the intraprocedural control-flow graph for the body of a
+    /// subroutine, with the subroutine entry as the entry. Blocks outside of
+    /// the body are unreachable.
+    #[must_use]
+    pub fn subroutine(graph: &CallGraph, sub: SubId) -> Self {
+        let ir = graph.ir();
+        let mut edges = Vec::new();
+        for &from in graph[sub].blocks() {
+            let mut edge = |to, kind| edges.push(Edge { from, to, kind });
+            match *ir[from].exit() {
+                Exit::Fallthrough(to) => edge(to, EdgeKind::Fallthrough),
+                Exit::Jmp(to) => edge(to, EdgeKind::Jmp),
+                Exit::Jz { zero, other } => {
+                    edge(zero, EdgeKind::Jz);
+                    edge(other, EdgeKind::Fallthrough);
+                }
+                Exit::Jn { neg, other } => {
+                    edge(neg, EdgeKind::Jn);
+                    edge(other, EdgeKind::Fallthrough);
+                }
+                Exit::Call { callee, ret } => {
+                    let subs = graph.subroutines();
+                    if subs.iter().any(|s| s.entry() == callee && s.returns()) {
+                        edge(ret, EdgeKind::CallReturn);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Cfg::from_edges(ir.blocks().len(), graph[sub].entry(), edges)
+    }
+
+    fn from_edges(len: usize, entry: BlockId, mut edges: Vec<Edge>) -> Self {
+        edges.sort_unstable();
+        edges.dedup();
+
+        let mut succs = vec![Vec::new(); len];
+        let mut preds = vec![Vec::new(); len];
+        for (i, edge) in edges.iter().enumerate() {
+            succs[usize::from(edge.from)].push(i);
+            preds[usize::from(edge.to)].push(i);
+        }
+        let mut cfg = Cfg {
+            entry,
+            edges,
+            succs,
+            preds,
+            reachable: vec![false; len],
+            idoms: vec![None; len],
+            rpo: Vec::new(),
+            loops: Vec::new(),
+        };
+        cfg.compute_rpo();
+        cfg.compute_dominators();
+        cfg.compute_loops();
+        cfg
+    }
+
+    /// Computes reachability and reverse postorder with an iterative
+    /// depth-first search from the entry.
+    fn compute_rpo(&mut self) {
+        let mut postorder = Vec::new();
+        let mut stack = vec![(self.entry, 0)];
+        self.reachable[usize::from(self.entry)] = true;
+        while let Some((b, i)) = stack.last_mut() {
+            let b = *b;
+            if let Some(&e) = self.succs[usize::from(b)].get(*i) {
+                *i += 1;
+                let to = self.edges[e].to;
+                if !self.reachable[usize::from(to)] {
+                    self.reachable[usize::from(to)] = true;
+                    stack.push((to, 0));
+                }
+            } else {
+                postorder.push(b);
+                stack.pop();
+            }
+        }
+        postorder.reverse();
+        self.rpo = postorder;
+    }
+
+    /// Computes immediate dominators with the algorithm from “A Simple, Fast
+    /// Dominance Algorithm” by Cooper, Harvey, and Kennedy.
+    fn compute_dominators(&mut self) {
+        let mut order = vec![usize::MAX; self.succs.len()];
+        for (i, &b) in self.rpo.iter().enumerate() {
+            order[usize::from(b)] = i;
+        }
+        // The entry is its own dominator during the computation.
+        let entry = usize::from(self.entry);
+        self.idoms[entry] = Some(self.entry);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in self.rpo.iter().skip(1) {
+                let mut new_idom = None;
+                for &e in &self.preds[usize::from(b)] {
+                    let p = self.edges[e].from;
+                    if self.idoms[usize::from(p)].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => p,
+                        Some(idom) => intersect(&self.idoms, &order, p, idom),
+                    });
+                }
+                if new_idom != self.idoms[usize::from(b)] {
+                    self.idoms[usize::from(b)] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        self.idoms[entry] = None;
+    }
+
+    /// Finds natural loops from back edges, which are edges to a block that
+    /// dominates the source.
+    fn compute_loops(&mut self) {
+        let mut loops: Vec<Loop> = Vec::new();
+        for edge in &self.edges {
+            if !self.reachable[usize::from(edge.from)] || !self.dominates(edge.to, edge.from) {
+                continue;
+            }
+            let header = edge.to;
+            let mut in_body = vec![false; self.succs.len()];
+            in_body[usize::from(header)] = true;
+            let mut stack = vec![edge.from];
+            while let Some(b) = stack.pop() {
+                if in_body[usize::from(b)] {
+                    continue;
+                }
+                in_body[usize::from(b)] = true;
+                for &e in &self.preds[usize::from(b)] {
+                    let p = self.edges[e].from;
+                    if self.reachable[usize::from(p)] {
+                        stack.push(p);
+                    }
+                }
+            }
+            let body = (0..in_body.len())
+                .filter(|&b| in_body[b])
+                .map(BlockId::from)
+                .collect::<Vec<_>>();
+            if let Some(l) = loops.iter_mut().find(|l| l.header == header) {
+                l.latches.push(edge.from);
+                let mut merged = l.body.clone();
+                merged.extend(body);
+                merged.sort_unstable();
+                merged.dedup();
+                l.body = merged;
+            } else {
+                loops.push(Loop {
+                    header,
+                    latches: vec![edge.from],
+                    body,
+                });
+            }
+        }
+        self.loops = loops;
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn entry(&self) -> BlockId {
+        self.entry
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn edges(&self) -> &[Edge] {
+        &self.edges
+    }
+
+    /// Edges out of a block.
+    pub fn succs(&self, b: BlockId) -> impl Iterator<Item = &Edge> + '_ {
+        self.succs[usize::from(b)].iter().map(|&e| &self.edges[e])
+    }
+
+    /// Edges into a block.
+    pub fn preds(&self, b: BlockId) -> impl Iterator<Item = &Edge> + '_ {
+        self.preds[usize::from(b)].iter().map(|&e| &self.edges[e])
+    }
+
+    /// Returns whether the block is reachable from the entry.
+    #[inline]
+    #[must_use]
+    pub fn is_reachable(&self, b: BlockId) -> bool {
+        self.reachable[usize::from(b)]
+    }
+
+    /// Reachable blocks in reverse postorder.
+    #[inline]
+    #[must_use]
+    pub fn rpo(&self) -> &[BlockId] {
+        &self.rpo
+    }
+
+    /// Immediate dominator of a block. It is `None` for the entry and
+    /// unreachable blocks.
+    #[inline]
+    #[must_use]
+    pub fn idom(&self, b: BlockId) -> Option<BlockId> {
+        self.idoms[usize::from(b)]
+    }
+
+    /// Returns whether `a` dominates `b`. Every reachable block dominates
+    /// itself.
+    #[must_use]
+    pub fn dominates(&self, a: BlockId, b: BlockId) -> bool {
+        if !self.is_reachable(b) {
+            return false;
+        }
+        let mut b = Some(b);
+        while let Some(dom) = b {
+            if dom == a {
+                return true;
+            }
+            b = self.idom(dom);
+        }
+        false
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn loops(&self) -> &[Loop] {
+        &self.loops
+    }
+
+    /// Writes the graph in Graphviz DOT format, with each block labeled by
+    /// its stack IR. Unreachable blocks are gray and back edges are bold.
+    #[must_use]
+    pub fn to_dot(&self, ir: &Ir) -> String {
+        let mut dot = String::new();
+        dot.push_str("digraph cfg {\n");
+        dot.push_str("  node [shape=box, fontname=monospace];\n");
+        for block in ir.blocks() {
+            let id = block.id();
+            let label = block
+                .to_string()
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\l");
+            let color = if self.is_reachable(id) {
+                ""
+            } else {
+                ", color=gray, fontcolor=gray"
+            };
+            writeln!(dot, "  {id} [label=\"{label}\"{color}];").unwrap();
+        }
+        for edge in &self.edges {
+            let mut attrs = Vec::new();
+            match edge.kind {
+                EdgeKind::Fallthrough | EdgeKind::Jmp => {}
+                EdgeKind::Jz => attrs.push("label=\"z\""),
+                EdgeKind::Jn => attrs.push("label=\"n\""),
+                EdgeKind::Call | EdgeKind::CallReturn => attrs.push("style=dashed"),
+                EdgeKind::Ret => attrs.push("style=dotted"),
+            }
+            if self.is_reachable(edge.from) && self.dominates(edge.to, edge.from) {
+                attrs.push("penwidth=2");
+            }
+            write!(dot, "  {} -> {}", edge.from, edge.to).unwrap();
+            if !attrs.is_empty() {
+                write!(dot, " [{}]", attrs.join(", ")).unwrap();
+            }
+            dot.push_str(";\n");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+fn intersect(idoms: &[Option<BlockId>], order: &[usize], a: BlockId, b: BlockId) -> BlockId {
+    let (mut a, mut b) = (a, b);
+    while a != b {
+        while order[usize::from(a)] > order[usize::from(b)] {
+            a = idoms[usize::from(a)].unwrap();
+        }
+        while order[usize::from(b)] > order[usize::from(a)] {
+            b = idoms[usize::from(b)].unwrap();
+        }
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use bitvec::prelude::*;
+
+    use super::*;
+    use crate::ws::syntax::LabelOrder;
+    use crate::ws::tests::get_tutorial_insts;
+
+    #[test]
+    fn tutorial() {
+        let program = Program::new(get_tutorial_insts(), LabelOrder::Def);
+        let ir = Ir::new(&program);
+        let cfg = Cfg::new(&program, &ir);
+        let b = BlockId;
+        assert_eq!(&[b(0), b(1), b(3), b(2)], cfg.rpo());
+        assert!(!cfg.is_reachable(b(4)));
+        assert_eq!(None, cfg.idom(b(0)));
+        assert_eq!(Some(b(0)), cfg.idom(b(1)));
+        assert_eq!(Some(b(1)), cfg.idom(b(2)));
+        assert_eq!(Some(b(1)), cfg.idom(b(3)));
+        assert_eq!(
+            &[Loop {
+                header: b(1),
+                latches: vec![b(2)],
+                body: vec![b(1), b(2)]
+            }],
+            cfg.loops(),
+        );
+        let dot = cfg.to_dot(&ir);
+        assert!(dot.contains("block_1 -> block_3 [label=\"z\"];"));
+        assert!(dot.contains("block_2 -> block_1 [penwidth=2];"));
+    }
+
+    #[test]
+    fn calls() {
+        let insts = vec![
+            Inst::Call(bitvec![1]),
+            Inst::Call(bitvec![1]),
+            Inst::End,
+            Inst::Label(bitvec![1]),
+            Inst::Ret,
+        ];
+        let program = Program::new(insts, LabelOrder::Def);
+        let ir = Ir::new(&program);
+        let cfg = Cfg::new(&program, &ir);
+        let edges = cfg
+            .edges()
+            .iter()
+            .map(|e| (e.from.0, e.to.0, e.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (0, 3, EdgeKind::Call),
+                (1, 3, EdgeKind::Call),
+                (3, 1, EdgeKind::Ret),
+                (3, 2, EdgeKind::Ret),
+            ],
+            edges,
+        );
+        assert!((0..4).all(|i| cfg.is_reachable(BlockId(i))));
+        // Return edges are context-insensitive, so the second call appears to
+        // be reached in a cycle through the callee.
+        assert_eq!(
+            &[Loop {
+                header: BlockId(3),
+                latches: vec![BlockId(1)],
+                body: vec![BlockId(1), BlockId(3)]
+            }],
+            cfg.loops(),
+        );
+
+        // Within the main subroutine, each call continues at its return site.
+        let graph = CallGraph::new(ir);
+        let cfg = Cfg::subroutine(&graph, SubId(0));
+        let edges = cfg
+            .edges()
+            .iter()
+            .map(|e| (e.from.0, e.to.0, e.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![(0, 1, EdgeKind::CallReturn), (1, 2, EdgeKind::CallReturn)],
+            edges,
+        );
+        assert!(!cfg.is_reachable(BlockId(3)));
+        assert!(cfg.loops().is_empty());
+    }
+}
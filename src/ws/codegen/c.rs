@@ -0,0 +1,293 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! C backend.
+//!
+//! Each block becomes a sequence of calls into the runtime in a single `main`
+//! function. Jumps and calls are `goto`s to block labels, and `ret` pops a
+//! return site index from an explicit call stack and dispatches on it with a
+//! `switch`. The runtime keeps values as `int64_t` and falls back to GMP when
+//! arithmetic overflows, so the output must be linked with `-lgmp`.
+
+use std::fmt::Write;
+
+use crate::ws::inst::Inst;
+use crate::ws::ir::{BlockId, Exit, Ir};
+use crate::ws::syntax::ProgramInst;
+
+const RUNTIME: &str = include_str!("runtime.c");
+
+/// Compiles the blocks of a program to C.
+#[must_use]
+pub fn emit_c(ir: &Ir) -> String {
+    let ret_sites = ir.ret_sites();
+    let targeted = ir.jump_targets();
+    // Return sites are numbered in order of the calls.
+    let mut site = 0;
+
+    let mut c = String::new();
+    c.push_str(RUNTIME);
+    c.push_str("\nint main(void) {\n");
+    for block in ir.blocks() {
+        let id = block.id();
+        if targeted[usize::from(id)] {
+            writeln!(c, "{id}:;").unwrap();
+        }
+        for inst in block.body() {
+            writeln!(c, "    {}", emit_inst(inst)).unwrap();
+        }
+        let next = BlockId(id.0 + 1);
+        let goto = |c: &mut String, to: BlockId| {
+            if to != next {
+                writeln!(c, "    goto {to};").unwrap();
+            }
+        };
+        match *block.exit() {
+            Exit::Fallthrough(to) | Exit::Jmp(to) => goto(&mut c, to),
+            Exit::Jz { zero, other } => {
+                writeln!(c, "    if (ws_pop_zero()) goto {zero};").unwrap();
+                goto(&mut c, other);
+            }
+            Exit::Jn { neg, other } => {
+                writeln!(c, "    if (ws_pop_neg()) goto {neg};").unwrap();
+                goto(&mut c, other);
+            }
+            Exit::Call { callee, .. } => {
+                writeln!(c, "    ws_call({site});").unwrap();
+                writeln!(c, "    goto {callee};").unwrap();
+                site += 1;
+            }
+            Exit::Ret => {
+                c.push_str("    switch (ws_ret()) {\n");
+                for (site, ret) in ret_sites.iter().enumerate() {
+                    writeln!(c, "    case {site}: goto {ret};").unwrap();
+                }
+                c.push_str("    }\n");
+            }
+            Exit::End => c.push_str("    ws_end();\n"),
+            Exit::Unterminated => c.push_str("    ws_error(\"unterminated program\");\n"),
+            Exit::UndefinedLabel(l) => writeln!(c, "    ws_error(\"undefined {l}\");").unwrap(),
+            Exit::Error(_) => c.push_str("    ws_error(\"invalid instruction\");\n"),
+        }
+    }
+    c.push_str("}\n");
+    c
+}
+
+fn emit_inst(inst: &ProgramInst) -> String {
+    match inst {
+        Inst::Push(n) => match n.to_i64() {
+            Some(i64::MIN) => "ws_push_small(INT64_MIN);".to_owned(),
+            Some(n) => format!("ws_push_small({n});"),
+            None => format!("ws_push_big(\"{}\");", **n),
+        },
+        Inst::Dup => "ws_dup();".to_owned(),
+        Inst::Copy(n) => match n.to_i64() {
+            Some(n) if n >= 0 => format!("ws_copy({n});"),
+            _ => "ws_error(\"copy out of range\");".to_owned(),
+        },
+        Inst::Swap => "ws_swap();".to_owned(),
+        Inst::Drop => "ws_drop();".to_owned(),
+        Inst::Slide(n) => {
+            let n = n.to_i64().unwrap_or(if **n < 0 { 0 } else { i64::MAX });
+            format!("ws_slide({});", n.max(0))
+        }
+        Inst::Add => "ws_add();".to_owned(),
+        Inst::Sub => "ws_sub();".to_owned(),
+        Inst::Mul => "ws_mul();".to_owned(),
+        Inst::Div => "ws_div();".to_owned(),
+        Inst::Mod => "ws_mod();".to_owned(),
+        Inst::Store => "ws_store();".to_owned(),
+        Inst::Retrieve => "ws_retrieve();".to_owned(),
+        Inst::Printc => "ws_printc();".to_owned(),
+        Inst::Printi => "ws_printi();".to_owned(),
+        Inst::Readc => "ws_readc();".to_owned(),
+        Inst::Readi => "ws_readi();".to_owned(),
+        Inst::DumpStack => "ws_dump_stack();".to_owned(),
+        Inst::DumpHeap => "ws_dump_heap();".to_owned(),
+        Inst::Shuffle | Inst::DumpTrace => {
+            format!("ws_error(\"unsupported instruction: {}\");", inst.opcode())
+        }
+        Inst::Label(_)
+        | Inst::Call(_)
+        | Inst::Jmp(_)
+        | Inst::Jz(_)
+        | Inst::Jn(_)
+        | Inst::Ret
+        | Inst::End
+        | Inst::Error(_) => unreachable!("terminator in block body"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use bitvec::prelude::*;
+    use rug::Integer;
+
+    use super::*;
+    use crate::ws::codegen::tests::{build_and_run, print_each, push};
+    use crate::ws::inst::RawInst;
+    use crate::ws::syntax::{LabelOrder, Program};
+    use crate::ws::tests::get_tutorial_insts;
+
+    fn compile(insts: Vec<RawInst>) -> String {
+        emit_c(&Ir::new(&Program::new(insts, LabelOrder::Def)))
+    }
+
+    fn run(name: &str, c: &str) -> Option<String> {
+        build_and_run(name, "c", c, |src, exe| {
+            let mut cmd = Command::new("cc");
+            cmd.arg(src).arg("-o").arg(exe).arg("-lgmp");
+            vec![cmd]
+        })
+    }
+
+    fn pairs(pairs: &[(i64, i64)]) -> Vec<(Integer, Integer)> {
+        let pairs = pairs
+            .iter()
+            .map(|&(a, b)| (Integer::from(a), Integer::from(b)));
+        pairs.collect()
+    }
+
+    #[test]
+    fn tutorial() {
+        let program = Program::new(get_tutorial_insts(), LabelOrder::Def);
+        let c = emit_c(&Ir::new(&program));
+        let main = &c[c.find("int main(void) {\n").unwrap()..];
+        let expected = "\
+int main(void) {
+    ws_push_small(1);
+block_1:;
+    ws_dup();
+    ws_printi();
+    ws_push_small(10);
+    ws_printc();
+    ws_push_small(1);
+    ws_add();
+    ws_dup();
+    ws_push_small(11);
+    ws_sub();
+    if (ws_pop_zero()) goto block_3;
+    goto block_1;
+block_3:;
+    ws_drop();
+    ws_end();
+    ws_error(\"unterminated program\");
+}
+";
+        assert_eq!(expected, main);
+    }
+
+    #[test]
+    fn call_ret() {
+        // Three call sites, one of them nested. Each `ret` dispatches on all
+        // of the sites.
+        let c = compile(vec![
+            push(1),
+            Inst::Call(bitvec![1]),
+            push(2),
+            Inst::Call(bitvec![1]),
+            Inst::Call(bitvec![1, 0]),
+            Inst::End,
+            Inst::Label(bitvec![1]),
+            Inst::Printi,
+            Inst::Ret,
+            Inst::Label(bitvec![1, 0]),
+            push(3),
+            Inst::Call(bitvec![1]),
+            Inst::Ret,
+        ]);
+        for site in 0..3 {
+            assert!(c.contains(&format!("    ws_call({site});\n")), "{c}");
+            assert!(c.contains(&format!("    case {site}: goto ")), "{c}");
+        }
+        assert_eq!(2, c.matches("switch (ws_ret())").count());
+        if let Some(out) = run("call_ret", &c) {
+            assert_eq!("123", out);
+        }
+    }
+
+    #[test]
+    fn floor_div_mod() {
+        let pairs = pairs(&[(7, 2), (-7, 2), (7, -2), (-7, -2), (-6, 3), (i64::MIN, -1)]);
+        let mut insts = print_each(&pairs, &Inst::Div);
+        insts.extend(print_each(&pairs, &Inst::Mod));
+        insts.push(Inst::End);
+        if let Some(out) = run("floor_div_mod", &compile(insts)) {
+            let div = "3\n-4\n-4\n3\n-2\n9223372036854775808\n";
+            let rem = "1\n1\n-1\n-1\n0\n0\n";
+            assert_eq!(format!("{div}{rem}"), out);
+        }
+    }
+
+    #[test]
+    fn heap() {
+        // Addresses in different pages, including negative addresses, and a
+        // value that needs GMP.
+        let cells = [(-1, 5), (4096, 6), (0, 7), (1 << 40, 8), (-1, 9)];
+        let mut insts = Vec::new();
+        for (addr, n) in cells {
+            insts.extend([push(addr), push(n), Inst::Store]);
+        }
+        insts.extend([push(1), push(i64::MAX), push(2), Inst::Mul, Inst::Store]);
+        for addr in [-1, 4096, 0, 1 << 40, 12345, 1] {
+            insts.extend([
+                push(addr),
+                Inst::Retrieve,
+                Inst::Printi,
+                push(32),
+                Inst::Printc,
+            ]);
+        }
+        insts.push(Inst::End);
+        if let Some(out) = run("heap", &compile(insts)) {
+            assert_eq!("9 6 7 8 0 18446744073709551614 ", out);
+        }
+    }
+
+    #[test]
+    fn gmp_fallback() {
+        let max = i64::MAX;
+        let mut insts = print_each(&pairs(&[(max, 1), (i64::MIN, 1)]), &Inst::Add);
+        insts.extend(print_each(&pairs(&[(i64::MIN, 1), (max, -1)]), &Inst::Sub));
+        insts.extend(print_each(
+            &pairs(&[(max, max), (i64::MIN, -1)]),
+            &Inst::Mul,
+        ));
+        // Overflow then demote back to 64 bits, which is compared as zero.
+        insts.extend([
+            push(max),
+            push(1),
+            Inst::Add,
+            push(max),
+            Inst::Sub,
+            push(1),
+            Inst::Sub,
+        ]);
+        insts.extend([
+            Inst::Jz(bitvec![1]),
+            push(0),
+            Inst::Printi,
+            Inst::Label(bitvec![1]),
+        ]);
+        insts.push(Inst::End);
+        if let Some(out) = run("gmp_fallback", &compile(insts)) {
+            let expected = "\
+9223372036854775808
+-9223372036854775807
+-9223372036854775809
+9223372036854775808
+85070591730234615847396907784232501249
+9223372036854775808
+";
+            assert_eq!(expected, out);
+        }
+    }
+}
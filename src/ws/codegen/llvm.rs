@@ -0,0 +1,552 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! LLVM IR backend.
+//!
+//! Emits a textual LLVM module from the SSA IR, with block parameters as phi
+//! nodes, together with a small runtime written in LLVM IR for IO, the heap,
+//! and the call stack. The output links against only libc, so `llc` and
+//! `clang` can build it directly.
+//!
+//! The output uses opaque pointers, so it needs LLVM 15 or later. LLVM 14 can
+//! build it when `-opaque-pointers` is passed to `llc`, or `-Xclang
+//! -opaque-pointers` to `clang`, and older versions cannot.
+//!
+//! Values are `i64`. Arithmetic that [`Ranges`] proves to fit is emitted
+//! unchecked and the rest traps on overflow, so programs that need larger
+//! integers fail with an error rather than compute wrong results.
+
+use std::fmt::Write;
+
+use crate::ws::ir::BlockId;
+use crate::ws::range::{Ranges, Width};
+use crate::ws::ssa::{Block, Edge, Exit, Op, Ssa, ValueId};
+
+const RUNTIME: &str = include_str!("runtime.ll");
+
+/// Compiles an SSA program to a textual LLVM module.
+#[must_use]
+pub fn emit_llvm(ssa: &Ssa) -> String {
+    Emitter::new(ssa).emit()
+}
+
+struct Emitter<'a> {
+    ssa: &'a Ssa,
+    ranges: Ranges,
+    /// Operands for the values, which are literals for constants.
+    operands: Vec<String>,
+    /// Incoming predecessors and arguments of each block.
+    incoming: Vec<Vec<(String, Vec<ValueId>)>>,
+    strings: Vec<String>,
+}
+
+impl<'a> Emitter<'a> {
+    fn new(ssa: &'a Ssa) -> Self {
+        let operands = (0..ssa.values().len())
+            .map(|v| format!("%{}", ValueId::from(v)))
+            .collect();
+        Emitter {
+            ssa,
+            ranges: Ranges::new(ssa),
+            operands,
+            incoming: vec![Vec::new(); ssa.blocks().len()],
+            strings: Vec::new(),
+        }
+    }
+
+    fn emit(mut self) -> String {
+        let ssa = self.ssa;
+        let ret_sites = ssa
+            .blocks()
+            .iter()
+            .filter_map(|b| match b.exit() {
+                Exit::Call { ret, .. } => Some(*ret),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        // Constants are used as literals, so resolve them before any uses.
+        for block in ssa.blocks() {
+            for stmt in block.stmts() {
+                if let (Op::Const(n), Some(def)) = (&stmt.op, stmt.def) {
+                    self.operands[usize::from(def)] = n.to_i64().unwrap_or(0).to_string();
+                }
+            }
+        }
+
+        // Collect the predecessors of each block for the phis.
+        let mut entry = String::new();
+        if ssa.blocks()[0].params().is_empty() {
+            self.incoming[0].push(("entry".to_owned(), Vec::new()));
+            entry.push_str("  br label %b0\n");
+        } else {
+            let msg = self.string("stack underflow");
+            writeln!(entry, "  call void @ws_error(ptr {msg})\n  unreachable").unwrap();
+        }
+        for block in ssa.blocks() {
+            let from = block_label(block.id());
+            match block.exit() {
+                Exit::Jmp(e) | Exit::Call { callee: e, .. } => self.add_edge(&from, e),
+                Exit::Jz { zero: e1, other: e2, .. } | Exit::Jn { neg: e1, other: e2, .. } => {
+                    if e1.target == e2.target {
+                        self.add_edge(&format!("{from}.t"), e1);
+                        self.add_edge(&format!("{from}.f"), e2);
+                    } else {
+                        self.add_edge(&from, e1);
+                        self.add_edge(&from, e2);
+                    }
+                }
+                Exit::Ret(args) => {
+                    for &site in &ret_sites {
+                        self.incoming[usize::from(site)].push((from.clone(), args.clone()));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut body = String::new();
+        let mut site = 0;
+        for block in ssa.blocks() {
+            self.emit_block(&mut body, block, &ret_sites, &mut site);
+        }
+
+        let mut ll = String::new();
+        ll.push_str(RUNTIME);
+        ll.push('\n');
+        for (i, s) in self.strings.iter().enumerate() {
+            let len = s.len() + 1;
+            let s = s
+                .bytes()
+                .map(|b| match b {
+                    b' '..=b'~' if b != b'"' && b != b'\\' => char::from(b).to_string(),
+                    _ => format!("\\{b:02X}"),
+                })
+                .collect::<String>();
+            writeln!(
+                ll,
+                "@.str.{i} = private unnamed_addr constant [{len} x i8] c\"{s}\\00\""
+            )
+            .unwrap();
+        }
+        ll.push_str("\ndefine i32 @main() {\nentry:\n");
+        ll.push_str(&entry);
+        ll.push_str(&body);
+        ll.push_str("}\n");
+        ll
+    }
+
+    /// Emits a block, where `site` counts the calls before it.
+    fn emit_block(
+        &mut self,
+        body: &mut String,
+        block: &Block,
+        ret_sites: &[BlockId],
+        site: &mut usize,
+    ) {
+        let id = block.id();
+        let label = block_label(id);
+        writeln!(body, "{label}:").unwrap();
+        let incoming = &self.incoming[usize::from(id)];
+        for (i, &param) in block.params().iter().enumerate() {
+            if incoming.is_empty() {
+                // The block has no predecessors.
+                writeln!(body, "  %{param} = add i64 0, 0").unwrap();
+                continue;
+            }
+            let pairs = incoming
+                .iter()
+                .map(|(pred, args)| format!("[ {}, %{pred} ]", self.operand(args[i])))
+                .collect::<Vec<_>>();
+            writeln!(body, "  %{param} = phi i64 {}", pairs.join(", ")).unwrap();
+        }
+        for stmt in block.stmts() {
+            self.emit_stmt(body, stmt.def, &stmt.op);
+        }
+        match block.exit() {
+            Exit::Jmp(e) => writeln!(body, "  br label %{}", block_label(e.target)).unwrap(),
+            Exit::Jz { cond, zero, other } => {
+                let c = self.operand(*cond);
+                writeln!(body, "  %{label}.c = icmp eq i64 {c}, 0").unwrap();
+                emit_branch(body, &label, zero, other);
+            }
+            Exit::Jn { cond, neg, other } => {
+                let c = self.operand(*cond);
+                writeln!(body, "  %{label}.c = icmp slt i64 {c}, 0").unwrap();
+                emit_branch(body, &label, neg, other);
+            }
+            Exit::Call { callee, .. } => {
+                writeln!(body, "  call void @ws_call(i64 {site})").unwrap();
+                writeln!(body, "  br label %{}", block_label(callee.target)).unwrap();
+                *site += 1;
+            }
+            Exit::Ret(_) => {
+                writeln!(body, "  %{label}.r = call i64 @ws_ret()").unwrap();
+                write!(body, "  switch i64 %{label}.r, label %{label}.bad [").unwrap();
+                for (i, &ret) in ret_sites.iter().enumerate() {
+                    write!(body, " i64 {i}, label %{}", block_label(ret)).unwrap();
+                }
+                writeln!(body, " ]\n{label}.bad:\n  unreachable").unwrap();
+            }
+            Exit::End => body.push_str("  call i32 @fflush(ptr null)\n  ret i32 0\n"),
+            Exit::Unterminated => self.emit_error(body, "unterminated program"),
+            Exit::UndefinedLabel(l) => self.emit_error(body, &format!("undefined {l}")),
+            Exit::Error(_) => self.emit_error(body, "invalid instruction"),
+        }
+    }
+
+    fn add_edge(&mut self, from: &str, edge: &Edge) {
+        self.incoming[usize::from(edge.target)].push((from.to_owned(), edge.args.clone()));
+    }
+
+    fn emit_stmt(&mut self, body: &mut String, def: Option<ValueId>, op: &Op) {
+        let def_name = def.map(|v| format!("%{v}")).unwrap_or_default();
+        match *op {
+            Op::Const(ref n) => {
+                if n.to_i64().is_none() {
+                    self.emit_call_error(body, "integer does not fit in 64 bits");
+                }
+            }
+            Op::Add(a, b) | Op::Sub(a, b) | Op::Mul(a, b) => {
+                let (inst, intrinsic) = match op {
+                    Op::Add(..) => ("add", "sadd"),
+                    Op::Sub(..) => ("sub", "ssub"),
+                    _ => ("mul", "smul"),
+                };
+                let (a, b) = (self.operand(a), self.operand(b));
+                if def.map_or(true, |v| self.ranges.width(v) == Width::I64) {
+                    writeln!(body, "  {def_name} = {inst} nsw i64 {a}, {b}").unwrap();
+                } else {
+                    writeln!(
+                        body,
+                        "  {def_name}.o = call {{ i64, i1 }} @llvm.{intrinsic}.with.overflow.i64(i64 {a}, i64 {b})\n  \
+                         {def_name} = extractvalue {{ i64, i1 }} {def_name}.o, 0\n  \
+                         {def_name}.f = extractvalue {{ i64, i1 }} {def_name}.o, 1\n  \
+                         call void @ws_check(i1 {def_name}.f)"
+                    )
+                    .unwrap();
+                }
+            }
+            Op::Div(a, b) | Op::Mod(a, b) => {
+                let f = if let Op::Div(..) = op {
+                    "ws_div"
+                } else {
+                    "ws_mod"
+                };
+                let (a, b) = (self.operand(a), self.operand(b));
+                writeln!(body, "  {def_name} = call i64 @{f}(i64 {a}, i64 {b})").unwrap();
+            }
+            Op::Store { addr, value } => {
+                let (addr, value) = (self.operand(addr), self.operand(value));
+                writeln!(body, "  call void @ws_store(i64 {addr}, i64 {value})").unwrap();
+            }
+            Op::Retrieve(addr) => {
+                let addr = self.operand(addr);
+                writeln!(body, "  {def_name} = call i64 @ws_retrieve(i64 {addr})").unwrap();
+            }
+            Op::Printc(v) | Op::Printi(v) | Op::Readc(v) | Op::Readi(v) => {
+                let f = match op {
+                    Op::Printc(_) => "ws_printc",
+                    Op::Printi(_) => "ws_printi",
+                    Op::Readc(_) => "ws_readc",
+                    _ => "ws_readi",
+                };
+                writeln!(body, "  call void @{f}(i64 {})", self.operand(v)).unwrap();
+            }
+            Op::DumpHeap => body.push_str("  call void @ws_dump_heap()\n"),
+            Op::DumpStack => self.emit_call_error(body, "unsupported instruction: dump_stack"),
+            Op::DumpTrace => self.emit_call_error(body, "unsupported instruction: dump_trace"),
+        }
+    }
+
+    fn emit_call_error(&mut self, body: &mut String, msg: &str) {
+        let msg = self.string(msg);
+        writeln!(body, "  call void @ws_error(ptr {msg})").unwrap();
+    }
+
+    fn emit_error(&mut self, body: &mut String, msg: &str) {
+        self.emit_call_error(body, msg);
+        body.push_str("  unreachable\n");
+    }
+
+    /// Interns a string constant and returns its global name.
+    fn string(&mut self, s: &str) -> String {
+        let i = self.strings.iter().position(|t| t == s).unwrap_or_else(|| {
+            self.strings.push(s.to_owned());
+            self.strings.len() - 1
+        });
+        format!("@.str.{i}")
+    }
+
+    fn operand(&self, v: ValueId) -> &str {
+        &self.operands[usize::from(v)]
+    }
+}
+
+fn emit_branch(body: &mut String, label: &str, t: &Edge, f: &Edge) {
+    if t.target == f.target {
+        // Phis distinguish edges by their predecessor, so give each edge
+        // its own block.
+        let target = block_label(t.target);
+        writeln!(
+            body,
+            "  br i1 %{label}.c, label %{label}.t, label %{label}.f"
+        )
+        .unwrap();
+        writeln!(body, "{label}.t:\n  br label %{target}").unwrap();
+        writeln!(body, "{label}.f:\n  br label %{target}").unwrap();
+    } else {
+        let (t, f) = (block_label(t.target), block_label(f.target));
+        writeln!(body, "  br i1 %{label}.c, label %{t}, label %{f}").unwrap();
+    }
+}
+
+fn block_label(id: BlockId) -> String {
+    format!("b{}", id.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use bitvec::prelude::*;
+    use rug::Integer;
+
+    use super::*;
+    use crate::ws::codegen::tests::{build_and_run, print_each, push};
+    use crate::ws::inst::{Inst, RawInst};
+    use crate::ws::ir::Ir;
+    use crate::ws::syntax::{LabelOrder, Program};
+    use crate::ws::tests::get_tutorial_insts;
+
+    /// Compiles the instructions and returns `main`.
+    fn compile(insts: Vec<RawInst>) -> String {
+        let program = Program::new(insts, LabelOrder::Def);
+        let ll = emit_llvm(&Ssa::new(&Ir::new(&program)).unwrap());
+        ll[ll.find("define i32 @main() {\n").unwrap()..].to_owned()
+    }
+
+    fn run(name: &str, insts: Vec<RawInst>) -> Option<String> {
+        let program = Program::new(insts, LabelOrder::Def);
+        let ll = emit_llvm(&Ssa::new(&Ir::new(&program)).unwrap());
+        build_and_run(name, "ll", &ll, |src, exe| {
+            let mut cmd = Command::new("clang");
+            cmd.arg(src).arg("-o").arg(exe);
+            vec![cmd]
+        })
+    }
+
+    #[test]
+    fn tutorial() {
+        let program = Program::new(get_tutorial_insts(), LabelOrder::Def);
+        let ssa = Ssa::new(&Ir::new(&program)).unwrap();
+        let ll = emit_llvm(&ssa);
+        let main = &ll[ll.find("define i32 @main() {\n").unwrap()..];
+        let expected = "\
+define i32 @main() {
+entry:
+  br label %b0
+b0:
+  br label %b1
+b1:
+  %v1 = phi i64 [ 1, %b0 ], [ %v7, %b2 ]
+  call void @ws_printi(i64 %v1)
+  call void @ws_printc(i64 10)
+  %v4.o = call { i64, i1 } @llvm.sadd.with.overflow.i64(i64 %v1, i64 1)
+  %v4 = extractvalue { i64, i1 } %v4.o, 0
+  %v4.f = extractvalue { i64, i1 } %v4.o, 1
+  call void @ws_check(i1 %v4.f)
+  %v6.o = call { i64, i1 } @llvm.ssub.with.overflow.i64(i64 %v4, i64 11)
+  %v6 = extractvalue { i64, i1 } %v6.o, 0
+  %v6.f = extractvalue { i64, i1 } %v6.o, 1
+  call void @ws_check(i1 %v6.f)
+  %b1.c = icmp eq i64 %v6, 0
+  br i1 %b1.c, label %b3, label %b2
+b2:
+  %v7 = phi i64 [ %v4, %b1 ]
+  br label %b1
+b3:
+  %v8 = phi i64 [ %v4, %b1 ]
+  call i32 @fflush(ptr null)
+  ret i32 0
+b4:
+  call void @ws_error(ptr @.str.0)
+  unreachable
+}
+";
+        assert_eq!(expected, main);
+    }
+
+    #[test]
+    fn jz_same_targets() {
+        // Both edges of the `jz` pass the same value to the next block, so
+        // each gets its own block for the phi to tell them apart.
+        let main = compile(vec![
+            push(0),
+            push(5),
+            Inst::Store,
+            push(0),
+            Inst::Retrieve,
+            Inst::Dup,
+            Inst::Jz(bitvec![1]),
+            Inst::Label(bitvec![1]),
+            Inst::Printi,
+            Inst::End,
+        ]);
+        let expected = "\
+define i32 @main() {
+entry:
+  br label %b0
+b0:
+  call void @ws_store(i64 0, i64 5)
+  %v3 = call i64 @ws_retrieve(i64 0)
+  %b0.c = icmp eq i64 %v3, 0
+  br i1 %b0.c, label %b0.t, label %b0.f
+b0.t:
+  br label %b1
+b0.f:
+  br label %b1
+b1:
+  %v4 = phi i64 [ %v3, %b0.t ], [ %v3, %b0.f ]
+  call void @ws_printi(i64 %v4)
+  call i32 @fflush(ptr null)
+  ret i32 0
+b2:
+  call void @ws_error(ptr @.str.0)
+  unreachable
+}
+";
+        assert_eq!(expected, main);
+    }
+
+    #[test]
+    fn ret_switch() {
+        // Each `ret` switches on the call sites, numbered in block order, and
+        // the phi at the callee entry takes the argument from each caller.
+        let main = compile(vec![
+            push(1),
+            Inst::Call(bitvec![1]),
+            push(2),
+            Inst::Call(bitvec![1]),
+            Inst::Call(bitvec![1, 0]),
+            Inst::End,
+            Inst::Label(bitvec![1]),
+            Inst::Printi,
+            Inst::Ret,
+            Inst::Label(bitvec![1, 0]),
+            push(3),
+            Inst::Call(bitvec![1]),
+            Inst::Ret,
+        ]);
+        let expected = "\
+define i32 @main() {
+entry:
+  br label %b0
+b0:
+  call void @ws_call(i64 0)
+  br label %b4
+b1:
+  call void @ws_call(i64 1)
+  br label %b4
+b2:
+  call void @ws_call(i64 2)
+  br label %b5
+b3:
+  call i32 @fflush(ptr null)
+  ret i32 0
+b4:
+  %v2 = phi i64 [ 1, %b0 ], [ 2, %b1 ], [ 3, %b5 ]
+  call void @ws_printi(i64 %v2)
+  %b4.r = call i64 @ws_ret()
+  switch i64 %b4.r, label %b4.bad [ i64 0, label %b1 i64 1, label %b2 i64 2, label %b3 i64 3, label %b6 ]
+b4.bad:
+  unreachable
+b5:
+  call void @ws_call(i64 3)
+  br label %b4
+b6:
+  %b6.r = call i64 @ws_ret()
+  switch i64 %b6.r, label %b6.bad [ i64 0, label %b1 i64 1, label %b2 i64 2, label %b3 i64 3, label %b6 ]
+b6.bad:
+  unreachable
+b7:
+  call void @ws_error(ptr @.str.0)
+  unreachable
+}
+";
+        assert_eq!(expected, main);
+    }
+
+    #[test]
+    fn overflow_checks() {
+        // A value read from input may be any size, so adding to it is checked,
+        // but its remainder is small, so adding to that is not.
+        let main = compile(vec![
+            push(0),
+            Inst::Readi,
+            push(0),
+            Inst::Retrieve,
+            push(1),
+            Inst::Add,
+            Inst::Printi,
+            push(0),
+            Inst::Retrieve,
+            push(100),
+            Inst::Mod,
+            push(1),
+            Inst::Add,
+            Inst::Printi,
+            Inst::End,
+        ]);
+        let expected = "\
+define i32 @main() {
+entry:
+  br label %b0
+b0:
+  call void @ws_readi(i64 0)
+  %v2 = call i64 @ws_retrieve(i64 0)
+  %v4.o = call { i64, i1 } @llvm.sadd.with.overflow.i64(i64 %v2, i64 1)
+  %v4 = extractvalue { i64, i1 } %v4.o, 0
+  %v4.f = extractvalue { i64, i1 } %v4.o, 1
+  call void @ws_check(i1 %v4.f)
+  call void @ws_printi(i64 %v4)
+  %v6 = call i64 @ws_retrieve(i64 0)
+  %v8 = call i64 @ws_mod(i64 %v6, i64 100)
+  %v10 = add nsw i64 %v8, 1
+  call void @ws_printi(i64 %v10)
+  call i32 @fflush(ptr null)
+  ret i32 0
+b1:
+  call void @ws_error(ptr @.str.0)
+  unreachable
+}
+";
+        assert_eq!(expected, main);
+    }
+
+    #[test]
+    fn run_tutorial() {
+        if let Some(out) = run("llvm_tutorial", get_tutorial_insts()) {
+            assert_eq!("1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n", out);
+        }
+    }
+
+    #[test]
+    fn run_floor_div_mod() {
+        let pairs = [(7, 2), (-7, 2), (7, -2), (-7, -2), (-6, 3), (i64::MIN, 1)];
+        let pairs = pairs.map(|(a, b)| (Integer::from(a), Integer::from(b)));
+        let mut insts = print_each(&pairs, &Inst::Div);
+        insts.extend(print_each(&pairs, &Inst::Mod));
+        insts.push(Inst::End);
+        if let Some(out) = run("llvm_floor_div_mod", insts) {
+            let div = "3\n-4\n-4\n3\n-2\n-9223372036854775808\n";
+            let rem = "1\n1\n-1\n-1\n0\n0\n";
+            assert_eq!(format!("{div}{rem}"), out);
+        }
+    }
+}
@@ -0,0 +1,18 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Code generation from the stack IR to other languages.
+
+pub mod c;
+pub mod llvm;
+pub mod rust;
+pub mod wasm;
+pub mod x86_64;
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,519 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+// Runtime for Whitespace programs compiled to C by nebula2.
+//
+// Values are 64-bit integers until an operation overflows, after which they
+// are promoted to GMP integers. Results that fit in a long are demoted again.
+
+#include <inttypes.h>
+#include <limits.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include <gmp.h>
+
+typedef struct {
+    int64_t i;
+    // When non-null, the value is this GMP integer and i is unused.
+    mpz_ptr z;
+} ws_value;
+
+static ws_value *ws_stack;
+static size_t ws_sp, ws_stack_cap;
+static int *ws_calls;
+static size_t ws_csp, ws_calls_cap;
+
+#define WS_PAGE_BITS 12
+#define WS_PAGE_SIZE ((int64_t)1 << WS_PAGE_BITS)
+
+typedef struct {
+    int64_t page;
+    ws_value *cells;
+} ws_page;
+
+// Open-addressed hash table of heap pages, keyed by page number.
+static ws_page *ws_heap;
+static size_t ws_heap_len, ws_heap_cap;
+
+static inline void ws_error(const char *msg) {
+    fflush(stdout);
+    fprintf(stderr, "error: %s\n", msg);
+    exit(1);
+}
+
+static inline void *ws_alloc(void *ptr, size_t n, size_t size) {
+    if (size != 0 && n > SIZE_MAX / size) {
+        ws_error("out of memory");
+    }
+    ptr = realloc(ptr, n * size);
+    if (!ptr && n != 0) {
+        ws_error("out of memory");
+    }
+    return ptr;
+}
+
+static inline void ws_mpz_set_i64(mpz_ptr z, int64_t v) {
+    if (v >= LONG_MIN && v <= LONG_MAX) {
+        mpz_set_si(z, (long)v);
+    } else {
+        uint64_t mag = v < 0 ? -(uint64_t)v : (uint64_t)v;
+        mpz_import(z, 1, 1, sizeof mag, 0, 0, &mag);
+        if (v < 0) {
+            mpz_neg(z, z);
+        }
+    }
+}
+
+static inline mpz_ptr ws_mpz_new(void) {
+    mpz_ptr z = ws_alloc(NULL, 1, sizeof(__mpz_struct));
+    mpz_init(z);
+    return z;
+}
+
+static inline void ws_free(ws_value *v) {
+    if (v->z) {
+        mpz_clear(v->z);
+        free(v->z);
+        v->z = NULL;
+    }
+}
+
+static inline ws_value ws_clone(const ws_value *v) {
+    ws_value c = {v->i, NULL};
+    if (v->z) {
+        c.z = ws_mpz_new();
+        mpz_set(c.z, v->z);
+    }
+    return c;
+}
+
+// Takes ownership of z and demotes it when it fits.
+static inline ws_value ws_from_mpz(mpz_ptr z) {
+    ws_value v = {0, z};
+    if (mpz_fits_slong_p(z)) {
+        v.i = mpz_get_si(z);
+        mpz_clear(z);
+        free(z);
+        v.z = NULL;
+    }
+    return v;
+}
+
+static inline void ws_to_mpz(mpz_ptr out, const ws_value *v) {
+    if (v->z) {
+        mpz_set(out, v->z);
+    } else {
+        ws_mpz_set_i64(out, v->i);
+    }
+}
+
+static inline int ws_sign(const ws_value *v) {
+    return v->z ? mpz_sgn(v->z) : (v->i > 0) - (v->i < 0);
+}
+
+static inline void ws_need(size_t n) {
+    if (ws_sp < n) {
+        ws_error("stack underflow");
+    }
+}
+
+static inline void ws_push(ws_value v) {
+    if (ws_sp == ws_stack_cap) {
+        ws_stack_cap = ws_stack_cap ? ws_stack_cap * 2 : 1024;
+        ws_stack = ws_alloc(ws_stack, ws_stack_cap, sizeof *ws_stack);
+    }
+    ws_stack[ws_sp++] = v;
+}
+
+static inline ws_value ws_pop(void) {
+    ws_need(1);
+    return ws_stack[--ws_sp];
+}
+
+static inline void ws_push_small(int64_t i) {
+    ws_value v = {i, NULL};
+    ws_push(v);
+}
+
+static inline void ws_push_big(const char *dec) {
+    mpz_ptr z = ws_mpz_new();
+    mpz_set_str(z, dec, 10);
+    ws_push(ws_from_mpz(z));
+}
+
+static inline void ws_dup(void) {
+    ws_need(1);
+    ws_push(ws_clone(&ws_stack[ws_sp - 1]));
+}
+
+static inline void ws_copy(int64_t n) {
+    if (n < 0 || (uint64_t)n >= ws_sp) {
+        ws_error("copy out of range");
+    }
+    ws_push(ws_clone(&ws_stack[ws_sp - 1 - (size_t)n]));
+}
+
+static inline void ws_swap(void) {
+    ws_need(2);
+    ws_value t = ws_stack[ws_sp - 1];
+    ws_stack[ws_sp - 1] = ws_stack[ws_sp - 2];
+    ws_stack[ws_sp - 2] = t;
+}
+
+static inline void ws_drop(void) {
+    ws_value v = ws_pop();
+    ws_free(&v);
+}
+
+static inline void ws_slide(int64_t n) {
+    ws_need(1);
+    ws_value top = ws_pop();
+    for (; n > 0 && ws_sp > 0; n--) {
+        ws_drop();
+    }
+    ws_push(top);
+}
+
+typedef void (*ws_mpz_op)(mpz_ptr, mpz_srcptr, mpz_srcptr);
+
+static inline void ws_big_op(ws_mpz_op op, ws_value *a, ws_value *b) {
+    mpz_ptr x = ws_mpz_new(), y = ws_mpz_new();
+    ws_to_mpz(x, a);
+    ws_to_mpz(y, b);
+    op(x, x, y);
+    mpz_clear(y);
+    free(y);
+    ws_free(a);
+    ws_free(b);
+    *a = ws_from_mpz(x);
+}
+
+// Checked arithmetic: each stores the result and returns 0, or returns 1
+// without storing when it would overflow.
+
+static inline int ws_add_overflow(int64_t a, int64_t b, int64_t *r) {
+    if ((b > 0 && a > INT64_MAX - b) || (b < 0 && a < INT64_MIN - b)) {
+        return 1;
+    }
+    *r = a + b;
+    return 0;
+}
+
+static inline int ws_sub_overflow(int64_t a, int64_t b, int64_t *r) {
+    if ((b < 0 && a > INT64_MAX + b) || (b > 0 && a < INT64_MIN + b)) {
+        return 1;
+    }
+    *r = a - b;
+    return 0;
+}
+
+static inline int ws_mul_overflow(int64_t a, int64_t b, int64_t *r) {
+    if (a > 0 ? (b > 0 ? a > INT64_MAX / b : b < INT64_MIN / a)
+              : (b > 0 ? a < INT64_MIN / b : a != 0 && b < INT64_MAX / a)) {
+        return 1;
+    }
+    *r = a * b;
+    return 0;
+}
+
+#define WS_ARITH(name, checked, mpz_fn)                                        \
+    static inline void name(void) {                                            \
+        ws_need(2);                                                            \
+        ws_value *a = &ws_stack[ws_sp - 2], *b = &ws_stack[ws_sp - 1];         \
+        int64_t r;                                                             \
+        if (!a->z && !b->z && !checked(a->i, b->i, &r)) {                      \
+            a->i = r;                                                          \
+        } else {                                                               \
+            ws_big_op(mpz_fn, a, b);                                           \
+        }                                                                      \
+        ws_sp--;                                                               \
+    }
+
+WS_ARITH(ws_add, ws_add_overflow, mpz_add)
+WS_ARITH(ws_sub, ws_sub_overflow, mpz_sub)
+WS_ARITH(ws_mul, ws_mul_overflow, mpz_mul)
+
+// Division and modulo round towards negative infinity.
+static inline void ws_divmod(int want_rem) {
+    ws_need(2);
+    ws_value *a = &ws_stack[ws_sp - 2], *b = &ws_stack[ws_sp - 1];
+    if (ws_sign(b) == 0) {
+        ws_error("division by zero");
+    }
+    if (!a->z && !b->z && !(a->i == INT64_MIN && b->i == -1)) {
+        int64_t q = a->i / b->i, r = a->i % b->i;
+        if (r != 0 && (r < 0) != (b->i < 0)) {
+            q--;
+            r += b->i;
+        }
+        a->i = want_rem ? r : q;
+    } else {
+        ws_big_op(want_rem ? mpz_fdiv_r : mpz_fdiv_q, a, b);
+    }
+    ws_sp--;
+}
+
+static inline void ws_div(void) { ws_divmod(0); }
+static inline void ws_mod(void) { ws_divmod(1); }
+
+static inline int64_t ws_addr(const ws_value *v) {
+    if (v->z) {
+        ws_error("heap address out of range");
+    }
+    return v->i;
+}
+
+static inline size_t ws_hash(int64_t page) {
+    uint64_t h = (uint64_t)page * UINT64_C(0x9e3779b97f4a7c15);
+    return (size_t)(h >> 32);
+}
+
+// Returns the cell at the address, allocating its page when create is set,
+// or NULL when the page does not exist.
+static inline ws_value *ws_cell(int64_t addr, int create) {
+    int64_t page = addr >> WS_PAGE_BITS;
+    if (ws_heap_cap) {
+        for (size_t i = ws_hash(page) & (ws_heap_cap - 1);; i = (i + 1) & (ws_heap_cap - 1)) {
+            if (!ws_heap[i].cells) {
+                break;
+            }
+            if (ws_heap[i].page == page) {
+                return &ws_heap[i].cells[addr & (WS_PAGE_SIZE - 1)];
+            }
+        }
+    }
+    if (!create) {
+        return NULL;
+    }
+    if (2 * (ws_heap_len + 1) > ws_heap_cap) {
+        size_t old_cap = ws_heap_cap;
+        ws_page *old = ws_heap;
+        ws_heap_cap = old_cap ? old_cap * 2 : 16;
+        ws_heap = ws_alloc(NULL, ws_heap_cap, sizeof *ws_heap);
+        memset(ws_heap, 0, ws_heap_cap * sizeof *ws_heap);
+        for (size_t j = 0; j < old_cap; j++) {
+            if (old[j].cells) {
+                size_t i = ws_hash(old[j].page) & (ws_heap_cap - 1);
+                while (ws_heap[i].cells) {
+                    i = (i + 1) & (ws_heap_cap - 1);
+                }
+                ws_heap[i] = old[j];
+            }
+        }
+        free(old);
+    }
+    size_t i = ws_hash(page) & (ws_heap_cap - 1);
+    while (ws_heap[i].cells) {
+        i = (i + 1) & (ws_heap_cap - 1);
+    }
+    ws_heap[i].page = page;
+    ws_heap[i].cells = ws_alloc(NULL, WS_PAGE_SIZE, sizeof(ws_value));
+    memset(ws_heap[i].cells, 0, WS_PAGE_SIZE * sizeof(ws_value));
+    ws_heap_len++;
+    return &ws_heap[i].cells[addr & (WS_PAGE_SIZE - 1)];
+}
+
+static inline void ws_store_at(int64_t addr, ws_value v) {
+    ws_value *cell = ws_cell(addr, 1);
+    ws_free(cell);
+    *cell = v;
+}
+
+static inline void ws_store(void) {
+    ws_need(2);
+    ws_value v = ws_pop();
+    ws_value addr = ws_pop();
+    ws_store_at(ws_addr(&addr), v);
+    ws_free(&addr);
+}
+
+static inline void ws_retrieve(void) {
+    ws_need(1);
+    ws_value *top = &ws_stack[ws_sp - 1];
+    ws_value *cell = ws_cell(ws_addr(top), 0);
+    // Unset cells are 0.
+    ws_value v = {0, NULL};
+    if (cell) {
+        v = ws_clone(cell);
+    }
+    *top = v;
+}
+
+static inline void ws_printc(void) {
+    ws_value v = ws_pop();
+    if (v.z || v.i < 0 || v.i > 0x10ffff || (v.i >= 0xd800 && v.i <= 0xdfff)) {
+        ws_error("invalid character");
+    }
+    uint32_t c = (uint32_t)v.i;
+    if (c < 0x80) {
+        putchar((int)c);
+    } else if (c < 0x800) {
+        putchar((int)(0xc0 | c >> 6));
+        putchar((int)(0x80 | (c & 0x3f)));
+    } else if (c < 0x10000) {
+        putchar((int)(0xe0 | c >> 12));
+        putchar((int)(0x80 | (c >> 6 & 0x3f)));
+        putchar((int)(0x80 | (c & 0x3f)));
+    } else {
+        putchar((int)(0xf0 | c >> 18));
+        putchar((int)(0x80 | (c >> 12 & 0x3f)));
+        putchar((int)(0x80 | (c >> 6 & 0x3f)));
+        putchar((int)(0x80 | (c & 0x3f)));
+    }
+}
+
+static inline void ws_print_value(FILE *f, const ws_value *v) {
+    if (v->z) {
+        mpz_out_str(f, 10, v->z);
+    } else {
+        fprintf(f, "%" PRId64, v->i);
+    }
+}
+
+static inline void ws_printi(void) {
+    ws_value v = ws_pop();
+    ws_print_value(stdout, &v);
+    ws_free(&v);
+}
+
+static inline int ws_getc(void) {
+    fflush(stdout);
+    int c = getchar();
+    if (c == EOF) {
+        ws_error("end of input");
+    }
+    return c;
+}
+
+static inline void ws_readc(void) {
+    ws_need(1);
+    ws_value addr = ws_pop();
+    int c = ws_getc();
+    int64_t ch = c;
+    int extra = c >= 0xf0 ? 3 : c >= 0xe0 ? 2 : c >= 0xc0 ? 1 : 0;
+    if (extra) {
+        ch = c & (0x3f >> extra);
+        for (int i = 0; i < extra; i++) {
+            ch = ch << 6 | (ws_getc() & 0x3f);
+        }
+    }
+    ws_value v = {ch, NULL};
+    ws_store_at(ws_addr(&addr), v);
+    ws_free(&addr);
+}
+
+static inline void ws_readi(void) {
+    ws_need(1);
+    ws_value addr = ws_pop();
+    char *line = NULL;
+    size_t len = 0, cap = 0;
+    for (int c = ws_getc(); c != '\n'; c = getchar()) {
+        if (c == EOF) {
+            break;
+        }
+        if (len + 1 >= cap) {
+            cap = cap ? cap * 2 : 64;
+            line = ws_alloc(line, cap, 1);
+        }
+        line[len++] = (char)c;
+    }
+    if (!line) {
+        ws_error("invalid integer");
+    }
+    line[len] = '\0';
+    char *start = line;
+    while (*start == ' ' || *start == '\t') {
+        start++;
+    }
+    while (len > 0 && (line[len - 1] == ' ' || line[len - 1] == '\t' || line[len - 1] == '\r')) {
+        line[--len] = '\0';
+    }
+    mpz_ptr z = ws_mpz_new();
+    if (*start == '+' || mpz_set_str(z, start, 10) != 0) {
+        ws_error("invalid integer");
+    }
+    free(line);
+    ws_store_at(ws_addr(&addr), ws_from_mpz(z));
+    ws_free(&addr);
+}
+
+static inline int ws_pop_zero(void) {
+    ws_value v = ws_pop();
+    int s = ws_sign(&v);
+    ws_free(&v);
+    return s == 0;
+}
+
+static inline int ws_pop_neg(void) {
+    ws_value v = ws_pop();
+    int s = ws_sign(&v);
+    ws_free(&v);
+    return s < 0;
+}
+
+static inline void ws_call(int site) {
+    if (ws_csp == ws_calls_cap) {
+        ws_calls_cap = ws_calls_cap ? ws_calls_cap * 2 : 256;
+        ws_calls = ws_alloc(ws_calls, ws_calls_cap, sizeof *ws_calls);
+    }
+    ws_calls[ws_csp++] = site;
+}
+
+static inline int ws_ret(void) {
+    if (ws_csp == 0) {
+        ws_error("return with empty call stack");
+    }
+    return ws_calls[--ws_csp];
+}
+
+static inline void ws_dump_stack(void) {
+    fflush(stdout);
+    for (size_t i = 0; i < ws_sp; i++) {
+        if (i != 0) {
+            fputc(' ', stderr);
+        }
+        ws_print_value(stderr, &ws_stack[i]);
+    }
+    fputc('\n', stderr);
+}
+
+static inline int ws_cmp_page(const void *a, const void *b) {
+    int64_t x = ((const ws_page *)a)->page, y = ((const ws_page *)b)->page;
+    return (x > y) - (x < y);
+}
+
+static inline void ws_dump_heap(void) {
+    fflush(stdout);
+    ws_page *pages = ws_alloc(NULL, ws_heap_len, sizeof *pages);
+    size_t n = 0;
+    for (size_t i = 0; i < ws_heap_cap; i++) {
+        if (ws_heap[i].cells) {
+            pages[n++] = ws_heap[i];
+        }
+    }
+    qsort(pages, n, sizeof *pages, ws_cmp_page);
+    for (size_t i = 0; i < n; i++) {
+        for (int64_t j = 0; j < WS_PAGE_SIZE; j++) {
+            ws_value *v = &pages[i].cells[j];
+            if (v->z || v->i != 0) {
+                fprintf(stderr, "%" PRId64 ": ", pages[i].page * WS_PAGE_SIZE + j);
+                ws_print_value(stderr, v);
+                fputc('\n', stderr);
+            }
+        }
+    }
+    free(pages);
+}
+
+static inline void ws_end(void) {
+    fflush(stdout);
+    exit(0);
+}
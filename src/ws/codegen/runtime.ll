@@ -0,0 +1,361 @@
+; Copyright (C) 2022 Thalia Archibald
+;
+; Nebula 2 is free software: you can redistribute it and/or modify it under the
+; terms of the GNU Lesser General Public License as published by the Free
+; Software Foundation, either version 3 of the License, or (at your option) any
+; later version. You should have received a copy of the GNU Lesser General
+; Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+; Runtime for Whitespace programs compiled to LLVM IR by nebula2.
+;
+; Values are i64 and arithmetic that may overflow is checked. The heap is a
+; dense array of i64 that grows to the largest address, and the call stack is
+; an array of return site indices.
+
+@ws_heap = internal global ptr null
+@ws_heap_cap = internal global i64 0
+@ws_calls = internal global ptr null
+@ws_calls_len = internal global i64 0
+@ws_calls_cap = internal global i64 0
+
+@.err_fmt = private unnamed_addr constant [11 x i8] c"error: %s\0A\00"
+@.i64_fmt = private unnamed_addr constant [5 x i8] c"%lld\00"
+@.readi_fmt = private unnamed_addr constant [6 x i8] c" %lld\00"
+@.heap_fmt = private unnamed_addr constant [12 x i8] c"%lld: %lld\0A\00"
+@.overflow = private unnamed_addr constant [17 x i8] c"integer overflow\00"
+@.div_zero = private unnamed_addr constant [17 x i8] c"division by zero\00"
+@.bad_char = private unnamed_addr constant [18 x i8] c"invalid character\00"
+@.eof = private unnamed_addr constant [13 x i8] c"end of input\00"
+@.bad_int = private unnamed_addr constant [16 x i8] c"invalid integer\00"
+@.bad_addr = private unnamed_addr constant [26 x i8] c"heap address out of range\00"
+@.no_ret = private unnamed_addr constant [29 x i8] c"return with empty call stack\00"
+@.oom = private unnamed_addr constant [14 x i8] c"out of memory\00"
+
+declare i32 @printf(ptr, ...)
+declare i32 @dprintf(i32, ptr, ...)
+declare i32 @putchar(i32)
+declare i32 @getchar()
+declare i32 @scanf(ptr, ...)
+declare i32 @fflush(ptr)
+declare ptr @realloc(ptr, i64)
+declare void @exit(i32) noreturn
+declare void @llvm.memset.p0.i64(ptr, i8, i64, i1)
+declare { i64, i1 } @llvm.sadd.with.overflow.i64(i64, i64)
+declare { i64, i1 } @llvm.ssub.with.overflow.i64(i64, i64)
+declare { i64, i1 } @llvm.smul.with.overflow.i64(i64, i64)
+
+define internal void @ws_error(ptr %msg) noreturn cold {
+  call i32 @fflush(ptr null)
+  call i32 (i32, ptr, ...) @dprintf(i32 2, ptr @.err_fmt, ptr %msg)
+  call void @exit(i32 1)
+  unreachable
+}
+
+define internal void @ws_check(i1 %overflow) {
+  br i1 %overflow, label %fail, label %ok
+fail:
+  call void @ws_error(ptr @.overflow)
+  unreachable
+ok:
+  ret void
+}
+
+; Division and modulo round towards negative infinity.
+define internal i64 @ws_div(i64 %a, i64 %b) {
+  %zero = icmp eq i64 %b, 0
+  br i1 %zero, label %div_zero, label %nonzero
+div_zero:
+  call void @ws_error(ptr @.div_zero)
+  unreachable
+nonzero:
+  %min = icmp eq i64 %a, -9223372036854775808
+  %neg_one = icmp eq i64 %b, -1
+  %overflow = and i1 %min, %neg_one
+  call void @ws_check(i1 %overflow)
+  %q = sdiv i64 %a, %b
+  %r = srem i64 %a, %b
+  %r_nonzero = icmp ne i64 %r, 0
+  %r_neg = icmp slt i64 %r, 0
+  %b_neg = icmp slt i64 %b, 0
+  %signs = xor i1 %r_neg, %b_neg
+  %adjust = and i1 %r_nonzero, %signs
+  %q_floor = sub i64 %q, 1
+  %res = select i1 %adjust, i64 %q_floor, i64 %q
+  ret i64 %res
+}
+
+define internal i64 @ws_mod(i64 %a, i64 %b) {
+  %zero = icmp eq i64 %b, 0
+  br i1 %zero, label %div_zero, label %nonzero
+div_zero:
+  call void @ws_error(ptr @.div_zero)
+  unreachable
+nonzero:
+  ; Avoid srem overflow for INT64_MIN % -1.
+  %neg_one = icmp eq i64 %b, -1
+  %b_safe = select i1 %neg_one, i64 1, i64 %b
+  %r = srem i64 %a, %b_safe
+  %r_nonzero = icmp ne i64 %r, 0
+  %r_neg = icmp slt i64 %r, 0
+  %b_neg = icmp slt i64 %b, 0
+  %signs = xor i1 %r_neg, %b_neg
+  %adjust = and i1 %r_nonzero, %signs
+  %r_floor = add i64 %r, %b
+  %res = select i1 %adjust, i64 %r_floor, i64 %r
+  ret i64 %res
+}
+
+; Grows an array of i64 to hold at least the index, zeroing the new elements.
+define internal void @ws_grow(ptr %array, ptr %cap_ptr, i64 %index) {
+  %cap = load i64, ptr %cap_ptr
+  %double = shl i64 %cap, 1
+  %need = add i64 %index, 1
+  %double_enough = icmp ugt i64 %double, %need
+  %cap1 = select i1 %double_enough, i64 %double, i64 %need
+  %small = icmp ult i64 %cap1, 1024
+  %new_cap = select i1 %small, i64 1024, i64 %cap1
+  %too_large = icmp ugt i64 %new_cap, 1152921504606846975
+  br i1 %too_large, label %oom, label %alloc
+alloc:
+  %old = load ptr, ptr %array
+  %bytes = shl i64 %new_cap, 3
+  %new = call ptr @realloc(ptr %old, i64 %bytes)
+  %null = icmp eq ptr %new, null
+  br i1 %null, label %oom, label %zero
+oom:
+  call void @ws_error(ptr @.oom)
+  unreachable
+zero:
+  %tail = getelementptr i64, ptr %new, i64 %cap
+  %tail_len = sub i64 %new_cap, %cap
+  %tail_bytes = shl i64 %tail_len, 3
+  call void @llvm.memset.p0.i64(ptr %tail, i8 0, i64 %tail_bytes, i1 false)
+  store ptr %new, ptr %array
+  store i64 %new_cap, ptr %cap_ptr
+  ret void
+}
+
+define internal ptr @ws_cell(i64 %addr) {
+  %neg = icmp slt i64 %addr, 0
+  br i1 %neg, label %bad, label %nonneg
+bad:
+  call void @ws_error(ptr @.bad_addr)
+  unreachable
+nonneg:
+  %cap = load i64, ptr @ws_heap_cap
+  %fits = icmp ult i64 %addr, %cap
+  br i1 %fits, label %done, label %grow
+grow:
+  call void @ws_grow(ptr @ws_heap, ptr @ws_heap_cap, i64 %addr)
+  br label %done
+done:
+  %heap = load ptr, ptr @ws_heap
+  %cell = getelementptr i64, ptr %heap, i64 %addr
+  ret ptr %cell
+}
+
+define internal void @ws_store(i64 %addr, i64 %value) {
+  %cell = call ptr @ws_cell(i64 %addr)
+  store i64 %value, ptr %cell
+  ret void
+}
+
+define internal i64 @ws_retrieve(i64 %addr) {
+  %cell = call ptr @ws_cell(i64 %addr)
+  %value = load i64, ptr %cell
+  ret i64 %value
+}
+
+define internal void @ws_putb(i64 %b) {
+  %c = trunc i64 %b to i32
+  call i32 @putchar(i32 %c)
+  ret void
+}
+
+; Writes a character as UTF-8.
+define internal void @ws_printc(i64 %c) {
+  %neg = icmp slt i64 %c, 0
+  %large = icmp sgt i64 %c, 1114111
+  %ge_surrogate = icmp sge i64 %c, 55296
+  %le_surrogate = icmp sle i64 %c, 57343
+  %surrogate = and i1 %ge_surrogate, %le_surrogate
+  %out = or i1 %neg, %large
+  %bad = or i1 %out, %surrogate
+  br i1 %bad, label %invalid, label %valid
+invalid:
+  call void @ws_error(ptr @.bad_char)
+  unreachable
+valid:
+  %cont0 = and i64 %c, 63
+  %b0 = or i64 %cont0, 128
+  %shr6 = lshr i64 %c, 6
+  %cont1 = and i64 %shr6, 63
+  %b1 = or i64 %cont1, 128
+  %shr12 = lshr i64 %c, 12
+  %cont2 = and i64 %shr12, 63
+  %b2 = or i64 %cont2, 128
+  %shr18 = lshr i64 %c, 18
+  %is1 = icmp ult i64 %c, 128
+  br i1 %is1, label %len1, label %not1
+len1:
+  call void @ws_putb(i64 %c)
+  ret void
+not1:
+  %is2 = icmp ult i64 %c, 2048
+  br i1 %is2, label %len2, label %not2
+len2:
+  %lead2 = or i64 %shr6, 192
+  call void @ws_putb(i64 %lead2)
+  call void @ws_putb(i64 %b0)
+  ret void
+not2:
+  %is3 = icmp ult i64 %c, 65536
+  br i1 %is3, label %len3, label %len4
+len3:
+  %lead3 = or i64 %shr12, 224
+  call void @ws_putb(i64 %lead3)
+  call void @ws_putb(i64 %b1)
+  call void @ws_putb(i64 %b0)
+  ret void
+len4:
+  %lead4 = or i64 %shr18, 240
+  call void @ws_putb(i64 %lead4)
+  call void @ws_putb(i64 %b2)
+  call void @ws_putb(i64 %b1)
+  call void @ws_putb(i64 %b0)
+  ret void
+}
+
+define internal void @ws_printi(i64 %n) {
+  call i32 (ptr, ...) @printf(ptr @.i64_fmt, i64 %n)
+  ret void
+}
+
+define internal i64 @ws_getc() {
+  call i32 @fflush(ptr null)
+  %c = call i32 @getchar()
+  %eof = icmp slt i32 %c, 0
+  br i1 %eof, label %fail, label %ok
+fail:
+  call void @ws_error(ptr @.eof)
+  unreachable
+ok:
+  %c64 = zext i32 %c to i64
+  ret i64 %c64
+}
+
+; Reads a UTF-8 character to the address.
+define internal void @ws_readc(i64 %addr) {
+entry:
+  %c = call i64 @ws_getc()
+  %ge_c0 = icmp uge i64 %c, 192
+  %ge_e0 = icmp uge i64 %c, 224
+  %ge_f0 = icmp uge i64 %c, 240
+  %n1 = zext i1 %ge_c0 to i64
+  %n2 = zext i1 %ge_e0 to i64
+  %n3 = zext i1 %ge_f0 to i64
+  %n12 = add i64 %n1, %n2
+  %extra = add i64 %n12, %n3
+  %mask = lshr i64 63, %extra
+  %masked = and i64 %c, %mask
+  %multi = icmp ne i64 %extra, 0
+  %init = select i1 %multi, i64 %masked, i64 %c
+  br label %loop
+loop:
+  %i = phi i64 [ 0, %entry ], [ %i1, %body ]
+  %acc = phi i64 [ %init, %entry ], [ %acc1, %body ]
+  %more = icmp ult i64 %i, %extra
+  br i1 %more, label %body, label %done
+body:
+  %b = call i64 @ws_getc()
+  %bits = and i64 %b, 63
+  %shifted = shl i64 %acc, 6
+  %acc1 = or i64 %shifted, %bits
+  %i1 = add i64 %i, 1
+  br label %loop
+done:
+  call void @ws_store(i64 %addr, i64 %acc)
+  ret void
+}
+
+define internal void @ws_readi(i64 %addr) {
+  %n = alloca i64
+  call i32 @fflush(ptr null)
+  %read = call i32 (ptr, ...) @scanf(ptr @.readi_fmt, ptr %n)
+  %ok = icmp eq i32 %read, 1
+  br i1 %ok, label %store, label %fail
+fail:
+  call void @ws_error(ptr @.bad_int)
+  unreachable
+store:
+  %value = load i64, ptr %n
+  call void @ws_store(i64 %addr, i64 %value)
+  br label %skip
+skip:
+  ; Discard the rest of the line.
+  %c = call i32 @getchar()
+  %eol = icmp eq i32 %c, 10
+  %eof = icmp slt i32 %c, 0
+  %done = or i1 %eol, %eof
+  br i1 %done, label %return, label %skip
+return:
+  ret void
+}
+
+define internal void @ws_call(i64 %site) {
+  %len = load i64, ptr @ws_calls_len
+  %cap = load i64, ptr @ws_calls_cap
+  %full = icmp eq i64 %len, %cap
+  br i1 %full, label %grow, label %push
+grow:
+  call void @ws_grow(ptr @ws_calls, ptr @ws_calls_cap, i64 %len)
+  br label %push
+push:
+  %calls = load ptr, ptr @ws_calls
+  %slot = getelementptr i64, ptr %calls, i64 %len
+  store i64 %site, ptr %slot
+  %len1 = add i64 %len, 1
+  store i64 %len1, ptr @ws_calls_len
+  ret void
+}
+
+define internal i64 @ws_ret() {
+  %len = load i64, ptr @ws_calls_len
+  %empty = icmp eq i64 %len, 0
+  br i1 %empty, label %fail, label %pop
+fail:
+  call void @ws_error(ptr @.no_ret)
+  unreachable
+pop:
+  %len1 = sub i64 %len, 1
+  store i64 %len1, ptr @ws_calls_len
+  %calls = load ptr, ptr @ws_calls
+  %slot = getelementptr i64, ptr %calls, i64 %len1
+  %site = load i64, ptr %slot
+  ret i64 %site
+}
+
+define internal void @ws_dump_heap() {
+entry:
+  call i32 @fflush(ptr null)
+  %cap = load i64, ptr @ws_heap_cap
+  %heap = load ptr, ptr @ws_heap
+  br label %loop
+loop:
+  %i = phi i64 [ 0, %entry ], [ %i1, %next ]
+  %more = icmp ult i64 %i, %cap
+  br i1 %more, label %body, label %done
+body:
+  %cell = getelementptr i64, ptr %heap, i64 %i
+  %value = load i64, ptr %cell
+  %set = icmp ne i64 %value, 0
+  br i1 %set, label %print, label %next
+print:
+  call i32 (i32, ptr, ...) @dprintf(i32 2, ptr @.heap_fmt, i64 %i, i64 %value)
+  br label %next
+next:
+  %i1 = add i64 %i, 1
+  br label %loop
+done:
+  ret void
+}
@@ -0,0 +1,237 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+// Runtime for Whitespace programs compiled to Rust.
+//
+// The compiler defines the `Addr` and `Cell` types for heap addresses and
+// values as the narrowest types that hold them, which are `i64`, `i128`, or
+// `rug::Integer`.
+
+#![allow(
+    dead_code,
+    unreachable_code,
+    unused_assignments,
+    unused_imports,
+    unused_mut,
+    unused_parens,
+    unused_variables
+)]
+
+use std::collections::HashMap;
+use std::io::{self, Bytes, Read, StdinLock, Stdout, Write};
+use std::process;
+
+use rug::Integer;
+
+struct Runtime {
+    heap: HashMap<Addr, Cell>,
+    /// Return blocks of the active calls.
+    calls: Vec<usize>,
+    stdin: Bytes<StdinLock<'static>>,
+    stdout: io::BufWriter<Stdout>,
+}
+
+impl Runtime {
+    fn new() -> Self {
+        Runtime {
+            heap: HashMap::new(),
+            calls: Vec::new(),
+            stdin: io::stdin().lock().bytes(),
+            stdout: io::BufWriter::new(io::stdout()),
+        }
+    }
+
+    fn error(&mut self, msg: &str) -> ! {
+        let _ = self.stdout.flush();
+        eprintln!("error: {msg}");
+        process::exit(1);
+    }
+
+    fn end(&mut self) {
+        if self.stdout.flush().is_err() {
+            self.error("write failed");
+        }
+    }
+
+    fn store(&mut self, addr: Addr, value: Cell) {
+        self.heap.insert(addr, value);
+    }
+
+    fn retrieve(&mut self, addr: &Addr) -> Cell {
+        // Unset cells read as 0.
+        self.heap
+            .get(addr)
+            .cloned()
+            .unwrap_or_else(|| Cell::from(0u8))
+    }
+
+    fn printc(&mut self, c: Option<u32>) {
+        match c.and_then(char::from_u32) {
+            Some(c) => {
+                if write!(self.stdout, "{c}").is_err() {
+                    self.error("write failed");
+                }
+            }
+            None => self.error("invalid character"),
+        }
+    }
+
+    fn printi<T: std::fmt::Display>(&mut self, n: T) {
+        if write!(self.stdout, "{n}").is_err() {
+            self.error("write failed");
+        }
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        match self.stdin.next() {
+            Some(Ok(b)) => Some(b),
+            Some(Err(_)) => self.error("read failed"),
+            None => None,
+        }
+    }
+
+    fn readc(&mut self, addr: Addr) {
+        if self.stdout.flush().is_err() {
+            self.error("write failed");
+        }
+        let Some(b) = self.read_byte() else {
+            self.error("end of input");
+        };
+        let len = match b {
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => self.error("invalid UTF-8"),
+        };
+        let mut buf = [b, 0, 0, 0];
+        for i in 1..len {
+            match self.read_byte() {
+                Some(b) => buf[i] = b,
+                None => self.error("invalid UTF-8"),
+            }
+        }
+        let c = match std::str::from_utf8(&buf[..len]) {
+            Ok(s) => s.chars().next().unwrap(),
+            Err(_) => self.error("invalid UTF-8"),
+        };
+        self.store(addr, Cell::from(u32::from(c)));
+    }
+
+    fn readi(&mut self, addr: Addr) {
+        if self.stdout.flush().is_err() {
+            self.error("write failed");
+        }
+        let mut line = Vec::new();
+        loop {
+            match self.read_byte() {
+                Some(b'\n') => break,
+                Some(b) => line.push(b),
+                None if line.is_empty() => self.error("end of input"),
+                None => break,
+            }
+        }
+        let n = std::str::from_utf8(&line)
+            .ok()
+            .map(str::trim)
+            .filter(|s| !s.starts_with('+'))
+            .and_then(|s| s.parse::<Cell>().ok());
+        match n {
+            Some(n) => self.store(addr, n),
+            None => self.error("invalid integer"),
+        }
+    }
+
+    fn call(&mut self, ret: usize) {
+        self.calls.push(ret);
+    }
+
+    fn ret(&mut self) -> usize {
+        match self.calls.pop() {
+            Some(ret) => ret,
+            None => self.error("return outside of call"),
+        }
+    }
+
+    fn dump_heap(&mut self) {
+        let _ = self.stdout.flush();
+        let mut cells = self.heap.iter().collect::<Vec<_>>();
+        cells.sort();
+        for (addr, value) in cells {
+            if *value != 0 {
+                eprintln!("{addr}: {value}");
+            }
+        }
+    }
+}
+
+// Division and modulo round toward negative infinity.
+
+fn div_i64(rt: &mut Runtime, a: i64, b: i64) -> i64 {
+    if b == 0 {
+        rt.error("division by zero");
+    }
+    let (q, r) = (a / b, a.wrapping_rem(b));
+    if r != 0 && (r < 0) != (b < 0) {
+        q - 1
+    } else {
+        q
+    }
+}
+
+fn mod_i64(rt: &mut Runtime, a: i64, b: i64) -> i64 {
+    if b == 0 {
+        rt.error("division by zero");
+    }
+    // `%` panics for the minimum divided by -1.
+    let r = a.wrapping_rem(b);
+    if r != 0 && (r < 0) != (b < 0) {
+        r + b
+    } else {
+        r
+    }
+}
+
+fn div_i128(rt: &mut Runtime, a: i128, b: i128) -> i128 {
+    if b == 0 {
+        rt.error("division by zero");
+    }
+    let (q, r) = (a / b, a.wrapping_rem(b));
+    if r != 0 && (r < 0) != (b < 0) {
+        q - 1
+    } else {
+        q
+    }
+}
+
+fn mod_i128(rt: &mut Runtime, a: i128, b: i128) -> i128 {
+    if b == 0 {
+        rt.error("division by zero");
+    }
+    // `%` panics for the minimum divided by -1.
+    let r = a.wrapping_rem(b);
+    if r != 0 && (r < 0) != (b < 0) {
+        r + b
+    } else {
+        r
+    }
+}
+
+fn div_big(rt: &mut Runtime, a: Integer, b: Integer) -> Integer {
+    if b == 0 {
+        rt.error("division by zero");
+    }
+    a.div_rem_floor(b).0
+}
+
+fn mod_big(rt: &mut Runtime, a: Integer, b: Integer) -> Integer {
+    if b == 0 {
+        rt.error("division by zero");
+    }
+    a.div_rem_floor(b).1
+}
@@ -0,0 +1,480 @@
+# Copyright (C) 2022 Thalia Archibald
+#
+# Nebula 2 is free software: you can redistribute it and/or modify it under the
+# terms of the GNU Lesser General Public License as published by the Free
+# Software Foundation, either version 3 of the License, or (at your option) any
+# later version. You should have received a copy of the GNU Lesser General
+# Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+# Runtime for Whitespace programs compiled to x86-64 assembly for Linux.
+#
+# The runtime uses only syscalls, so programs link without libc:
+#
+#     as prog.s -o prog.o && ld prog.o -o prog
+#
+# Routines clobber only %rax, %rcx, %rdx, %rsi, %rdi, and %r11, so compiled
+# code keeps its values in the other registers. Arguments are passed in %rdi
+# and %rsi, except for ws_div and ws_mod, which divide %rax by %rcx. Errors
+# are reported on stderr and exit with status 1.
+#
+# The heap is a fixed array of cells, which the kernel zeroes on demand, so
+# unset cells read as 0. Calls use the machine stack with a depth limit.
+
+    .set WS_HEAP_CELLS, 1 << 24
+    .set WS_MAX_DEPTH, 1 << 19
+    .set WS_BUF_SIZE, 4096
+
+    .bss
+    .p2align 4
+ws_heap:
+    .skip 8 * WS_HEAP_CELLS
+# One past the highest address that has been stored to.
+ws_heap_end:
+    .skip 8
+ws_depth:
+    .skip 8
+ws_out:
+    .skip WS_BUF_SIZE
+ws_out_len:
+    .skip 8
+ws_in:
+    .skip WS_BUF_SIZE
+ws_in_pos:
+    .skip 8
+ws_in_len:
+    .skip 8
+
+    .data
+ws_out_fd:
+    .quad 1
+
+    .section .rodata
+ws_msg_error:
+    .ascii "error: "
+ws_msg_overflow:
+    .ascii "integer overflow"
+ws_msg_div_zero:
+    .ascii "division by zero"
+ws_msg_heap:
+    .ascii "heap address out of range"
+ws_msg_char:
+    .ascii "invalid character"
+ws_msg_utf8:
+    .ascii "invalid UTF-8"
+ws_msg_eof:
+    .ascii "end of input"
+ws_msg_int:
+    .ascii "invalid integer"
+ws_msg_ret:
+    .ascii "return outside of call"
+ws_msg_calls:
+    .ascii "call stack overflow"
+ws_msg_newline:
+    .ascii "\n"
+
+    .text
+
+# Prints "error: ", the message at %rsi with length %rdx, and a newline to
+# stderr, then exits.
+ws_error:
+    pushq %rsi
+    pushq %rdx
+    call ws_flush
+    leaq ws_msg_error(%rip), %rsi
+    movl $7, %edx
+    movl $2, %edi
+    movl $1, %eax
+    syscall
+    popq %rdx
+    popq %rsi
+    movl $2, %edi
+    movl $1, %eax
+    syscall
+    leaq ws_msg_newline(%rip), %rsi
+    movl $1, %edx
+    movl $2, %edi
+    movl $1, %eax
+    syscall
+    movl $1, %edi
+    movl $60, %eax
+    syscall
+
+ws_err_overflow:
+    leaq ws_msg_overflow(%rip), %rsi
+    movl $16, %edx
+    jmp ws_error
+ws_err_div_zero:
+    leaq ws_msg_div_zero(%rip), %rsi
+    movl $16, %edx
+    jmp ws_error
+ws_err_heap:
+    leaq ws_msg_heap(%rip), %rsi
+    movl $25, %edx
+    jmp ws_error
+ws_err_char:
+    leaq ws_msg_char(%rip), %rsi
+    movl $17, %edx
+    jmp ws_error
+ws_err_utf8:
+    leaq ws_msg_utf8(%rip), %rsi
+    movl $13, %edx
+    jmp ws_error
+ws_err_eof:
+    leaq ws_msg_eof(%rip), %rsi
+    movl $12, %edx
+    jmp ws_error
+ws_err_int:
+    leaq ws_msg_int(%rip), %rsi
+    movl $15, %edx
+    jmp ws_error
+
+# Flushes the output buffer to ws_out_fd. A failed write exits silently,
+# since the error could not be reported either.
+ws_flush:
+    movq ws_out_len(%rip), %rdx
+    testq %rdx, %rdx
+    jz 2f
+    leaq ws_out(%rip), %rsi
+1:  movq ws_out_fd(%rip), %rdi
+    movl $1, %eax
+    syscall
+    testq %rax, %rax
+    jle 3f
+    addq %rax, %rsi
+    subq %rax, %rdx
+    jnz 1b
+    movq $0, ws_out_len(%rip)
+2:  ret
+3:  movl $1, %edi
+    movl $60, %eax
+    syscall
+
+# Flushes the output and exits successfully.
+ws_exit:
+    call ws_flush
+    xorl %edi, %edi
+    movl $60, %eax
+    syscall
+
+# Writes the byte in %dil to the output buffer.
+ws_putb:
+    movq ws_out_len(%rip), %rax
+    cmpq $WS_BUF_SIZE, %rax
+    jne 1f
+    pushq %rdi
+    call ws_flush
+    popq %rdi
+    xorl %eax, %eax
+1:  leaq ws_out(%rip), %rcx
+    movb %dil, (%rcx,%rax)
+    incq %rax
+    movq %rax, ws_out_len(%rip)
+    ret
+
+# Returns the next input byte in %rax, or -1 at EOF.
+ws_getb:
+    movq ws_in_pos(%rip), %rax
+    cmpq ws_in_len(%rip), %rax
+    jb 1f
+    xorl %eax, %eax
+    xorl %edi, %edi
+    leaq ws_in(%rip), %rsi
+    movl $WS_BUF_SIZE, %edx
+    syscall
+    testq %rax, %rax
+    jle 2f
+    movq %rax, ws_in_len(%rip)
+    xorl %eax, %eax
+1:  leaq ws_in(%rip), %rcx
+    movzbl (%rcx,%rax), %edx
+    incq %rax
+    movq %rax, ws_in_pos(%rip)
+    movl %edx, %eax
+    ret
+2:  movq $0, ws_in_pos(%rip)
+    movq $0, ws_in_len(%rip)
+    movq $-1, %rax
+    ret
+
+# Divides %rax by %rcx, rounding toward negative infinity.
+ws_div:
+    testq %rcx, %rcx
+    jz ws_err_div_zero
+    cmpq $-1, %rcx
+    je 1f
+    cqto
+    idivq %rcx
+    testq %rdx, %rdx
+    jz 2f
+    xorq %rcx, %rdx
+    jns 2f
+    decq %rax
+2:  ret
+1:  negq %rax
+    jo ws_err_overflow
+    ret
+
+# Computes %rax modulo %rcx, with the sign of the divisor.
+ws_mod:
+    testq %rcx, %rcx
+    jz ws_err_div_zero
+    cmpq $-1, %rcx
+    je 1f
+    cqto
+    idivq %rcx
+    movq %rdx, %rax
+    testq %rax, %rax
+    jz 2f
+    xorq %rcx, %rdx
+    jns 2f
+    addq %rcx, %rax
+2:  ret
+1:  xorl %eax, %eax
+    ret
+
+# Returns the address of the heap cell at %rdi in %rax.
+ws_cell:
+    cmpq $WS_HEAP_CELLS, %rdi
+    jae ws_err_heap
+    leaq ws_heap(%rip), %rax
+    leaq (%rax,%rdi,8), %rax
+    ret
+
+# Stores %rsi at the heap address %rdi.
+ws_store:
+    call ws_cell
+    movq %rsi, (%rax)
+    cmpq ws_heap_end(%rip), %rdi
+    jb 1f
+    leaq 1(%rdi), %rcx
+    movq %rcx, ws_heap_end(%rip)
+1:  ret
+
+# Returns the value at the heap address %rdi in %rax.
+ws_retrieve:
+    call ws_cell
+    movq (%rax), %rax
+    ret
+
+# Prints the code point in %rdi as UTF-8.
+ws_printc:
+    cmpq $0x10ffff, %rdi
+    ja ws_err_char
+    cmpq $0x80, %rdi
+    jb ws_putb
+    pushq %rdi
+    cmpq $0x800, %rdi
+    jb 2f
+    cmpq $0x10000, %rdi
+    jb 3f
+    shrq $18, %rdi
+    orl $0xf0, %edi
+    call ws_putb
+    movq (%rsp), %rdi
+    shrq $12, %rdi
+    andl $0x3f, %edi
+    orl $0x80, %edi
+    call ws_putb
+    jmp 4f
+3:  shrq $12, %rdi
+    orl $0xe0, %edi
+    call ws_putb
+4:  movq (%rsp), %rdi
+    shrq $6, %rdi
+    andl $0x3f, %edi
+    orl $0x80, %edi
+    call ws_putb
+    jmp 5f
+2:  shrq $6, %rdi
+    orl $0xc0, %edi
+    call ws_putb
+5:  popq %rdi
+    andl $0x3f, %edi
+    orl $0x80, %edi
+    jmp ws_putb
+
+# Prints %rdi in decimal. The digits are computed from the negated magnitude,
+# which can represent the magnitude of every i64, into a buffer on the stack.
+ws_printi:
+    subq $32, %rsp
+    leaq 32(%rsp), %rsi
+    movq %rdi, %rax
+    testq %rax, %rax
+    js 1f
+    negq %rax
+1:  movl $10, %ecx
+2:  cqto
+    idivq %rcx
+    decq %rsi
+    movl $48, %r11d
+    subl %edx, %r11d
+    movb %r11b, (%rsi)
+    testq %rax, %rax
+    jnz 2b
+    testq %rdi, %rdi
+    jns 3f
+    decq %rsi
+    movb $45, (%rsi)
+3:  movq %rsi, (%rsp)
+4:  movq (%rsp), %rsi
+    leaq 32(%rsp), %rax
+    cmpq %rax, %rsi
+    je 5f
+    movzbl (%rsi), %edi
+    incq %rsi
+    movq %rsi, (%rsp)
+    call ws_putb
+    jmp 4b
+5:  addq $32, %rsp
+    ret
+
+# Reads a UTF-8 character to the heap address %rdi.
+ws_readc:
+    pushq %rdi
+    subq $16, %rsp
+    call ws_flush
+    call ws_getb
+    testq %rax, %rax
+    js ws_err_eof
+    cmpq $0x80, %rax
+    jb 3f
+    cmpq $0xc0, %rax
+    jb ws_err_utf8
+    cmpq $0xe0, %rax
+    jb 1f
+    cmpq $0xf0, %rax
+    jb 2f
+    cmpq $0xf8, %rax
+    jae ws_err_utf8
+    andl $0x07, %eax
+    movq $3, 8(%rsp)
+    jmp 4f
+2:  andl $0x0f, %eax
+    movq $2, 8(%rsp)
+    jmp 4f
+1:  andl $0x1f, %eax
+    movq $1, 8(%rsp)
+4:  movq %rax, (%rsp)
+5:  call ws_getb
+    testq %rax, %rax
+    js ws_err_utf8
+    movl %eax, %ecx
+    andl $0xc0, %ecx
+    cmpl $0x80, %ecx
+    jne ws_err_utf8
+    andl $0x3f, %eax
+    movq (%rsp), %rcx
+    shlq $6, %rcx
+    orq %rcx, %rax
+    movq %rax, (%rsp)
+    decq 8(%rsp)
+    jnz 5b
+3:  movq %rax, %rsi
+    addq $16, %rsp
+    popq %rdi
+    jmp ws_store
+
+# Reads a line containing a decimal integer, surrounded by optional spaces or
+# tabs, to the heap address %rdi. The magnitude is accumulated negated at
+# (%rsp), with flags at 8(%rsp) for a sign (1) and for any digits (2).
+ws_readi:
+    pushq %rdi
+    subq $16, %rsp
+    movq $0, (%rsp)
+    movq $0, 8(%rsp)
+    call ws_flush
+    call ws_getb
+    testq %rax, %rax
+    js ws_err_eof
+1:  cmpq $32, %rax
+    je 2f
+    cmpq $9, %rax
+    jne 3f
+2:  call ws_getb
+    jmp 1b
+3:  cmpq $45, %rax
+    jne 4f
+    orq $1, 8(%rsp)
+    call ws_getb
+4:  leaq -48(%rax), %rcx
+    cmpq $9, %rcx
+    ja 5f
+    orq $2, 8(%rsp)
+    movq (%rsp), %rax
+    imulq $10, %rax
+    jo ws_err_int
+    subq %rcx, %rax
+    jo ws_err_int
+    movq %rax, (%rsp)
+    call ws_getb
+    jmp 4b
+5:  cmpq $32, %rax
+    je 6f
+    cmpq $9, %rax
+    jne 7f
+6:  call ws_getb
+    jmp 5b
+7:  testq %rax, %rax
+    js 8f
+    cmpq $10, %rax
+    jne ws_err_int
+8:  testq $2, 8(%rsp)
+    jz ws_err_int
+    movq (%rsp), %rsi
+    testq $1, 8(%rsp)
+    jnz 9f
+    negq %rsi
+    jo ws_err_int
+9:  addq $16, %rsp
+    popq %rdi
+    jmp ws_store
+
+# Counts a call before the compiled code calls its target.
+ws_enter:
+    incq ws_depth(%rip)
+    cmpq $WS_MAX_DEPTH, ws_depth(%rip)
+    ja 1f
+    ret
+1:  leaq ws_msg_calls(%rip), %rsi
+    movl $19, %edx
+    jmp ws_error
+
+# Returns from a compiled call. This is jumped to, so the return address of
+# the call is on top of the stack.
+ws_ret:
+    cmpq $0, ws_depth(%rip)
+    je 1f
+    decq ws_depth(%rip)
+    ret
+1:  leaq ws_msg_ret(%rip), %rsi
+    movl $22, %edx
+    jmp ws_error
+
+# Prints each nonzero heap cell as "address: value" to stderr.
+ws_dump_heap:
+    call ws_flush
+    movq $2, ws_out_fd(%rip)
+    pushq $0
+1:  movq (%rsp), %rdi
+    cmpq ws_heap_end(%rip), %rdi
+    jae 3f
+    leaq ws_heap(%rip), %rax
+    cmpq $0, (%rax,%rdi,8)
+    je 2f
+    call ws_printi
+    movl $58, %edi
+    call ws_putb
+    movl $32, %edi
+    call ws_putb
+    movq (%rsp), %rdi
+    leaq ws_heap(%rip), %rax
+    movq (%rax,%rdi,8), %rdi
+    call ws_printi
+    movl $10, %edi
+    call ws_putb
+2:  incq (%rsp)
+    jmp 1b
+3:  popq %rax
+    call ws_flush
+    movq $1, ws_out_fd(%rip)
+    ret
@@ -0,0 +1,317 @@
+;; Copyright (C) 2022 Thalia Archibald
+;;
+;; Nebula 2 is free software: you can redistribute it and/or modify it under the
+;; terms of the GNU Lesser General Public License as published by the Free
+;; Software Foundation, either version 3 of the License, or (at your option) any
+;; later version. You should have received a copy of the GNU Lesser General
+;; Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+;; Runtime for Whitespace programs compiled to WebAssembly.
+;;
+;; Characters are exchanged with the host as Unicode code points, with
+;; `read_char` returning -1 at EOF. Values are i64 and overflow traps. Errors
+;; trap with `unreachable`.
+;;
+;; Linear memory holds the value stack, the call stack of return blocks, and
+;; the heap, in that order. The heap grows on demand and fresh pages are
+;; zeroed, so unset cells read as 0.
+
+(module
+  (import "env" "read_char" (func $read_char (result i32)))
+  (import "env" "write_char" (func $write_char (param i32)))
+
+  (memory (export "memory") 13)
+
+  ;; Value stack: 65536 i64 values at 0.
+  (global $sp (mut i32) (i32.const 0))
+  (global $stack_cap i32 (i32.const 65536))
+  ;; Call stack: 65536 i32 block indices at 0x80000.
+  (global $csp (mut i32) (i32.const 0))
+  (global $calls_base i32 (i32.const 0x80000))
+  (global $calls_cap i32 (i32.const 65536))
+  ;; Heap: i64 cells at 0xC0000, addressed from 0.
+  (global $heap_base i64 (i64.const 0xC0000))
+  (global $heap_cap i64 (i64.const 0x1000_0000))
+
+  (func $push (param $v i64)
+    (if (i32.ge_u (global.get $sp) (global.get $stack_cap))
+      (then (unreachable)))
+    (i64.store (i32.shl (global.get $sp) (i32.const 3)) (local.get $v))
+    (global.set $sp (i32.add (global.get $sp) (i32.const 1))))
+
+  (func $pop (result i64)
+    (if (i32.eqz (global.get $sp))
+      (then (unreachable)))
+    (global.set $sp (i32.sub (global.get $sp) (i32.const 1)))
+    (i64.load (i32.shl (global.get $sp) (i32.const 3))))
+
+  (func $dup
+    (local $v i64)
+    (local.set $v (call $pop))
+    (call $push (local.get $v))
+    (call $push (local.get $v)))
+
+  (func $copy (param $n i64)
+    (if (i64.ge_u (local.get $n) (i64.extend_i32_u (global.get $sp)))
+      (then (unreachable)))
+    (call $push
+      (i64.load
+        (i32.shl
+          (i32.sub
+            (i32.sub (global.get $sp) (i32.const 1))
+            (i32.wrap_i64 (local.get $n)))
+          (i32.const 3)))))
+
+  (func $swap
+    (local $a i64)
+    (local $b i64)
+    (local.set $b (call $pop))
+    (local.set $a (call $pop))
+    (call $push (local.get $b))
+    (call $push (local.get $a)))
+
+  (func $drop
+    (drop (call $pop)))
+
+  (func $slide (param $n i64)
+    (local $top i64)
+    (local.set $top (call $pop))
+    (if (i64.gt_u (local.get $n) (i64.extend_i32_u (global.get $sp)))
+      (then (unreachable)))
+    (global.set $sp
+      (i32.sub (global.get $sp) (i32.wrap_i64 (local.get $n))))
+    (call $push (local.get $top)))
+
+  (func $add
+    (local $a i64)
+    (local $b i64)
+    (local $r i64)
+    (local.set $b (call $pop))
+    (local.set $a (call $pop))
+    (local.set $r (i64.add (local.get $a) (local.get $b)))
+    ;; Overflow when both operands have a sign different from the result.
+    (if (i64.lt_s
+          (i64.and
+            (i64.xor (local.get $a) (local.get $r))
+            (i64.xor (local.get $b) (local.get $r)))
+          (i64.const 0))
+      (then (unreachable)))
+    (call $push (local.get $r)))
+
+  (func $sub
+    (local $a i64)
+    (local $b i64)
+    (local $r i64)
+    (local.set $b (call $pop))
+    (local.set $a (call $pop))
+    (local.set $r (i64.sub (local.get $a) (local.get $b)))
+    ;; Overflow when the operands differ in sign and the result differs from
+    ;; the minuend.
+    (if (i64.lt_s
+          (i64.and
+            (i64.xor (local.get $a) (local.get $b))
+            (i64.xor (local.get $a) (local.get $r)))
+          (i64.const 0))
+      (then (unreachable)))
+    (call $push (local.get $r)))
+
+  (func $mul
+    (local $a i64)
+    (local $b i64)
+    (local $r i64)
+    (local.set $b (call $pop))
+    (local.set $a (call $pop))
+    (local.set $r (i64.mul (local.get $a) (local.get $b)))
+    ;; Dividing i64::MIN by -1 would trap, so check that case separately.
+    (if (i64.eq (local.get $a) (i64.const -1))
+      (then
+        (if (i64.eq (local.get $b) (i64.const 0x8000_0000_0000_0000))
+          (then (unreachable))))
+      (else
+        (if (i64.ne (local.get $a) (i64.const 0))
+          (then
+            (if (i64.ne (i64.div_s (local.get $r) (local.get $a)) (local.get $b))
+              (then (unreachable)))))))
+    (call $push (local.get $r)))
+
+  ;; Division and modulo round toward negative infinity. `i64.div_s` traps on
+  ;; division by zero and on overflow.
+  (func $div
+    (local $a i64)
+    (local $b i64)
+    (local $q i64)
+    (local.set $b (call $pop))
+    (local.set $a (call $pop))
+    (local.set $q (i64.div_s (local.get $a) (local.get $b)))
+    (if (i32.and
+          (i64.ne (i64.rem_s (local.get $a) (local.get $b)) (i64.const 0))
+          (i32.ne
+            (i64.lt_s (local.get $a) (i64.const 0))
+            (i64.lt_s (local.get $b) (i64.const 0))))
+      (then (local.set $q (i64.sub (local.get $q) (i64.const 1)))))
+    (call $push (local.get $q)))
+
+  (func $mod
+    (local $b i64)
+    (local $r i64)
+    (local.set $b (call $pop))
+    (local.set $r (i64.rem_s (call $pop) (local.get $b)))
+    (if (i32.and
+          (i64.ne (local.get $r) (i64.const 0))
+          (i32.ne
+            (i64.lt_s (local.get $r) (i64.const 0))
+            (i64.lt_s (local.get $b) (i64.const 0))))
+      (then (local.set $r (i64.add (local.get $r) (local.get $b)))))
+    (call $push (local.get $r)))
+
+  ;; Returns the memory address of a heap cell, growing memory to hold it.
+  (func $cell (param $addr i64) (result i32)
+    (local $end i64)
+    (local $size i64)
+    (if (i64.ge_u (local.get $addr) (global.get $heap_cap))
+      (then (unreachable)))
+    (local.set $end
+      (i64.add
+        (global.get $heap_base)
+        (i64.shl (i64.add (local.get $addr) (i64.const 1)) (i64.const 3))))
+    (local.set $size (i64.shl (i64.extend_i32_u (memory.size)) (i64.const 16)))
+    (if (i64.gt_u (local.get $end) (local.get $size))
+      (then
+        (if (i32.eq
+              (memory.grow
+                (i32.wrap_i64
+                  (i64.shr_u
+                    (i64.add
+                      (i64.sub (local.get $end) (local.get $size))
+                      (i64.const 0xffff))
+                    (i64.const 16))))
+              (i32.const -1))
+          (then (unreachable)))))
+    (i32.wrap_i64 (i64.sub (local.get $end) (i64.const 8))))
+
+  (func $store
+    (local $v i64)
+    (local.set $v (call $pop))
+    (i64.store (call $cell (call $pop)) (local.get $v)))
+
+  (func $retrieve
+    (call $push (i64.load (call $cell (call $pop)))))
+
+  (func $printc
+    (local $c i64)
+    (local.set $c (call $pop))
+    (if (i64.gt_u (local.get $c) (i64.const 0x10_ffff))
+      (then (unreachable)))
+    (call $write_char (i32.wrap_i64 (local.get $c))))
+
+  ;; Prints the digits of a non-positive number, which can represent the
+  ;; magnitude of every i64.
+  (func $print_digits (param $n i64)
+    (if (i64.le_s (local.get $n) (i64.const -10))
+      (then (call $print_digits (i64.div_s (local.get $n) (i64.const 10)))))
+    (call $write_char
+      (i32.sub
+        (i32.const 48)
+        (i32.wrap_i64 (i64.rem_s (local.get $n) (i64.const 10))))))
+
+  (func $printi
+    (local $n i64)
+    (local.set $n (call $pop))
+    (if (i64.lt_s (local.get $n) (i64.const 0))
+      (then (call $write_char (i32.const 45)))
+      (else (local.set $n (i64.sub (i64.const 0) (local.get $n)))))
+    (call $print_digits (local.get $n)))
+
+  (func $readc
+    (local $c i32)
+    (local.set $c (call $read_char))
+    (if (i32.lt_s (local.get $c) (i32.const 0))
+      (then (unreachable)))
+    (i64.store (call $cell (call $pop)) (i64.extend_i32_u (local.get $c))))
+
+  ;; Reads a line containing a decimal integer, surrounded by optional spaces
+  ;; or tabs. The magnitude is accumulated as a negative number, so that
+  ;; i64::MIN can be read.
+  (func $readi
+    (local $addr i32)
+    (local $c i32)
+    (local $neg i32)
+    (local $digits i32)
+    (local $n i64)
+    (local.set $addr (call $cell (call $pop)))
+    (local.set $c (call $read_char))
+    (if (i32.lt_s (local.get $c) (i32.const 0))
+      (then (unreachable)))
+    (block $space_done
+      (loop $space
+        (br_if $space_done
+          (i32.and
+            (i32.ne (local.get $c) (i32.const 32))
+            (i32.ne (local.get $c) (i32.const 9))))
+        (local.set $c (call $read_char))
+        (br $space)))
+    (if (i32.eq (local.get $c) (i32.const 45))
+      (then
+        (local.set $neg (i32.const 1))
+        (local.set $c (call $read_char))))
+    (block $digits_done
+      (loop $digit
+        (br_if $digits_done
+          (i32.gt_u (i32.sub (local.get $c) (i32.const 48)) (i32.const 9)))
+        (if (i64.lt_s (local.get $n) (i64.const -922_337_203_685_477_580))
+          (then (unreachable)))
+        (local.set $n (i64.mul (local.get $n) (i64.const 10)))
+        (if (i64.lt_s
+              (local.get $n)
+              (i64.add
+                (i64.const 0x8000_0000_0000_0000)
+                (i64.extend_i32_u (i32.sub (local.get $c) (i32.const 48)))))
+          (then (unreachable)))
+        (local.set $n
+          (i64.sub
+            (local.get $n)
+            (i64.extend_i32_u (i32.sub (local.get $c) (i32.const 48)))))
+        (local.set $digits (i32.const 1))
+        (local.set $c (call $read_char))
+        (br $digit)))
+    (block $trailing_done
+      (loop $trailing
+        (br_if $trailing_done
+          (i32.and
+            (i32.ne (local.get $c) (i32.const 32))
+            (i32.ne (local.get $c) (i32.const 9))))
+        (local.set $c (call $read_char))
+        (br $trailing)))
+    (if (i32.or
+          (i32.eqz (local.get $digits))
+          (i32.and
+            (i32.ne (local.get $c) (i32.const 10))
+            (i32.ge_s (local.get $c) (i32.const 0))))
+      (then (unreachable)))
+    (if (i32.eqz (local.get $neg))
+      (then
+        (if (i64.eq (local.get $n) (i64.const 0x8000_0000_0000_0000))
+          (then (unreachable)))
+        (local.set $n (i64.sub (i64.const 0) (local.get $n)))))
+    (i64.store (local.get $addr) (local.get $n)))
+
+  (func $call (param $ret i32)
+    (if (i32.ge_u (global.get $csp) (global.get $calls_cap))
+      (then (unreachable)))
+    (i32.store
+      (i32.add (global.get $calls_base) (i32.shl (global.get $csp) (i32.const 2)))
+      (local.get $ret))
+    (global.set $csp (i32.add (global.get $csp) (i32.const 1))))
+
+  (func $ret (result i32)
+    (if (i32.eqz (global.get $csp))
+      (then (unreachable)))
+    (global.set $csp (i32.sub (global.get $csp) (i32.const 1)))
+    (i32.load
+      (i32.add (global.get $calls_base) (i32.shl (global.get $csp) (i32.const 2)))))
+
+  (func $pop_zero (result i32)
+    (i64.eqz (call $pop)))
+
+  (func $pop_neg (result i32)
+    (i64.lt_s (call $pop) (i64.const 0)))
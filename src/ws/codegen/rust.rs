@@ -0,0 +1,450 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Rust backend.
+//!
+//! Emits a standalone Rust program from the SSA IR, which depends on only the
+//! `rug` crate. Each value is typed as the narrowest of `i64`, `i128`, and
+//! `rug::Integer` that [`Ranges`] proves holds it, and arithmetic is computed
+//! in the widest type of its operands and result, so it never overflows.
+//!
+//! Blocks are arms of a `match` on the current block in a loop. Values are
+//! local to their block, except for block parameters, which are variables in
+//! `main` that edges assign.
+
+use std::fmt::Write;
+
+use crate::ws::ir::BlockId;
+use crate::ws::range::{Ranges, Width};
+use crate::ws::ssa::{Block, Edge, Exit, Op, Ssa, ValueId};
+
+const RUNTIME: &str = include_str!("runtime.rs");
+
+/// Compiles an SSA program to a Rust program.
+#[must_use]
+pub fn emit_rust(ssa: &Ssa) -> String {
+    Emitter::new(ssa).emit()
+}
+
+struct Emitter<'a> {
+    ssa: &'a Ssa,
+    ranges: Ranges,
+    /// Type of heap addresses.
+    addr: Width,
+    /// Type of heap values.
+    cell: Width,
+}
+
+impl<'a> Emitter<'a> {
+    fn new(ssa: &'a Ssa) -> Self {
+        let ranges = Ranges::new(ssa);
+        let (mut addr, mut cell) = (Width::I64, ranges.heap().width());
+        for stmt in ssa.blocks().iter().flat_map(Block::stmts) {
+            match stmt.op {
+                Op::Store { addr: a, value } => {
+                    addr = addr.max(ranges.width(a));
+                    cell = cell.max(ranges.width(value));
+                }
+                Op::Retrieve(a) | Op::Readc(a) | Op::Readi(a) => {
+                    addr = addr.max(ranges.width(a));
+                }
+                _ => {}
+            }
+        }
+        Emitter { ssa, ranges, addr, cell }
+    }
+
+    fn emit(&self) -> String {
+        let mut rs = String::new();
+        rs.push_str(RUNTIME);
+        writeln!(rs, "\ntype Addr = {};", ty(self.addr)).unwrap();
+        writeln!(rs, "type Cell = {};", ty(self.cell)).unwrap();
+        rs.push_str("\nfn main() {\n    let mut rt = Runtime::new();\n");
+        for block in self.ssa.blocks() {
+            for &p in block.params() {
+                let w = self.ranges.width(p);
+                let zero = if w == Width::Big {
+                    "Integer::new()"
+                } else {
+                    "0"
+                };
+                writeln!(rs, "    let mut {p}: {} = {zero};", ty(w)).unwrap();
+            }
+        }
+        if !self.ssa.blocks()[0].params().is_empty() {
+            rs.push_str("    rt.error(\"stack underflow\");\n");
+        }
+        rs.push_str("    let mut block = 0;\n    loop {\n        match block {\n");
+        for block in self.ssa.blocks() {
+            writeln!(rs, "            {} => {{", block.id().0).unwrap();
+            self.emit_block(&mut rs, block);
+            rs.push_str("            }\n");
+        }
+        rs.push_str("            _ => unreachable!(),\n        }\n    }\n}\n");
+        rs
+    }
+
+    fn emit_block(&self, rs: &mut String, block: &Block) {
+        const INDENT: &str = "                ";
+        for stmt in block.stmts() {
+            let line = match (stmt.def, &stmt.op) {
+                (Some(def), op) => {
+                    let w = self.ranges.width(def);
+                    format!("let {def}: {} = {};", ty(w), self.emit_expr(op, w))
+                }
+                (None, op) => self.emit_effect(op),
+            };
+            writeln!(rs, "{INDENT}{line}").unwrap();
+        }
+        match block.exit() {
+            Exit::Jmp(e) => self.emit_jump(rs, INDENT, e),
+            Exit::Jz { cond, zero, other } => {
+                writeln!(rs, "{INDENT}if {cond} == 0 {{").unwrap();
+                self.emit_jump(rs, &format!("{INDENT}    "), zero);
+                writeln!(rs, "{INDENT}}} else {{").unwrap();
+                self.emit_jump(rs, &format!("{INDENT}    "), other);
+                writeln!(rs, "{INDENT}}}").unwrap();
+            }
+            Exit::Jn { cond, neg, other } => {
+                writeln!(rs, "{INDENT}if {cond} < 0 {{").unwrap();
+                self.emit_jump(rs, &format!("{INDENT}    "), neg);
+                writeln!(rs, "{INDENT}}} else {{").unwrap();
+                self.emit_jump(rs, &format!("{INDENT}    "), other);
+                writeln!(rs, "{INDENT}}}").unwrap();
+            }
+            Exit::Call { callee, ret } => {
+                writeln!(rs, "{INDENT}rt.call({});", ret.0).unwrap();
+                self.emit_jump(rs, INDENT, callee);
+            }
+            Exit::Ret(args) => {
+                writeln!(rs, "{INDENT}match rt.ret() {{").unwrap();
+                for ret in self.ret_sites() {
+                    writeln!(rs, "{INDENT}    {} => {{", ret.0).unwrap();
+                    let edge = Edge { target: ret, args: args.clone() };
+                    self.emit_jump(rs, &format!("{INDENT}        "), &edge);
+                    writeln!(rs, "{INDENT}    }}").unwrap();
+                }
+                writeln!(rs, "{INDENT}    _ => unreachable!(),\n{INDENT}}}").unwrap();
+            }
+            Exit::End => writeln!(rs, "{INDENT}rt.end();\n{INDENT}return;").unwrap(),
+            Exit::Unterminated => {
+                writeln!(rs, "{INDENT}rt.error(\"unterminated program\");").unwrap();
+            }
+            Exit::UndefinedLabel(l) => {
+                writeln!(rs, "{INDENT}rt.error({:?});", format!("undefined {l}")).unwrap();
+            }
+            Exit::Error(_) => writeln!(rs, "{INDENT}rt.error(\"invalid instruction\");").unwrap(),
+        }
+    }
+
+    /// Assigns the arguments of an edge to the parameters of its target, then
+    /// branches to it.
+    fn emit_jump(&self, rs: &mut String, indent: &str, edge: &Edge) {
+        let (params, args): (Vec<_>, Vec<_>) = self.ssa[edge.target]
+            .params()
+            .iter()
+            .zip(&edge.args)
+            .filter(|(p, a)| p != a)
+            .map(|(&p, &a)| (p.to_string(), self.value(a, self.ranges.width(p))))
+            .unzip();
+        match params.len() {
+            0 => {}
+            1 => writeln!(rs, "{indent}{} = {};", params[0], args[0]).unwrap(),
+            _ => writeln!(
+                rs,
+                "{indent}({}) = ({});",
+                params.join(", "),
+                args.join(", ")
+            )
+            .unwrap(),
+        }
+        writeln!(rs, "{indent}block = {};", edge.target.0).unwrap();
+    }
+
+    /// Emits an operation that defines a value of width `w`.
+    fn emit_expr(&self, op: &Op, w: Width) -> String {
+        match *op {
+            Op::Const(ref n) => match w {
+                Width::I64 | Width::I128 => n.to_string(),
+                Width::Big => format!("\"{n}\".parse::<Integer>().unwrap()"),
+            },
+            Op::Add(a, b) | Op::Sub(a, b) | Op::Mul(a, b) => {
+                let op = match op {
+                    Op::Add(..) => "+",
+                    Op::Sub(..) => "-",
+                    _ => "*",
+                };
+                let wide = self.wide(a, b, w);
+                let expr = if wide == Width::Big {
+                    format!(
+                        "Integer::from(&{} {op} &{})",
+                        self.borrow(a, wide),
+                        self.borrow(b, wide)
+                    )
+                } else {
+                    format!("({} {op} {})", self.value(a, wide), self.value(b, wide))
+                };
+                convert(&expr, wide, w)
+            }
+            Op::Div(a, b) | Op::Mod(a, b) => {
+                let f = if let Op::Div(..) = op { "div" } else { "mod" };
+                let wide = self.wide(a, b, w);
+                let suffix = match wide {
+                    Width::I64 => "i64",
+                    Width::I128 => "i128",
+                    Width::Big => "big",
+                };
+                let expr = format!(
+                    "{f}_{suffix}(&mut rt, {}, {})",
+                    self.value(a, wide),
+                    self.value(b, wide)
+                );
+                convert(&expr, wide, w)
+            }
+            Op::Retrieve(addr) => {
+                let expr = format!("rt.retrieve(&{})", self.borrow(addr, self.addr));
+                convert(&expr, self.cell, w)
+            }
+            _ => unreachable!("operation does not define a value"),
+        }
+    }
+
+    /// Emits an operation that does not define a value.
+    fn emit_effect(&self, op: &Op) -> String {
+        match *op {
+            Op::Store { addr, value } => format!(
+                "rt.store({}, {});",
+                self.value(addr, self.addr),
+                self.value(value, self.cell)
+            ),
+            Op::Printc(v) => match self.ranges.width(v) {
+                Width::I64 | Width::I128 => format!("rt.printc(u32::try_from({v}).ok());"),
+                Width::Big => format!("rt.printc({v}.to_u32());"),
+            },
+            Op::Printi(v) => format!("rt.printi(&{v});"),
+            Op::Readc(addr) => format!("rt.readc({});", self.value(addr, self.addr)),
+            Op::Readi(addr) => format!("rt.readi({});", self.value(addr, self.addr)),
+            Op::DumpHeap => "rt.dump_heap();".to_owned(),
+            Op::DumpStack => "rt.error(\"unsupported instruction: dump_stack\");".to_owned(),
+            Op::DumpTrace => "rt.error(\"unsupported instruction: dump_trace\");".to_owned(),
+            _ => unreachable!("operation defines a value"),
+        }
+    }
+
+    /// Width to compute an operation in, so that neither the operands nor the
+    /// result are truncated.
+    fn wide(&self, a: ValueId, b: ValueId, w: Width) -> Width {
+        w.max(self.ranges.width(a)).max(self.ranges.width(b))
+    }
+
+    /// Returns an owned value converted to width `to`.
+    fn value(&self, v: ValueId, to: Width) -> String {
+        let from = self.ranges.width(v);
+        if from == Width::Big && to == Width::Big {
+            format!("{v}.clone()")
+        } else {
+            convert(&v.to_string(), from, to)
+        }
+    }
+
+    /// Returns a value converted to width `to`, which is borrowed when it is
+    /// already the right type.
+    fn borrow(&self, v: ValueId, to: Width) -> String {
+        if self.ranges.width(v) == to {
+            v.to_string()
+        } else {
+            self.value(v, to)
+        }
+    }
+
+    fn ret_sites(&self) -> impl Iterator<Item = BlockId> + '_ {
+        self.ssa.blocks().iter().filter_map(|b| match b.exit() {
+            Exit::Call { ret, .. } => Some(*ret),
+            _ => None,
+        })
+    }
+}
+
+/// Converts an owned expression between widths. Narrowing conversions are
+/// only emitted where the ranges prove the value fits.
+fn convert(expr: &str, from: Width, to: Width) -> String {
+    match (from, to) {
+        _ if from == to => expr.to_owned(),
+        (Width::I64, Width::I128) => format!("i128::from({expr})"),
+        (_, Width::Big) => format!("Integer::from({expr})"),
+        (Width::I128, Width::I64) => format!("({expr} as i64)"),
+        (Width::Big, Width::I64) => format!("{expr}.to_i64().unwrap()"),
+        (Width::Big, Width::I128) => format!("{expr}.to_i128().unwrap()"),
+        _ => unreachable!(),
+    }
+}
+
+fn ty(w: Width) -> &'static str {
+    match w {
+        Width::I64 => "i64",
+        Width::I128 => "i128",
+        Width::Big => "Integer",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rug::Integer;
+
+    use super::*;
+    use crate::ws::codegen::tests::{push, push_big};
+    use crate::ws::inst::Inst;
+    use crate::ws::ir::Ir;
+    use crate::ws::syntax::{LabelOrder, Program};
+    use crate::ws::tests::get_tutorial_insts;
+
+    #[test]
+    fn tutorial() {
+        let program = Program::new(get_tutorial_insts(), LabelOrder::Def);
+        let ssa = Ssa::new(&Ir::new(&program)).unwrap();
+        let rs = emit_rust(&ssa);
+        let main = &rs[rs.find("type Addr").unwrap()..];
+        let expected = "\
+type Addr = i64;
+type Cell = i64;
+
+fn main() {
+    let mut rt = Runtime::new();
+    let mut v1: Integer = Integer::new();
+    let mut v7: Integer = Integer::new();
+    let mut v8: Integer = Integer::new();
+    let mut block = 0;
+    loop {
+        match block {
+            0 => {
+                let v0: i64 = 1;
+                v1 = Integer::from(v0);
+                block = 1;
+            }
+            1 => {
+                rt.printi(&v1);
+                let v2: i64 = 10;
+                rt.printc(u32::try_from(v2).ok());
+                let v3: i64 = 1;
+                let v4: Integer = Integer::from(&v1 + &Integer::from(v3));
+                let v5: i64 = 11;
+                let v6: Integer = Integer::from(&v4 - &Integer::from(v5));
+                if v6 == 0 {
+                    v8 = v4.clone();
+                    block = 3;
+                } else {
+                    v7 = v4.clone();
+                    block = 2;
+                }
+            }
+            2 => {
+                v1 = v7.clone();
+                block = 1;
+            }
+            3 => {
+                rt.end();
+                return;
+            }
+            4 => {
+                rt.error(\"unterminated program\");
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+";
+        assert_eq!(expected, main);
+    }
+
+    #[test]
+    fn widths() {
+        // Values that fit in i64, a product that needs i128, and a value read
+        // from input, which needs Integer. Division and modulo use the helper
+        // for the width they are computed in and narrow the result.
+        let program = Program::new(
+            vec![
+                push(6),
+                push(7),
+                Inst::Mul,
+                push(-7),
+                push(2),
+                Inst::Div,
+                Inst::Mod,
+                Inst::Printi,
+                push_big(Integer::from(i64::MAX)),
+                push(2),
+                Inst::Mul,
+                push(3),
+                Inst::Div,
+                push(5),
+                Inst::Mod,
+                Inst::Printi,
+                push(0),
+                Inst::Readi,
+                push(0),
+                Inst::Retrieve,
+                push(2),
+                Inst::Div,
+                push(3),
+                Inst::Mod,
+                Inst::Printi,
+                Inst::End,
+            ],
+            LabelOrder::Def,
+        );
+        let rs = emit_rust(&Ssa::new(&Ir::new(&program)).unwrap());
+        let main = &rs[rs.find("type Addr").unwrap()..];
+        let expected = "\
+type Addr = i64;
+type Cell = Integer;
+
+fn main() {
+    let mut rt = Runtime::new();
+    let mut block = 0;
+    loop {
+        match block {
+            0 => {
+                let v0: i64 = 6;
+                let v1: i64 = 7;
+                let v2: i64 = (v0 * v1);
+                let v3: i64 = -7;
+                let v4: i64 = 2;
+                let v5: i64 = div_i64(&mut rt, v3, v4);
+                let v6: i64 = mod_i64(&mut rt, v2, v5);
+                rt.printi(&v6);
+                let v7: i64 = 9223372036854775807;
+                let v8: i64 = 2;
+                let v9: i128 = (i128::from(v7) * i128::from(v8));
+                let v10: i64 = 3;
+                let v11: i64 = (div_i128(&mut rt, v9, i128::from(v10)) as i64);
+                let v12: i64 = 5;
+                let v13: i64 = mod_i64(&mut rt, v11, v12);
+                rt.printi(&v13);
+                let v14: i64 = 0;
+                rt.readi(v14);
+                let v15: i64 = 0;
+                let v16: Integer = rt.retrieve(&v15);
+                let v17: i64 = 2;
+                let v18: Integer = div_big(&mut rt, v16.clone(), Integer::from(v17));
+                let v19: i64 = 3;
+                let v20: i64 = mod_big(&mut rt, v18.clone(), Integer::from(v19)).to_i64().unwrap();
+                rt.printi(&v20);
+                rt.end();
+                return;
+            }
+            1 => {
+                rt.error(\"unterminated program\");
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+";
+        assert_eq!(expected, main);
+    }
+}
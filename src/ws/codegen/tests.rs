@@ -0,0 +1,87 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Helpers for the backend tests.
+
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::{self, Command};
+
+use rug::Integer;
+
+use crate::ws::inst::{Inst, RawInst};
+use crate::ws::syntax::IntLiteral;
+
+pub(crate) fn push(n: i64) -> RawInst {
+    push_big(Integer::from(n))
+}
+
+pub(crate) fn push_big(n: Integer) -> RawInst {
+    Inst::Push(IntLiteral::from(n).bits().clone())
+}
+
+/// Instructions that print each result of `op` on a pair of operands, one per
+/// line.
+pub(crate) fn print_each(pairs: &[(Integer, Integer)], op: &RawInst) -> Vec<RawInst> {
+    let mut insts = Vec::new();
+    for (a, b) in pairs {
+        insts.push(push_big(a.clone()));
+        insts.push(push_big(b.clone()));
+        insts.push(op.clone());
+        insts.push(Inst::Printi);
+        insts.push(push(i64::from(b'\n')));
+        insts.push(Inst::Printc);
+    }
+    insts
+}
+
+/// Writes the source to a temporary directory, builds it with the commands
+/// from `build`, which are given the source and executable paths, then runs
+/// the executable and returns its stdout. Returns `None` when a build tool is
+/// not installed, so the test can be skipped.
+///
+/// # Panics
+///
+/// Panics when the build or the program fails.
+pub(crate) fn build_and_run<F: FnOnce(&Path, &Path) -> Vec<Command>>(
+    name: &str,
+    ext: &str,
+    src: &str,
+    build: F,
+) -> Option<String> {
+    let dir = env::temp_dir().join(format!("nebula2-{name}-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (src_path, exe_path) = (dir.join(format!("{name}.{ext}")), dir.join(name));
+    fs::write(&src_path, src).unwrap();
+    for mut cmd in build(&src_path, &exe_path) {
+        let output = match cmd.output() {
+            Ok(output) => output,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                eprintln!("skipping {name}: {:?} is not installed", cmd.get_program());
+                fs::remove_dir_all(&dir).unwrap();
+                return None;
+            }
+            Err(err) => panic!("{err}"),
+        };
+        assert!(
+            output.status.success(),
+            "build failed:\n{}",
+            String::from_utf8_lossy(&output.stderr),
+        );
+    }
+    let output = Command::new(&exe_path).output().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(
+        output.status.success(),
+        "run failed:\n{}",
+        String::from_utf8_lossy(&output.stderr),
+    );
+    Some(String::from_utf8(output.stdout).unwrap())
+}
@@ -0,0 +1,338 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! WebAssembly text backend.
+//!
+//! WebAssembly has only structured control flow, so the blocks are placed in a
+//! dispatch loop: a `br_table` on the index of the current block branches out
+//! of nested `block`s to the start of its code. Blocks that are only reached by
+//! falling through do not need an index and share the `block` of the one
+//! before. Jumps set the index and restart the loop, and `ret` takes the index
+//! from a shadow call stack in linear memory.
+//!
+//! The module imports `read_char` and `write_char` from `env` and exports
+//! `main` and `memory`. See `runtime.wat` for the conventions.
+
+use std::fmt::Write;
+
+use crate::ws::inst::Inst;
+use crate::ws::ir::{BlockId, Exit, Ir};
+use crate::ws::syntax::ProgramInst;
+
+const RUNTIME: &str = include_str!("runtime.wat");
+
+/// Compiles the blocks of a program to a WebAssembly text module.
+#[must_use]
+pub fn emit_wat(ir: &Ir) -> String {
+    // Blocks that are jumped to get an index in the dispatch table. The entry
+    // block is at index 0.
+    let mut targeted = ir.jump_targets();
+    targeted[0] = true;
+    let dispatch = (0..ir.blocks().len())
+        .map(BlockId::from)
+        .filter(|&b| targeted[usize::from(b)])
+        .collect::<Vec<_>>();
+    // Index of each targeted block in the dispatch table.
+    let index = targeted
+        .iter()
+        .scan(0, |n, &t| {
+            let i = *n;
+            *n += usize::from(t);
+            Some(i)
+        })
+        .collect::<Vec<_>>();
+    let jump = |w: &mut String, to: BlockId| {
+        let i = index[usize::from(to)];
+        writeln!(w, "    i32.const {i}\n    local.set $pc\n    br $dispatch").unwrap();
+    };
+
+    let mut w = String::new();
+    w.push_str(RUNTIME);
+    w.push_str("\n  (func (export \"main\")\n    (local $pc i32)\n    loop $dispatch\n");
+    for b in dispatch.iter().rev() {
+        writeln!(w, "    block ${b}").unwrap();
+    }
+    w.push_str("    local.get $pc\n    br_table");
+    for b in &dispatch {
+        write!(w, " ${b}").unwrap();
+    }
+    w.push('\n');
+    for block in ir.blocks() {
+        let id = block.id();
+        if targeted[usize::from(id)] {
+            writeln!(w, "    end ;; {id}").unwrap();
+        }
+        for inst in block.body() {
+            emit_inst(&mut w, inst);
+        }
+        let next = BlockId(id.0 + 1);
+        let goto = |w: &mut String, to: BlockId| {
+            if to != next {
+                jump(w, to);
+            }
+        };
+        match *block.exit() {
+            Exit::Fallthrough(to) | Exit::Jmp(to) => goto(&mut w, to),
+            Exit::Jz { zero, other } => {
+                w.push_str("    call $pop_zero\n    if\n");
+                jump(&mut w, zero);
+                w.push_str("    end\n");
+                goto(&mut w, other);
+            }
+            Exit::Jn { neg, other } => {
+                w.push_str("    call $pop_neg\n    if\n");
+                jump(&mut w, neg);
+                w.push_str("    end\n");
+                goto(&mut w, other);
+            }
+            Exit::Call { callee, ret } => {
+                let ret = index[usize::from(ret)];
+                writeln!(w, "    i32.const {ret}\n    call $call").unwrap();
+                jump(&mut w, callee);
+            }
+            Exit::Ret => w.push_str("    call $ret\n    local.set $pc\n    br $dispatch\n"),
+            Exit::End => w.push_str("    return\n"),
+            Exit::Unterminated | Exit::UndefinedLabel(_) | Exit::Error(_) => {
+                w.push_str("    unreachable\n");
+            }
+        }
+    }
+    w.push_str("    end\n  )\n)\n");
+    w
+}
+
+fn emit_inst(w: &mut String, inst: &ProgramInst) {
+    match inst {
+        Inst::Push(n) => match n.to_i64() {
+            Some(n) => writeln!(w, "    i64.const {n}\n    call $push").unwrap(),
+            None => w.push_str("    unreachable\n"),
+        },
+        Inst::Copy(n) => match n.to_i64() {
+            Some(n) if n >= 0 => writeln!(w, "    i64.const {n}\n    call $copy").unwrap(),
+            _ => w.push_str("    unreachable\n"),
+        },
+        Inst::Slide(n) => {
+            let n = n.to_i64().unwrap_or(if **n < 0 { 0 } else { i64::MAX });
+            writeln!(w, "    i64.const {}\n    call $slide", n.max(0)).unwrap();
+        }
+        Inst::Dup
+        | Inst::Swap
+        | Inst::Drop
+        | Inst::Add
+        | Inst::Sub
+        | Inst::Mul
+        | Inst::Div
+        | Inst::Mod
+        | Inst::Store
+        | Inst::Retrieve
+        | Inst::Printc
+        | Inst::Printi
+        | Inst::Readc
+        | Inst::Readi => writeln!(w, "    call ${}", inst.opcode()).unwrap(),
+        Inst::Shuffle | Inst::DumpStack | Inst::DumpHeap | Inst::DumpTrace => {
+            writeln!(w, "    unreachable ;; unsupported: {}", inst.opcode()).unwrap();
+        }
+        Inst::Label(_)
+        | Inst::Call(_)
+        | Inst::Jmp(_)
+        | Inst::Jz(_)
+        | Inst::Jn(_)
+        | Inst::Ret
+        | Inst::End
+        | Inst::Error(_) => unreachable!("terminator in block body"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitvec::prelude::*;
+
+    use super::*;
+    use crate::ws::codegen::tests::push;
+    use crate::ws::inst::RawInst;
+    use crate::ws::syntax::{LabelOrder, Program};
+    use crate::ws::tests::get_tutorial_insts;
+
+    /// Compiles the instructions, checks that the module is well-formed, and
+    /// returns `main`.
+    fn compile(insts: Vec<RawInst>) -> String {
+        let wat = emit_wat(&Ir::new(&Program::new(insts, LabelOrder::Def)));
+        if let Err(err) = wat::parse_str(&wat) {
+            panic!("invalid module: {err}\n{wat}");
+        }
+        wat[wat.find("  (func (export \"main\")\n").unwrap()..].to_owned()
+    }
+
+    #[test]
+    fn tutorial() {
+        let main = compile(get_tutorial_insts());
+        let expected = "  (func (export \"main\")
+    (local $pc i32)
+    loop $dispatch
+    block $block_3
+    block $block_1
+    block $block_0
+    local.get $pc
+    br_table $block_0 $block_1 $block_3
+    end ;; block_0
+    i64.const 1
+    call $push
+    end ;; block_1
+    call $dup
+    call $printi
+    i64.const 10
+    call $push
+    call $printc
+    i64.const 1
+    call $push
+    call $add
+    call $dup
+    i64.const 11
+    call $push
+    call $sub
+    call $pop_zero
+    if
+    i32.const 2
+    local.set $pc
+    br $dispatch
+    end
+    i32.const 1
+    local.set $pc
+    br $dispatch
+    end ;; block_3
+    call $drop
+    return
+    unreachable
+    end
+  )
+)
+";
+        assert_eq!(expected, main);
+    }
+
+    #[test]
+    fn call_ret() {
+        // Calls push the dispatch index of the return block to the shadow call
+        // stack, which `ret` pops to dispatch on.
+        let main = compile(vec![
+            push(1),
+            Inst::Call(bitvec![1]),
+            push(2),
+            Inst::Call(bitvec![1]),
+            Inst::Call(bitvec![1, 0]),
+            Inst::End,
+            Inst::Label(bitvec![1]),
+            Inst::Printi,
+            Inst::Ret,
+            Inst::Label(bitvec![1, 0]),
+            push(3),
+            Inst::Call(bitvec![1]),
+            Inst::Ret,
+        ]);
+        let expected = "  (func (export \"main\")
+    (local $pc i32)
+    loop $dispatch
+    block $block_6
+    block $block_5
+    block $block_4
+    block $block_3
+    block $block_2
+    block $block_1
+    block $block_0
+    local.get $pc
+    br_table $block_0 $block_1 $block_2 $block_3 $block_4 $block_5 $block_6
+    end ;; block_0
+    i64.const 1
+    call $push
+    i32.const 1
+    call $call
+    i32.const 4
+    local.set $pc
+    br $dispatch
+    end ;; block_1
+    i64.const 2
+    call $push
+    i32.const 2
+    call $call
+    i32.const 4
+    local.set $pc
+    br $dispatch
+    end ;; block_2
+    i32.const 3
+    call $call
+    i32.const 5
+    local.set $pc
+    br $dispatch
+    end ;; block_3
+    return
+    end ;; block_4
+    call $printi
+    call $ret
+    local.set $pc
+    br $dispatch
+    end ;; block_5
+    i64.const 3
+    call $push
+    i32.const 6
+    call $call
+    i32.const 4
+    local.set $pc
+    br $dispatch
+    end ;; block_6
+    call $ret
+    local.set $pc
+    br $dispatch
+    unreachable
+    end
+  )
+)
+";
+        assert_eq!(expected, main);
+    }
+
+    #[test]
+    fn jz_forward() {
+        // The `jz` target is not the next block, so it gets an index, but the
+        // block it skips is only reached by falling through, so it does not.
+        let main = compile(vec![
+            push(0),
+            Inst::Jz(bitvec![1]),
+            push(7),
+            Inst::Printi,
+            Inst::Label(bitvec![1]),
+            Inst::End,
+        ]);
+        let expected = "  (func (export \"main\")
+    (local $pc i32)
+    loop $dispatch
+    block $block_2
+    block $block_0
+    local.get $pc
+    br_table $block_0 $block_2
+    end ;; block_0
+    i64.const 0
+    call $push
+    call $pop_zero
+    if
+    i32.const 1
+    local.set $pc
+    br $dispatch
+    end
+    i64.const 7
+    call $push
+    call $printi
+    end ;; block_2
+    return
+    unreachable
+    end
+  )
+)
+";
+        assert_eq!(expected, main);
+    }
+}
@@ -0,0 +1,558 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! x86-64 assembly backend.
+//!
+//! Emits GNU assembly for Linux from the SSA IR, with a runtime that uses
+//! syscalls instead of libc. Values are `i64` and arithmetic traps on
+//! overflow.
+//!
+//! Values are local to their block, so registers are allocated by a linear
+//! scan over each block, spilling to static slots when they run out. Block
+//! parameters are at fixed locations by their index, which are the same for
+//! every block, so edges and returns move their arguments to the same places.
+//! Whitespace calls are machine calls.
+
+use std::fmt::{self, Display, Formatter, Write};
+
+use crate::ws::ir::BlockId;
+use crate::ws::ssa::{Block, Edge, Exit, Op, Ssa, ValueId};
+
+const RUNTIME: &str = include_str!("runtime.s");
+
+/// Registers for values, which the runtime preserves.
+const REGS: [&str; 9] = [
+    "%rbx", "%rbp", "%r12", "%r13", "%r14", "%r15", "%r8", "%r9", "%r10",
+];
+/// Number of block parameters that are kept in registers.
+const PARAM_REGS: usize = 4;
+
+/// Compiles an SSA program to x86-64 assembly.
+#[must_use]
+pub fn emit_x86_64(ssa: &Ssa) -> String {
+    let mut e = Emitter {
+        ssa,
+        locs: vec![None; ssa.values().len()],
+        spills: 0,
+        params: 0,
+        strings: Vec::new(),
+        asm: String::new(),
+    };
+    e.asm.push_str("\n    .globl _start\n_start:\n");
+    if !ssa.blocks()[0].params().is_empty() {
+        e.emit_error("stack underflow");
+    }
+    for block in ssa.blocks() {
+        e.emit_block(block);
+    }
+
+    let mut s = String::new();
+    s.push_str(RUNTIME);
+    s.push_str(&e.asm);
+    s.push_str("\n    .bss\n    .p2align 3\n");
+    writeln!(s, "ws_params:\n    .skip {}", 8 * e.params.max(1)).unwrap();
+    writeln!(s, "ws_spill:\n    .skip {}", 8 * e.spills.max(1)).unwrap();
+    if !e.strings.is_empty() {
+        s.push_str("\n    .section .rodata\n");
+        for (i, msg) in e.strings.iter().enumerate() {
+            writeln!(s, ".Lstr{i}:\n    .ascii \"{}\"", escape(msg)).unwrap();
+        }
+    }
+    s
+}
+
+/// Location of a value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Loc {
+    Reg(usize),
+    /// Block parameter past those in registers.
+    Param(usize),
+    Spill(usize),
+}
+
+struct Emitter<'a> {
+    ssa: &'a Ssa,
+    locs: Vec<Option<Loc>>,
+    /// Number of spill slots used by any block.
+    spills: usize,
+    /// Number of parameters in memory used by any block.
+    params: usize,
+    strings: Vec<String>,
+    asm: String,
+}
+
+/// Allocation state within a block.
+struct Alloc {
+    regs: [bool; REGS.len()],
+    spills: Vec<bool>,
+}
+
+impl Emitter<'_> {
+    fn emit_block(&mut self, block: &Block) {
+        let id = block.id();
+        writeln!(self.asm, "{}:", label(id)).unwrap();
+
+        // Index of the last statement that uses each value, where the exit is
+        // after the statements.
+        let mut last = vec![None; self.ssa.values().len()];
+        for (i, stmt) in block.stmts().iter().enumerate() {
+            for v in stmt.op.uses() {
+                last[usize::from(v)] = Some(i);
+            }
+        }
+        for v in block.exit().uses() {
+            last[usize::from(v)] = Some(block.stmts().len());
+        }
+
+        let mut alloc = Alloc {
+            regs: [false; REGS.len()],
+            spills: Vec::new(),
+        };
+        for (i, &p) in block.params().iter().enumerate() {
+            let loc = param_loc(i);
+            self.locs[usize::from(p)] = Some(loc);
+            if last[usize::from(p)].is_some() {
+                alloc.take(loc);
+            }
+            if let Loc::Param(i) = loc {
+                self.params = self.params.max(i + 1);
+            }
+        }
+
+        for (i, stmt) in block.stmts().iter().enumerate() {
+            let uses = stmt.op.uses();
+            let operands = uses.iter().map(|&v| self.loc(v)).collect::<Vec<_>>();
+            for &v in &uses {
+                if last[usize::from(v)] == Some(i) {
+                    alloc.free(self.loc(v));
+                }
+            }
+            let def = stmt.def.map(|def| {
+                let loc = alloc.alloc();
+                if let Loc::Spill(i) = loc {
+                    self.spills = self.spills.max(i + 1);
+                }
+                self.locs[usize::from(def)] = Some(loc);
+                if last[usize::from(def)].is_none() {
+                    alloc.free(loc);
+                }
+                loc
+            });
+            self.emit_op(&stmt.op, &operands, def);
+        }
+
+        let next = BlockId(id.0 + 1);
+        match block.exit() {
+            Exit::Jmp(e) => self.emit_jump(e, Some(next)),
+            Exit::Jz { cond, zero, other } => self.emit_branch(id, *cond, "e", zero, other),
+            Exit::Jn { cond, neg, other } => self.emit_branch(id, *cond, "l", neg, other),
+            Exit::Call { callee, ret } => {
+                self.emit_moves(callee);
+                writeln!(
+                    self.asm,
+                    "    call ws_enter\n    call {}",
+                    label(callee.target)
+                )
+                .unwrap();
+                if *ret != next {
+                    writeln!(self.asm, "    jmp {}", label(*ret)).unwrap();
+                }
+            }
+            Exit::Ret(args) => {
+                let moves = args
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &a)| (self.loc(a), param_loc(i)))
+                    .collect::<Vec<_>>();
+                self.emit_parallel_moves(&moves);
+                self.asm.push_str("    jmp ws_ret\n");
+            }
+            Exit::End => self.asm.push_str("    jmp ws_exit\n"),
+            Exit::Unterminated => self.emit_error("unterminated program"),
+            Exit::UndefinedLabel(l) => self.emit_error(&format!("undefined {l}")),
+            Exit::Error(_) => self.emit_error("invalid instruction"),
+        }
+    }
+
+    fn emit_op(&mut self, op: &Op, operands: &[Loc], def: Option<Loc>) {
+        let asm = &mut self.asm;
+        match *op {
+            Op::Const(ref n) => match (n.to_i64(), def) {
+                (Some(n), Some(d)) => emit_imm(asm, n, d),
+                (Some(_), None) => {}
+                (None, _) => self.emit_error("integer does not fit in 64 bits"),
+            },
+            Op::Add(..) | Op::Sub(..) | Op::Mul(..) => {
+                let inst = match op {
+                    Op::Add(..) => "addq",
+                    Op::Sub(..) => "subq",
+                    _ => "imulq",
+                };
+                let (a, b) = (operands[0], operands[1]);
+                writeln!(asm, "    movq {a}, %rax\n    {inst} {b}, %rax").unwrap();
+                asm.push_str("    jo ws_err_overflow\n");
+                emit_result(asm, def);
+            }
+            Op::Div(..) | Op::Mod(..) => {
+                let f = if let Op::Div(..) = op {
+                    "ws_div"
+                } else {
+                    "ws_mod"
+                };
+                let (a, b) = (operands[0], operands[1]);
+                writeln!(asm, "    movq {a}, %rax\n    movq {b}, %rcx\n    call {f}").unwrap();
+                emit_result(asm, def);
+            }
+            Op::Store { .. } => {
+                let (addr, value) = (operands[0], operands[1]);
+                writeln!(asm, "    movq {addr}, %rdi\n    movq {value}, %rsi").unwrap();
+                asm.push_str("    call ws_store\n");
+            }
+            Op::Retrieve(_) => {
+                writeln!(asm, "    movq {}, %rdi\n    call ws_retrieve", operands[0]).unwrap();
+                emit_result(asm, def);
+            }
+            Op::Printc(_) | Op::Printi(_) | Op::Readc(_) | Op::Readi(_) => {
+                let f = match op {
+                    Op::Printc(_) => "ws_printc",
+                    Op::Printi(_) => "ws_printi",
+                    Op::Readc(_) => "ws_readc",
+                    _ => "ws_readi",
+                };
+                writeln!(asm, "    movq {}, %rdi\n    call {f}", operands[0]).unwrap();
+            }
+            Op::DumpHeap => asm.push_str("    call ws_dump_heap\n"),
+            Op::DumpStack => self.emit_error("unsupported instruction: dump_stack"),
+            Op::DumpTrace => self.emit_error("unsupported instruction: dump_trace"),
+        }
+    }
+
+    /// Emits a conditional branch from a block, where `cc` is the condition
+    /// code for comparing the condition to 0 that takes the `t` edge.
+    fn emit_branch(&mut self, id: BlockId, cond: ValueId, cc: &str, t: &Edge, f: &Edge) {
+        writeln!(self.asm, "    cmpq $0, {}", self.loc(cond)).unwrap();
+        if self.moves(t).is_empty() {
+            writeln!(self.asm, "    j{cc} {}", label(t.target)).unwrap();
+        } else {
+            let skip = format!("{}.f", label(id));
+            let ncc = if cc == "e" { "ne" } else { "ge" };
+            writeln!(self.asm, "    j{ncc} {skip}").unwrap();
+            self.emit_jump(t, None);
+            writeln!(self.asm, "{skip}:").unwrap();
+        }
+        self.emit_jump(f, Some(BlockId(id.0 + 1)));
+    }
+
+    /// Emits a jump along an edge, which falls through when the target is
+    /// `next`.
+    fn emit_jump(&mut self, edge: &Edge, next: Option<BlockId>) {
+        self.emit_moves(edge);
+        if Some(edge.target) != next {
+            writeln!(self.asm, "    jmp {}", label(edge.target)).unwrap();
+        }
+    }
+
+    fn moves(&self, edge: &Edge) -> Vec<(Loc, Loc)> {
+        edge.args
+            .iter()
+            .enumerate()
+            .map(|(i, &a)| (self.loc(a), param_loc(i)))
+            .filter(|(src, dst)| src != dst)
+            .collect()
+    }
+
+    fn emit_moves(&mut self, edge: &Edge) {
+        let moves = self.moves(edge);
+        self.emit_parallel_moves(&moves);
+    }
+
+    /// Moves values from sources to destinations as if simultaneously. When
+    /// no destination is the source of a later move, they are moved in order,
+    /// and otherwise through the stack.
+    fn emit_parallel_moves(&mut self, moves: &[(Loc, Loc)]) {
+        let moves = moves
+            .iter()
+            .filter(|(src, dst)| src != dst)
+            .collect::<Vec<_>>();
+        let in_order = moves
+            .iter()
+            .enumerate()
+            .all(|(i, (_, dst))| moves[i + 1..].iter().all(|(src, _)| src != dst));
+        if in_order {
+            for &&(src, dst) in &moves {
+                emit_move(&mut self.asm, src, dst);
+            }
+        } else {
+            for (src, _) in &moves {
+                writeln!(self.asm, "    pushq {src}").unwrap();
+            }
+            for (_, dst) in moves.iter().rev() {
+                writeln!(self.asm, "    popq {dst}").unwrap();
+            }
+        }
+    }
+
+    fn emit_error(&mut self, msg: &str) {
+        let i = self
+            .strings
+            .iter()
+            .position(|s| s == msg)
+            .unwrap_or_else(|| {
+                self.strings.push(msg.to_owned());
+                self.strings.len() - 1
+            });
+        writeln!(
+            self.asm,
+            "    leaq .Lstr{i}(%rip), %rsi\n    movl ${}, %edx\n    jmp ws_error",
+            msg.len()
+        )
+        .unwrap();
+    }
+
+    fn loc(&self, v: ValueId) -> Loc {
+        self.locs[usize::from(v)].expect("value used before definition")
+    }
+}
+
+impl Alloc {
+    fn take(&mut self, loc: Loc) {
+        if let Loc::Reg(r) = loc {
+            self.regs[r] = true;
+        }
+    }
+
+    fn free(&mut self, loc: Loc) {
+        match loc {
+            Loc::Reg(r) => self.regs[r] = false,
+            Loc::Spill(i) => self.spills[i] = false,
+            Loc::Param(_) => {}
+        }
+    }
+
+    fn alloc(&mut self) -> Loc {
+        if let Some(r) = self.regs.iter().position(|&used| !used) {
+            self.regs[r] = true;
+            return Loc::Reg(r);
+        }
+        let i = self
+            .spills
+            .iter()
+            .position(|&used| !used)
+            .unwrap_or_else(|| {
+                self.spills.push(false);
+                self.spills.len() - 1
+            });
+        self.spills[i] = true;
+        Loc::Spill(i)
+    }
+}
+
+impl Display for Loc {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            Loc::Reg(r) => write!(f, "{}", REGS[r]),
+            Loc::Param(i) => write!(f, "ws_params+{}(%rip)", 8 * i),
+            Loc::Spill(i) => write!(f, "ws_spill+{}(%rip)", 8 * i),
+        }
+    }
+}
+
+fn param_loc(i: usize) -> Loc {
+    if i < PARAM_REGS {
+        Loc::Reg(i)
+    } else {
+        Loc::Param(i - PARAM_REGS)
+    }
+}
+
+fn emit_move(asm: &mut String, src: Loc, dst: Loc) {
+    if let (Loc::Reg(_), _) | (_, Loc::Reg(_)) = (src, dst) {
+        writeln!(asm, "    movq {src}, {dst}").unwrap();
+    } else {
+        writeln!(asm, "    movq {src}, %rax\n    movq %rax, {dst}").unwrap();
+    }
+}
+
+fn emit_imm(asm: &mut String, n: i64, dst: Loc) {
+    if i32::try_from(n).is_ok() {
+        writeln!(asm, "    movq ${n}, {dst}").unwrap();
+    } else if let Loc::Reg(_) = dst {
+        writeln!(asm, "    movabsq ${n}, {dst}").unwrap();
+    } else {
+        writeln!(asm, "    movabsq ${n}, %rax\n    movq %rax, {dst}").unwrap();
+    }
+}
+
+fn emit_result(asm: &mut String, def: Option<Loc>) {
+    if let Some(d) = def {
+        writeln!(asm, "    movq %rax, {d}").unwrap();
+    }
+}
+
+fn label(id: BlockId) -> String {
+    format!(".Lb{}", id.0)
+}
+
+/// Escapes a string for `.ascii`.
+fn escape(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'"' | b'\\' => format!("\\{}", char::from(b)),
+            b' '..=b'~' => char::from(b).to_string(),
+            _ => format!("\\{b:03o}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use bitvec::prelude::*;
+
+    use super::*;
+    use crate::ws::codegen::tests::{build_and_run, push};
+    use crate::ws::inst::{Inst, RawInst};
+    use crate::ws::ir::Ir;
+    use crate::ws::syntax::{LabelOrder, Program};
+    use crate::ws::tests::get_tutorial_insts;
+
+    fn compile(insts: Vec<RawInst>) -> String {
+        let program = Program::new(insts, LabelOrder::Def);
+        emit_x86_64(&Ssa::new(&Ir::new(&program)).unwrap())
+    }
+
+    fn run(name: &str, asm: &str) -> Option<String> {
+        build_and_run(name, "s", asm, |src, exe| {
+            let obj = exe.with_extension("o");
+            let mut as_cmd = Command::new("as");
+            as_cmd.arg(src).arg("-o").arg(&obj);
+            let mut ld_cmd = Command::new("ld");
+            ld_cmd.arg(&obj).arg("-o").arg(exe);
+            vec![as_cmd, ld_cmd]
+        })
+    }
+
+    #[test]
+    fn tutorial() {
+        let program = Program::new(get_tutorial_insts(), LabelOrder::Def);
+        let ssa = Ssa::new(&Ir::new(&program)).unwrap();
+        let asm = emit_x86_64(&ssa);
+        let start = &asm[asm.find("_start:\n").unwrap()..];
+        let expected = "\
+_start:
+.Lb0:
+    movq $1, %rbx
+.Lb1:
+    movq %rbx, %rdi
+    call ws_printi
+    movq $10, %rbp
+    movq %rbp, %rdi
+    call ws_printc
+    movq $1, %rbp
+    movq %rbx, %rax
+    addq %rbp, %rax
+    jo ws_err_overflow
+    movq %rax, %rbx
+    movq $11, %rbp
+    movq %rbx, %rax
+    subq %rbp, %rax
+    jo ws_err_overflow
+    movq %rax, %rbp
+    cmpq $0, %rbp
+    je .Lb3
+.Lb2:
+    jmp .Lb1
+.Lb3:
+    jmp ws_exit
+.Lb4:
+    leaq .Lstr0(%rip), %rsi
+    movl $20, %edx
+    jmp ws_error
+
+    .bss
+    .p2align 3
+ws_params:
+    .skip 8
+ws_spill:
+    .skip 8
+
+    .section .rodata
+.Lstr0:
+    .ascii \"unterminated program\"
+";
+        assert_eq!(expected, start);
+    }
+
+    #[test]
+    fn spill() {
+        // Twelve values are live at once, more than there are registers, and
+        // are passed around a loop that swaps the top two each iteration, so
+        // the moves on the back edge form a cycle.
+        let mut insts = vec![push(0), push(3), Inst::Store];
+        insts.extend((1..=12).map(push));
+        insts.extend([
+            Inst::Label(bitvec![1]),
+            Inst::Swap,
+            push(0),
+            Inst::Retrieve,
+            push(1),
+            Inst::Sub,
+            Inst::Dup,
+            push(0),
+            Inst::Swap,
+            Inst::Store,
+            Inst::Jz(bitvec![1, 0]),
+            Inst::Jmp(bitvec![1]),
+            Inst::Label(bitvec![1, 0]),
+        ]);
+        for _ in 0..12 {
+            insts.extend([Inst::Printi, push(32), Inst::Printc]);
+        }
+        insts.push(Inst::End);
+        let asm = compile(insts);
+        assert!(asm.contains("    movq $12, ws_spill+16(%rip)\n"), "{asm}");
+        assert!(asm.contains("    pushq ws_params+56(%rip)\n"), "{asm}");
+        if let Some(out) = run("spill", &asm) {
+            assert_eq!("11 12 10 9 8 7 6 5 4 3 2 1 ", out);
+        }
+    }
+
+    #[test]
+    fn call_ret() {
+        // Arguments and results are passed in the parameter locations across
+        // machine calls, including a nested call.
+        let asm = compile(vec![
+            push(1),
+            Inst::Call(bitvec![1]),
+            Inst::Printi,
+            push(2),
+            Inst::Call(bitvec![1]),
+            Inst::Printi,
+            push(3),
+            push(4),
+            Inst::Call(bitvec![1, 0]),
+            Inst::Printi,
+            Inst::End,
+            Inst::Label(bitvec![1]),
+            Inst::Dup,
+            Inst::Printi,
+            push(10),
+            Inst::Mul,
+            Inst::Ret,
+            Inst::Label(bitvec![1, 0]),
+            Inst::Add,
+            Inst::Call(bitvec![1]),
+            Inst::Ret,
+        ]);
+        assert_eq!(4, asm.matches("    call .Lb").count(), "{asm}");
+        if let Some(out) = run("call_ret", &asm) {
+            assert_eq!("110220770", out);
+        }
+    }
+}
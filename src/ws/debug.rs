@@ -0,0 +1,383 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Interactive step debugger for Whitespace programs.
+//!
+//! The debugger reads commands line by line from the same stream that the
+//! program reads its input from. Instructions are shown in disassembly syntax,
+//! with labels named by the assembly source when it has names.
+
+use std::io::{self, BufRead, Write};
+
+use rug::Integer;
+
+use crate::ws::interp::{fmt_inst, fmt_stack, label_name, Interpreter};
+use crate::ws::syntax::{InstId, LabelId, LabelLiteral, Program};
+
+#[derive(Clone, Debug)]
+pub struct Debugger<'a> {
+    interp: Interpreter<'a>,
+    breakpoints: Vec<Breakpoint>,
+    /// Watched heap addresses, with the last seen value.
+    watches: Vec<(Integer, Integer)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Breakpoint {
+    /// Stops at any definition of the label.
+    Label(LabelId),
+    Inst(InstId),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Resume {
+    /// Executes the given number of instructions.
+    Step(usize),
+    /// Executes one instruction, running calls to completion.
+    Next,
+    /// Runs until the current call returns.
+    Finish,
+    Continue,
+}
+
+const HELP: &str = "\
+commands:
+  break [LABEL|@INST]   set a breakpoint, or list breakpoints and watches
+  delete [LABEL|@INST]  delete a breakpoint, or all breakpoints and watches
+  watch ADDR            stop when a heap cell changes
+  step [N], s           execute N instructions
+  next, n               execute one instruction, stepping over calls
+  finish                run until the current call returns
+  continue, c           run until a breakpoint or watch triggers
+  stack                 print the stack, from the bottom
+  heap                  print the set heap cells
+  backtrace, bt         print the call stack
+  list, l               print the instructions around the current one
+  help                  print this help
+  quit, q               exit the debugger
+";
+
+/// Number of instructions listed on each side of the current one.
+const LIST_CONTEXT: usize = 5;
+
+impl<'a> Debugger<'a> {
+    #[must_use]
+    pub fn new(program: &'a Program) -> Self {
+        Debugger {
+            interp: Interpreter::new(program),
+            breakpoints: Vec::new(),
+            watches: Vec::new(),
+        }
+    }
+
+    /// Reads and executes commands until `quit` or the end of the input.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when reading commands or writing output fails. Errors
+    /// in the program are reported to `out` instead.
+    pub fn repl<R: BufRead, W: Write>(&mut self, input: &mut R, out: &mut W) -> io::Result<()> {
+        self.print_location(out)?;
+        loop {
+            write!(out, "(debug) ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(out)?;
+                return Ok(());
+            }
+            if !self.exec(&line, input, out)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Executes a command and returns whether to keep debugging. The program
+    /// reads from `input` and writes to `out`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when reading input or writing output fails. Errors in
+    /// the program are reported to `out` instead.
+    pub fn exec<R: BufRead, W: Write>(
+        &mut self,
+        line: &str,
+        input: &mut R,
+        out: &mut W,
+    ) -> io::Result<bool> {
+        let mut words = line.split_whitespace();
+        let Some(cmd) = words.next() else {
+            return Ok(true);
+        };
+        let arg = words.next();
+        match (cmd, arg) {
+            ("break" | "b", None) => self.print_breakpoints(out)?,
+            ("break" | "b", Some(arg)) => match self.parse_breakpoint(arg) {
+                Some(bp) => {
+                    if !self.breakpoints.contains(&bp) {
+                        self.breakpoints.push(bp);
+                    }
+                    writeln!(out, "breakpoint at {}", self.fmt_breakpoint(bp))?;
+                }
+                None => writeln!(out, "unknown label or instruction: {arg}")?,
+            },
+            ("delete" | "d", None) => {
+                self.breakpoints.clear();
+                self.watches.clear();
+            }
+            ("delete" | "d", Some(arg)) => match self.parse_breakpoint(arg) {
+                Some(bp) if self.breakpoints.contains(&bp) => {
+                    self.breakpoints.retain(|&b| b != bp);
+                }
+                _ => writeln!(out, "no breakpoint at {arg}")?,
+            },
+            ("watch" | "w", Some(arg)) => match Integer::from_str_radix(arg, 10) {
+                Ok(addr) => {
+                    let value = self.cell(&addr);
+                    writeln!(out, "watching heap[{addr}] = {value}")?;
+                    if !self.watches.iter().any(|(a, _)| *a == addr) {
+                        self.watches.push((addr, value));
+                    }
+                }
+                Err(_) => writeln!(out, "invalid address: {arg}")?,
+            },
+            ("step" | "s", None) => self.resume(Resume::Step(1), input, out)?,
+            ("step" | "s", Some(arg)) => match arg.parse() {
+                Ok(n) => self.resume(Resume::Step(n), input, out)?,
+                Err(_) => writeln!(out, "invalid count: {arg}")?,
+            },
+            ("next" | "n", None) => self.resume(Resume::Next, input, out)?,
+            ("finish", None) => self.resume(Resume::Finish, input, out)?,
+            ("continue" | "c", None) => self.resume(Resume::Continue, input, out)?,
+            ("stack", None) => writeln!(out, "{}", fmt_stack(self.interp.stack()))?,
+            ("heap", None) => {
+                for (addr, value) in self.interp.heap() {
+                    writeln!(out, "{addr}: {value}")?;
+                }
+            }
+            ("backtrace" | "bt", None) => {
+                let pc = self.interp.pc();
+                writeln!(out, "#0 {}", self.fmt_inst(pc))?;
+                for (i, &call) in self.interp.calls().iter().rev().enumerate() {
+                    writeln!(out, "#{} {}", i + 1, self.fmt_inst(call))?;
+                }
+            }
+            ("list" | "l", None) => self.print_list(out)?,
+            ("help" | "h", None) => write!(out, "{HELP}")?,
+            ("quit" | "q", None) => return Ok(false),
+            _ => writeln!(out, "invalid command: {}", line.trim())?,
+        }
+        Ok(true)
+    }
+
+    fn resume<R: BufRead, W: Write>(
+        &mut self,
+        mode: Resume,
+        input: &mut R,
+        out: &mut W,
+    ) -> io::Result<()> {
+        if self.interp.is_halted() {
+            return writeln!(out, "program has ended");
+        }
+        let depth = self.interp.calls().len();
+        let mut steps = 0;
+        loop {
+            let pc = self.interp.pc();
+            let mut debug = Vec::new();
+            let res = self.interp.step(input, out, &mut debug);
+            out.write_all(&debug)?;
+            if let Err(err) = res {
+                writeln!(out, "\nerror: {err:?}")?;
+                return self.print_location(out);
+            }
+            out.flush()?;
+            steps += 1;
+            if self.interp.is_halted() {
+                writeln!(out, "\nprogram ended at {}", self.fmt_inst(pc))?;
+                return Ok(());
+            }
+            let watched = self.check_watches(out)?;
+            let done = match mode {
+                Resume::Step(n) => steps >= n,
+                Resume::Next => self.interp.calls().len() <= depth,
+                Resume::Finish => self.interp.calls().len() < depth,
+                Resume::Continue => false,
+            };
+            if watched || done || self.at_breakpoint() {
+                return self.print_location(out);
+            }
+        }
+    }
+
+    /// Reports changed watched cells and returns whether any changed.
+    fn check_watches<W: Write>(&mut self, out: &mut W) -> io::Result<bool> {
+        let mut changed = false;
+        for (addr, last) in &mut self.watches {
+            let value = self.interp.heap().get(addr).cloned().unwrap_or_default();
+            if value != *last {
+                writeln!(out, "heap[{addr}]: {last} -> {value}")?;
+                *last = value;
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        let pc = self.interp.pc();
+        self.breakpoints.iter().any(|&bp| match bp {
+            Breakpoint::Label(l) => self.interp.program().labels()[l].defs().contains(&pc),
+            Breakpoint::Inst(inst) => inst == pc,
+        })
+    }
+
+    fn parse_breakpoint(&self, arg: &str) -> Option<Breakpoint> {
+        if let Some(inst) = arg.strip_prefix('@') {
+            let inst = inst.parse::<usize>().ok()?;
+            return (inst < self.interp.program().insts().len())
+                .then(|| Breakpoint::Inst(InstId::from(inst)));
+        }
+        let labels = self.interp.program().labels();
+        let label = labels.iter().find(|label| {
+            label.names().iter().any(|(_, name)| name == arg)
+                || LabelLiteral::from_bits(label.bits().clone()).to_string() == arg
+        })?;
+        Some(Breakpoint::Label(label.id()))
+    }
+
+    fn cell(&self, addr: &Integer) -> Integer {
+        self.interp.heap().get(addr).cloned().unwrap_or_default()
+    }
+
+    fn print_location<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        if self.interp.is_halted() {
+            writeln!(out, "program has ended")
+        } else {
+            writeln!(out, "{}", self.fmt_inst(self.interp.pc()))
+        }
+    }
+
+    fn print_breakpoints<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for &bp in &self.breakpoints {
+            writeln!(out, "breakpoint at {}", self.fmt_breakpoint(bp))?;
+        }
+        for (addr, value) in &self.watches {
+            writeln!(out, "watch heap[{addr}] = {value}")?;
+        }
+        Ok(())
+    }
+
+    fn print_list<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let pc = usize::from(self.interp.pc());
+        let len = self.interp.program().insts().len();
+        let start = pc.saturating_sub(LIST_CONTEXT);
+        let end = (pc + LIST_CONTEXT + 1).min(len);
+        for i in start..end {
+            let id = InstId::from(i);
+            let marker = if i == pc { "=>" } else { "  " };
+            let bp = if self.breakpoints.contains(&Breakpoint::Inst(id)) {
+                "*"
+            } else {
+                " "
+            };
+            writeln!(out, "{marker}{bp}{}", self.fmt_inst(id))?;
+        }
+        Ok(())
+    }
+
+    fn fmt_breakpoint(&self, bp: Breakpoint) -> String {
+        match bp {
+            Breakpoint::Label(l) => label_name(&self.interp.program().labels()[l], None),
+            Breakpoint::Inst(inst) => format!("@{}", usize::from(inst)),
+        }
+    }
+
+    /// Formats an instruction with its index.
+    fn fmt_inst(&self, id: InstId) -> String {
+        let program = self.interp.program();
+        if usize::from(id) >= program.insts().len() {
+            return format!("{:>4}  <end of program>", usize::from(id));
+        }
+        format!("{:>4}  {}", usize::from(id), fmt_inst(program, id))
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn interp(&self) -> &Interpreter<'a> {
+        &self.interp
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn interp_mut(&mut self) -> &mut Interpreter<'a> {
+        &mut self.interp
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use bitvec::prelude::*;
+
+    use super::*;
+    use crate::ws::inst::Inst;
+    use crate::ws::syntax::LabelOrder;
+
+    #[test]
+    fn session() {
+        let program = Program::new(
+            vec![
+                Inst::Push(bitvec![0, 0]),
+                Inst::Push(bitvec![0, 1, 1, 1]),
+                Inst::Call(bitvec![1]),
+                Inst::End,
+                Inst::Label(bitvec![1]),
+                Inst::Store,
+                Inst::Ret,
+            ],
+            LabelOrder::Def,
+        );
+        let mut debugger = Debugger::new(&program);
+        let mut input =
+            Cursor::new("break 1\nnext\nc\nbt\nwatch 0\nstep\nstep\nfinish\nheap\nlist\nc\nc\n");
+        let mut out = Vec::new();
+        debugger.repl(&mut input, &mut out).unwrap();
+        let expected = "   0  push 0
+(debug) breakpoint at 1
+(debug)    1  push 7
+(debug)    4  label 1
+(debug) #0    4  label 1
+#1    2  call 1
+(debug) watching heap[0] = 0
+(debug)    5  store
+(debug) heap[0]: 0 -> 7
+   6  ret
+(debug)    3  end
+(debug) 0: 7
+(debug)       0  push 0
+      1  push 7
+      2  call 1
+=>    3  end
+      4  label 1
+      5  store
+      6  ret
+(debug) 
+program ended at    3  end
+(debug) program has ended
+(debug) 
+";
+        assert_eq!(expected, String::from_utf8(out).unwrap());
+    }
+}
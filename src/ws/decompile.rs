@@ -0,0 +1,961 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Decompiler from jumps to structured pseudocode.
+//!
+//! Each subroutine is structured separately on its intraprocedural
+//! control-flow graph with the algorithm from “Beyond Relooper: Recursive
+//! Translation of Unstructured Control Flow to Structured Control Flow” by
+//! Norman Ramsey. A block is placed inside the block that immediately
+//! dominates it, loop headers open loops, and blocks with several forward
+//! predecessors are placed after a labeled region that is broken out of to
+//! reach them. The result is then simplified to `if`/`else` and `while` where
+//! possible. Subroutines with irreducible control flow are printed as labeled
+//! blocks with `goto`s instead.
+//!
+//! Stack values are the SSA values, named as locals, and block parameters are
+//! assigned on the edges to them. Constants and arithmetic used once are
+//! folded into the expression that uses them.
+
+use std::fmt::{self, Display, Formatter, Write};
+
+use crate::ws::cfg::{Cfg, EdgeKind};
+use crate::ws::ir::BlockId;
+use crate::ws::ssa::{Edge, Exit, Op, Ssa, SsaError, ValueDef, ValueId};
+use crate::ws::subroutine::{CallGraph, SubId};
+
+/// Decompiles the subroutines of a program to C-like pseudocode.
+///
+/// # Errors
+///
+/// Returns an error when a subroutine cannot be lowered to SSA form.
+pub fn decompile(graph: &CallGraph) -> Result<String, SsaError> {
+    let ssa = Ssa::new(graph.ir())?;
+    let mut uses = vec![0u32; ssa.values().len()];
+    for block in ssa.blocks() {
+        let stmt_uses = block.stmts().iter().flat_map(|stmt| stmt.op.uses());
+        for v in stmt_uses.chain(block.exit().uses()) {
+            uses[usize::from(v)] += 1;
+        }
+    }
+    let inline = ssa
+        .values()
+        .iter()
+        .zip(&uses)
+        .map(|(&def, &n)| match op_of(&ssa, def) {
+            Some(Op::Const(_)) => true,
+            Some(Op::Add(..) | Op::Sub(..) | Op::Mul(..)) => n == 1,
+            _ => false,
+        })
+        .collect::<Vec<_>>();
+
+    let mut w = String::new();
+    for sub in graph.subroutines() {
+        let cfg = Cfg::subroutine(graph, sub.id());
+        let d = Decompiler::new(graph, &ssa, &cfg, &uses, &inline);
+        let body = if d.is_reducible() {
+            simplify(d.tree(sub.entry()))
+        } else {
+            d.unstructured()
+        };
+        if sub.id() != SubId(0) {
+            w.push('\n');
+        }
+        write!(w, "{}(", sub_name(sub.id())).unwrap();
+        write_list(
+            &mut w,
+            ssa[sub.entry()].params().iter().map(ToString::to_string),
+        );
+        w.push_str(") {\n");
+        render(&mut w, &body, 1, None);
+        w.push_str("}\n");
+    }
+    Ok(w)
+}
+
+struct Decompiler<'a> {
+    graph: &'a CallGraph,
+    ssa: &'a Ssa,
+    cfg: &'a Cfg,
+    /// Number of uses of each value.
+    uses: &'a [u32],
+    /// Whether each value is folded into its use.
+    inline: &'a [bool],
+    /// Index of each block in reverse postorder.
+    order: Vec<usize>,
+    /// Children of each block in the dominator tree, in reverse postorder.
+    children: Vec<Vec<BlockId>>,
+}
+
+/// Structured statement.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Stmt {
+    Line(String),
+    /// Line that does not continue to the next statement.
+    Exit(String),
+    If {
+        cond: Cond,
+        then: Vec<Stmt>,
+        else_: Vec<Stmt>,
+    },
+    /// Loop that repeats from its header. It has a condition only after
+    /// simplification.
+    Loop {
+        header: BlockId,
+        cond: Option<Cond>,
+        body: Vec<Stmt>,
+    },
+    /// Region that is broken out of to reach the merge block after it.
+    Region {
+        merge: BlockId,
+        body: Vec<Stmt>,
+    },
+    Break(Target),
+    Continue(BlockId),
+    Label(BlockId),
+    Goto(BlockId),
+}
+
+/// Target of a `break`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Target {
+    Loop(BlockId),
+    Region(BlockId),
+}
+
+/// Condition of `jz` or `jn`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Cond {
+    value: String,
+    neg: bool,
+    negated: bool,
+}
+
+impl<'a> Decompiler<'a> {
+    fn new(
+        graph: &'a CallGraph,
+        ssa: &'a Ssa,
+        cfg: &'a Cfg,
+        uses: &'a [u32],
+        inline: &'a [bool],
+    ) -> Self {
+        let len = ssa.blocks().len();
+        let mut order = vec![usize::MAX; len];
+        let mut children = vec![Vec::new(); len];
+        for (i, &b) in cfg.rpo().iter().enumerate() {
+            order[usize::from(b)] = i;
+            if let Some(idom) = cfg.idom(b) {
+                children[usize::from(idom)].push(b);
+            }
+        }
+        Decompiler {
+            graph,
+            ssa,
+            cfg,
+            uses,
+            inline,
+            order,
+            children,
+        }
+    }
+
+    /// Returns whether every retreating edge is a back edge to a loop header.
+    fn is_reducible(&self) -> bool {
+        self.cfg.edges().iter().all(|e| {
+            !self.cfg.is_reachable(e.from) || !self.is_backward(e.from, e.to) || {
+                self.cfg.dominates(e.to, e.from)
+            }
+        })
+    }
+
+    fn is_backward(&self, from: BlockId, to: BlockId) -> bool {
+        self.order[usize::from(to)] <= self.order[usize::from(from)]
+    }
+
+    /// Returns whether a block has several forward predecessors, so it can
+    /// not be placed inside any one of them.
+    fn is_merge(&self, b: BlockId) -> bool {
+        let preds = self.cfg.preds(b);
+        preds.filter(|e| !self.is_backward(e.from, b)).count() > 1
+    }
+
+    fn is_loop_header(&self, b: BlockId) -> bool {
+        self.cfg.preds(b).any(|e| self.is_backward(e.from, b))
+    }
+
+    /// Translates a block and the blocks it dominates.
+    fn tree(&self, b: BlockId) -> Vec<Stmt> {
+        let mut merges = self.children[usize::from(b)]
+            .iter()
+            .copied()
+            .filter(|&c| self.is_merge(c))
+            .collect::<Vec<_>>();
+        merges.reverse();
+        let body = self.within(b, &merges);
+        if self.is_loop_header(b) {
+            vec![Stmt::Loop { header: b, cond: None, body }]
+        } else {
+            body
+        }
+    }
+
+    /// Translates a block nested in regions for the merge blocks it
+    /// dominates, which are ordered from the last to the first.
+    fn within(&self, b: BlockId, merges: &[BlockId]) -> Vec<Stmt> {
+        if let Some((&merge, rest)) = merges.split_first() {
+            let mut stmts = vec![Stmt::Region {
+                merge,
+                body: self.within(b, rest),
+            }];
+            stmts.extend(self.tree(merge));
+            return stmts;
+        }
+        let mut stmts = self.body(b);
+        self.exit(b, &mut stmts, &|to, stmts: &mut Vec<Stmt>| {
+            if self.is_backward(b, to) {
+                stmts.push(Stmt::Continue(to));
+            } else if self.is_merge(to) {
+                stmts.push(Stmt::Break(Target::Region(to)));
+            } else {
+                stmts.extend(self.tree(to));
+            }
+        });
+        stmts
+    }
+
+    /// Translates the blocks in reverse postorder with labels and `goto`s.
+    fn unstructured(&self) -> Vec<Stmt> {
+        let mut stmts = Vec::new();
+        for &b in self.cfg.rpo() {
+            stmts.push(Stmt::Label(b));
+            stmts.extend(self.body(b));
+            self.exit(b, &mut stmts, &|to, stmts: &mut Vec<Stmt>| {
+                stmts.push(Stmt::Goto(to));
+            });
+        }
+        // Jumps to the next block fall through.
+        let mut out: Vec<Stmt> = Vec::with_capacity(stmts.len());
+        for stmt in simplify(stmts) {
+            if let Stmt::Label(b) = stmt {
+                if out.last() == Some(&Stmt::Goto(b)) {
+                    out.pop();
+                }
+            }
+            out.push(stmt);
+        }
+        out
+    }
+
+    fn body(&self, b: BlockId) -> Vec<Stmt> {
+        let mut stmts = Vec::new();
+        for stmt in self.ssa[b].stmts() {
+            let line = match (&stmt.op, stmt.def) {
+                (
+                    Op::Const(_) | Op::Add(..) | Op::Sub(..) | Op::Mul(..) | Op::Retrieve(_),
+                    Some(def),
+                ) if self.inline[usize::from(def)] || self.uses[usize::from(def)] == 0 => {
+                    continue;
+                }
+                (op, Some(def)) => format!("{def} = {};", self.op(op)),
+                (&Op::Store { addr, value }, None) => {
+                    format!("heap[{}] = {};", self.expr(addr), self.expr(value))
+                }
+                (&Op::Printc(v), _) => format!("printc({});", self.expr(v)),
+                (&Op::Printi(v), _) => format!("printi({});", self.expr(v)),
+                (&Op::Readc(addr), _) => format!("heap[{}] = readc();", self.expr(addr)),
+                (&Op::Readi(addr), _) => format!("heap[{}] = readi();", self.expr(addr)),
+                (Op::DumpStack, _) => "dump_stack();".to_owned(),
+                (Op::DumpHeap, _) => "dump_heap();".to_owned(),
+                (Op::DumpTrace, _) => "dump_trace();".to_owned(),
+                (op, None) => format!("{};", self.op(op)),
+            };
+            stmts.push(Stmt::Line(line));
+        }
+        stmts
+    }
+
+    /// Translates the exit of a block, with `jump` translating the transfer
+    /// of control to another block.
+    fn exit(&self, b: BlockId, stmts: &mut Vec<Stmt>, jump: &dyn Fn(BlockId, &mut Vec<Stmt>)) {
+        let branch = |edge: &Edge, stmts: &mut Vec<Stmt>| {
+            self.assign(b, edge, stmts);
+            jump(edge.target, stmts);
+        };
+        let cond = |cond: ValueId, neg: bool, taken: &Edge, other: &Edge| {
+            let (mut then, mut else_) = (Vec::new(), Vec::new());
+            branch(taken, &mut then);
+            branch(other, &mut else_);
+            Stmt::If {
+                cond: Cond {
+                    value: self.expr(cond),
+                    neg,
+                    negated: false,
+                },
+                then,
+                else_,
+            }
+        };
+        match self.ssa[b].exit() {
+            Exit::Jmp(edge) => branch(edge, stmts),
+            Exit::Jz { cond: c, zero, other } => stmts.push(cond(*c, false, zero, other)),
+            Exit::Jn { cond: c, neg, other } => stmts.push(cond(*c, true, neg, other)),
+            Exit::Call { callee, ret } => {
+                let name = self
+                    .graph
+                    .subroutines()
+                    .iter()
+                    .find(|s| s.entry() == callee.target)
+                    .map_or_else(|| callee.target.to_string(), |s| sub_name(s.id()));
+                let mut line = String::new();
+                match self.ssa[*ret].params() {
+                    [] => {}
+                    [v] => write!(line, "{v} = ").unwrap(),
+                    params => {
+                        line.push('(');
+                        write_list(&mut line, params.iter().map(ToString::to_string));
+                        line.push_str(") = ");
+                    }
+                }
+                line.push_str(&name);
+                line.push('(');
+                write_list(&mut line, callee.args.iter().map(|&v| self.expr(v)));
+                line.push_str(");");
+                if self.cfg.succs(b).any(|e| e.kind == EdgeKind::CallReturn) {
+                    stmts.push(Stmt::Line(line));
+                    jump(*ret, stmts);
+                } else {
+                    stmts.push(Stmt::Exit(line));
+                }
+            }
+            Exit::Ret(args) => {
+                let mut line = "return".to_owned();
+                if !args.is_empty() {
+                    line.push(' ');
+                    write_list(&mut line, args.iter().map(|&v| self.expr(v)));
+                }
+                line.push(';');
+                stmts.push(Stmt::Exit(line));
+            }
+            Exit::End => stmts.push(Stmt::Exit("exit();".to_owned())),
+            Exit::Unterminated => stmts.push(Stmt::Exit("error(\"unterminated\");".to_owned())),
+            Exit::UndefinedLabel(l) => {
+                stmts.push(Stmt::Exit(format!("error(\"undefined label {l}\");")));
+            }
+            Exit::Error(err) => stmts.push(Stmt::Exit(format!("error(\"{err:?}\");"))),
+        }
+    }
+
+    /// Assigns the arguments of an edge to the parameters of its target,
+    /// skipping unused parameters. The assignment is parallel for a block
+    /// that jumps to itself.
+    fn assign(&self, from: BlockId, edge: &Edge, stmts: &mut Vec<Stmt>) {
+        let pairs = self.ssa[edge.target]
+            .params()
+            .iter()
+            .zip(&edge.args)
+            .filter(|(&param, _)| self.uses[usize::from(param)] != 0)
+            .collect::<Vec<_>>();
+        if edge.target == from && pairs.len() > 1 {
+            let mut line = "(".to_owned();
+            write_list(&mut line, pairs.iter().map(|(p, _)| p.to_string()));
+            line.push_str(") = (");
+            write_list(&mut line, pairs.iter().map(|(_, &a)| self.expr(a)));
+            line.push_str(");");
+            stmts.push(Stmt::Line(line));
+        } else {
+            for (param, &arg) in pairs {
+                stmts.push(Stmt::Line(format!("{param} = {};", self.expr(arg))));
+            }
+        }
+    }
+
+    /// Formats a value, folding it if it is inlined.
+    fn expr(&self, v: ValueId) -> String {
+        if self.inline[usize::from(v)] {
+            if let Some(op) = op_of(self.ssa, self.ssa.values()[usize::from(v)]) {
+                return self.op(op);
+            }
+        }
+        v.to_string()
+    }
+
+    /// Formats a value as the operand of a binary operator.
+    fn operand(&self, v: ValueId) -> String {
+        let expr = self.expr(v);
+        match op_of(self.ssa, self.ssa.values()[usize::from(v)]) {
+            Some(Op::Add(..) | Op::Sub(..) | Op::Mul(..)) if self.inline[usize::from(v)] => {
+                format!("({expr})")
+            }
+            _ => expr,
+        }
+    }
+
+    fn op(&self, op: &Op) -> String {
+        let binary = |a, sym, b| format!("{} {sym} {}", self.operand(a), self.operand(b));
+        match *op {
+            Op::Const(ref n) => n.to_string(),
+            Op::Add(a, b) => binary(a, "+", b),
+            Op::Sub(a, b) => binary(a, "-", b),
+            Op::Mul(a, b) => binary(a, "*", b),
+            Op::Div(a, b) => binary(a, "/", b),
+            Op::Mod(a, b) => binary(a, "%", b),
+            Op::Retrieve(addr) => format!("heap[{}]", self.expr(addr)),
+            _ => op.to_string(),
+        }
+    }
+}
+
+fn sub_name(sub: SubId) -> String {
+    if sub == SubId(0) {
+        "main".to_owned()
+    } else {
+        sub.to_string()
+    }
+}
+
+fn op_of(ssa: &Ssa, def: ValueDef) -> Option<&Op> {
+    match def {
+        ValueDef::Stmt(b, i) => Some(&ssa[b].stmts()[i].op),
+        ValueDef::Param(..) => None,
+    }
+}
+
+fn write_list<I: Iterator<Item = String>>(w: &mut String, items: I) {
+    for (i, item) in items.enumerate() {
+        if i != 0 {
+            w.push_str(", ");
+        }
+        w.push_str(&item);
+    }
+}
+
+/// Simplifies structured statements by removing jumps to where control would
+/// reach anyways, removing regions that are not broken out of, and forming
+/// `while` loops.
+fn simplify(stmts: Vec<Stmt>) -> Vec<Stmt> {
+    let mut out = Vec::with_capacity(stmts.len());
+    for stmt in stmts {
+        match stmt {
+            Stmt::Loop { header, cond, mut body } => {
+                // Simplifying can move jumps to the end, such as from a nested
+                // loop.
+                remove_tail(&mut body, &Stmt::Continue(header));
+                let mut body = simplify(body);
+                remove_tail(&mut body, &Stmt::Continue(header));
+                // A loop without any `break` can instead break before the
+                // first `if` that leaves it and continue after it.
+                let mut after = Vec::new();
+                if !jumps_to(&body, header, true) {
+                    let exit = body.iter_mut().find_map(|stmt| match stmt {
+                        Stmt::If { then, else_, .. }
+                            if else_.is_empty()
+                                && ends_in_jump(then)
+                                && !jumps_to(then, header, false) =>
+                        {
+                            Some(then)
+                        }
+                        _ => None,
+                    });
+                    if let Some(then) = exit {
+                        after = std::mem::replace(then, vec![Stmt::Break(Target::Loop(header))]);
+                    }
+                }
+                let mut cond = cond;
+                if let Some(Stmt::If { cond: c, then, else_ }) = body.first() {
+                    if cond.is_none()
+                        && else_.is_empty()
+                        && then == &[Stmt::Break(Target::Loop(header))]
+                    {
+                        cond = Some(c.negate());
+                        body.remove(0);
+                    }
+                }
+                out.push(Stmt::Loop { header, cond, body });
+                out.extend(after);
+            }
+            Stmt::Region { merge, mut body } => {
+                remove_tail(&mut body, &Stmt::Break(Target::Region(merge)));
+                // Breaking out of a loop at the end of the region reaches the
+                // merge block.
+                if let Some(Stmt::Loop { header, body: loop_body, .. }) = body.last_mut() {
+                    retarget(loop_body, merge, *header);
+                }
+                let mut body = simplify(body);
+                remove_tail(&mut body, &Stmt::Break(Target::Region(merge)));
+                if breaks_to(&body, merge) {
+                    out.push(Stmt::Region { merge, body });
+                } else {
+                    out.extend(body);
+                }
+            }
+            Stmt::If { cond, then, else_ } => {
+                let (mut cond, mut then, mut else_) = (cond, simplify(then), simplify(else_));
+                if then.is_empty() && else_.is_empty() {
+                    continue;
+                }
+                if then.is_empty() {
+                    cond = cond.negate();
+                    std::mem::swap(&mut then, &mut else_);
+                }
+                if ends_in_jump(&then) {
+                    out.push(Stmt::If { cond, then, else_: Vec::new() });
+                    out.extend(else_);
+                } else {
+                    out.push(Stmt::If { cond, then, else_ });
+                }
+            }
+            stmt => out.push(stmt),
+        }
+    }
+    out
+}
+
+/// Removes a jump from the end of every path through the statements.
+fn remove_tail(stmts: &mut Vec<Stmt>, jump: &Stmt) {
+    match stmts.last_mut() {
+        Some(Stmt::If { then, else_, .. }) => {
+            remove_tail(then, jump);
+            remove_tail(else_, jump);
+        }
+        Some(last) if last == jump => {
+            stmts.pop();
+        }
+        _ => {}
+    }
+}
+
+/// Returns whether control does not continue after the statements.
+fn ends_in_jump(stmts: &[Stmt]) -> bool {
+    match stmts.last() {
+        Some(Stmt::Exit(_) | Stmt::Break(_) | Stmt::Continue(_) | Stmt::Goto(_)) => true,
+        Some(Stmt::If { then, else_, .. }) => ends_in_jump(then) && ends_in_jump(else_),
+        _ => false,
+    }
+}
+
+/// Returns whether any statement breaks out of the loop, or if `any`, also
+/// continues it.
+fn jumps_to(stmts: &[Stmt], header: BlockId, only_break: bool) -> bool {
+    stmts.iter().any(|stmt| match stmt {
+        Stmt::Break(Target::Loop(h)) => *h == header,
+        Stmt::Continue(h) => !only_break && *h == header,
+        Stmt::If { then, else_, .. } => {
+            jumps_to(then, header, only_break) || jumps_to(else_, header, only_break)
+        }
+        Stmt::Loop { body, .. } | Stmt::Region { body, .. } => jumps_to(body, header, only_break),
+        _ => false,
+    })
+}
+
+/// Returns whether any statement breaks out of the region.
+fn breaks_to(stmts: &[Stmt], merge: BlockId) -> bool {
+    stmts.iter().any(|stmt| match stmt {
+        Stmt::Break(Target::Region(m)) => *m == merge,
+        Stmt::If { then, else_, .. } => breaks_to(then, merge) || breaks_to(else_, merge),
+        Stmt::Loop { body, .. } | Stmt::Region { body, .. } => breaks_to(body, merge),
+        _ => false,
+    })
+}
+
+/// Replaces breaks out of a region with breaks out of a loop.
+fn retarget(stmts: &mut [Stmt], merge: BlockId, header: BlockId) {
+    for stmt in stmts {
+        match stmt {
+            Stmt::Break(target) if *target == Target::Region(merge) => {
+                *target = Target::Loop(header);
+            }
+            Stmt::If { then, else_, .. } => {
+                retarget(then, merge, header);
+                retarget(else_, merge, header);
+            }
+            Stmt::Loop { body, .. } | Stmt::Region { body, .. } => retarget(body, merge, header),
+            _ => {}
+        }
+    }
+}
+
+/// Returns whether a jump to the loop is nested in another loop, so needs a
+/// label.
+fn needs_label(stmts: &[Stmt], header: BlockId, nested: bool) -> bool {
+    stmts.iter().any(|stmt| match stmt {
+        Stmt::Break(Target::Loop(h)) | Stmt::Continue(h) => nested && *h == header,
+        Stmt::If { then, else_, .. } => {
+            needs_label(then, header, nested) || needs_label(else_, header, nested)
+        }
+        Stmt::Loop { body, .. } => needs_label(body, header, true),
+        Stmt::Region { body, .. } => needs_label(body, header, nested),
+        _ => false,
+    })
+}
+
+/// Writes the statements at an indentation level, within the innermost loop.
+fn render(w: &mut String, stmts: &[Stmt], depth: usize, innermost: Option<BlockId>) {
+    let indent = "    ".repeat(depth);
+    let jump = |w: &mut String, keyword: &str, header: BlockId| {
+        if innermost == Some(header) {
+            writeln!(w, "{indent}{keyword};").unwrap();
+        } else {
+            writeln!(w, "{indent}{keyword} {header};").unwrap();
+        }
+    };
+    for stmt in stmts {
+        match stmt {
+            Stmt::Line(line) | Stmt::Exit(line) => writeln!(w, "{indent}{line}").unwrap(),
+            Stmt::If { cond, then, else_ } => {
+                writeln!(w, "{indent}if ({cond}) {{").unwrap();
+                render(w, then, depth + 1, innermost);
+                if !else_.is_empty() {
+                    writeln!(w, "{indent}}} else {{").unwrap();
+                    render(w, else_, depth + 1, innermost);
+                }
+                writeln!(w, "{indent}}}").unwrap();
+            }
+            Stmt::Loop { header, cond, body } => {
+                w.push_str(&indent);
+                if needs_label(body, *header, false) {
+                    write!(w, "{header}: ").unwrap();
+                }
+                match cond {
+                    Some(cond) => writeln!(w, "while ({cond}) {{").unwrap(),
+                    None => w.push_str("while (true) {\n"),
+                }
+                render(w, body, depth + 1, Some(*header));
+                writeln!(w, "{indent}}}").unwrap();
+            }
+            Stmt::Region { merge, body } => {
+                writeln!(w, "{indent}{merge}: {{").unwrap();
+                render(w, body, depth + 1, innermost);
+                writeln!(w, "{indent}}}").unwrap();
+            }
+            Stmt::Break(Target::Loop(header)) => jump(w, "break", *header),
+            Stmt::Break(Target::Region(merge)) => writeln!(w, "{indent}break {merge};").unwrap(),
+            Stmt::Continue(header) => jump(w, "continue", *header),
+            Stmt::Label(b) => writeln!(w, "{}{b}:", "    ".repeat(depth - 1)).unwrap(),
+            Stmt::Goto(b) => writeln!(w, "{indent}goto {b};").unwrap(),
+        }
+    }
+}
+
+impl Cond {
+    fn negate(&self) -> Self {
+        Cond {
+            negated: !self.negated,
+            ..self.clone()
+        }
+    }
+}
+
+impl Display for Cond {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let op = match (self.neg, self.negated) {
+            (false, false) => "==",
+            (false, true) => "!=",
+            (true, false) => "<",
+            (true, true) => ">=",
+        };
+        write!(f, "{} {op} 0", self.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitvec::prelude::*;
+    use rug::Integer;
+
+    use super::*;
+    use crate::ws::inst::{Inst, RawInst};
+    use crate::ws::ir::Ir;
+    use crate::ws::syntax::{IntLiteral, LabelOrder, Program};
+    use crate::ws::tests::get_tutorial_insts;
+
+    fn push(n: i64) -> RawInst {
+        Inst::Push(IntLiteral::from(Integer::from(n)).bits().clone())
+    }
+
+    fn decompile_insts(insts: Vec<RawInst>) -> Result<String, SsaError> {
+        let program = Program::new(insts, LabelOrder::Def);
+        decompile(&CallGraph::new(Ir::new(&program)))
+    }
+
+    #[test]
+    fn tutorial() {
+        let program = Program::new(get_tutorial_insts(), LabelOrder::Def);
+        let graph = CallGraph::new(Ir::new(&program));
+        let expected = "main() {
+    v1 = 1;
+    while (true) {
+        printi(v1);
+        printc(10);
+        v4 = v1 + 1;
+        if (v4 - 11 == 0) {
+            break;
+        }
+        v7 = v4;
+        v1 = v7;
+    }
+    exit();
+}
+";
+        assert_eq!(Ok(expected.to_owned()), decompile(&graph));
+    }
+
+    #[test]
+    fn if_else() {
+        // Both branches of the `jn` merge before the last `printi`.
+        let insts = vec![
+            push(0),
+            Inst::Readi,
+            push(0),
+            Inst::Retrieve,
+            Inst::Jn(bitvec![1]),
+            push(2),
+            Inst::Printi,
+            Inst::Jmp(bitvec![1, 0]),
+            Inst::Label(bitvec![1]),
+            push(1),
+            Inst::Printi,
+            Inst::Label(bitvec![1, 0]),
+            push(3),
+            Inst::Printi,
+            Inst::End,
+        ];
+        let expected = "\
+main() {
+    heap[0] = readi();
+    v2 = heap[0];
+    if (v2 < 0) {
+        printi(1);
+    } else {
+        printi(2);
+    }
+    printi(3);
+    exit();
+}
+";
+        assert_eq!(Ok(expected.to_owned()), decompile_insts(insts));
+    }
+
+    #[test]
+    fn loop_break() {
+        // The loop exits at the top, with a one-armed `if` in its body.
+        let insts = vec![
+            Inst::Label(bitvec![1]),
+            push(0),
+            Inst::Retrieve,
+            Inst::Jz(bitvec![1, 1]),
+            push(0),
+            Inst::Retrieve,
+            push(2),
+            Inst::Mod,
+            Inst::Jz(bitvec![1, 0]),
+            push(1),
+            Inst::Printi,
+            Inst::Label(bitvec![1, 0]),
+            push(0),
+            push(0),
+            Inst::Retrieve,
+            push(2),
+            Inst::Div,
+            Inst::Store,
+            Inst::Jmp(bitvec![1]),
+            Inst::Label(bitvec![1, 1]),
+            push(4),
+            Inst::Printi,
+            Inst::End,
+        ];
+        let expected = "\
+main() {
+    while (true) {
+        v1 = heap[0];
+        if (v1 == 0) {
+            break;
+        }
+        v3 = heap[0];
+        v5 = v3 % 2;
+        if (v5 != 0) {
+            printi(1);
+        }
+        v9 = heap[0];
+        v11 = v9 / 2;
+        heap[0] = v11;
+    }
+    printi(4);
+    exit();
+}
+";
+        assert_eq!(Ok(expected.to_owned()), decompile_insts(insts));
+    }
+
+    #[test]
+    fn nested_break() {
+        // The inner loop exits both loops to the end of the program, so it
+        // breaks out of a labeled block.
+        let insts = vec![
+            push(0),
+            push(3),
+            Inst::Store,
+            Inst::Label(bitvec![1]),
+            push(0),
+            Inst::Retrieve,
+            Inst::Jz(bitvec![1, 0, 0, 1]),
+            push(1),
+            push(2),
+            Inst::Store,
+            Inst::Label(bitvec![1, 0]),
+            push(1),
+            Inst::Retrieve,
+            Inst::Jz(bitvec![1, 1]),
+            push(1),
+            push(1),
+            Inst::Retrieve,
+            push(1),
+            Inst::Sub,
+            Inst::Store,
+            push(0),
+            Inst::Retrieve,
+            push(2),
+            Inst::Sub,
+            Inst::Jz(bitvec![1, 0, 0, 0]),
+            Inst::Jmp(bitvec![1, 0]),
+            Inst::Label(bitvec![1, 1]),
+            push(0),
+            push(0),
+            Inst::Retrieve,
+            push(1),
+            Inst::Sub,
+            Inst::Store,
+            Inst::Jmp(bitvec![1]),
+            Inst::Label(bitvec![1, 0, 0, 0]),
+            push(8),
+            Inst::Printi,
+            Inst::Label(bitvec![1, 0, 0, 1]),
+            Inst::End,
+        ];
+        let expected = "\
+main() {
+    heap[0] = 3;
+    while (true) {
+        block_8: {
+            v3 = heap[0];
+            if (v3 != 0) {
+                heap[1] = 2;
+                while (true) {
+                    v7 = heap[1];
+                    if (v7 == 0) {
+                        break;
+                    }
+                    v10 = heap[1];
+                    heap[1] = v10 - 1;
+                    v14 = heap[0];
+                    if (v14 - 2 == 0) {
+                        printi(8);
+                        break block_8;
+                    }
+                }
+                v19 = heap[0];
+                heap[0] = v19 - 1;
+                continue;
+            }
+        }
+        exit();
+    }
+}
+";
+        assert_eq!(Ok(expected.to_owned()), decompile_insts(insts));
+    }
+
+    #[test]
+    fn irreducible() {
+        // The loop is entered both at its head and in its middle, so it cannot be
+        // structured and is printed with `goto`s.
+        let insts = vec![
+            push(0),
+            Inst::Readi,
+            push(0),
+            Inst::Retrieve,
+            Inst::Jz(bitvec![1, 0]),
+            Inst::Label(bitvec![1]),
+            push(1),
+            Inst::Printi,
+            push(0),
+            push(0),
+            Inst::Retrieve,
+            push(1),
+            Inst::Sub,
+            Inst::Store,
+            push(0),
+            Inst::Retrieve,
+            Inst::Jn(bitvec![1, 1]),
+            Inst::Label(bitvec![1, 0]),
+            push(2),
+            Inst::Printi,
+            Inst::Jmp(bitvec![1]),
+            Inst::Label(bitvec![1, 1]),
+            Inst::End,
+        ];
+        let expected = "\
+main() {
+block_0:
+    heap[0] = readi();
+    v2 = heap[0];
+    if (v2 == 0) {
+        goto block_2;
+    }
+block_1:
+    printi(1);
+    v6 = heap[0];
+    heap[0] = v6 - 1;
+    v10 = heap[0];
+    if (v10 < 0) {
+        goto block_3;
+    }
+    goto block_2;
+block_3:
+    exit();
+block_2:
+    printi(2);
+    goto block_1;
+}
+";
+        assert_eq!(Ok(expected.to_owned()), decompile_insts(insts));
+    }
+
+    #[test]
+    fn calls() {
+        // The subroutine takes two values from the stack and leaves one.
+        let insts = vec![
+            push(0),
+            Inst::Readi,
+            push(0),
+            Inst::Retrieve,
+            push(3),
+            Inst::Call(bitvec![1]),
+            Inst::Printi,
+            Inst::End,
+            Inst::Label(bitvec![1]),
+            Inst::Dup,
+            Inst::Mul,
+            Inst::Add,
+            Inst::Ret,
+        ];
+        let expected = "\
+main() {
+    heap[0] = readi();
+    v2 = heap[0];
+    v4 = sub_1(v2, 3);
+    printi(v4);
+    exit();
+}
+
+sub_1(v5, v6) {
+    return v5 + (v6 * v6);
+}
+";
+        assert_eq!(Ok(expected.to_owned()), decompile_insts(insts));
+    }
+}
@@ -0,0 +1,318 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Heap access analysis.
+//!
+//! Every `store`, `retrieve`, `readc`, and `readi` accesses the heap at an
+//! address, which [`Ranges`] bounds. An address with exactly one possible
+//! value is constant and accesses a scalar cell. Other addresses are dynamic,
+//! and overlapping dynamic ranges are merged into array regions. A cell can be
+//! promoted to an SSA value when no dynamic access may alias it, since then
+//! every access to it is known statically.
+
+use std::collections::BTreeMap;
+
+use rug::Integer;
+
+use crate::ws::ir::BlockId;
+use crate::ws::range::{Range, Ranges};
+use crate::ws::ssa::{Op, Ssa};
+
+/// Heap accesses of a program, classified into scalar cells and arrays.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HeapLayout {
+    accesses: Vec<Access>,
+    cells: Vec<Cell>,
+    arrays: Vec<Region>,
+}
+
+/// Statement that accesses the heap.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Access {
+    pub block: BlockId,
+    /// Index of the statement in the block.
+    pub stmt: usize,
+    pub kind: AccessKind,
+    pub addr: Address,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AccessKind {
+    Store,
+    Retrieve,
+    Readc,
+    Readi,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Address {
+    /// The address is always the same.
+    Const(Integer),
+    /// The address may be any in the range.
+    Dynamic(Range),
+}
+
+/// Cell accessed at a constant address.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Cell {
+    pub addr: Integer,
+    /// Indices of the accesses to the cell.
+    pub accesses: Vec<usize>,
+    /// Whether the cell can be kept in an SSA value instead of the heap.
+    pub promotable: bool,
+}
+
+/// Range of addresses that is accessed dynamically, like an array.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Region {
+    pub range: Range,
+    /// Indices of the accesses that may be in the region, including constant
+    /// accesses within it.
+    pub accesses: Vec<usize>,
+}
+
+/// Whether two accesses may refer to the same cell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Alias {
+    /// The accesses are never to the same cell.
+    No,
+    /// The accesses may be to the same cell.
+    May,
+    /// The accesses are always to the same cell.
+    Must,
+}
+
+impl HeapLayout {
+    /// Collects and classifies the heap accesses in reachable blocks.
+    #[must_use]
+    pub fn new(ssa: &Ssa, ranges: &Ranges) -> Self {
+        let mut accesses = Vec::new();
+        let mut dumps_heap = false;
+        for block in ssa.blocks() {
+            for (i, stmt) in block.stmts().iter().enumerate() {
+                let (kind, addr) = match stmt.op {
+                    Op::Store { addr, .. } => (AccessKind::Store, addr),
+                    Op::Retrieve(addr) => (AccessKind::Retrieve, addr),
+                    Op::Readc(addr) => (AccessKind::Readc, addr),
+                    Op::Readi(addr) => (AccessKind::Readi, addr),
+                    Op::DumpHeap => {
+                        dumps_heap = true;
+                        continue;
+                    }
+                    _ => continue,
+                };
+                let addr = match ranges.range(addr) {
+                    // The access is unreachable.
+                    Range::Empty => continue,
+                    r @ Range::Interval { .. } => match r.as_exact() {
+                        Some(n) => Address::Const(n.clone()),
+                        None => Address::Dynamic(r.clone()),
+                    },
+                };
+                accesses.push(Access {
+                    block: block.id(),
+                    stmt: i,
+                    kind,
+                    addr,
+                });
+            }
+        }
+
+        // Merge overlapping dynamic ranges in order of their lower bounds.
+        let mut dynamic = accesses
+            .iter()
+            .enumerate()
+            .filter_map(|(i, a)| match &a.addr {
+                Address::Dynamic(Range::Interval { lo, hi }) => Some((lo, hi, i)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        dynamic.sort();
+        let mut arrays: Vec<Region> = Vec::new();
+        for (lo, hi, i) in dynamic {
+            let range = Range::Interval { lo: lo.clone(), hi: hi.clone() };
+            match arrays.last_mut() {
+                Some(last) if last.range.intersects(&range) => {
+                    last.range = last.range.join(&range);
+                    last.accesses.push(i);
+                }
+                _ => arrays.push(Region { range, accesses: vec![i] }),
+            }
+        }
+
+        let mut by_addr = BTreeMap::<&Integer, Vec<usize>>::new();
+        for (i, a) in accesses.iter().enumerate() {
+            if let Address::Const(n) = &a.addr {
+                by_addr.entry(n).or_default().push(i);
+            }
+        }
+        let cells = by_addr
+            .into_iter()
+            .map(|(addr, cell_accesses)| {
+                let region = arrays.iter_mut().find(|r| r.range.contains(addr));
+                // Dumping the heap reads every cell, so it would need the
+                // promoted values.
+                let promotable = region.is_none() && !dumps_heap;
+                if let Some(region) = region {
+                    region.accesses.extend_from_slice(&cell_accesses);
+                    region.accesses.sort_unstable();
+                }
+                Cell {
+                    addr: addr.clone(),
+                    accesses: cell_accesses,
+                    promotable,
+                }
+            })
+            .collect();
+        HeapLayout { accesses, cells, arrays }
+    }
+
+    /// Heap accesses in order of block, then statement.
+    #[inline]
+    #[must_use]
+    pub fn accesses(&self) -> &[Access] {
+        &self.accesses
+    }
+
+    /// Cells accessed at constant addresses, in ascending order of address.
+    #[inline]
+    #[must_use]
+    pub fn cells(&self) -> &[Cell] {
+        &self.cells
+    }
+
+    /// Disjoint regions accessed at dynamic addresses, in ascending order.
+    #[inline]
+    #[must_use]
+    pub fn arrays(&self) -> &[Region] {
+        &self.arrays
+    }
+
+    /// Returns the cell at a constant address, if it is accessed.
+    #[must_use]
+    pub fn cell(&self, addr: &Integer) -> Option<&Cell> {
+        self.cells
+            .binary_search_by(|c| c.addr.cmp(addr))
+            .ok()
+            .map(|i| &self.cells[i])
+    }
+
+    /// Classifies whether two accesses, by index, may refer to the same cell.
+    #[must_use]
+    pub fn alias(&self, a: usize, b: usize) -> Alias {
+        match (&self.accesses[a].addr, &self.accesses[b].addr) {
+            (Address::Const(a), Address::Const(b)) => {
+                if a == b {
+                    Alias::Must
+                } else {
+                    Alias::No
+                }
+            }
+            (Address::Const(n), Address::Dynamic(r)) | (Address::Dynamic(r), Address::Const(n)) => {
+                if r.contains(n) {
+                    Alias::May
+                } else {
+                    Alias::No
+                }
+            }
+            (Address::Dynamic(r1), Address::Dynamic(r2)) => {
+                if r1.intersects(r2) {
+                    Alias::May
+                } else {
+                    Alias::No
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws::inst::{Inst, RawInst};
+    use crate::ws::ir::Ir;
+    use crate::ws::range::Bound;
+    use crate::ws::syntax::{IntLiteral, LabelOrder, Program};
+
+    fn push(n: i32) -> RawInst {
+        Inst::Push(IntLiteral::from(Integer::from(n)).bits().clone())
+    }
+
+    fn layout(insts: Vec<RawInst>) -> HeapLayout {
+        let ssa = Ssa::new(&Ir::new(&Program::new(insts, LabelOrder::Def))).unwrap();
+        HeapLayout::new(&ssa, &Ranges::new(&ssa))
+    }
+
+    #[test]
+    fn cells_and_arrays() {
+        let insts = vec![
+            // 0: store 0, 5
+            push(0),
+            push(5),
+            Inst::Store,
+            // 1: retrieve 0
+            push(0),
+            Inst::Retrieve,
+            Inst::Printi,
+            // 2: readi 1
+            push(1),
+            Inst::Readi,
+            // 3: retrieve 1
+            push(1),
+            Inst::Retrieve,
+            // 4: store (retrieve 1) % 8 + 100, 42
+            push(8),
+            Inst::Mod,
+            push(100),
+            Inst::Add,
+            push(42),
+            Inst::Store,
+            // 5: retrieve 103
+            push(103),
+            Inst::Retrieve,
+            Inst::Printi,
+            Inst::End,
+        ];
+        let heap = layout(insts);
+        assert_eq!(6, heap.accesses().len());
+        let cells = heap
+            .cells()
+            .iter()
+            .map(|c| (c.addr.to_i32().unwrap(), c.accesses.clone(), c.promotable))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (0, vec![0, 1], true),
+                (1, vec![2, 3], true),
+                (103, vec![5], false),
+            ],
+            cells,
+        );
+        assert_eq!(
+            &[Region {
+                range: Range::Interval {
+                    lo: Bound::from(100),
+                    hi: Bound::from(107),
+                },
+                accesses: vec![4, 5],
+            }],
+            heap.arrays(),
+        );
+        assert_eq!(Alias::Must, heap.alias(0, 1));
+        assert_eq!(Alias::No, heap.alias(1, 2));
+        assert_eq!(Alias::No, heap.alias(0, 4));
+        assert_eq!(Alias::May, heap.alias(4, 5));
+    }
+
+    #[test]
+    fn dump_heap() {
+        let insts = vec![push(0), push(5), Inst::Store, Inst::DumpHeap, Inst::End];
+        let heap = layout(insts);
+        assert!(!heap.cell(&Integer::new()).unwrap().promotable);
+    }
+}
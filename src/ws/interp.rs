@@ -0,0 +1,708 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Whitespace interpreter.
+//!
+//! Values and heap cells are arbitrary-precision and unset cells read as 0.
+//! Division and modulo round toward negative infinity, as in Haskell, like the
+//! reference interpreter. A label defined more than once jumps to its first
+//! definition. `readi` reads a line and `readc` reads a UTF-8 character.
+//!
+//! The interpreter executes one instruction per [`Interpreter::step`], so that
+//! it can be driven by a debugger. When an instruction fails, the program
+//! counter stays on it.
+//!
+//! Diagnostics are written to a separate `debug` stream, after flushing
+//! `stdout`. Their formats are specific to Nebula and do not follow the
+//! interpreters that introduced the debug extensions. `dump_stack`
+//! (`debug_printstack`) writes the stack from the bottom on one line and
+//! `dump_heap` (`debug_printheap`) writes each non-zero cell as `addr: value`
+//! by address, as the compiled runtimes do. Nebula treats `dump_trace`
+//! (`trace`) as a backtrace and writes the `call` instruction of each active
+//! call, innermost first. With tracing enabled, every executed instruction is
+//! logged with the stack before and after it.
+//!
+//! [`Limits`] bound the resources of untrusted programs. An instruction that
+//! would exceed a limit fails before it changes any state, except that `readi`
+//! has consumed its line.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, ErrorKind, Write};
+
+use rug::ops::{DivRounding, RemRounding};
+use rug::Integer;
+
+use crate::ws::inst::{Inst, InstArg, InstError, Opcode};
+use crate::ws::syntax::{InstId, LabelData, LabelId, LabelLiteral, Program};
+
+#[derive(Clone, Debug)]
+pub struct Interpreter<'a> {
+    program: &'a Program,
+    /// First definition of each label.
+    label_defs: Vec<Option<InstId>>,
+    pc: usize,
+    stack: Vec<Integer>,
+    heap: BTreeMap<Integer, Integer>,
+    /// Instructions of the active calls.
+    calls: Vec<InstId>,
+    halted: bool,
+    trace: bool,
+    limits: Limits,
+    /// Number of instructions that have executed.
+    executed: u64,
+}
+
+/// Resource limits for the interpreter. `None` leaves a resource unbounded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Limits {
+    /// Maximum number of instructions to execute.
+    pub insts: Option<u64>,
+    /// Maximum number of values on the stack.
+    pub stack: Option<usize>,
+    /// Maximum number of active calls.
+    pub calls: Option<usize>,
+    /// Maximum number of heap cells that have been set.
+    pub heap: Option<usize>,
+    /// Maximum width in bits of any value, in two's complement.
+    pub bits: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum InterpError {
+    /// The instruction needs more values than are on the stack.
+    StackUnderflow,
+    /// `copy` has a negative argument.
+    NegativeCopy,
+    DivByZero,
+    /// `ret` has no call to return to.
+    RetUnderflow,
+    UndefinedLabel(LabelId),
+    /// `printc` has a value that is not a Unicode scalar value.
+    InvalidChar,
+    /// `readc` or `readi` found no more input.
+    EndOfInput,
+    /// `readi` read a line that is not a decimal integer.
+    InvalidInteger,
+    /// `readc` read invalid UTF-8.
+    InvalidUtf8,
+    /// Execution continued past the last instruction.
+    Unterminated,
+    /// The instruction could not be parsed.
+    InvalidInst(InstError),
+    /// The instruction has no defined behavior in the interpreter.
+    Unsupported(Opcode),
+    /// The instruction limit has been reached.
+    InstLimit,
+    /// The stack would exceed its limit.
+    StackLimit,
+    /// The call stack would exceed its limit.
+    CallLimit,
+    /// The heap would exceed its limit.
+    HeapLimit,
+    /// A value would be wider than the bit width limit.
+    IntLimit,
+    IoError(ErrorKind),
+}
+
+impl<'a> Interpreter<'a> {
+    #[must_use]
+    pub fn new(program: &'a Program) -> Self {
+        let label_defs = program
+            .labels()
+            .iter()
+            .map(|label| label.defs().first().copied())
+            .collect();
+        Interpreter {
+            program,
+            label_defs,
+            pc: 0,
+            stack: Vec::new(),
+            heap: BTreeMap::new(),
+            calls: Vec::new(),
+            halted: false,
+            trace: false,
+            limits: Limits::default(),
+            executed: 0,
+        }
+    }
+
+    /// Runs the program until it ends or fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when an instruction fails. The program counter is left
+    /// on the failing instruction.
+    pub fn run<R: BufRead, W: Write, D: Write>(
+        &mut self,
+        stdin: &mut R,
+        stdout: &mut W,
+        debug: &mut D,
+    ) -> Result<(), InterpError> {
+        let res = (|| {
+            while !self.halted {
+                self.step(stdin, stdout, debug)?;
+            }
+            Ok(())
+        })();
+        stdout.flush()?;
+        res
+    }
+
+    /// Executes the instruction at the program counter. It does nothing once
+    /// the program has ended.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when the instruction fails, leaving the program counter
+    /// on it.
+    pub fn step<R: BufRead, W: Write, D: Write>(
+        &mut self,
+        stdin: &mut R,
+        stdout: &mut W,
+        debug: &mut D,
+    ) -> Result<(), InterpError> {
+        if self.halted {
+            return Ok(());
+        }
+        if !self.trace {
+            return self.exec(stdin, stdout, debug);
+        }
+        let pc = self.pc();
+        let before = fmt_stack(&self.stack);
+        let res = self.exec(stdin, stdout, debug);
+        stdout.flush()?;
+        let inst = fmt_inst(self.program, pc);
+        match &res {
+            Ok(()) => writeln!(
+                debug,
+                "{:>4}  {inst}  {before} -> {}",
+                usize::from(pc),
+                fmt_stack(&self.stack)
+            )?,
+            Err(err) => writeln!(debug, "{:>4}  {inst}  {before} -> {err:?}", usize::from(pc))?,
+        }
+        res
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn exec<R: BufRead, W: Write, D: Write>(
+        &mut self,
+        stdin: &mut R,
+        stdout: &mut W,
+        debug: &mut D,
+    ) -> Result<(), InterpError> {
+        let inst = self
+            .program
+            .insts()
+            .get(self.pc)
+            .ok_or(InterpError::Unterminated)?;
+        if matches!(self.limits.insts, Some(max) if self.executed >= max) {
+            return Err(InterpError::InstLimit);
+        }
+        let mut next = self.pc + 1;
+        match inst {
+            Inst::Push(n) => {
+                self.check_int(n)?;
+                self.push((**n).clone())?;
+            }
+            Inst::Dup => self.push(self.peek(0)?.clone())?,
+            Inst::Copy(n) => {
+                if **n < 0 {
+                    return Err(InterpError::NegativeCopy);
+                }
+                let n = n.to_usize().ok_or(InterpError::StackUnderflow)?;
+                self.push(self.peek(n)?.clone())?;
+            }
+            Inst::Swap => {
+                let len = self.need(2)?;
+                self.stack.swap(len - 1, len - 2);
+            }
+            Inst::Drop => {
+                self.pop()?;
+            }
+            Inst::Slide(n) => {
+                // A negative `slide` discards nothing.
+                let n = if **n < 0 {
+                    0
+                } else {
+                    n.to_usize().ok_or(InterpError::StackUnderflow)?
+                };
+                let len = self.need(n.saturating_add(1))?;
+                self.stack.drain(len - 1 - n..len - 1);
+            }
+            Inst::Add => self.arith(|a, b| Integer::from(a + b))?,
+            Inst::Sub => self.arith(|a, b| Integer::from(a - b))?,
+            Inst::Mul => self.arith(|a, b| Integer::from(a * b))?,
+            Inst::Div | Inst::Mod => {
+                if *self.peek(0)? == 0 {
+                    self.need(2)?;
+                    return Err(InterpError::DivByZero);
+                }
+                if let Inst::Div = inst {
+                    self.arith(|a, b| Integer::from(a.div_floor(b)))?;
+                } else {
+                    self.arith(|a, b| Integer::from(a.rem_floor(b)))?;
+                }
+            }
+            Inst::Store => {
+                self.need(2)?;
+                self.check_heap(self.peek(1)?)?;
+                let value = self.stack.pop().unwrap_or_default();
+                let addr = self.stack.pop().unwrap_or_default();
+                self.heap.insert(addr, value);
+            }
+            Inst::Retrieve => {
+                let addr = self.pop()?;
+                let value = self.heap.get(&addr).cloned().unwrap_or_default();
+                self.stack.push(value);
+            }
+            Inst::Label(_) => {}
+            Inst::Call(l) => {
+                next = self.jump_target(*l)?;
+                if matches!(self.limits.calls, Some(max) if self.calls.len() >= max) {
+                    return Err(InterpError::CallLimit);
+                }
+                self.calls.push(InstId::from(self.pc));
+            }
+            Inst::Jmp(l) => next = self.jump_target(*l)?,
+            Inst::Jz(l) | Inst::Jn(l) => {
+                let target = self.jump_target(*l)?;
+                let n = self.pop()?;
+                let taken = if let Inst::Jz(_) = inst {
+                    n == 0
+                } else {
+                    n < 0
+                };
+                if taken {
+                    next = target;
+                }
+            }
+            Inst::Ret => {
+                let call = self.calls.pop().ok_or(InterpError::RetUnderflow)?;
+                next = usize::from(call) + 1;
+            }
+            Inst::End => {
+                self.halted = true;
+                next = self.pc;
+            }
+            Inst::Printc => {
+                let n = self.peek(0)?;
+                let c = n
+                    .to_u32()
+                    .and_then(char::from_u32)
+                    .ok_or(InterpError::InvalidChar)?;
+                write!(stdout, "{c}")?;
+                self.pop()?;
+            }
+            Inst::Printi => {
+                write!(stdout, "{}", self.peek(0)?)?;
+                self.pop()?;
+            }
+            Inst::Readc | Inst::Readi => {
+                self.check_heap(self.peek(0)?)?;
+                stdout.flush()?;
+                let value = if let Inst::Readc = inst {
+                    read_char(stdin)?
+                } else {
+                    read_int(stdin)?
+                };
+                self.check_int(&value)?;
+                let addr = self.pop()?;
+                self.heap.insert(addr, value);
+            }
+            Inst::DumpStack => {
+                stdout.flush()?;
+                self.dump_stack(debug)?;
+            }
+            Inst::DumpHeap => {
+                stdout.flush()?;
+                self.dump_heap(debug)?;
+            }
+            Inst::DumpTrace => {
+                stdout.flush()?;
+                self.dump_trace(debug)?;
+            }
+            Inst::Shuffle => return Err(InterpError::Unsupported(inst.opcode())),
+            Inst::Error(err) => return Err(InterpError::InvalidInst(err.clone())),
+        }
+        self.pc = next;
+        self.executed += 1;
+        Ok(())
+    }
+
+    fn jump_target(&self, l: LabelId) -> Result<usize, InterpError> {
+        match self.label_defs[usize::from(l)] {
+            Some(def) => Ok(usize::from(def)),
+            None => Err(InterpError::UndefinedLabel(l)),
+        }
+    }
+
+    /// Checks that the stack has at least `n` values and returns its length.
+    fn need(&self, n: usize) -> Result<usize, InterpError> {
+        if self.stack.len() < n {
+            return Err(InterpError::StackUnderflow);
+        }
+        Ok(self.stack.len())
+    }
+
+    /// Returns the value `n` below the top of the stack.
+    fn peek(&self, n: usize) -> Result<&Integer, InterpError> {
+        let len = self.need(n.saturating_add(1))?;
+        Ok(&self.stack[len - 1 - n])
+    }
+
+    fn pop(&mut self) -> Result<Integer, InterpError> {
+        self.stack.pop().ok_or(InterpError::StackUnderflow)
+    }
+
+    /// Pushes a value, which has been checked against the bit width limit.
+    fn push(&mut self, n: Integer) -> Result<(), InterpError> {
+        if matches!(self.limits.stack, Some(max) if self.stack.len() >= max) {
+            return Err(InterpError::StackLimit);
+        }
+        self.stack.push(n);
+        Ok(())
+    }
+
+    fn arith<F: FnOnce(&Integer, &Integer) -> Integer>(&mut self, f: F) -> Result<(), InterpError> {
+        let len = self.need(2)?;
+        let n = f(&self.stack[len - 2], &self.stack[len - 1]);
+        self.check_int(&n)?;
+        self.stack.truncate(len - 2);
+        self.stack.push(n);
+        Ok(())
+    }
+
+    fn check_int(&self, n: &Integer) -> Result<(), InterpError> {
+        if matches!(self.limits.bits, Some(max) if n.signed_bits() > max) {
+            return Err(InterpError::IntLimit);
+        }
+        Ok(())
+    }
+
+    /// Checks that a store to the address would not exceed the heap limit.
+    fn check_heap(&self, addr: &Integer) -> Result<(), InterpError> {
+        if let Some(max) = self.limits.heap {
+            if self.heap.len() >= max && !self.heap.contains_key(addr) {
+                return Err(InterpError::HeapLimit);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the stack from the bottom, separated by spaces.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when writing fails.
+    pub fn dump_stack<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let stack = self.stack.iter().map(ToString::to_string);
+        writeln!(w, "{}", stack.collect::<Vec<_>>().join(" "))
+    }
+
+    /// Writes the non-zero heap cells by address.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when writing fails.
+    pub fn dump_heap<W: Write>(&self, mut w: W) -> io::Result<()> {
+        for (addr, value) in &self.heap {
+            if *value != 0 {
+                writeln!(w, "{addr}: {value}")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the `call` instructions of the active calls, innermost first. This
+    /// backtrace is Nebula's own meaning for `dump_trace`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when writing fails.
+    pub fn dump_trace<W: Write>(&self, mut w: W) -> io::Result<()> {
+        for &call in self.calls.iter().rev() {
+            writeln!(
+                w,
+                "{:>4}  {}",
+                usize::from(call),
+                fmt_inst(self.program, call)
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Sets the resource limits, which apply from the next instruction.
+    #[inline]
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Enables or disables logging each executed instruction to `debug`.
+    #[inline]
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn program(&self) -> &'a Program {
+        self.program
+    }
+
+    /// Instruction to execute next.
+    #[inline]
+    #[must_use]
+    pub fn pc(&self) -> InstId {
+        InstId::from(self.pc)
+    }
+
+    /// Values on the stack, from the bottom to the top.
+    #[inline]
+    #[must_use]
+    pub fn stack(&self) -> &[Integer] {
+        &self.stack
+    }
+
+    /// Cells that have been set, by address.
+    #[inline]
+    #[must_use]
+    pub fn heap(&self) -> &BTreeMap<Integer, Integer> {
+        &self.heap
+    }
+
+    /// `call` instructions of the active calls, from the outermost.
+    #[inline]
+    #[must_use]
+    pub fn calls(&self) -> &[InstId] {
+        &self.calls
+    }
+
+    /// Number of instructions that have executed.
+    #[inline]
+    #[must_use]
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    /// Returns whether the program has executed `end`.
+    #[inline]
+    #[must_use]
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Returns the first definition of a label, if it is defined.
+    #[inline]
+    #[must_use]
+    pub fn label_def(&self, l: LabelId) -> Option<InstId> {
+        self.label_defs[usize::from(l)]
+    }
+}
+
+/// Formats an instruction in disassembly syntax, with labels named by
+/// [`label_name`].
+#[must_use]
+pub fn fmt_inst(program: &Program, id: InstId) -> String {
+    let inst = &program.insts()[id];
+    if let Inst::Error(err) = inst {
+        return format!("error {err:?}");
+    }
+    inst.clone()
+        .map_arg(|_, arg| -> Result<_, InstError> {
+            match arg {
+                InstArg::Int(n) => Ok(InstArg::Int(n)),
+                InstArg::Label(l) => Ok(InstArg::Label(label_name(&program.labels()[l], Some(id)))),
+            }
+        })
+        .to_string()
+}
+
+/// Names a label by its name in the assembly source at the instruction, else
+/// any of its names, else its literal.
+#[must_use]
+pub fn label_name(label: &LabelData, inst: Option<InstId>) -> String {
+    let names = label.names();
+    names
+        .iter()
+        .find(|&&(id, _)| Some(id) == inst)
+        .or_else(|| names.first())
+        .map_or_else(
+            || LabelLiteral::from_bits(label.bits().clone()).to_string(),
+            |(_, name)| name.clone(),
+        )
+}
+
+pub(crate) fn fmt_stack(stack: &[Integer]) -> String {
+    let stack = stack.iter().map(ToString::to_string);
+    format!("[{}]", stack.collect::<Vec<_>>().join(", "))
+}
+
+/// Reads a UTF-8 character.
+fn read_char<R: BufRead>(stdin: &mut R) -> Result<Integer, InterpError> {
+    let mut buf = [0; 4];
+    read_byte(stdin, &mut buf[..1])?.ok_or(InterpError::EndOfInput)?;
+    let len = match buf[0] {
+        0x00..=0x7f => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return Err(InterpError::InvalidUtf8),
+    };
+    for i in 1..len {
+        read_byte(stdin, &mut buf[i..=i])?.ok_or(InterpError::InvalidUtf8)?;
+    }
+    match std::str::from_utf8(&buf[..len]).map(|s| s.chars().next()) {
+        Ok(Some(c)) => Ok(Integer::from(u32::from(c))),
+        _ => Err(InterpError::InvalidUtf8),
+    }
+}
+
+fn read_byte<R: BufRead>(stdin: &mut R, buf: &mut [u8]) -> io::Result<Option<()>> {
+    loop {
+        match stdin.read(buf) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(())),
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Reads a line with a decimal integer, surrounded by optional whitespace.
+fn read_int<R: BufRead>(stdin: &mut R) -> Result<Integer, InterpError> {
+    let mut line = Vec::new();
+    if stdin.read_until(b'\n', &mut line)? == 0 {
+        return Err(InterpError::EndOfInput);
+    }
+    let line = std::str::from_utf8(&line).map_err(|_| InterpError::InvalidInteger)?;
+    let line = line.trim();
+    if line.starts_with('+') {
+        return Err(InterpError::InvalidInteger);
+    }
+    Integer::from_str_radix(line, 10).map_err(|_| InterpError::InvalidInteger)
+}
+
+impl From<io::Error> for InterpError {
+    #[inline]
+    fn from(err: io::Error) -> Self {
+        InterpError::IoError(err.kind())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitvec::prelude::*;
+
+    use super::*;
+    use crate::ws::inst::RawInst;
+    use crate::ws::syntax::LabelOrder;
+    use crate::ws::tests::get_tutorial_insts;
+
+    #[test]
+    fn tutorial() {
+        let program = Program::new(get_tutorial_insts(), LabelOrder::Def);
+        let mut out = Vec::new();
+        let mut interp = Interpreter::new(&program);
+        interp
+            .run(&mut io::empty(), &mut out, &mut io::sink())
+            .unwrap();
+        assert_eq!(b"1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n", out.as_slice());
+        assert!(interp.is_halted());
+        assert!(interp.stack().is_empty());
+    }
+
+    #[test]
+    fn trace_and_dumps() {
+        let program = Program::new(
+            vec![
+                Inst::Push(bitvec![0, 1, 0, 1]),
+                Inst::Push(bitvec![0, 1, 1]),
+                Inst::Call(bitvec![1]),
+                Inst::Label(bitvec![1]),
+                Inst::Store,
+                Inst::DumpStack,
+                Inst::DumpHeap,
+                Inst::DumpTrace,
+                Inst::End,
+            ],
+            LabelOrder::Def,
+        );
+        let mut debug = Vec::new();
+        let mut interp = Interpreter::new(&program);
+        interp.set_trace(true);
+        interp
+            .run(&mut io::empty(), &mut io::sink(), &mut debug)
+            .unwrap();
+        let expected = "   0  push 5  [] -> [5]
+   1  push 3  [5] -> [5, 3]
+   2  call 1  [5, 3] -> [5, 3]
+   3  label 1  [5, 3] -> [5, 3]
+   4  store  [5, 3] -> []
+
+   5  dump_stack  [] -> []
+5: 3
+   6  dump_heap  [] -> []
+   2  call 1
+   7  dump_trace  [] -> []
+   8  end  [] -> []
+";
+        assert_eq!(expected, String::from_utf8(debug).unwrap());
+    }
+
+    #[test]
+    fn limits() {
+        let run = |insts: Vec<RawInst>, limits: Limits| {
+            let program = Program::new(insts, LabelOrder::Def);
+            let mut interp = Interpreter::new(&program);
+            interp.set_limits(limits);
+            let res = interp.run(&mut io::empty(), &mut io::sink(), &mut io::sink());
+            (res, usize::from(interp.pc()), interp.executed())
+        };
+        let tutorial = get_tutorial_insts;
+        let insts = Limits {
+            insts: Some(10),
+            ..Limits::default()
+        };
+        assert_eq!(
+            (Err(InterpError::InstLimit), 10, 10),
+            run(tutorial(), insts)
+        );
+        let stack = Limits {
+            stack: Some(1),
+            ..Limits::default()
+        };
+        assert_eq!((Err(InterpError::StackLimit), 2, 2), run(tutorial(), stack));
+        let bits = Limits {
+            bits: Some(4),
+            ..Limits::default()
+        };
+        assert_eq!((Err(InterpError::IntLimit), 4, 4), run(tutorial(), bits));
+
+        let recurse = vec![Inst::Label(bitvec![1]), Inst::Call(bitvec![1])];
+        let calls = Limits {
+            calls: Some(3),
+            ..Limits::default()
+        };
+        assert_eq!((Err(InterpError::CallLimit), 1, 7), run(recurse, calls));
+        let stores = vec![
+            Inst::Push(bitvec![0, 1]),
+            Inst::Push(bitvec![0, 1]),
+            Inst::Store,
+            Inst::Push(bitvec![0, 1, 0]),
+            Inst::Push(bitvec![0, 1]),
+            Inst::Store,
+            Inst::End,
+        ];
+        let heap = Limits {
+            heap: Some(1),
+            ..Limits::default()
+        };
+        assert_eq!((Err(InterpError::HeapLimit), 5, 5), run(stores, heap));
+    }
+}
@@ -0,0 +1,674 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Typed stack-oriented IR for Whitespace.
+//!
+//! A [`Program`] is split into basic blocks, which begin at labels and end
+//! at control flow instructions. Labels and control flow instructions are
+//! removed from the block bodies and represented by the block's labels and
+//! [`Exit`], so bodies are straight-line stack code. Each block records its
+//! [`StackEffect`] and, when it can be determined statically, the height of
+//! the stack on entry.
+//!
+//! Duplicate labels resolve to their first definition, as in wspace. Jumps to
+//! undefined labels target a synthetic empty block, which exits with
+//! [`Exit::UndefinedLabel`], so that every edge has a block.
+
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::ops::{Index, IndexMut, Range};
+
+use rug::Integer;
+use smallvec::{smallvec, SmallVec};
+
+use crate::ws::inst::{Inst, InstError};
+use crate::ws::syntax::{id_index, InstId, LabelId, Program, ProgramInst};
+
+/// Whitespace program split into basic blocks.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Ir {
+    blocks: Vec<Block>,
+    /// The block that each label resolves to.
+    label_blocks: Vec<BlockId>,
+    /// The block that contains each instruction.
+    inst_blocks: Vec<BlockId>,
+}
+
+id_index!(BlockId(u32) indexes Block in Vec<Block>, [Block]);
+
+/// Basic block of straight-line stack code.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Block {
+    id: BlockId,
+    /// Labels defined at the head of this block.
+    labels: SmallVec<[LabelId; 1]>,
+    /// Instructions, excluding labels and the exit.
+    body: Vec<ProgramInst>,
+    exit: Exit,
+    /// Range of instructions in the program, including labels and the exit.
+    /// It is empty for synthetic blocks.
+    span: Range<usize>,
+    /// Stack effect of the body and the exit.
+    effect: StackEffect,
+    /// Height of the stack on entry.
+    entry_height: StackHeight,
+}
+
+/// Control flow at the end of a block.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Exit {
+    /// Continues to the next block.
+    Fallthrough(BlockId),
+    /// `jmp`
+    Jmp(BlockId),
+    /// `jz`: pops the top and jumps to `zero` if it is 0, otherwise continues
+    /// to `other`.
+    Jz { zero: BlockId, other: BlockId },
+    /// `jn`: pops the top and jumps to `neg` if it is negative, otherwise
+    /// continues to `other`.
+    Jn { neg: BlockId, other: BlockId },
+    /// `call`: jumps to `callee`, which returns to `ret`.
+    Call { callee: BlockId, ret: BlockId },
+    /// `ret`
+    Ret,
+    /// `end`
+    End,
+    /// Execution reaches the end of the program without `end`.
+    Unterminated,
+    /// A jump to a label that is not defined.
+    UndefinedLabel(LabelId),
+    /// An instruction that could not be parsed.
+    Error(InstError),
+}
+
+/// Effect on the stack of an instruction or sequence of instructions.
+///
+/// It requires `pops` values to be on the stack, then leaves `pushes` values
+/// in their place, and holds at most `max` values above the values beneath
+/// those it pops.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct StackEffect {
+    pub pops: usize,
+    pub pushes: usize,
+    pub max: usize,
+}
+
+/// Statically-known height of the stack at a point.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StackHeight {
+    /// The point is not reachable from the entry.
+    Unreached,
+    /// The height is the same on every path.
+    Exact(usize),
+    /// The height differs between paths, or depends on a loop.
+    Varying,
+}
+
+impl Ir {
+    /// Splits a program into basic blocks and computes the stack heights.
+    #[must_use]
+    pub fn new(program: &Program) -> Self {
+        let insts = program.insts();
+        let mut builder = Builder::default();
+
+        // Assign blocks to instructions in a first pass, so that forward jumps
+        // can be resolved.
+        let mut inst_blocks = Vec::with_capacity(insts.len());
+        let mut next = BlockId(0);
+        let mut block_open = false;
+        for inst in insts {
+            match inst {
+                Inst::Label(_) => {
+                    if block_open {
+                        next.0 += 1;
+                        block_open = false;
+                    }
+                    inst_blocks.push(next);
+                }
+                inst if is_terminator(inst) => {
+                    inst_blocks.push(next);
+                    next.0 += 1;
+                    block_open = false;
+                }
+                _ => {
+                    inst_blocks.push(next);
+                    block_open = true;
+                }
+            }
+        }
+        // One more block for the end of the program, then one for each
+        // undefined label.
+        let len = next.0 as usize + 1;
+        let mut undefined = Vec::new();
+        let label_blocks = program
+            .labels()
+            .iter()
+            .map(|label| {
+                if let Some(&def) = label.defs().first() {
+                    inst_blocks[usize::from(def)]
+                } else {
+                    undefined.push(label.id());
+                    BlockId::from(len + undefined.len() - 1)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // Build the blocks in a second pass.
+        let mut start = 0;
+        for (i, inst) in insts.iter().enumerate() {
+            match inst {
+                Inst::Label(l) => {
+                    if !builder.body.is_empty() {
+                        let next = builder.next_id();
+                        builder.finish(Exit::Fallthrough(next), start..i);
+                        start = i;
+                    }
+                    builder.labels.push(*l);
+                }
+                _ if is_terminator(inst) => {
+                    let next = builder.next_id();
+                    let target = |l: &LabelId| label_blocks[usize::from(*l)];
+                    let exit = match inst {
+                        Inst::Call(l) => Exit::Call { callee: target(l), ret: next },
+                        Inst::Jmp(l) => Exit::Jmp(target(l)),
+                        Inst::Jz(l) => Exit::Jz { zero: target(l), other: next },
+                        Inst::Jn(l) => Exit::Jn { neg: target(l), other: next },
+                        Inst::Ret => Exit::Ret,
+                        Inst::End => Exit::End,
+                        Inst::Error(err) => Exit::Error(err.clone()),
+                        _ => unreachable!(),
+                    };
+                    builder.finish(exit, start..i + 1);
+                    start = i + 1;
+                }
+                _ => builder.body.push(inst.clone()),
+            }
+        }
+        builder.finish(Exit::Unterminated, start..insts.len());
+        debug_assert_eq!(len, builder.blocks.len());
+        for l in undefined {
+            builder.finish(Exit::UndefinedLabel(l), insts.len()..insts.len());
+        }
+
+        let mut ir = Ir {
+            blocks: builder.blocks,
+            label_blocks,
+            inst_blocks,
+        };
+        ir.compute_heights();
+        ir
+    }
+
+    /// Computes the stack height on entry to each block with a forward
+    /// dataflow analysis. Returns from `ret` flow to every return site, so
+    /// heights that depend on the call site are `Varying`.
+    fn compute_heights(&mut self) {
+        let ret_sites = self.ret_sites();
+
+        let mut queue = VecDeque::new();
+        self.blocks[0].entry_height = StackHeight::Exact(0);
+        queue.push_back(BlockId(0));
+        while let Some(id) = queue.pop_front() {
+            let block = &self.blocks[id];
+            let exit_height = match block.entry_height {
+                StackHeight::Exact(h) if h < block.effect.pops => continue,
+                StackHeight::Exact(h) => {
+                    StackHeight::Exact(h - block.effect.pops + block.effect.pushes)
+                }
+                height => height,
+            };
+            let succs: SmallVec<[BlockId; 2]> = match block.exit {
+                Exit::Ret => ret_sites.iter().copied().collect(),
+                Exit::Call { callee, .. } => smallvec![callee],
+                _ => block.exit.targets(),
+            };
+            for succ in succs {
+                let entry = &mut self.blocks[succ].entry_height;
+                let joined = entry.join(exit_height);
+                if joined != *entry {
+                    *entry = joined;
+                    queue.push_back(succ);
+                }
+            }
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    /// The block that a label resolves to.
+    #[inline]
+    #[must_use]
+    pub fn label_block(&self, label: LabelId) -> BlockId {
+        self.label_blocks[usize::from(label)]
+    }
+
+    /// The block that contains an instruction in the program.
+    #[inline]
+    #[must_use]
+    pub fn inst_block(&self, inst: InstId) -> BlockId {
+        self.inst_blocks[usize::from(inst)]
+    }
+
+    /// Return sites of every `call`, in order of the calls.
+    #[must_use]
+    pub fn ret_sites(&self) -> Vec<BlockId> {
+        self.blocks
+            .iter()
+            .filter_map(|b| match b.exit {
+                Exit::Call { ret, .. } => Some(ret),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns for each block whether it is entered other than by falling
+    /// through from the previous block, which is when it needs a label in
+    /// generated code.
+    #[must_use]
+    pub fn jump_targets(&self) -> Vec<bool> {
+        let mut targeted = vec![false; self.blocks.len()];
+        for block in &self.blocks {
+            let next = BlockId(block.id.0 + 1);
+            let (taken, other) = match block.exit {
+                Exit::Fallthrough(to) | Exit::Jmp(to) => (None, Some(to)),
+                Exit::Jz { zero: taken, other } | Exit::Jn { neg: taken, other } => {
+                    (Some(taken), Some(other))
+                }
+                Exit::Call { callee, ret } => (Some(callee), Some(ret)),
+                _ => (None, None),
+            };
+            if let Some(taken) = taken {
+                targeted[usize::from(taken)] = true;
+            }
+            if let Some(other) = other {
+                if other != next || matches!(block.exit, Exit::Call { .. }) {
+                    targeted[usize::from(other)] = true;
+                }
+            }
+        }
+        targeted
+    }
+
+    /// Blocks that statically underflow the stack, because their entry height
+    /// is known and less than the number of values they pop.
+    #[must_use]
+    pub fn underflows(&self) -> Vec<BlockId> {
+        self.blocks
+            .iter()
+            .filter(|b| matches!(b.entry_height, StackHeight::Exact(h) if h < b.effect.pops))
+            .map(|b| b.id)
+            .collect()
+    }
+}
+
+impl Index<BlockId> for Ir {
+    type Output = Block;
+
+    #[inline]
+    fn index(&self, id: BlockId) -> &Block {
+        &self.blocks[id]
+    }
+}
+
+impl IndexMut<BlockId> for Ir {
+    #[inline]
+    fn index_mut(&mut self, id: BlockId) -> &mut Block {
+        &mut self.blocks[id]
+    }
+}
+
+#[derive(Debug, Default)]
+struct Builder {
+    blocks: Vec<Block>,
+    labels: SmallVec<[LabelId; 1]>,
+    body: Vec<ProgramInst>,
+}
+
+impl Builder {
+    #[inline]
+    fn next_id(&self) -> BlockId {
+        BlockId::from(self.blocks.len() + 1)
+    }
+
+    fn finish(&mut self, exit: Exit, span: Range<usize>) {
+        let body = std::mem::take(&mut self.body);
+        let effect = body
+            .iter()
+            .map(StackEffect::of)
+            .fold(StackEffect::default(), StackEffect::then)
+            .then(exit.effect());
+        self.blocks.push(Block {
+            id: BlockId::from(self.blocks.len()),
+            labels: std::mem::take(&mut self.labels),
+            body,
+            exit,
+            span,
+            effect,
+            entry_height: StackHeight::Unreached,
+        });
+    }
+}
+
+#[inline]
+fn is_terminator(inst: &ProgramInst) -> bool {
+    matches!(
+        inst,
+        Inst::Call(_)
+            | Inst::Jmp(_)
+            | Inst::Jz(_)
+            | Inst::Jn(_)
+            | Inst::Ret
+            | Inst::End
+            | Inst::Error(_)
+    )
+}
+
+impl Block {
+    #[inline]
+    #[must_use]
+    pub fn id(&self) -> BlockId {
+        self.id
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn labels(&self) -> &[LabelId] {
+        &self.labels
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn body(&self) -> &[ProgramInst] {
+        &self.body
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn exit(&self) -> &Exit {
+        &self.exit
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn span(&self) -> Range<usize> {
+        self.span.clone()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn effect(&self) -> StackEffect {
+        self.effect
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn entry_height(&self) -> StackHeight {
+        self.entry_height
+    }
+
+    /// Height of the stack after the block, if it is statically known and the
+    /// block does not underflow.
+    #[must_use]
+    pub fn exit_height(&self) -> Option<usize> {
+        match self.entry_height {
+            StackHeight::Exact(h) if h >= self.effect.pops => {
+                Some(h - self.effect.pops + self.effect.pushes)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Exit {
+    /// Blocks that this exit jumps or continues to. For `call`, this is both
+    /// the callee and the return site.
+    #[must_use]
+    pub fn targets(&self) -> SmallVec<[BlockId; 2]> {
+        match *self {
+            Exit::Fallthrough(b) | Exit::Jmp(b) => smallvec![b],
+            Exit::Jz { zero: b1, other: b2 }
+            | Exit::Jn { neg: b1, other: b2 }
+            | Exit::Call { callee: b1, ret: b2 } => smallvec![b1, b2],
+            Exit::Ret
+            | Exit::End
+            | Exit::Unterminated
+            | Exit::UndefinedLabel(_)
+            | Exit::Error(_) => SmallVec::new(),
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn effect(&self) -> StackEffect {
+        match self {
+            Exit::Jz { .. } | Exit::Jn { .. } => StackEffect::new(1, 0),
+            _ => StackEffect::default(),
+        }
+    }
+}
+
+impl StackEffect {
+    #[inline]
+    #[must_use]
+    pub const fn new(pops: usize, pushes: usize) -> Self {
+        let max = if pops > pushes { pops } else { pushes };
+        StackEffect { pops, pushes, max }
+    }
+
+    /// Stack effect of an instruction. The argument of `copy` and `slide` is
+    /// saturated, so large arguments require an unsatisfiable number of
+    /// values, and a negative `slide` discards nothing.
+    #[must_use]
+    pub fn of(inst: &ProgramInst) -> Self {
+        let arg = |n: &Integer| n.to_usize().unwrap_or(usize::MAX);
+        match inst {
+            Inst::Push(_) => StackEffect::new(0, 1),
+            Inst::Dup => StackEffect::new(1, 2),
+            Inst::Copy(n) if **n < 0 => StackEffect::new(usize::MAX, usize::MAX),
+            Inst::Copy(n) => {
+                let n = arg(n).saturating_add(1);
+                StackEffect::new(n, n.saturating_add(1))
+            }
+            Inst::Swap => StackEffect::new(2, 2),
+            Inst::Slide(n) if **n < 0 => StackEffect::new(1, 1),
+            Inst::Slide(n) => StackEffect::new(arg(n).saturating_add(1), 1),
+            Inst::Add | Inst::Sub | Inst::Mul | Inst::Div | Inst::Mod => StackEffect::new(2, 1),
+            Inst::Store => StackEffect::new(2, 0),
+            Inst::Retrieve => StackEffect::new(1, 1),
+            Inst::Drop
+            | Inst::Jz(_)
+            | Inst::Jn(_)
+            | Inst::Printc
+            | Inst::Printi
+            | Inst::Readc
+            | Inst::Readi => StackEffect::new(1, 0),
+            Inst::Label(_)
+            | Inst::Call(_)
+            | Inst::Jmp(_)
+            | Inst::Ret
+            | Inst::End
+            | Inst::Shuffle
+            | Inst::DumpStack
+            | Inst::DumpHeap
+            | Inst::DumpTrace
+            | Inst::Error(_) => StackEffect::default(),
+        }
+    }
+
+    /// Composes this effect with an effect that follows it.
+    #[must_use]
+    pub fn then(self, next: StackEffect) -> Self {
+        let matched = self.pushes.min(next.pops);
+        StackEffect {
+            pops: self.pops.saturating_add(next.pops - matched),
+            pushes: (self.pushes - matched).saturating_add(next.pushes),
+            max: (self.max.saturating_add(next.pops - matched))
+                .max((self.pushes - matched).saturating_add(next.max)),
+        }
+    }
+}
+
+impl StackHeight {
+    /// Joins the heights from two paths.
+    #[inline]
+    #[must_use]
+    pub fn join(self, other: StackHeight) -> Self {
+        match (self, other) {
+            (StackHeight::Unreached, h) | (h, StackHeight::Unreached) => h,
+            (StackHeight::Exact(a), StackHeight::Exact(b)) if a == b => StackHeight::Exact(a),
+            _ => StackHeight::Varying,
+        }
+    }
+}
+
+impl Display for Ir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for block in &self.blocks {
+            write!(f, "{block}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Block {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.id)?;
+        for l in &self.labels {
+            write!(f, " {l}")?;
+        }
+        let StackEffect { pops, pushes, max } = self.effect;
+        write!(f, " (pops {pops}, pushes {pushes}, max {max}")?;
+        match self.entry_height {
+            StackHeight::Unreached => write!(f, ", unreached")?,
+            StackHeight::Exact(h) => write!(f, ", height {h}")?,
+            StackHeight::Varying => {}
+        }
+        writeln!(f, ")")?;
+        for inst in &self.body {
+            writeln!(f, "    {inst}")?;
+        }
+        writeln!(f, "    {}", self.exit)
+    }
+}
+
+impl Display for Exit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Exit::Fallthrough(b) => write!(f, "fallthrough {b}"),
+            Exit::Jmp(b) => write!(f, "jmp {b}"),
+            Exit::Jz { zero, other } => write!(f, "jz {zero} else {other}"),
+            Exit::Jn { neg, other } => write!(f, "jn {neg} else {other}"),
+            Exit::Call { callee, ret } => write!(f, "call {callee} then {ret}"),
+            Exit::Ret => write!(f, "ret"),
+            Exit::End => write!(f, "end"),
+            Exit::Unterminated => write!(f, "unterminated"),
+            Exit::UndefinedLabel(l) => write!(f, "undefined {l}"),
+            Exit::Error(err) => write!(f, "error {err:?}"),
+        }
+    }
+}
+
+impl Display for BlockId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "block_{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws::syntax::LabelOrder;
+    use crate::ws::tests::get_tutorial_insts;
+
+    #[test]
+    fn tutorial() {
+        let program = Program::new(get_tutorial_insts(), LabelOrder::Def);
+        let ir = Ir::new(&program);
+        let blocks = ir.blocks();
+        assert_eq!(5, blocks.len());
+        assert_eq!(&Exit::Fallthrough(BlockId(1)), blocks[0].exit());
+        assert_eq!(
+            &Exit::Jz {
+                zero: BlockId(3),
+                other: BlockId(2)
+            },
+            blocks[1].exit()
+        );
+        assert_eq!(&Exit::Jmp(BlockId(1)), blocks[2].exit());
+        assert_eq!(&Exit::End, blocks[3].exit());
+        assert_eq!(&Exit::Unterminated, blocks[4].exit());
+        assert_eq!(9, blocks[1].body().len());
+
+        assert_eq!(
+            StackEffect { pops: 0, pushes: 1, max: 1 },
+            blocks[0].effect()
+        );
+        assert_eq!(
+            StackEffect { pops: 1, pushes: 1, max: 3 },
+            blocks[1].effect()
+        );
+        assert_eq!(
+            StackEffect { pops: 1, pushes: 0, max: 1 },
+            blocks[3].effect()
+        );
+        let heights = blocks.iter().map(Block::entry_height).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                StackHeight::Exact(0),
+                StackHeight::Exact(1),
+                StackHeight::Exact(1),
+                StackHeight::Exact(1),
+                StackHeight::Unreached,
+            ],
+            heights,
+        );
+        assert!(ir.underflows().is_empty());
+    }
+
+    #[test]
+    fn undefined_and_varying() {
+        use bitvec::prelude::*;
+        let insts = vec![
+            Inst::Label(bitvec![1]),
+            Inst::Push(bitvec![0, 1]),
+            Inst::Dup,
+            Inst::Jz(bitvec![1, 0]),
+            Inst::Jmp(bitvec![1]),
+        ];
+        let ir = Ir::new(&Program::new(insts, LabelOrder::Def));
+        let blocks = ir.blocks();
+        assert_eq!(4, blocks.len());
+        assert_eq!(
+            &Exit::Jz {
+                zero: BlockId(3),
+                other: BlockId(1)
+            },
+            blocks[0].exit()
+        );
+        assert_eq!(&Exit::UndefinedLabel(LabelId(1)), blocks[3].exit());
+        assert_eq!(StackHeight::Varying, blocks[0].entry_height());
+        assert_eq!(StackHeight::Varying, blocks[3].entry_height());
+        assert_eq!(StackHeight::Unreached, blocks[2].entry_height());
+    }
+
+    #[test]
+    fn effect_compose() {
+        let e = StackEffect::new(2, 1)
+            .then(StackEffect::new(0, 1))
+            .then(StackEffect::new(3, 0));
+        assert_eq!(StackEffect { pops: 3, pushes: 0, max: 3 }, e);
+        let e = StackEffect::new(0, 1)
+            .then(StackEffect::new(1, 2))
+            .then(StackEffect::new(0, 1));
+        assert_eq!(StackEffect { pops: 0, pushes: 3, max: 3 }, e);
+    }
+}
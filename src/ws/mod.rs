@@ -18,9 +18,22 @@
 pub use token::Token;
 
 pub mod assembly;
+pub mod cfg;
+pub mod codegen;
+pub mod debug;
+pub mod decompile;
 pub mod gmh;
+pub mod heap;
 pub mod inst;
+pub mod interp;
+pub mod ir;
+pub mod opt;
 pub mod parse;
+pub mod profile;
+pub mod range;
+pub mod ssa;
+pub mod structured;
+pub mod subroutine;
 pub mod syntax;
 pub mod token;
 
@@ -0,0 +1,104 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Dead-code elimination.
+
+use crate::ws::cfg::Cfg;
+use crate::ws::inst::Inst;
+use crate::ws::ir::Ir;
+use crate::ws::syntax::{InstId, LabelOrder, Program};
+
+/// Removes instructions in blocks that are unreachable from the entry and
+/// label definitions that are not the target of any remaining jump or call.
+///
+/// Only the first definition of a label is kept, since later definitions
+/// are never jumped to. Since unreachable blocks are never executed and
+/// labels have no effect, the behavior of the program is unchanged.
+#[must_use]
+pub fn eliminate_dead_code(program: &Program) -> Program {
+    let ir = Ir::new(program);
+    let cfg = Cfg::new(program, &ir);
+    let insts = program.insts();
+    let live = (0..insts.len())
+        .map(|i| cfg.is_reachable(ir.inst_block(InstId::from(i))))
+        .collect::<Vec<_>>();
+
+    let mut used = vec![false; program.labels().len()];
+    for (inst, _) in insts.iter().zip(&live).filter(|(_, &live)| live) {
+        if let Inst::Call(l) | Inst::Jmp(l) | Inst::Jz(l) | Inst::Jn(l) = inst {
+            used[usize::from(*l)] = true;
+        }
+    }
+
+    let raw = insts
+        .iter()
+        .enumerate()
+        .filter(|&(i, inst)| {
+            live[i]
+                && match inst {
+                    Inst::Label(l) => {
+                        used[usize::from(*l)]
+                            && program.labels()[*l].defs().first() == Some(&InstId::from(i))
+                    }
+                    _ => true,
+                }
+        })
+        .map(|(_, inst)| program.raw_inst(inst))
+        .collect();
+    Program::new(raw, LabelOrder::Def)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitvec::prelude::*;
+
+    use super::*;
+    use crate::ws::tests::get_tutorial_insts;
+
+    #[test]
+    fn tutorial() {
+        let program = Program::new(get_tutorial_insts(), LabelOrder::Def);
+        assert_eq!(program, eliminate_dead_code(&program));
+    }
+
+    #[test]
+    fn unreachable() {
+        let insts = vec![
+            Inst::Label(bitvec![0]),
+            Inst::Push(bitvec![0, 1]),
+            Inst::Jmp(bitvec![1]),
+            Inst::Push(bitvec![0, 1, 0]),
+            Inst::Jmp(bitvec![1, 1]),
+            Inst::Label(bitvec![1]),
+            Inst::Printi,
+            Inst::Label(bitvec![1, 1]),
+            Inst::Label(bitvec![1]),
+            Inst::Call(bitvec![1, 0]),
+            Inst::End,
+            Inst::Label(bitvec![1, 0]),
+            Inst::Ret,
+            Inst::Label(bitvec![1, 0, 0]),
+            Inst::Printc,
+        ];
+        let expected = vec![
+            Inst::Push(bitvec![0, 1]),
+            Inst::Jmp(bitvec![1]),
+            Inst::Label(bitvec![1]),
+            Inst::Printi,
+            Inst::Call(bitvec![1, 0]),
+            Inst::End,
+            Inst::Label(bitvec![1, 0]),
+            Inst::Ret,
+        ];
+        let program = Program::new(insts, LabelOrder::Def);
+        assert_eq!(
+            Program::new(expected, LabelOrder::Def),
+            eliminate_dead_code(&program),
+        );
+    }
+}
@@ -0,0 +1,15 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Optimization passes over Whitespace programs.
+//!
+//! Each pass takes a resolved program and rebuilds a new one from the raw
+//! instructions it keeps, so labels are resolved again afterwards.
+
+pub mod dce;
+pub mod peephole;
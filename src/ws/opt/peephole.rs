@@ -0,0 +1,146 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Constant folding and stack peephole optimizations.
+
+use rug::Integer;
+
+use crate::ws::inst::Inst;
+use crate::ws::syntax::{IntLiteral, LabelOrder, Program, ProgramInst};
+
+/// Rewrites short sequences of instructions into cheaper equivalents:
+///
+/// - `push a; push b; add` (and `sub`, `mul`, `div`, `mod`) is folded to
+///   `push c`
+/// - `push; drop`, `dup; drop`, and `swap; swap` are removed
+/// - `copy 0` is replaced with `dup`
+/// - `slide 0` is removed
+///
+/// Division and modulo round towards negative infinity, like the reference
+/// interpreter, and division by zero is left to fail at runtime. Since a label
+/// interrupts every pattern, no rewrite spans a jump target.
+#[must_use]
+pub fn optimize_peephole(program: &Program) -> Program {
+    let mut out: Vec<ProgramInst> = Vec::with_capacity(program.insts().len());
+    for inst in program.insts() {
+        match inst {
+            Inst::Copy(n) if **n == 0 => out.push(Inst::Dup),
+            Inst::Slide(n) if **n == 0 => {}
+            _ => out.push(inst.clone()),
+        }
+        while reduce(&mut out) {}
+    }
+    let raw = out.iter().map(|inst| program.raw_inst(inst)).collect();
+    Program::new(raw, LabelOrder::Def)
+}
+
+/// Rewrites a pattern at the end of the instructions and returns whether it
+/// made a change.
+fn reduce(out: &mut Vec<ProgramInst>) -> bool {
+    match out.as_slice() {
+        [.., Inst::Push(_) | Inst::Dup, Inst::Drop] | [.., Inst::Swap, Inst::Swap] => {
+            out.truncate(out.len() - 2);
+            true
+        }
+        [.., Inst::Push(a), Inst::Push(b), op] => {
+            let (a, b): (&Integer, &Integer) = (a, b);
+            let c = match op {
+                Inst::Add => Integer::from(a + b),
+                Inst::Sub => Integer::from(a - b),
+                Inst::Mul => Integer::from(a * b),
+                Inst::Div if *b != 0 => a.clone().div_rem_floor(b.clone()).0,
+                Inst::Mod if *b != 0 => a.clone().div_rem_floor(b.clone()).1,
+                _ => return false,
+            };
+            out.truncate(out.len() - 3);
+            out.push(Inst::Push(IntLiteral::from(c)));
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitvec::prelude::*;
+
+    use super::*;
+    use crate::ws::inst::RawInst;
+
+    fn push(n: i32) -> RawInst {
+        Inst::Push(IntLiteral::from(Integer::from(n)).bits().clone())
+    }
+
+    fn optimize(insts: Vec<RawInst>) -> Vec<RawInst> {
+        let program = optimize_peephole(&Program::new(insts, LabelOrder::Def));
+        program
+            .insts()
+            .iter()
+            .map(|inst| program.raw_inst(inst))
+            .collect()
+    }
+
+    #[test]
+    fn fold() {
+        let insts = vec![
+            push(2),
+            push(3),
+            push(4),
+            Inst::Mul,
+            Inst::Sub,
+            Inst::Printi,
+        ];
+        assert_eq!(vec![push(-10), Inst::Printi], optimize(insts));
+        for (a, b, div, rem) in [
+            (7, 2, 3, 1),
+            (-7, 2, -4, 1),
+            (7, -2, -4, -1),
+            (-7, -2, 3, -1),
+        ] {
+            assert_eq!(vec![push(div)], optimize(vec![push(a), push(b), Inst::Div]));
+            assert_eq!(vec![push(rem)], optimize(vec![push(a), push(b), Inst::Mod]));
+        }
+        let insts = vec![push(1), push(0), Inst::Div];
+        assert_eq!(insts, optimize(insts.clone()));
+    }
+
+    #[test]
+    fn stack() {
+        let insts = vec![
+            push(1),
+            Inst::Copy(bitvec![0]),
+            Inst::Swap,
+            push(5),
+            Inst::Drop,
+            Inst::Swap,
+            Inst::Slide(bitvec![0]),
+            Inst::Dup,
+            Inst::Drop,
+            Inst::Printi,
+            push(1),
+            Inst::Add,
+        ];
+        let expected = vec![push(1), Inst::Dup, Inst::Printi, push(1), Inst::Add];
+        assert_eq!(expected, optimize(insts));
+    }
+
+    #[test]
+    fn labels() {
+        let insts = vec![
+            push(1),
+            Inst::Label(bitvec![1]),
+            push(2),
+            Inst::Add,
+            Inst::Swap,
+            Inst::Label(bitvec![1, 0]),
+            Inst::Swap,
+            Inst::Jmp(bitvec![1]),
+        ];
+        assert_eq!(insts, optimize(insts.clone()));
+    }
+}
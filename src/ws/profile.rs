@@ -0,0 +1,316 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Execution profiler for the Whitespace interpreter.
+//!
+//! Executed instructions are counted per instruction, per label, and per
+//! inferred subroutine. An instruction counts towards the closest label
+//! defined before it in the program and towards the subroutine of the active
+//! call, so shared code is charged to the caller that ran it. Stacks of active
+//! calls are recorded in the folded format read by `flamegraph.pl` and
+//! `inferno`, with frames named by the called labels.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::io::{BufRead, Write};
+
+use crate::ws::inst::Inst;
+use crate::ws::interp::{fmt_inst, label_name, InterpError, Interpreter};
+use crate::ws::ir::BlockId;
+use crate::ws::subroutine::{CallGraph, SubId};
+use crate::ws::syntax::{InstId, LabelId, Program};
+
+#[derive(Clone, Debug)]
+pub struct Profiler<'a> {
+    interp: Interpreter<'a>,
+    graph: CallGraph,
+    /// Subroutine that starts at each block.
+    entry_subs: HashMap<BlockId, SubId>,
+    /// Closest label defined at or before each instruction.
+    inst_labels: Vec<Option<LabelId>>,
+    inst_counts: Vec<u64>,
+    sub_counts: Vec<u64>,
+    /// Instruction counts by the stack of active `call` instructions.
+    stacks: HashMap<Vec<InstId>, u64>,
+    /// Instructions executed since the call stack last changed.
+    pending: u64,
+    peak_stack: usize,
+    peak_heap: usize,
+}
+
+impl<'a> Profiler<'a> {
+    #[must_use]
+    pub fn new(program: &'a Program) -> Self {
+        let graph = program.call_graph();
+        let entry_subs = graph
+            .subroutines()
+            .iter()
+            .map(|sub| (sub.entry(), sub.id()))
+            .collect();
+        let mut label = None;
+        let inst_labels = program
+            .insts()
+            .iter()
+            .map(|inst| {
+                if let Inst::Label(l) = inst {
+                    label = Some(*l);
+                }
+                label
+            })
+            .collect();
+        Profiler {
+            interp: Interpreter::new(program),
+            entry_subs,
+            inst_labels,
+            inst_counts: vec![0; program.insts().len()],
+            sub_counts: vec![0; graph.subroutines().len()],
+            graph,
+            stacks: HashMap::new(),
+            pending: 0,
+            peak_stack: 0,
+            peak_heap: 0,
+        }
+    }
+
+    /// Runs the program until it ends or fails, counting the instructions
+    /// that execute. A failing instruction is not counted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when an instruction fails. The counts up to it are kept.
+    pub fn run<R: BufRead, W: Write, D: Write>(
+        &mut self,
+        stdin: &mut R,
+        stdout: &mut W,
+        debug: &mut D,
+    ) -> Result<(), InterpError> {
+        let mut sub = self.current_sub();
+        let res = (|| {
+            while !self.interp.is_halted() {
+                let pc = self.interp.pc();
+                let depth = self.interp.calls().len();
+                let top = self.interp.calls().last().copied();
+                self.interp.step(stdin, stdout, debug)?;
+                self.inst_counts[usize::from(pc)] += 1;
+                if let Some(sub) = sub {
+                    self.sub_counts[usize::from(sub)] += 1;
+                }
+                self.pending += 1;
+                if self.interp.calls().len() != depth {
+                    self.flush_stack(depth, top);
+                    sub = self.current_sub();
+                }
+                self.peak_stack = self.peak_stack.max(self.interp.stack().len());
+                self.peak_heap = self.peak_heap.max(self.interp.heap().len());
+            }
+            Ok(())
+        })();
+        stdout.flush()?;
+        self.flush_stack(self.interp.calls().len(), None);
+        res
+    }
+
+    /// Charges the pending count to the call stack before its last change,
+    /// which had `depth` calls with `top` innermost. The instruction that
+    /// changed it runs in the frame it was in.
+    fn flush_stack(&mut self, depth: usize, top: Option<InstId>) {
+        if self.pending == 0 {
+            return;
+        }
+        let calls = self.interp.calls();
+        let mut stack = calls[..depth.min(calls.len())].to_vec();
+        if depth > calls.len() {
+            stack.extend(top);
+        }
+        *self.stacks.entry(stack).or_default() += self.pending;
+        self.pending = 0;
+    }
+
+    /// Returns the subroutine of the active call, if it starts a subroutine.
+    fn current_sub(&self) -> Option<SubId> {
+        let Some(&call) = self.interp.calls().last() else {
+            return Some(SubId(0));
+        };
+        let Inst::Call(l) = self.interp.program().insts()[call] else {
+            return None;
+        };
+        let block = self.graph.ir().label_block(l);
+        self.entry_subs.get(&block).copied()
+    }
+
+    /// Formats the report, with each table sorted by decreasing count.
+    #[must_use]
+    pub fn report(&self) -> String {
+        let program = self.interp.program();
+        let total = self.inst_counts.iter().sum::<u64>();
+        let mut s = String::new();
+        let _ = writeln!(s, "instructions executed: {total}");
+        let _ = writeln!(s, "peak stack depth: {}", self.peak_stack);
+        let _ = writeln!(s, "peak heap size: {}", self.peak_heap);
+
+        let subs = self.sub_counts.iter().enumerate().map(|(i, &count)| {
+            let sub = &self.graph.subroutines()[i];
+            let labels = self.graph.ir()[sub.entry()].labels().iter();
+            let labels = labels.map(|&l| label_name(&program.labels()[l], None));
+            let name = [sub.id().to_string()].into_iter().chain(labels);
+            (count, name.collect::<Vec<_>>().join(" "))
+        });
+        fmt_table(&mut s, "subroutines", total, subs);
+
+        let mut labels = BTreeMap::<Option<LabelId>, u64>::new();
+        for (i, &count) in self.inst_counts.iter().enumerate() {
+            *labels.entry(self.inst_labels[i]).or_default() += count;
+        }
+        let labels = labels.into_iter().map(|(l, count)| match l {
+            Some(l) => (count, label_name(&program.labels()[l], None)),
+            None => (count, "(start)".to_owned()),
+        });
+        fmt_table(&mut s, "labels", total, labels);
+
+        let insts = self.inst_counts.iter().enumerate().map(|(i, &count)| {
+            let inst = fmt_inst(program, InstId::from(i));
+            (count, format!("{i:>4}  {inst}"))
+        });
+        fmt_table(&mut s, "instructions", total, insts);
+        s
+    }
+
+    /// Formats the instruction counts by call stack in the folded format, one
+    /// stack per line, sorted by stack. Calls to the same label from different
+    /// sites are merged.
+    #[must_use]
+    pub fn folded(&self) -> String {
+        let program = self.interp.program();
+        let mut stacks = BTreeMap::<String, u64>::new();
+        for (stack, count) in &self.stacks {
+            let frames = stack.iter().map(|&call| match &program.insts()[call] {
+                Inst::Call(l) => label_name(&program.labels()[*l], Some(call)),
+                _ => "?".to_owned(),
+            });
+            let frames = ["main".to_owned()].into_iter().chain(frames);
+            *stacks
+                .entry(frames.collect::<Vec<_>>().join(";"))
+                .or_default() += count;
+        }
+        let mut s = String::new();
+        for (stack, count) in stacks {
+            let _ = writeln!(s, "{stack} {count}");
+        }
+        s
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn interp(&self) -> &Interpreter<'a> {
+        &self.interp
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn interp_mut(&mut self) -> &mut Interpreter<'a> {
+        &mut self.interp
+    }
+
+    /// Executed instructions by instruction.
+    #[inline]
+    #[must_use]
+    pub fn inst_counts(&self) -> &[u64] {
+        &self.inst_counts
+    }
+
+    /// Executed instructions by inferred subroutine.
+    #[inline]
+    #[must_use]
+    pub fn sub_counts(&self) -> &[u64] {
+        &self.sub_counts
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn peak_stack(&self) -> usize {
+        self.peak_stack
+    }
+
+    /// Largest number of heap cells that have been set.
+    #[inline]
+    #[must_use]
+    pub fn peak_heap(&self) -> usize {
+        self.peak_heap
+    }
+}
+
+/// Writes the rows with a non-zero count, sorted by decreasing count and then
+/// in their original order.
+fn fmt_table<I: Iterator<Item = (u64, String)>>(s: &mut String, title: &str, total: u64, rows: I) {
+    let mut rows = rows.filter(|&(count, _)| count != 0).collect::<Vec<_>>();
+    rows.sort_by_key(|&(count, _)| Reverse(count));
+    let _ = writeln!(s, "\n{title}:");
+    for (count, name) in rows {
+        #[allow(clippy::cast_precision_loss)]
+        let percent = count as f64 * 100.0 / total as f64;
+        let _ = writeln!(s, "{count:>10} {percent:>6.2}%  {name}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use bitvec::prelude::*;
+
+    use super::*;
+    use crate::ws::syntax::LabelOrder;
+
+    #[test]
+    fn calls() {
+        let program = Program::new(
+            vec![
+                Inst::Push(bitvec![0, 1, 0]),
+                Inst::Call(bitvec![1]),
+                Inst::Call(bitvec![1]),
+                Inst::End,
+                Inst::Label(bitvec![1]),
+                Inst::Push(bitvec![0, 1]),
+                Inst::Drop,
+                Inst::Ret,
+            ],
+            LabelOrder::Def,
+        );
+        let mut profiler = Profiler::new(&program);
+        let (mut stdin, mut stdout) = (io::empty(), io::sink());
+        profiler
+            .run(&mut stdin, &mut stdout, &mut io::sink())
+            .unwrap();
+        assert_eq!(&[4, 8], profiler.sub_counts());
+        assert_eq!("main 4\nmain;1 8\n", profiler.folded());
+        let expected = "instructions executed: 12
+peak stack depth: 2
+peak heap size: 0
+
+subroutines:
+         8  66.67%  sub_1 1
+         4  33.33%  sub_0
+
+labels:
+         8  66.67%  1
+         4  33.33%  (start)
+
+instructions:
+         2  16.67%     4  label 1
+         2  16.67%     5  push 1
+         2  16.67%     6  drop
+         2  16.67%     7  ret
+         1   8.33%     0  push 2
+         1   8.33%     1  call 1
+         1   8.33%     2  call 1
+         1   8.33%     3  end
+";
+        assert_eq!(expected, profiler.report());
+    }
+}
@@ -0,0 +1,545 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Range analysis for constraining the bit width of arbitrary-precision
+//! integers.
+//!
+//! Whitespace integers are unbounded, but most values in practice fit in a
+//! machine word. This computes an interval for every SSA value with a forward
+//! dataflow analysis, so backends can use `i64` or `i128` where a value
+//! provably fits and fall back to `rug::Integer` only where it does not.
+//!
+//! The heap is summarized by a single interval, which is the join of every
+//! value that may be stored. Block parameters are widened to the `i64` and
+//! `i128` bounds and then to infinity after a few rounds, so loops that grow a
+//! value terminate the analysis.
+
+use std::fmt::{self, Display, Formatter};
+
+use rug::Integer;
+
+use crate::ws::ir::BlockId;
+use crate::ws::ssa::{Edge, Exit, Op, Ssa, ValueId};
+
+/// Number of times a parameter may grow before it is widened.
+const WIDEN_AFTER: u32 = 4;
+
+/// Range of the characters that `readc` may produce, including -1 for EOF
+/// in implementations that do not treat it as an error.
+const READC_MIN: i32 = -1;
+const READC_MAX: i32 = 0x0010_ffff;
+
+/// Ranges of the values in an SSA program.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Ranges {
+    values: Vec<Range>,
+    heap: Range,
+}
+
+/// Interval of integers that a value may take.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Range {
+    /// The value is never defined, because it is unreachable or its definition
+    /// always fails.
+    Empty,
+    /// Values between the bounds, inclusive. `lo` is never `PosInf` and `hi`
+    /// is never `NegInf`.
+    Interval { lo: Bound, hi: Bound },
+}
+
+/// Bound of an interval.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Bound {
+    NegInf,
+    Finite(Integer),
+    PosInf,
+}
+
+/// Smallest integer type that holds every value in a range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Width {
+    I64,
+    I128,
+    Big,
+}
+
+impl Ranges {
+    /// Computes the ranges of the values in an SSA program.
+    #[must_use]
+    pub fn new(ssa: &Ssa) -> Self {
+        let mut values = vec![Range::Empty; ssa.values().len()];
+        let mut joins = vec![0; ssa.values().len()];
+        // Unset heap cells read as 0.
+        let mut heap = Range::exact(Integer::new());
+        let mut heap_joins = 0;
+        let mut reached = vec![false; ssa.blocks().len()];
+        reached[0] = true;
+        let ret_sites = ssa
+            .blocks()
+            .iter()
+            .filter_map(|b| match b.exit() {
+                Exit::Call { ret, .. } => Some(*ret),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in ssa.blocks() {
+                if !reached[usize::from(block.id())] {
+                    continue;
+                }
+                for stmt in block.stmts() {
+                    let v = |v: ValueId| &values[usize::from(v)];
+                    let stored = match stmt.op {
+                        Op::Store { value, .. } => Some(v(value).clone()),
+                        Op::Readc(_) => Some(Range::Interval {
+                            lo: Bound::from(READC_MIN),
+                            hi: Bound::from(READC_MAX),
+                        }),
+                        Op::Readi(_) => Some(Range::full()),
+                        _ => None,
+                    };
+                    if let Some(stored) = stored {
+                        let joined = heap.join(&stored);
+                        if joined != heap {
+                            heap_joins += 1;
+                            heap = heap.widen(joined, heap_joins);
+                            changed = true;
+                        }
+                    }
+                    let range = match stmt.op {
+                        Op::Const(ref n) => Range::exact(n.clone()),
+                        Op::Add(a, b) => add(v(a), v(b)),
+                        Op::Sub(a, b) => add(v(a), &v(b).neg()),
+                        Op::Mul(a, b) => mul(v(a), v(b)),
+                        Op::Div(a, b) => div(v(a), v(b)),
+                        Op::Mod(a, b) => rem(v(a), v(b)),
+                        Op::Retrieve(_) => heap.clone(),
+                        _ => continue,
+                    };
+                    if let Some(def) = stmt.def {
+                        values[usize::from(def)] = range;
+                    }
+                }
+
+                let mut flow = |target: BlockId, args: &[ValueId]| {
+                    if !reached[usize::from(target)] {
+                        reached[usize::from(target)] = true;
+                        changed = true;
+                    }
+                    for (&param, &arg) in ssa[target].params().iter().zip(args) {
+                        let (param, arg) = (usize::from(param), usize::from(arg));
+                        let joined = values[param].join(&values[arg]);
+                        if joined != values[param] {
+                            joins[param] += 1;
+                            values[param] = values[param].widen(joined, joins[param]);
+                            changed = true;
+                        }
+                    }
+                };
+                for &Edge { target, ref args } in block.exit().edges() {
+                    flow(target, args);
+                }
+                if let Exit::Ret(args) = block.exit() {
+                    for &site in &ret_sites {
+                        flow(site, args);
+                    }
+                }
+            }
+        }
+        Ranges { values, heap }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn range(&self, v: ValueId) -> &Range {
+        &self.values[usize::from(v)]
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn width(&self, v: ValueId) -> Width {
+        self.range(v).width()
+    }
+
+    /// Range of every value that may be stored in the heap.
+    #[inline]
+    #[must_use]
+    pub fn heap(&self) -> &Range {
+        &self.heap
+    }
+}
+
+impl Range {
+    #[inline]
+    #[must_use]
+    pub fn exact(n: Integer) -> Self {
+        Range::Interval {
+            lo: Bound::Finite(n.clone()),
+            hi: Bound::Finite(n),
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn full() -> Self {
+        Range::Interval {
+            lo: Bound::NegInf,
+            hi: Bound::PosInf,
+        }
+    }
+
+    /// Smallest range that contains both ranges.
+    #[must_use]
+    pub fn join(&self, other: &Range) -> Range {
+        match (self, other) {
+            (Range::Empty, r) | (r, Range::Empty) => r.clone(),
+            (Range::Interval { lo: lo1, hi: hi1 }, Range::Interval { lo: lo2, hi: hi2 }) => {
+                Range::Interval {
+                    lo: lo1.min(lo2).clone(),
+                    hi: hi1.max(hi2).clone(),
+                }
+            }
+        }
+    }
+
+    /// Returns whether the ranges have any integer in common.
+    #[must_use]
+    pub fn intersects(&self, other: &Range) -> bool {
+        match (self, other) {
+            (Range::Interval { lo: lo1, hi: hi1 }, Range::Interval { lo: lo2, hi: hi2 }) => {
+                lo1 <= hi2 && lo2 <= hi1
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the integer, if the range contains exactly one.
+    #[must_use]
+    pub fn as_exact(&self) -> Option<&Integer> {
+        match self {
+            Range::Interval {
+                lo: Bound::Finite(lo),
+                hi: Bound::Finite(hi),
+            } if lo == hi => Some(lo),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the range contains the integer.
+    #[must_use]
+    pub fn contains(&self, n: &Integer) -> bool {
+        match self {
+            Range::Empty => false,
+            Range::Interval { lo, hi } => {
+                let n = Bound::Finite(n.clone());
+                *lo <= n && n <= *hi
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn width(&self) -> Width {
+        let fits = |min: Integer, max: Integer| match self {
+            Range::Empty => true,
+            Range::Interval { lo, hi } => *lo >= Bound::Finite(min) && *hi <= Bound::Finite(max),
+        };
+        if fits(Integer::from(i64::MIN), Integer::from(i64::MAX)) {
+            Width::I64
+        } else if fits(Integer::from(i128::MIN), Integer::from(i128::MAX)) {
+            Width::I128
+        } else {
+            Width::Big
+        }
+    }
+
+    fn neg(&self) -> Range {
+        match self {
+            Range::Empty => Range::Empty,
+            Range::Interval { lo, hi } => Range::Interval { lo: hi.neg(), hi: lo.neg() },
+        }
+    }
+
+    /// Widens the bounds that grew in `new` to the next of the `i64` bound,
+    /// the `i128` bound, and infinity, once the range has grown enough times.
+    fn widen(&self, new: Range, joins: u32) -> Range {
+        let (Range::Interval { lo: old_lo, hi: old_hi }, Range::Interval { lo, hi }) = (self, &new)
+        else {
+            return new;
+        };
+        if joins <= WIDEN_AFTER {
+            return new;
+        }
+        let lo = if lo < old_lo {
+            [i64::MIN.into(), i128::MIN.into()]
+                .into_iter()
+                .map(Bound::Finite)
+                .find(|t| t <= lo)
+                .unwrap_or(Bound::NegInf)
+        } else {
+            lo.clone()
+        };
+        let hi = if hi > old_hi {
+            [i64::MAX.into(), i128::MAX.into()]
+                .into_iter()
+                .map(Bound::Finite)
+                .find(|t| t >= hi)
+                .unwrap_or(Bound::PosInf)
+        } else {
+            hi.clone()
+        };
+        Range::Interval { lo, hi }
+    }
+}
+
+impl Bound {
+    fn signum(&self) -> i32 {
+        match self {
+            Bound::NegInf => -1,
+            Bound::Finite(n) => n.cmp0() as i32,
+            Bound::PosInf => 1,
+        }
+    }
+
+    fn neg(&self) -> Bound {
+        match self {
+            Bound::NegInf => Bound::PosInf,
+            Bound::Finite(n) => Bound::Finite(Integer::from(-n)),
+            Bound::PosInf => Bound::NegInf,
+        }
+    }
+
+    /// Adds bounds. They are never infinities of opposite signs.
+    fn add(&self, other: &Bound) -> Bound {
+        match (self, other) {
+            (Bound::Finite(a), Bound::Finite(b)) => Bound::Finite(Integer::from(a + b)),
+            (Bound::NegInf, _) | (_, Bound::NegInf) => Bound::NegInf,
+            _ => Bound::PosInf,
+        }
+    }
+
+    fn mul(&self, other: &Bound) -> Bound {
+        match (self, other) {
+            (Bound::Finite(a), Bound::Finite(b)) => Bound::Finite(Integer::from(a * b)),
+            _ => match self.signum() * other.signum() {
+                0 => Bound::Finite(Integer::new()),
+                s if s < 0 => Bound::NegInf,
+                _ => Bound::PosInf,
+            },
+        }
+    }
+
+    fn finite(&self) -> Option<&Integer> {
+        match self {
+            Bound::Finite(n) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+impl From<i32> for Bound {
+    #[inline]
+    fn from(n: i32) -> Self {
+        Bound::Finite(Integer::from(n))
+    }
+}
+
+fn add(a: &Range, b: &Range) -> Range {
+    let (Range::Interval { lo: alo, hi: ahi }, Range::Interval { lo: blo, hi: bhi }) = (a, b)
+    else {
+        return Range::Empty;
+    };
+    Range::Interval {
+        lo: alo.add(blo),
+        hi: ahi.add(bhi),
+    }
+}
+
+fn mul(a: &Range, b: &Range) -> Range {
+    let (Range::Interval { lo: alo, hi: ahi }, Range::Interval { lo: blo, hi: bhi }) = (a, b)
+    else {
+        return Range::Empty;
+    };
+    let corners = [alo.mul(blo), alo.mul(bhi), ahi.mul(blo), ahi.mul(bhi)];
+    Range::Interval {
+        lo: corners.iter().min().unwrap().clone(),
+        hi: corners.iter().max().unwrap().clone(),
+    }
+}
+
+/// Floor division. Division by zero fails, so it contributes no values.
+fn div(a: &Range, b: &Range) -> Range {
+    let (Range::Interval { lo: alo, hi: ahi }, Range::Interval { lo: blo, hi: bhi }) = (a, b)
+    else {
+        return Range::Empty;
+    };
+    let zero = Bound::from(0);
+    if *blo == zero && *bhi == zero {
+        return Range::Empty;
+    }
+    // Floor division is monotonic in each operand when the divisor does not
+    // cross 0, so the extremes are at the corners.
+    if let (Some(alo), Some(ahi), Some(blo), Some(bhi)) =
+        (alo.finite(), ahi.finite(), blo.finite(), bhi.finite())
+    {
+        if *blo > 0 || *bhi < 0 {
+            let q = |a: &Integer, b: &Integer| Bound::Finite(a.clone().div_rem_floor(b.clone()).0);
+            let corners = [q(alo, blo), q(alo, bhi), q(ahi, blo), q(ahi, bhi)];
+            return Range::Interval {
+                lo: corners.iter().min().unwrap().clone(),
+                hi: corners.iter().max().unwrap().clone(),
+            };
+        }
+    }
+    // Otherwise, the magnitude of the quotient is at most that of the
+    // dividend.
+    let m = alo.neg().max(ahi.clone());
+    Range::Interval { lo: m.neg(), hi: m }
+}
+
+/// Floor modulo, which has the sign of the divisor.
+fn rem(a: &Range, b: &Range) -> Range {
+    let (Range::Interval { lo: alo, hi: ahi }, Range::Interval { lo: blo, hi: bhi }) = (a, b)
+    else {
+        return Range::Empty;
+    };
+    let zero = Bound::from(0);
+    let one = Bound::from(1);
+    if *blo > zero {
+        let mut hi = bhi.add(&one.neg());
+        if *alo >= zero {
+            hi = hi.min(ahi.clone());
+        }
+        Range::Interval { lo: zero, hi }
+    } else if *bhi < zero {
+        let mut lo = blo.add(&one);
+        if *ahi <= zero {
+            lo = lo.max(alo.clone());
+        }
+        Range::Interval { lo, hi: zero }
+    } else if *blo == zero && *bhi == zero {
+        Range::Empty
+    } else {
+        let m = blo.neg().max(bhi.clone()).add(&one.neg());
+        Range::Interval { lo: m.neg(), hi: m }
+    }
+}
+
+impl Display for Range {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Range::Empty => write!(f, "empty"),
+            Range::Interval { lo, hi } => write!(f, "[{lo}, {hi}]"),
+        }
+    }
+}
+
+impl Display for Bound {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Bound::NegInf => write!(f, "-inf"),
+            Bound::Finite(n) => write!(f, "{n}"),
+            Bound::PosInf => write!(f, "+inf"),
+        }
+    }
+}
+
+impl Display for Width {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Width::I64 => write!(f, "i64"),
+            Width::I128 => write!(f, "i128"),
+            Width::Big => write!(f, "big"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws::inst::{Inst, RawInst};
+    use crate::ws::ir::Ir;
+    use crate::ws::syntax::{IntLiteral, LabelOrder, Program};
+    use crate::ws::tests::get_tutorial_insts;
+
+    fn range(lo: i32, hi: i32) -> Range {
+        Range::Interval {
+            lo: Bound::from(lo),
+            hi: Bound::from(hi),
+        }
+    }
+
+    fn push(n: Integer) -> RawInst {
+        Inst::Push(IntLiteral::from(n).bits().clone())
+    }
+
+    fn widths(insts: Vec<RawInst>) -> Vec<Width> {
+        let ssa = Ssa::new(&Ir::new(&Program::new(insts, LabelOrder::Def))).unwrap();
+        let ranges = Ranges::new(&ssa);
+        (0..ssa.values().len())
+            .map(|v| ranges.width(ValueId::from(v)))
+            .collect()
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(range(-4, 3), div(&range(-7, 7), &range(2, 2)));
+        assert_eq!(range(-7, 7), div(&range(-7, 7), &range(-2, 2)));
+        assert_eq!(range(0, 3), rem(&range(-7, 7), &range(1, 4)));
+        assert_eq!(range(0, 2), rem(&range(0, 2), &range(1, 256)));
+        assert_eq!(range(-3, 0), rem(&range(-7, 7), &range(-4, -1)));
+        assert_eq!(range(-4, 4), rem(&range(-7, 7), &range(-5, 5)));
+        assert_eq!(Range::Empty, rem(&range(1, 1), &range(0, 0)));
+        let r = mul(&range(-2, 3), &Range::Interval {
+            lo: Bound::from(1),
+            hi: Bound::PosInf,
+        });
+        assert_eq!(Range::full(), r);
+    }
+
+    #[test]
+    fn tutorial() {
+        let w = widths(get_tutorial_insts());
+        // The loop counter grows without a bound that the analysis can see.
+        assert_eq!(Width::Big, w[1]);
+        assert_eq!(Width::I64, w[0]);
+        assert_eq!(Width::I64, w[5]);
+    }
+
+    #[test]
+    fn widths_of_ops() {
+        use Width::*;
+        let one = || push(Integer::from(1));
+        let p62 = || push(Integer::from(1) << 62u32);
+        let p124 = || push(Integer::from(1) << 124u32);
+        let insts = vec![
+            one(),
+            Inst::Readi,
+            one(),
+            Inst::Retrieve,
+            push(Integer::from(256)),
+            Inst::Mod,
+            p62(),
+            p62(),
+            Inst::Add,
+            p62(),
+            p62(),
+            Inst::Mul,
+            p124(),
+            p124(),
+            Inst::Mul,
+            Inst::End,
+        ];
+        let expected = vec![
+            I64, I64, Big, I64, I64, I64, I64, I128, I64, I64, I128, I128, I128, Big,
+        ];
+        assert_eq!(expected, widths(insts));
+    }
+}
@@ -0,0 +1,735 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! SSA IR for Whitespace with block parameters instead of phis.
+//!
+//! Lowering from the stack IR in [`ws::ir`](crate::ws::ir) turns stack slots
+//! into values. Stack manipulation instructions (`dup`, `copy`, `swap`,
+//! `drop`, and `slide`) become renamings and generate no code. The stack on
+//! entry to a block becomes its parameters, and edges pass the top of the stack
+//! as arguments.
+//!
+//! The number of parameters of a block is the number of values from the entry
+//! stack that it or any of its successors reads. Values below that are left
+//! on the stack untouched. `ret` is treated as an edge to every return site,
+//! so all return sites have the same number of parameters.
+//!
+//! Values are only used in the block that defines them, since they are passed
+//! between blocks as arguments.
+
+use std::fmt::{self, Display, Formatter};
+use std::ops::{Index, IndexMut};
+
+use rug::Integer;
+
+use crate::ws::inst::{Inst, InstError, Opcode};
+use crate::ws::ir::{self, BlockId, Ir};
+use crate::ws::syntax::{id_index, LabelId};
+
+/// Maximum number of parameters of a block. Programs that need more, such as
+/// those with loops that pop values without bound, are not lowered.
+pub const MAX_PARAMS: usize = 256;
+
+/// Whitespace program in SSA form.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Ssa {
+    blocks: Vec<Block>,
+    values: Vec<ValueDef>,
+}
+
+id_index!(ValueId(u32) indexes ValueDef in Vec<ValueDef>, [ValueDef]);
+
+/// Basic block in SSA form.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Block {
+    id: BlockId,
+    /// Values on the stack on entry, from the bottom to the top.
+    params: Vec<ValueId>,
+    stmts: Vec<Stmt>,
+    exit: Exit,
+}
+
+/// Statement that defines at most one value.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Stmt {
+    pub def: Option<ValueId>,
+    pub op: Op,
+}
+
+/// Operations on values.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Op {
+    /// `push`: defines a constant.
+    Const(Integer),
+    Add(ValueId, ValueId),
+    Sub(ValueId, ValueId),
+    Mul(ValueId, ValueId),
+    Div(ValueId, ValueId),
+    Mod(ValueId, ValueId),
+    /// `store`: stores the value at the address.
+    Store {
+        addr: ValueId,
+        value: ValueId,
+    },
+    /// `retrieve`: defines the value at the address.
+    Retrieve(ValueId),
+    Printc(ValueId),
+    Printi(ValueId),
+    /// `readc`: reads a character to the address.
+    Readc(ValueId),
+    /// `readi`: reads an integer to the address.
+    Readi(ValueId),
+    /// `dump_stack`: dumps the values on the stack below the parameters.
+    DumpStack,
+    DumpHeap,
+    DumpTrace,
+}
+
+/// Control flow at the end of a block.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Exit {
+    /// Unconditional jump, including fallthrough.
+    Jmp(Edge),
+    /// `jz`: jumps to `zero` if `cond` is 0, otherwise `other`.
+    Jz {
+        cond: ValueId,
+        zero: Edge,
+        other: Edge,
+    },
+    /// `jn`: jumps to `neg` if `cond` is negative, otherwise `other`.
+    Jn {
+        cond: ValueId,
+        neg: Edge,
+        other: Edge,
+    },
+    /// `call`: jumps to `callee`, which returns to `ret`.
+    Call {
+        callee: Edge,
+        ret: BlockId,
+    },
+    /// `ret`: returns the arguments to the return site.
+    Ret(Vec<ValueId>),
+    End,
+    Unterminated,
+    UndefinedLabel(LabelId),
+    Error(InstError),
+}
+
+/// Jump to a block with arguments for its parameters.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Edge {
+    pub target: BlockId,
+    pub args: Vec<ValueId>,
+}
+
+/// Definition site of a value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ValueDef {
+    /// Parameter with the index in the block.
+    Param(BlockId, usize),
+    /// Statement with the index in the block.
+    Stmt(BlockId, usize),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SsaError {
+    /// The block needs more than [`MAX_PARAMS`] values on entry.
+    StackDepth(BlockId),
+    /// The instruction accesses the stack dynamically.
+    Unsupported(BlockId, Opcode),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum VerifyError {
+    /// The value is used in a block other than where it is defined or before
+    /// its definition.
+    UseBeforeDef(BlockId, ValueId),
+    /// The value's definition site does not match where it is defined.
+    WrongDef(ValueId),
+    /// The number of arguments differs from the number of parameters.
+    ArgCount(BlockId, BlockId),
+    /// The number of values returned differs from the number of parameters of
+    /// a return site.
+    RetCount(BlockId, BlockId),
+    /// A jump targets a block that does not exist.
+    NoBlock(BlockId, BlockId),
+}
+
+impl Ssa {
+    /// Lowers the stack IR to SSA form.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when a block needs too many values on entry or accesses
+    /// the stack dynamically.
+    pub fn new(ir: &Ir) -> Result<Self, SsaError> {
+        let params = ParamCounts::new(ir)?;
+        let mut ssa = Ssa {
+            blocks: Vec::with_capacity(ir.blocks().len()),
+            values: Vec::new(),
+        };
+        for block in ir.blocks() {
+            ssa.lower_block(block, &params)?;
+        }
+        Ok(ssa)
+    }
+
+    fn lower_block(&mut self, block: &ir::Block, counts: &ParamCounts) -> Result<(), SsaError> {
+        let id = block.id();
+        let params = (0..counts.blocks[id.0 as usize])
+            .map(|i| self.def(ValueDef::Param(id, i)))
+            .collect::<Vec<_>>();
+        let mut stack = params.clone();
+        let mut stmts = Vec::new();
+        for inst in block.body() {
+            // The parameter count covers every pop, so the stack never
+            // underflows here.
+            let top = |stack: &Vec<ValueId>, n: usize| stack[stack.len() - 1 - n];
+            let op = match inst {
+                Inst::Push(n) => Op::Const((**n).clone()),
+                Inst::Dup => {
+                    stack.push(top(&stack, 0));
+                    continue;
+                }
+                Inst::Copy(n) => {
+                    // Negative and out of range arguments have already been
+                    // rejected by the parameter count.
+                    stack.push(top(&stack, n.to_usize().unwrap()));
+                    continue;
+                }
+                Inst::Swap => {
+                    let len = stack.len();
+                    stack.swap(len - 1, len - 2);
+                    continue;
+                }
+                Inst::Drop => {
+                    stack.pop();
+                    continue;
+                }
+                Inst::Slide(n) => {
+                    let v = stack.pop().unwrap();
+                    let n = n.to_usize().unwrap_or(0);
+                    stack.truncate(stack.len() - n);
+                    stack.push(v);
+                    continue;
+                }
+                Inst::Add | Inst::Sub | Inst::Mul | Inst::Div | Inst::Mod => {
+                    let rhs = stack.pop().unwrap();
+                    let lhs = stack.pop().unwrap();
+                    match inst {
+                        Inst::Add => Op::Add(lhs, rhs),
+                        Inst::Sub => Op::Sub(lhs, rhs),
+                        Inst::Mul => Op::Mul(lhs, rhs),
+                        Inst::Div => Op::Div(lhs, rhs),
+                        _ => Op::Mod(lhs, rhs),
+                    }
+                }
+                Inst::Store => {
+                    let value = stack.pop().unwrap();
+                    let addr = stack.pop().unwrap();
+                    Op::Store { addr, value }
+                }
+                Inst::Retrieve => Op::Retrieve(stack.pop().unwrap()),
+                Inst::Printc => Op::Printc(stack.pop().unwrap()),
+                Inst::Printi => Op::Printi(stack.pop().unwrap()),
+                Inst::Readc => Op::Readc(stack.pop().unwrap()),
+                Inst::Readi => Op::Readi(stack.pop().unwrap()),
+                Inst::DumpStack => Op::DumpStack,
+                Inst::DumpHeap => Op::DumpHeap,
+                Inst::DumpTrace => Op::DumpTrace,
+                Inst::Shuffle => return Err(SsaError::Unsupported(id, Opcode::Shuffle)),
+                Inst::Label(_)
+                | Inst::Call(_)
+                | Inst::Jmp(_)
+                | Inst::Jz(_)
+                | Inst::Jn(_)
+                | Inst::Ret
+                | Inst::End
+                | Inst::Error(_) => unreachable!("control flow in block body"),
+            };
+            let def = op
+                .has_def()
+                .then(|| self.def(ValueDef::Stmt(id, stmts.len())));
+            if let Some(def) = def {
+                stack.push(def);
+            }
+            stmts.push(Stmt { def, op });
+        }
+
+        let exit = lower_exit(block.exit(), stack, counts);
+        self.blocks.push(Block { id, params, stmts, exit });
+        Ok(())
+    }
+
+    #[inline]
+    fn def(&mut self, def: ValueDef) -> ValueId {
+        let id = ValueId::from(self.values.len());
+        self.values.push(def);
+        id
+    }
+}
+
+/// Lowers the exit of a block, given its stack after the body.
+fn lower_exit(exit: &ir::Exit, mut stack: Vec<ValueId>, counts: &ParamCounts) -> Exit {
+    let edge = |stack: &[ValueId], target: BlockId| Edge {
+        target,
+        args: stack[stack.len() - counts.blocks[target.0 as usize]..].to_vec(),
+    };
+    match *exit {
+        ir::Exit::Fallthrough(target) | ir::Exit::Jmp(target) => Exit::Jmp(edge(&stack, target)),
+        ir::Exit::Jz { zero, other } => {
+            let cond = stack.pop().unwrap();
+            Exit::Jz {
+                cond,
+                zero: edge(&stack, zero),
+                other: edge(&stack, other),
+            }
+        }
+        ir::Exit::Jn { neg, other } => {
+            let cond = stack.pop().unwrap();
+            Exit::Jn {
+                cond,
+                neg: edge(&stack, neg),
+                other: edge(&stack, other),
+            }
+        }
+        ir::Exit::Call { callee, ret } => Exit::Call {
+            callee: edge(&stack, callee),
+            ret,
+        },
+        ir::Exit::Ret => Exit::Ret(stack[stack.len() - counts.ret..].to_vec()),
+        ir::Exit::End => Exit::End,
+        ir::Exit::Unterminated => Exit::Unterminated,
+        ir::Exit::UndefinedLabel(l) => Exit::UndefinedLabel(l),
+        ir::Exit::Error(ref err) => Exit::Error(err.clone()),
+    }
+}
+
+/// Number of parameters of each block.
+struct ParamCounts {
+    blocks: Vec<usize>,
+    /// Number of parameters of every return site.
+    ret: usize,
+}
+
+impl ParamCounts {
+    /// Computes the least fixpoint of
+    /// `params(b) = max(pops(b), pops(b) + params(s) - pushes(b))` over the
+    /// successors `s` of each block `b`.
+    fn new(ir: &Ir) -> Result<Self, SsaError> {
+        let blocks = ir.blocks();
+        let ret_sites = blocks
+            .iter()
+            .filter_map(|b| match b.exit() {
+                ir::Exit::Call { ret, .. } => Some(*ret),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let mut counts = ParamCounts {
+            blocks: blocks.iter().map(|b| b.effect().pops).collect(),
+            ret: 0,
+        };
+        if let Some(b) = blocks.iter().find(|b| b.effect().pops > MAX_PARAMS) {
+            return Err(SsaError::StackDepth(b.id()));
+        }
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in blocks {
+                let succ_params = match *b.exit() {
+                    ir::Exit::Ret => counts.ret,
+                    ir::Exit::Call { callee, .. } => counts.blocks[callee.0 as usize],
+                    ref exit => exit
+                        .targets()
+                        .iter()
+                        .map(|s| counts.blocks[s.0 as usize])
+                        .max()
+                        .unwrap_or(0),
+                };
+                let effect = b.effect();
+                let params = effect.pops + succ_params.saturating_sub(effect.pushes);
+                let count = &mut counts.blocks[b.id().0 as usize];
+                if params > *count {
+                    if params > MAX_PARAMS {
+                        return Err(SsaError::StackDepth(b.id()));
+                    }
+                    *count = params;
+                    changed = true;
+                }
+            }
+            for &r in &ret_sites {
+                if counts.blocks[r.0 as usize] > counts.ret {
+                    counts.ret = counts.blocks[r.0 as usize];
+                    changed = true;
+                }
+            }
+            for &r in &ret_sites {
+                counts.blocks[r.0 as usize] = counts.ret;
+            }
+        }
+        Ok(counts)
+    }
+}
+
+impl Ssa {
+    #[inline]
+    #[must_use]
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn values(&self) -> &[ValueDef] {
+        &self.values
+    }
+
+    /// Checks that values are defined once, before their uses in the same
+    /// block, and that edges pass as many arguments as their targets have
+    /// parameters.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first violation that is found.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        let ret_sites = self
+            .blocks
+            .iter()
+            .filter_map(|b| match b.exit {
+                Exit::Call { ret, .. } => Some(ret),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let mut defined = vec![None; self.values.len()];
+        for block in &self.blocks {
+            let id = block.id;
+            for (i, &p) in block.params.iter().enumerate() {
+                if self.values[p] != ValueDef::Param(id, i) || defined[p.0 as usize].is_some() {
+                    return Err(VerifyError::WrongDef(p));
+                }
+                defined[p.0 as usize] = Some(id);
+            }
+            let check_use = |defined: &[Option<BlockId>], v: ValueId| {
+                if defined.get(v.0 as usize).copied().flatten() == Some(id) {
+                    Ok(())
+                } else {
+                    Err(VerifyError::UseBeforeDef(id, v))
+                }
+            };
+            for (i, stmt) in block.stmts.iter().enumerate() {
+                for v in stmt.op.uses() {
+                    check_use(&defined, v)?;
+                }
+                if let Some(def) = stmt.def {
+                    if self.values[def] != ValueDef::Stmt(id, i)
+                        || defined[def.0 as usize].is_some()
+                    {
+                        return Err(VerifyError::WrongDef(def));
+                    }
+                    defined[def.0 as usize] = Some(id);
+                }
+            }
+            for v in block.exit.uses() {
+                check_use(&defined, v)?;
+            }
+            for edge in block.exit.edges() {
+                let target = self
+                    .blocks
+                    .get(edge.target.0 as usize)
+                    .ok_or(VerifyError::NoBlock(id, edge.target))?;
+                if edge.args.len() != target.params.len() {
+                    return Err(VerifyError::ArgCount(id, edge.target));
+                }
+            }
+            if let Exit::Call { ret, .. } = block.exit {
+                self.blocks
+                    .get(ret.0 as usize)
+                    .ok_or(VerifyError::NoBlock(id, ret))?;
+            }
+            if let Exit::Ret(ref args) = block.exit {
+                for &r in &ret_sites {
+                    if args.len() != self[r].params.len() {
+                        return Err(VerifyError::RetCount(id, r));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Index<BlockId> for Ssa {
+    type Output = Block;
+
+    #[inline]
+    fn index(&self, id: BlockId) -> &Block {
+        &self.blocks[id.0 as usize]
+    }
+}
+
+impl IndexMut<BlockId> for Ssa {
+    #[inline]
+    fn index_mut(&mut self, id: BlockId) -> &mut Block {
+        &mut self.blocks[id.0 as usize]
+    }
+}
+
+impl Block {
+    #[inline]
+    #[must_use]
+    pub fn id(&self) -> BlockId {
+        self.id
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn params(&self) -> &[ValueId] {
+        &self.params
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn stmts(&self) -> &[Stmt] {
+        &self.stmts
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn exit(&self) -> &Exit {
+        &self.exit
+    }
+}
+
+impl Op {
+    /// Returns whether the operation defines a value.
+    #[inline]
+    #[must_use]
+    pub fn has_def(&self) -> bool {
+        matches!(
+            self,
+            Op::Const(_)
+                | Op::Add(..)
+                | Op::Sub(..)
+                | Op::Mul(..)
+                | Op::Div(..)
+                | Op::Mod(..)
+                | Op::Retrieve(_)
+        )
+    }
+
+    /// Values used by the operation.
+    #[must_use]
+    pub fn uses(&self) -> Vec<ValueId> {
+        match *self {
+            Op::Add(a, b) | Op::Sub(a, b) | Op::Mul(a, b) | Op::Div(a, b) | Op::Mod(a, b) => {
+                vec![a, b]
+            }
+            Op::Store { addr, value } => vec![addr, value],
+            Op::Retrieve(v) | Op::Printc(v) | Op::Printi(v) | Op::Readc(v) | Op::Readi(v) => {
+                vec![v]
+            }
+            Op::Const(_) | Op::DumpStack | Op::DumpHeap | Op::DumpTrace => Vec::new(),
+        }
+    }
+}
+
+impl Exit {
+    /// Edges to other blocks with arguments. This excludes the return site of
+    /// `call` and the return sites of `ret`.
+    #[must_use]
+    pub fn edges(&self) -> Vec<&Edge> {
+        match self {
+            Exit::Jmp(e) | Exit::Call { callee: e, .. } => vec![e],
+            Exit::Jz { zero: e1, other: e2, .. } | Exit::Jn { neg: e1, other: e2, .. } => {
+                vec![e1, e2]
+            }
+            Exit::Ret(_)
+            | Exit::End
+            | Exit::Unterminated
+            | Exit::UndefinedLabel(_)
+            | Exit::Error(_) => Vec::new(),
+        }
+    }
+
+    /// Values used by the exit, including arguments.
+    #[must_use]
+    pub fn uses(&self) -> Vec<ValueId> {
+        let mut uses = match self {
+            Exit::Jz { cond, .. } | Exit::Jn { cond, .. } => vec![*cond],
+            Exit::Ret(args) => args.clone(),
+            _ => Vec::new(),
+        };
+        for edge in self.edges() {
+            uses.extend_from_slice(&edge.args);
+        }
+        uses
+    }
+}
+
+impl Display for Ssa {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for block in &self.blocks {
+            write!(f, "{block}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Block {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(", self.id)?;
+        write_values(f, &self.params)?;
+        writeln!(f, "):")?;
+        for stmt in &self.stmts {
+            write!(f, "    ")?;
+            if let Some(def) = stmt.def {
+                write!(f, "{def} = ")?;
+            }
+            writeln!(f, "{}", stmt.op)?;
+        }
+        writeln!(f, "    {}", self.exit)
+    }
+}
+
+impl Display for Op {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Const(n) => write!(f, "const {n}"),
+            Op::Add(a, b) => write!(f, "add {a}, {b}"),
+            Op::Sub(a, b) => write!(f, "sub {a}, {b}"),
+            Op::Mul(a, b) => write!(f, "mul {a}, {b}"),
+            Op::Div(a, b) => write!(f, "div {a}, {b}"),
+            Op::Mod(a, b) => write!(f, "mod {a}, {b}"),
+            Op::Store { addr, value } => write!(f, "store {addr}, {value}"),
+            Op::Retrieve(addr) => write!(f, "retrieve {addr}"),
+            Op::Printc(v) => write!(f, "printc {v}"),
+            Op::Printi(v) => write!(f, "printi {v}"),
+            Op::Readc(addr) => write!(f, "readc {addr}"),
+            Op::Readi(addr) => write!(f, "readi {addr}"),
+            Op::DumpStack => write!(f, "dump_stack"),
+            Op::DumpHeap => write!(f, "dump_heap"),
+            Op::DumpTrace => write!(f, "dump_trace"),
+        }
+    }
+}
+
+impl Display for Exit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Exit::Jmp(e) => write!(f, "jmp {e}"),
+            Exit::Jz { cond, zero, other } => write!(f, "jz {cond}, {zero} else {other}"),
+            Exit::Jn { cond, neg, other } => write!(f, "jn {cond}, {neg} else {other}"),
+            Exit::Call { callee, ret } => write!(f, "call {callee} then {ret}"),
+            Exit::Ret(args) => {
+                write!(f, "ret(")?;
+                write_values(f, args)?;
+                write!(f, ")")
+            }
+            Exit::End => write!(f, "end"),
+            Exit::Unterminated => write!(f, "unterminated"),
+            Exit::UndefinedLabel(l) => write!(f, "undefined {l}"),
+            Exit::Error(err) => write!(f, "error {err:?}"),
+        }
+    }
+}
+
+impl Display for Edge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(", self.target)?;
+        write_values(f, &self.args)?;
+        write!(f, ")")
+    }
+}
+
+impl Display for ValueId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+fn write_values(f: &mut Formatter<'_>, values: &[ValueId]) -> fmt::Result {
+    for (i, v) in values.iter().enumerate() {
+        if i != 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{v}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bitvec::prelude::*;
+
+    use super::*;
+    use crate::ws::syntax::{LabelOrder, Program};
+    use crate::ws::tests::get_tutorial_insts;
+
+    #[test]
+    fn tutorial() {
+        let program = Program::new(get_tutorial_insts(), LabelOrder::Def);
+        let ssa = Ssa::new(&Ir::new(&program)).unwrap();
+        ssa.verify().unwrap();
+        let expected = "\
+block_0():
+    v0 = const 1
+    jmp block_1(v0)
+block_1(v1):
+    printi v1
+    v2 = const 10
+    printc v2
+    v3 = const 1
+    v4 = add v1, v3
+    v5 = const 11
+    v6 = sub v4, v5
+    jz v6, block_3(v4) else block_2(v4)
+block_2(v7):
+    jmp block_1(v7)
+block_3(v8):
+    end
+block_4():
+    unterminated
+";
+        assert_eq!(expected, ssa.to_string());
+    }
+
+    #[test]
+    fn calls() {
+        // Passes values through a subroutine that swaps its arguments.
+        let insts = vec![
+            Inst::Push(bitvec![0, 1]),
+            Inst::Push(bitvec![0, 1, 0]),
+            Inst::Push(bitvec![0, 1, 1]),
+            Inst::Call(bitvec![1]),
+            Inst::Printi,
+            Inst::Printi,
+            Inst::Printi,
+            Inst::End,
+            Inst::Label(bitvec![1]),
+            Inst::Swap,
+            Inst::Ret,
+        ];
+        let program = Program::new(insts, LabelOrder::Def);
+        let ssa = Ssa::new(&Ir::new(&program)).unwrap();
+        ssa.verify().unwrap();
+        // The return site reads 3 values, so they all pass through the callee.
+        assert_eq!(3, ssa.blocks()[1].params().len());
+        assert_eq!(3, ssa.blocks()[2].params().len());
+        assert!(matches!(ssa.blocks()[2].exit(), Exit::Ret(args) if args.len() == 3));
+    }
+
+    #[test]
+    fn unbounded() {
+        let insts = vec![Inst::Label(bitvec![1]), Inst::Drop, Inst::Jmp(bitvec![1])];
+        let program = Program::new(insts, LabelOrder::Def);
+        assert_eq!(
+            Err(SsaError::StackDepth(BlockId(0))),
+            Ssa::new(&Ir::new(&program))
+        );
+    }
+}
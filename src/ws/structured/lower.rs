@@ -0,0 +1,570 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Lowers the structured dialect to Whitespace.
+//!
+//! Locals live in a frame in the heap, so that functions can recurse. Address
+//! 0 holds the frame pointer, address 1 is scratch space for reading input, and
+//! frames start at address 2. A caller advances the frame pointer past its own
+//! frame around each call, and the callee stores its arguments from the stack
+//! into the first slots of its frame. Statements leave the stack as they found
+//! it and expressions push one value, so only arguments and return values are
+//! passed on the stack.
+//!
+//! Loops and conditionals become labels with `jmp`, `jz`, and `jn`, and each
+//! label is freshly allocated by a [`LabelResolver`].
+
+use std::collections::HashMap;
+
+use bitvec::vec::BitVec;
+use rug::Integer;
+
+use crate::ws::inst::{Inst, RawInst};
+use crate::ws::structured::{BinOp, CmpOp, Cond, Expr, Func, Program as StructuredProgram, Stmt};
+use crate::ws::syntax::{IntLiteral, LabelOrder, LabelResolver, Program};
+
+/// Heap address of the frame pointer.
+const FP: i64 = 0;
+/// Heap address that input is read to.
+const SCRATCH: i64 = 1;
+/// Heap address of the first frame.
+const FRAME_START: i64 = 2;
+
+const BUILTINS: [&str; 5] = ["printc", "printi", "readc", "readi", "exit"];
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LowerError {
+    /// There is no `main` function.
+    NoMain,
+    DuplicateFunc(String),
+    /// The function has the name of a builtin.
+    BuiltinFunc(String),
+    UndefinedVar(String),
+    UndefinedFunc(String),
+    ArgCount {
+        func: String,
+        expected: usize,
+        actual: usize,
+    },
+    /// The builtin is used as a value, but does not produce one.
+    NoValue(String),
+    BreakOutsideLoop,
+    ContinueOutsideLoop,
+}
+
+struct Lowerer<'a> {
+    resolver: LabelResolver,
+    insts: Vec<RawInst>,
+    /// Label and number of parameters of each function.
+    funcs: HashMap<&'a str, (BitVec, usize)>,
+    /// Slots of the locals in scope, innermost last.
+    locals: Vec<(&'a str, usize)>,
+    /// Number of slots allocated in the current frame.
+    slots: usize,
+    /// Number of slots in the current frame.
+    frame_len: usize,
+    /// Head and exit labels of the enclosing loops.
+    loops: Vec<(BitVec, BitVec)>,
+}
+
+impl StructuredProgram {
+    /// Lowers the program to Whitespace instructions.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when `main` is missing, a name is undefined or defined
+    /// twice, a call has the wrong number of arguments, or `break` or `continue`
+    /// is outside of a loop.
+    pub fn lower(&self) -> Result<Program, LowerError> {
+        let mut l = Lowerer {
+            resolver: LabelResolver::new(),
+            insts: Vec::new(),
+            funcs: HashMap::new(),
+            locals: Vec::new(),
+            slots: 0,
+            frame_len: 0,
+            loops: Vec::new(),
+        };
+        for func in &self.funcs {
+            if BUILTINS.contains(&func.name.as_str()) {
+                return Err(LowerError::BuiltinFunc(func.name.clone()));
+            }
+            let label = l.resolver.fresh();
+            if l.funcs
+                .insert(&func.name, (label, func.params.len()))
+                .is_some()
+            {
+                return Err(LowerError::DuplicateFunc(func.name.clone()));
+            }
+        }
+        if !l.funcs.contains_key("main") {
+            return Err(LowerError::NoMain);
+        }
+
+        l.store_const(FP, FRAME_START);
+        l.call("main", &[])?;
+        l.insts.push(Inst::End);
+        for func in &self.funcs {
+            l.func(func)?;
+        }
+        Ok(Program::with_resolver(l.insts, LabelOrder::Def, l.resolver))
+    }
+}
+
+impl<'a> Lowerer<'a> {
+    fn func(&mut self, func: &'a Func) -> Result<(), LowerError> {
+        let label = self.funcs[func.name.as_str()].0.clone();
+        self.insts.push(Inst::Label(label));
+        self.locals.clear();
+        self.slots = 0;
+        self.frame_len = func.params.len() + count_lets(&func.body);
+        // The last argument is on the top of the stack.
+        for param in &func.params {
+            self.locals.push((param, self.slots));
+            self.slots += 1;
+        }
+        for slot in (0..func.params.len()).rev() {
+            self.slot_addr(slot);
+            self.insts.push(Inst::Swap);
+            self.insts.push(Inst::Store);
+        }
+        self.block(&func.body)?;
+        if !matches!(func.body.last(), Some(Stmt::Return(_))) {
+            self.push(0);
+            self.insts.push(Inst::Ret);
+        }
+        Ok(())
+    }
+
+    fn block(&mut self, stmts: &'a [Stmt]) -> Result<(), LowerError> {
+        let scope = self.locals.len();
+        for stmt in stmts {
+            self.stmt(stmt)?;
+        }
+        self.locals.truncate(scope);
+        Ok(())
+    }
+
+    fn stmt(&mut self, stmt: &'a Stmt) -> Result<(), LowerError> {
+        match stmt {
+            Stmt::Let(name, expr) => {
+                self.expr(expr)?;
+                self.locals.push((name, self.slots));
+                self.slots += 1;
+                self.store_var(name)?;
+            }
+            Stmt::Assign(name, expr) => {
+                self.expr(expr)?;
+                self.store_var(name)?;
+            }
+            Stmt::If { cond, then, else_ } => {
+                let else_label = self.resolver.fresh();
+                self.branch(cond, &else_label)?;
+                self.block(then)?;
+                if else_.is_empty() {
+                    self.insts.push(Inst::Label(else_label));
+                } else {
+                    let end = self.resolver.fresh();
+                    self.insts.push(Inst::Jmp(end.clone()));
+                    self.insts.push(Inst::Label(else_label));
+                    self.block(else_)?;
+                    self.insts.push(Inst::Label(end));
+                }
+            }
+            Stmt::While { cond, body } => {
+                let (head, exit) = (self.resolver.fresh(), self.resolver.fresh());
+                self.insts.push(Inst::Label(head.clone()));
+                self.branch(cond, &exit)?;
+                self.loops.push((head.clone(), exit.clone()));
+                self.block(body)?;
+                self.loops.pop();
+                self.insts.push(Inst::Jmp(head));
+                self.insts.push(Inst::Label(exit));
+            }
+            Stmt::Break => {
+                let (_, exit) = self.loops.last().ok_or(LowerError::BreakOutsideLoop)?;
+                self.insts.push(Inst::Jmp(exit.clone()));
+            }
+            Stmt::Continue => {
+                let (head, _) = self.loops.last().ok_or(LowerError::ContinueOutsideLoop)?;
+                self.insts.push(Inst::Jmp(head.clone()));
+            }
+            Stmt::Return(expr) => {
+                match expr {
+                    Some(expr) => self.expr(expr)?,
+                    None => self.push(0),
+                }
+                self.insts.push(Inst::Ret);
+            }
+            Stmt::Expr(expr) => {
+                if let Expr::Call(name, args) = expr {
+                    if self.builtin_stmt(name, args)? {
+                        return Ok(());
+                    }
+                }
+                self.expr(expr)?;
+                self.insts.push(Inst::Drop);
+            }
+        }
+        Ok(())
+    }
+
+    /// Jumps to the label when the condition is false and continues when it
+    /// is true.
+    fn branch(&mut self, cond: &'a Cond, if_false: &BitVec) -> Result<(), LowerError> {
+        let Some((op, rhs)) = &cond.cmp else {
+            self.expr(&cond.lhs)?;
+            self.insts.push(Inst::Jz(if_false.clone()));
+            return Ok(());
+        };
+        // `a > b` and `a <= b` test the sign of `b - a`.
+        match op {
+            CmpOp::Eq | CmpOp::Ne | CmpOp::Lt | CmpOp::Ge => self.diff(&cond.lhs, rhs)?,
+            CmpOp::Gt | CmpOp::Le => self.diff(rhs, &cond.lhs)?,
+        }
+        match op {
+            CmpOp::Ne => self.insts.push(Inst::Jz(if_false.clone())),
+            CmpOp::Ge | CmpOp::Le => self.insts.push(Inst::Jn(if_false.clone())),
+            CmpOp::Eq | CmpOp::Lt | CmpOp::Gt => {
+                let if_true = self.resolver.fresh();
+                self.insts.push(if *op == CmpOp::Eq {
+                    Inst::Jz(if_true.clone())
+                } else {
+                    Inst::Jn(if_true.clone())
+                });
+                self.insts.push(Inst::Jmp(if_false.clone()));
+                self.insts.push(Inst::Label(if_true));
+            }
+        }
+        Ok(())
+    }
+
+    /// Pushes `a - b`.
+    fn diff(&mut self, a: &'a Expr, b: &'a Expr) -> Result<(), LowerError> {
+        self.expr(a)?;
+        if !matches!(b, Expr::Int(n) if *n == 0) {
+            self.expr(b)?;
+            self.insts.push(Inst::Sub);
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self, expr: &'a Expr) -> Result<(), LowerError> {
+        match expr {
+            Expr::Int(n) => self.push_int(n.clone()),
+            Expr::Var(name) => {
+                let slot = self.lookup(name)?;
+                self.slot_addr(slot);
+                self.insts.push(Inst::Retrieve);
+            }
+            Expr::Neg(expr) => {
+                if let Expr::Int(n) = &**expr {
+                    self.push_int(-n.clone());
+                } else {
+                    self.push(0);
+                    self.expr(expr)?;
+                    self.insts.push(Inst::Sub);
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                self.expr(lhs)?;
+                self.expr(rhs)?;
+                self.insts.push(match op {
+                    BinOp::Add => Inst::Add,
+                    BinOp::Sub => Inst::Sub,
+                    BinOp::Mul => Inst::Mul,
+                    BinOp::Div => Inst::Div,
+                    BinOp::Mod => Inst::Mod,
+                });
+            }
+            Expr::Call(name, args) if self.funcs.contains_key(name.as_str()) => {
+                self.call(name, args)?;
+            }
+            Expr::Call(name, args) => {
+                let read = match name.as_str() {
+                    "readc" => Inst::Readc,
+                    "readi" => Inst::Readi,
+                    "printc" | "printi" | "exit" => return Err(LowerError::NoValue(name.clone())),
+                    _ => return Err(LowerError::UndefinedFunc(name.clone())),
+                };
+                check_args(name, args, 0)?;
+                self.push(SCRATCH);
+                self.insts.push(read);
+                self.push(SCRATCH);
+                self.insts.push(Inst::Retrieve);
+            }
+        }
+        Ok(())
+    }
+
+    /// Lowers a call to a builtin that does not produce a value and returns
+    /// whether it is one.
+    fn builtin_stmt(&mut self, name: &str, args: &'a [Expr]) -> Result<bool, LowerError> {
+        let (inst, arity) = match name {
+            "printc" => (Inst::Printc, 1),
+            "printi" => (Inst::Printi, 1),
+            "exit" => (Inst::End, 0),
+            _ => return Ok(false),
+        };
+        check_args(name, args, arity)?;
+        for arg in args {
+            self.expr(arg)?;
+        }
+        self.insts.push(inst);
+        Ok(true)
+    }
+
+    /// Calls a function, advancing the frame pointer past the frame of the
+    /// caller for the duration of the call.
+    fn call(&mut self, name: &str, args: &'a [Expr]) -> Result<(), LowerError> {
+        let (label, arity) = &self.funcs[name];
+        let label = label.clone();
+        check_args(name, args, *arity)?;
+        for arg in args {
+            self.expr(arg)?;
+        }
+        if self.frame_len != 0 {
+            self.adjust_fp(Inst::Add);
+        }
+        self.insts.push(Inst::Call(label));
+        if self.frame_len != 0 {
+            self.adjust_fp(Inst::Sub);
+        }
+        Ok(())
+    }
+
+    /// Adds or subtracts the length of the current frame to the frame
+    /// pointer.
+    fn adjust_fp(&mut self, op: RawInst) {
+        self.push(FP);
+        self.push(FP);
+        self.insts.push(Inst::Retrieve);
+        self.push_int(Integer::from(self.frame_len));
+        self.insts.push(op);
+        self.insts.push(Inst::Store);
+    }
+
+    fn lookup(&self, name: &str) -> Result<usize, LowerError> {
+        match self.locals.iter().rev().find(|(local, _)| *local == name) {
+            Some(&(_, slot)) => Ok(slot),
+            None => Err(LowerError::UndefinedVar(name.to_owned())),
+        }
+    }
+
+    /// Stores the value on the top of the stack to a local.
+    fn store_var(&mut self, name: &str) -> Result<(), LowerError> {
+        let slot = self.lookup(name)?;
+        self.slot_addr(slot);
+        self.insts.push(Inst::Swap);
+        self.insts.push(Inst::Store);
+        Ok(())
+    }
+
+    /// Pushes the heap address of a slot in the current frame.
+    fn slot_addr(&mut self, slot: usize) {
+        self.push(FP);
+        self.insts.push(Inst::Retrieve);
+        if slot != 0 {
+            self.push_int(Integer::from(slot));
+            self.insts.push(Inst::Add);
+        }
+    }
+
+    fn store_const(&mut self, addr: i64, value: i64) {
+        self.push(addr);
+        self.push(value);
+        self.insts.push(Inst::Store);
+    }
+
+    fn push(&mut self, n: i64) {
+        self.push_int(Integer::from(n));
+    }
+
+    fn push_int(&mut self, n: Integer) {
+        self.insts
+            .push(Inst::Push(IntLiteral::from(n).bits().clone()));
+    }
+}
+
+fn check_args(func: &str, args: &[Expr], arity: usize) -> Result<(), LowerError> {
+    if args.len() == arity {
+        Ok(())
+    } else {
+        Err(LowerError::ArgCount {
+            func: func.to_owned(),
+            expected: arity,
+            actual: args.len(),
+        })
+    }
+}
+
+/// Counts the locals declared in a function body, each of which has its own
+/// slot.
+fn count_lets(stmts: &[Stmt]) -> usize {
+    stmts
+        .iter()
+        .map(|stmt| match stmt {
+            Stmt::Let(..) => 1,
+            Stmt::If { then, else_, .. } => count_lets(then) + count_lets(else_),
+            Stmt::While { body, .. } => count_lets(body),
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use bitvec::prelude::*;
+
+    use super::*;
+    use crate::ws::interp::Interpreter;
+
+    fn lower(src: &str) -> Result<Program, LowerError> {
+        StructuredProgram::parse(src).unwrap().lower()
+    }
+
+    fn run(src: &str, mut stdin: &[u8]) -> String {
+        let program = lower(src).unwrap();
+        let mut stdout = Vec::new();
+        Interpreter::new(&program)
+            .run(&mut stdin, &mut stdout, &mut io::sink())
+            .unwrap();
+        String::from_utf8(stdout).unwrap()
+    }
+
+    #[test]
+    fn countdown() {
+        let src = "fn main() { let i = 3; while (i) { printi(i); i = i - 1; } }";
+        let program = StructuredProgram::parse(src).unwrap().lower().unwrap();
+        let insts = program
+            .insts()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let expected = "push 0
+push 2
+store
+call label_0
+end
+label label_0
+push 3
+push 0
+retrieve
+swap
+store
+label label_1
+push 0
+retrieve
+retrieve
+jz label_2
+push 0
+retrieve
+retrieve
+printi
+push 0
+retrieve
+retrieve
+push 1
+sub
+push 0
+retrieve
+swap
+store
+jmp label_1
+label label_2
+push 0
+ret";
+        assert_eq!(expected, insts);
+        // Fresh labels count up from 1.
+        assert_eq!(&bitvec![1, 1], program.labels()[2].bits());
+    }
+
+    #[test]
+    fn fact() {
+        // The example from the module documentation.
+        let src = "
+fn main() {
+    let n = readi();
+    printi(fact(n));
+    printc('\\n');
+}
+
+fn fact(n) {
+    if (n <= 1) {
+        return 1;
+    }
+    return n * fact(n - 1);
+}";
+        assert_eq!("3628800\n", run(src, b"10\n"));
+        assert_eq!("1\n", run(src, b"0\n"));
+    }
+
+    #[test]
+    fn comparisons() {
+        // Prints whether a < b, a <= b, a == b, a != b, a >= b, and a > b for
+        // each pair.
+        let src = "
+fn main() {
+    let a = -1;
+    while (a <= 1) {
+        let b = -1;
+        while (b <= 1) {
+            row(a, b);
+            b = b + 1;
+        }
+        a = a + 1;
+    }
+}
+
+fn row(a, b) {
+    if (a < b) { printi(1); } else { printi(0); }
+    if (a <= b) { printi(1); } else { printi(0); }
+    if (a == b) { printi(1); } else { printi(0); }
+    if (a != b) { printi(1); } else { printi(0); }
+    if (a >= b) { printi(1); } else { printi(0); }
+    if (a > b) { printi(1); } else { printi(0); }
+    printc('\\n');
+}";
+        let expected = "\
+011010
+110100
+110100
+000111
+011010
+110100
+000111
+000111
+011010
+";
+        assert_eq!(expected, run(src, b""));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Err(LowerError::NoMain), lower("fn f() { }"));
+        assert_eq!(
+            Err(LowerError::UndefinedVar("x".to_owned())),
+            lower("fn main() { printi(x); }"),
+        );
+        assert_eq!(
+            Err(LowerError::ArgCount {
+                func: "f".to_owned(),
+                expected: 2,
+                actual: 1,
+            }),
+            lower("fn main() { f(1); } fn f(a, b) { }"),
+        );
+        assert_eq!(
+            Err(LowerError::BreakOutsideLoop),
+            lower("fn main() { if (1) { break; } }"),
+        );
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Structured dialect of Whitespace, with functions, loops, `if`/`else`, and
+//! locals, which lowers to labels and jumps.
+//!
+//! ```text
+//! fn main() {
+//!     let n = readi();
+//!     printi(fact(n));
+//!     printc('\n');
+//! }
+//!
+//! fn fact(n) {
+//!     if (n <= 1) {
+//!         return 1;
+//!     }
+//!     return n * fact(n - 1);
+//! }
+//! ```
+//!
+//! Values are arbitrary-precision integers and conditions compare two values
+//! or test one for being non-zero. Every function returns a value, which is 0
+//! when it returns without one. The builtins `printc`, `printi`, `readc`,
+//! `readi`, and `exit` perform the instructions of the same names.
+
+use rug::Integer;
+
+pub mod lower;
+pub mod parse;
+
+/// Structured program, which starts at `main`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Program {
+    pub funcs: Vec<Func>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Func {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Stmt {
+    /// `let x = e;`: declares a local in the enclosing block.
+    Let(String, Expr),
+    /// `x = e;`
+    Assign(String, Expr),
+    If {
+        cond: Cond,
+        then: Vec<Stmt>,
+        else_: Vec<Stmt>,
+    },
+    While {
+        cond: Cond,
+        body: Vec<Stmt>,
+    },
+    Break,
+    Continue,
+    Return(Option<Expr>),
+    /// Expression evaluated for its effects.
+    Expr(Expr),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Expr {
+    Int(Integer),
+    Var(String),
+    Neg(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    /// Call to a function or builtin.
+    Call(String, Vec<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    /// Division rounding toward negative infinity.
+    Div,
+    /// Modulo with the sign of the divisor.
+    Mod,
+}
+
+/// Condition of `if` or `while`. A condition without a comparison tests
+/// whether the value is non-zero.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Cond {
+    pub lhs: Expr,
+    pub cmp: Option<(CmpOp, Expr)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
@@ -0,0 +1,405 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Parser for the structured dialect.
+
+use rug::Integer;
+
+use crate::ws::structured::{BinOp, CmpOp, Cond, Expr, Func, Program, Stmt};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ParseError {
+    /// The character at the line can not start a token.
+    InvalidChar(usize, char),
+    /// The character literal at the line is malformed.
+    InvalidCharLiteral(usize),
+    /// The token at the line is not what was expected.
+    Unexpected { line: usize, expected: &'static str },
+    /// The source ends where more was expected.
+    UnexpectedEof { expected: &'static str },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Tok {
+    Ident(String),
+    Int(Integer),
+    Punct(&'static str),
+}
+
+const PUNCTS: [&str; 18] = [
+    "==", "!=", "<=", ">=", "<", ">", "=", "+", "-", "*", "/", "%", "(", ")", "{", "}", ",", ";",
+];
+
+struct Parser {
+    toks: Vec<(Tok, usize)>,
+    pos: usize,
+}
+
+impl Program {
+    /// Parses a program in the structured dialect. Comments start with `//`
+    /// and continue to the end of the line.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error at the first invalid token or unexpected token.
+    pub fn parse(src: &str) -> Result<Self, ParseError> {
+        let mut p = Parser { toks: lex(src)?, pos: 0 };
+        let mut funcs = Vec::new();
+        while p.pos < p.toks.len() {
+            funcs.push(p.func()?);
+        }
+        Ok(Program { funcs })
+    }
+}
+
+fn lex(src: &str) -> Result<Vec<(Tok, usize)>, ParseError> {
+    let mut toks = Vec::new();
+    let mut line = 1;
+    let mut rest = src;
+    while let Some(c) = rest.chars().next() {
+        if c == '\n' {
+            line += 1;
+        }
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+        } else if rest.starts_with("//") {
+            rest = &rest[rest.find('\n').unwrap_or(rest.len())..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            toks.push((Tok::Ident(rest[..len].to_owned()), line));
+            rest = &rest[len..];
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let n = Integer::from_str_radix(&rest[..len], 10)
+                .map_err(|_| ParseError::InvalidChar(line, c))?;
+            toks.push((Tok::Int(n), line));
+            rest = &rest[len..];
+        } else if c == '\'' {
+            let (ch, len) = char_literal(&rest[1..]).ok_or(ParseError::InvalidCharLiteral(line))?;
+            toks.push((Tok::Int(Integer::from(u32::from(ch))), line));
+            rest = &rest[1 + len..];
+        } else if let Some(&punct) = PUNCTS.iter().find(|&&p| rest.starts_with(p)) {
+            toks.push((Tok::Punct(punct), line));
+            rest = &rest[punct.len()..];
+        } else {
+            return Err(ParseError::InvalidChar(line, c));
+        }
+    }
+    Ok(toks)
+}
+
+/// Parses the rest of a character literal after the opening quote and
+/// returns the character and the length including the closing quote.
+fn char_literal(s: &str) -> Option<(char, usize)> {
+    let mut chars = s.chars();
+    let (ch, len) = match chars.next()? {
+        '\\' => {
+            let ch = match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                c @ ('\\' | '\'') => c,
+                _ => return None,
+            };
+            (ch, 2)
+        }
+        '\'' | '\n' => return None,
+        ch => (ch, ch.len_utf8()),
+    };
+    (chars.next()? == '\'').then_some((ch, len + 1))
+}
+
+impl Parser {
+    fn func(&mut self) -> Result<Func, ParseError> {
+        self.expect_keyword("fn")?;
+        let name = self.ident()?;
+        self.expect("(")?;
+        let mut params = Vec::new();
+        if !self.eat(")") {
+            loop {
+                params.push(self.ident()?);
+                if self.eat(")") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        let body = self.block()?;
+        Ok(Func { name, params, body })
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, ParseError> {
+        self.expect("{")?;
+        let mut stmts = Vec::new();
+        while !self.eat("}") {
+            stmts.push(self.stmt()?);
+        }
+        Ok(stmts)
+    }
+
+    fn stmt(&mut self) -> Result<Stmt, ParseError> {
+        let stmt = match self.peek() {
+            Some(Tok::Ident(kw)) if kw == "let" => {
+                self.pos += 1;
+                let name = self.ident()?;
+                self.expect("=")?;
+                Stmt::Let(name, self.expr()?)
+            }
+            Some(Tok::Ident(kw)) if kw == "if" => return self.if_stmt(),
+            Some(Tok::Ident(kw)) if kw == "while" => {
+                self.pos += 1;
+                let cond = self.cond()?;
+                let body = self.block()?;
+                return Ok(Stmt::While { cond, body });
+            }
+            Some(Tok::Ident(kw)) if kw == "break" => {
+                self.pos += 1;
+                Stmt::Break
+            }
+            Some(Tok::Ident(kw)) if kw == "continue" => {
+                self.pos += 1;
+                Stmt::Continue
+            }
+            Some(Tok::Ident(kw)) if kw == "return" => {
+                self.pos += 1;
+                if self.peek() == Some(&Tok::Punct(";")) {
+                    Stmt::Return(None)
+                } else {
+                    Stmt::Return(Some(self.expr()?))
+                }
+            }
+            Some(Tok::Ident(name))
+                if matches!(self.toks.get(self.pos + 1), Some((Tok::Punct("="), _))) =>
+            {
+                let name = name.clone();
+                self.pos += 2;
+                Stmt::Assign(name, self.expr()?)
+            }
+            _ => Stmt::Expr(self.expr()?),
+        };
+        self.expect(";")?;
+        Ok(stmt)
+    }
+
+    fn if_stmt(&mut self) -> Result<Stmt, ParseError> {
+        self.expect_keyword("if")?;
+        let cond = self.cond()?;
+        let then = self.block()?;
+        let else_ = if self.eat_keyword("else") {
+            if matches!(self.peek(), Some(Tok::Ident(kw)) if kw == "if") {
+                vec![self.if_stmt()?]
+            } else {
+                self.block()?
+            }
+        } else {
+            Vec::new()
+        };
+        Ok(Stmt::If { cond, then, else_ })
+    }
+
+    fn cond(&mut self) -> Result<Cond, ParseError> {
+        self.expect("(")?;
+        let lhs = self.expr()?;
+        let cmp = match self.peek() {
+            Some(&Tok::Punct(p)) => match p {
+                "==" => Some(CmpOp::Eq),
+                "!=" => Some(CmpOp::Ne),
+                "<" => Some(CmpOp::Lt),
+                "<=" => Some(CmpOp::Le),
+                ">" => Some(CmpOp::Gt),
+                ">=" => Some(CmpOp::Ge),
+                _ => None,
+            },
+            _ => None,
+        };
+        let cmp = match cmp {
+            Some(op) => {
+                self.pos += 1;
+                Some((op, self.expr()?))
+            }
+            None => None,
+        };
+        self.expect(")")?;
+        Ok(Cond { lhs, cmp })
+    }
+
+    fn expr(&mut self) -> Result<Expr, ParseError> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = if self.eat("+") {
+                BinOp::Add
+            } else if self.eat("-") {
+                BinOp::Sub
+            } else {
+                return Ok(lhs);
+            };
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, ParseError> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = if self.eat("*") {
+                BinOp::Mul
+            } else if self.eat("/") {
+                BinOp::Div
+            } else if self.eat("%") {
+                BinOp::Mod
+            } else {
+                return Ok(lhs);
+            };
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        if self.eat("-") {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        match self.next("expression")? {
+            Tok::Int(n) => Ok(Expr::Int(n)),
+            Tok::Ident(name) => {
+                if !self.eat("(") {
+                    return Ok(Expr::Var(name));
+                }
+                let mut args = Vec::new();
+                if !self.eat(")") {
+                    loop {
+                        args.push(self.expr()?);
+                        if self.eat(")") {
+                            break;
+                        }
+                        self.expect(",")?;
+                    }
+                }
+                Ok(Expr::Call(name, args))
+            }
+            Tok::Punct("(") => {
+                let expr = self.expr()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Tok::Punct(_) => Err(self.unexpected(self.pos - 1, "expression")),
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, ParseError> {
+        match self.next("identifier")? {
+            Tok::Ident(name) => Ok(name),
+            _ => Err(self.unexpected(self.pos - 1, "identifier")),
+        }
+    }
+
+    fn peek(&self) -> Option<&Tok> {
+        self.toks.get(self.pos).map(|(tok, _)| tok)
+    }
+
+    fn next(&mut self, expected: &'static str) -> Result<Tok, ParseError> {
+        let tok = self.peek().cloned();
+        self.pos += 1;
+        tok.ok_or(ParseError::UnexpectedEof { expected })
+    }
+
+    fn eat(&mut self, punct: &'static str) -> bool {
+        let ok = self.peek() == Some(&Tok::Punct(punct));
+        self.pos += usize::from(ok);
+        ok
+    }
+
+    fn eat_keyword(&mut self, kw: &str) -> bool {
+        let ok = matches!(self.peek(), Some(Tok::Ident(name)) if name == kw);
+        self.pos += usize::from(ok);
+        ok
+    }
+
+    fn expect(&mut self, punct: &'static str) -> Result<(), ParseError> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            Err(self.unexpected(self.pos, punct))
+        }
+    }
+
+    fn expect_keyword(&mut self, kw: &'static str) -> Result<(), ParseError> {
+        if self.eat_keyword(kw) {
+            Ok(())
+        } else {
+            Err(self.unexpected(self.pos, kw))
+        }
+    }
+
+    fn unexpected(&self, pos: usize, expected: &'static str) -> ParseError {
+        match self.toks.get(pos) {
+            Some(&(_, line)) => ParseError::Unexpected { line, expected },
+            None => ParseError::UnexpectedEof { expected },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let src = "// Count down
+fn main() {
+    let i = '\\n';
+    while (i) {
+        if (i % 2 == 0) { printi(-i); } else if (i > 3) { continue; }
+        i = i - 1;
+    }
+}
+";
+        let var = |name: &str| Expr::Var(name.to_owned());
+        let int = |n: u32| Expr::Int(Integer::from(n));
+        let expected = Program {
+            funcs: vec![Func {
+                name: "main".to_owned(),
+                params: Vec::new(),
+                body: vec![Stmt::Let("i".to_owned(), int(10)), Stmt::While {
+                    cond: Cond { lhs: var("i"), cmp: None },
+                    body: vec![
+                        Stmt::If {
+                            cond: Cond {
+                                lhs: Expr::Binary(BinOp::Mod, Box::new(var("i")), Box::new(int(2))),
+                                cmp: Some((CmpOp::Eq, int(0))),
+                            },
+                            then: vec![Stmt::Expr(Expr::Call("printi".to_owned(), vec![
+                                Expr::Neg(Box::new(var("i"))),
+                            ]))],
+                            else_: vec![Stmt::If {
+                                cond: Cond {
+                                    lhs: var("i"),
+                                    cmp: Some((CmpOp::Gt, int(3))),
+                                },
+                                then: vec![Stmt::Continue],
+                                else_: Vec::new(),
+                            }],
+                        },
+                        Stmt::Assign(
+                            "i".to_owned(),
+                            Expr::Binary(BinOp::Sub, Box::new(var("i")), Box::new(int(1))),
+                        ),
+                    ],
+                }],
+            }],
+        };
+        assert_eq!(Ok(expected), Program::parse(src));
+        assert_eq!(
+            Err(ParseError::Unexpected { line: 1, expected: ";" }),
+            Program::parse("fn main() { x = 1 }"),
+        );
+    }
+}
@@ -0,0 +1,432 @@
+// Copyright (C) 2022 Thalia Archibald
+//
+// Nebula 2 is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option) any
+// later version. You should have received a copy of the GNU Lesser General
+// Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
+
+//! Subroutine discovery and call-graph analysis.
+//!
+//! Whitespace has no explicit functions, so subroutines are inferred: the
+//! entry of the program is the main subroutine, and the target of every
+//! reachable `call` is the entry of another. The body of a subroutine is the
+//! set of blocks reachable from its entry without following calls into
+//! callees. A call continues at its return site only when the callee can
+//! reach a `ret`. Since jumps are unrestricted, a block may belong to several
+//! subroutines.
+
+use std::fmt::{self, Display, Formatter};
+use std::ops::{Index, IndexMut};
+
+use smallvec::SmallVec;
+
+use crate::ws::ir::{BlockId, Exit, Ir};
+use crate::ws::syntax::{id_index, Program};
+
+/// Subroutines of a program and the calls between them.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CallGraph {
+    ir: Ir,
+    subs: Vec<Subroutine>,
+    /// Subroutines that contain each block.
+    owners: Vec<SmallVec<[SubId; 1]>>,
+}
+
+id_index!(SubId(u32) indexes Subroutine in Vec<Subroutine>, [Subroutine]);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Subroutine {
+    id: SubId,
+    entry: BlockId,
+    /// Blocks in the body, in ascending order.
+    blocks: Vec<BlockId>,
+    /// Subroutines called from the body, in ascending order.
+    calls: Vec<SubId>,
+    /// Subroutines that call this one, in ascending order.
+    callers: Vec<SubId>,
+    returns: bool,
+    recursive: bool,
+}
+
+impl CallGraph {
+    /// Infers the subroutines of a program and the calls between them.
+    #[inline]
+    #[must_use]
+    pub fn from_program(program: &Program) -> Self {
+        CallGraph::new(Ir::new(program))
+    }
+
+    /// Discovers the subroutines in the blocks of a program.
+    #[must_use]
+    pub fn new(ir: Ir) -> Self {
+        let len = ir.blocks().len();
+        let mut entry_subs: Vec<Option<SubId>> = vec![None; len];
+        let mut subs = vec![Subroutine::new(SubId(0), BlockId(0))];
+        entry_subs[0] = Some(SubId(0));
+
+        // Whether a callee returns can depend on its own callees, so iterate
+        // until the bodies stop growing.
+        let mut changed = true;
+        while changed {
+            changed = false;
+            let mut i = 0;
+            while i < subs.len() {
+                let (blocks, callees, returns) = walk(&ir, subs[i].entry, |callee| {
+                    entry_subs[usize::from(callee)].map_or(false, |s| subs[s].returns)
+                });
+                let mut calls = Vec::with_capacity(callees.len());
+                for callee in callees {
+                    let id = *entry_subs[usize::from(callee)].get_or_insert_with(|| {
+                        let id = SubId::from(subs.len());
+                        subs.push(Subroutine::new(id, callee));
+                        id
+                    });
+                    calls.push(id);
+                }
+                calls.sort_unstable();
+                calls.dedup();
+                let sub = &mut subs[i];
+                if sub.blocks != blocks || sub.calls != calls || sub.returns != returns {
+                    sub.blocks = blocks;
+                    sub.calls = calls;
+                    sub.returns = returns;
+                    changed = true;
+                }
+                i += 1;
+            }
+        }
+
+        let mut owners = vec![SmallVec::new(); len];
+        for i in 0..subs.len() {
+            let id = SubId::from(i);
+            for &b in &subs[i].blocks {
+                owners[usize::from(b)].push(id);
+            }
+            for j in 0..subs[i].calls.len() {
+                let callee = subs[i].calls[j];
+                subs[callee].callers.push(id);
+            }
+        }
+        for i in 0..subs.len() {
+            subs[i].recursive = reaches(&subs, SubId::from(i));
+        }
+        CallGraph { ir, subs, owners }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn ir(&self) -> &Ir {
+        &self.ir
+    }
+
+    /// Subroutines in order of discovery. The first is the main subroutine,
+    /// which starts at the entry of the program.
+    #[inline]
+    #[must_use]
+    pub fn subroutines(&self) -> &[Subroutine] {
+        &self.subs
+    }
+
+    /// Subroutines that contain a block. It is empty when the block is
+    /// unreachable.
+    #[inline]
+    #[must_use]
+    pub fn owners(&self, b: BlockId) -> &[SubId] {
+        &self.owners[usize::from(b)]
+    }
+
+    /// Blocks that are contained in more than one subroutine.
+    #[must_use]
+    pub fn shared(&self) -> Vec<BlockId> {
+        (0..self.owners.len())
+            .filter(|&b| self.owners[b].len() > 1)
+            .map(BlockId::from)
+            .collect()
+    }
+
+    /// Blocks that are not contained in any subroutine.
+    #[must_use]
+    pub fn unreachable(&self) -> Vec<BlockId> {
+        (0..self.owners.len())
+            .filter(|&b| self.owners[b].is_empty())
+            .map(BlockId::from)
+            .collect()
+    }
+}
+
+impl Index<SubId> for CallGraph {
+    type Output = Subroutine;
+
+    #[inline]
+    fn index(&self, id: SubId) -> &Subroutine {
+        &self.subs[id]
+    }
+}
+
+impl Subroutine {
+    fn new(id: SubId, entry: BlockId) -> Self {
+        Subroutine {
+            id,
+            entry,
+            blocks: Vec::new(),
+            calls: Vec::new(),
+            callers: Vec::new(),
+            returns: false,
+            recursive: false,
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn id(&self) -> SubId {
+        self.id
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn entry(&self) -> BlockId {
+        self.entry
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn blocks(&self) -> &[BlockId] {
+        &self.blocks
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn calls(&self) -> &[SubId] {
+        &self.calls
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn callers(&self) -> &[SubId] {
+        &self.callers
+    }
+
+    /// Returns whether a `ret` is reachable in the body.
+    #[inline]
+    #[must_use]
+    pub fn returns(&self) -> bool {
+        self.returns
+    }
+
+    /// Returns whether the subroutine can call itself, directly or through
+    /// other subroutines.
+    #[inline]
+    #[must_use]
+    pub fn is_recursive(&self) -> bool {
+        self.recursive
+    }
+}
+
+/// Collects the blocks reachable from an entry without entering callees, the
+/// callees, and whether it reaches a `ret`.
+fn walk<F: Fn(BlockId) -> bool>(
+    ir: &Ir,
+    entry: BlockId,
+    callee_returns: F,
+) -> (Vec<BlockId>, Vec<BlockId>, bool) {
+    let mut seen = vec![false; ir.blocks().len()];
+    let mut callees = Vec::new();
+    let mut returns = false;
+    let mut stack = vec![entry];
+    while let Some(b) = stack.pop() {
+        if seen[usize::from(b)] {
+            continue;
+        }
+        seen[usize::from(b)] = true;
+        match *ir[b].exit() {
+            Exit::Fallthrough(to) | Exit::Jmp(to) => stack.push(to),
+            Exit::Jz { zero: a, other: b } | Exit::Jn { neg: a, other: b } => {
+                stack.push(b);
+                stack.push(a);
+            }
+            Exit::Call { callee, ret } => {
+                callees.push(callee);
+                if callee_returns(callee) {
+                    stack.push(ret);
+                }
+            }
+            Exit::Ret => returns = true,
+            Exit::End | Exit::Unterminated | Exit::UndefinedLabel(_) | Exit::Error(_) => {}
+        }
+    }
+    let blocks = (0..seen.len())
+        .filter(|&b| seen[b])
+        .map(BlockId::from)
+        .collect();
+    (blocks, callees, returns)
+}
+
+/// Returns whether a subroutine is reachable from itself in the call graph.
+fn reaches(subs: &[Subroutine], id: SubId) -> bool {
+    let mut seen = vec![false; subs.len()];
+    let mut stack = subs[id].calls.clone();
+    while let Some(s) = stack.pop() {
+        if s == id {
+            return true;
+        }
+        if !seen[usize::from(s)] {
+            seen[usize::from(s)] = true;
+            stack.extend_from_slice(&subs[s].calls);
+        }
+    }
+    false
+}
+
+/// Structured disassembly, with the blocks grouped by subroutine.
+impl Display for CallGraph {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, sub) in self.subs.iter().enumerate() {
+            if i != 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", sub.id)?;
+            for l in self.ir[sub.entry].labels() {
+                write!(f, " {l}")?;
+            }
+            writeln!(f, ":")?;
+            let list = |ids: &[SubId]| {
+                ids.iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            if !sub.callers.is_empty() {
+                writeln!(f, "    # called by {}", list(&sub.callers))?;
+            }
+            if !sub.calls.is_empty() {
+                writeln!(f, "    # calls {}", list(&sub.calls))?;
+            }
+            if sub.recursive {
+                writeln!(f, "    # recursive")?;
+            }
+            if !sub.returns && i != 0 {
+                writeln!(f, "    # does not return")?;
+            }
+            // List the entry first, since it is not always the lowest block.
+            let rest = sub.blocks.iter().filter(|&&b| b != sub.entry);
+            for &b in [sub.entry].iter().chain(rest) {
+                self.fmt_block(f, b, sub.id)?;
+            }
+        }
+        let unreachable = self.unreachable();
+        if !unreachable.is_empty() {
+            writeln!(f, "\nunreachable:")?;
+            for b in unreachable {
+                self.fmt_block(f, b, SubId(u32::MAX))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CallGraph {
+    fn fmt_block(&self, f: &mut Formatter<'_>, b: BlockId, sub: SubId) -> fmt::Result {
+        let block = &self.ir[b];
+        write!(f, "  {b}:")?;
+        for l in block.labels() {
+            write!(f, " {l}")?;
+        }
+        let others = self.owners(b).iter().filter(|&&s| s != sub);
+        let others = others.map(ToString::to_string).collect::<Vec<_>>();
+        if !others.is_empty() {
+            write!(f, " # shared with {}", others.join(", "))?;
+        }
+        writeln!(f)?;
+        for inst in block.body() {
+            writeln!(f, "    {inst}")?;
+        }
+        writeln!(f, "    {}", block.exit())
+    }
+}
+
+impl Display for SubId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "sub_{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitvec::prelude::*;
+
+    use super::*;
+    use crate::ws::inst::Inst;
+    use crate::ws::syntax::LabelOrder;
+    use crate::ws::tests::get_tutorial_insts;
+
+    fn ids(ids: &[u32]) -> Vec<BlockId> {
+        ids.iter().map(|&b| BlockId(b)).collect()
+    }
+
+    #[test]
+    fn tutorial() {
+        let program = Program::new(get_tutorial_insts(), LabelOrder::Def);
+        let graph = CallGraph::from_program(&program);
+        assert_eq!(1, graph.subroutines().len());
+        let main = &graph[SubId(0)];
+        assert_eq!(ids(&[0, 1, 2, 3]), main.blocks());
+        assert!(main.calls().is_empty() && !main.returns() && !main.is_recursive());
+        assert_eq!(ids(&[4]), graph.unreachable());
+    }
+
+    #[test]
+    fn shared() {
+        let insts = vec![
+            Inst::Call(bitvec![1]),
+            Inst::Call(bitvec![1, 0]),
+            Inst::End,
+            Inst::Label(bitvec![1]),
+            Inst::Jmp(bitvec![1, 1]),
+            Inst::Label(bitvec![1, 0]),
+            Inst::Call(bitvec![1]),
+            Inst::Jmp(bitvec![1, 1]),
+            Inst::Label(bitvec![1, 1]),
+            Inst::Ret,
+        ];
+        let graph = CallGraph::from_program(&Program::new(insts, LabelOrder::Def));
+        let subs = graph.subroutines();
+        assert_eq!(3, subs.len());
+        assert_eq!(&[SubId(1), SubId(2)], subs[0].calls());
+        assert_eq!(ids(&[3, 6]), subs[1].blocks());
+        assert_eq!(&[SubId(0), SubId(2)], subs[1].callers());
+        assert_eq!(ids(&[4, 5, 6]), subs[2].blocks());
+        assert!(subs[1].returns() && subs[2].returns());
+        assert!(subs.iter().all(|s| !s.is_recursive()));
+        assert_eq!(ids(&[6]), graph.shared());
+        assert_eq!(&[SubId(1), SubId(2)], graph.owners(BlockId(6)));
+        assert!(graph
+            .to_string()
+            .contains("  block_6: label_2 # shared with sub_2\n"));
+    }
+
+    #[test]
+    fn recursive() {
+        let insts = vec![
+            Inst::Call(bitvec![1]),
+            Inst::End,
+            Inst::Label(bitvec![1]),
+            Inst::Dup,
+            Inst::Jz(bitvec![1, 0]),
+            Inst::Call(bitvec![1]),
+            Inst::Label(bitvec![1, 0]),
+            Inst::Ret,
+            Inst::Label(bitvec![1, 1]),
+            Inst::Call(bitvec![1, 1]),
+            Inst::Ret,
+        ];
+        let graph = CallGraph::from_program(&Program::new(insts, LabelOrder::Def));
+        let subs = graph.subroutines();
+        assert_eq!(2, subs.len());
+        assert_eq!(ids(&[2, 3, 4]), subs[1].blocks());
+        assert_eq!(&[SubId(0), SubId(1)], subs[1].callers());
+        assert!(subs[1].returns() && subs[1].is_recursive());
+        // The infinitely recursive subroutine is unreachable from main.
+        assert_eq!(ids(&[5, 6, 7]), graph.unreachable());
+    }
+}
@@ -6,6 +6,7 @@
 // later version. You should have received a copy of the GNU Lesser General
 // Public License along with Nebula 2. If not, see http://www.gnu.org/licenses/.
 
+use std::cmp::Ordering;
 use std::fmt::{self, Display, Formatter};
 use std::intrinsics;
 use std::ops::{Deref, DerefMut};
@@ -212,6 +213,12 @@ impl IntLiteral {
         Ok(IntLiteral { bits, string, int })
     }
 
+    #[inline]
+    #[must_use]
+    pub fn bits(&self) -> &BitVec {
+        &self.bits
+    }
+
     #[inline]
     #[must_use]
     pub fn sign(&self) -> Sign {
@@ -273,6 +280,20 @@ impl From<BitVec> for IntLiteral {
     }
 }
 
+impl From<Integer> for IntLiteral {
+    #[inline]
+    fn from(int: Integer) -> Self {
+        // Zero is written with one digit, as is conventional.
+        let (sign, leading_zeros) = match int.cmp0() {
+            Ordering::Less => (Sign::Neg, 0),
+            Ordering::Equal => (Sign::Pos, 1),
+            Ordering::Greater => (Sign::Pos, 0),
+        };
+        let bits = convert::signed_bits_from_integer(&int, sign, leading_zeros);
+        IntLiteral { bits, string: None, int }
+    }
+}
+
 impl Deref for IntLiteral {
     type Target = Integer;
 
@@ -16,8 +16,11 @@ use rug::Integer;
 use smallvec::SmallVec;
 use static_assertions::assert_eq_size;
 
+use crate::syntax::Tokens;
 use crate::ws::inst::{Inst, InstArg, InstError, Opcode, RawInst};
+use crate::ws::subroutine::CallGraph;
 use crate::ws::syntax::{convert, IntLiteral};
+use crate::ws::token::{Token, TokenVec};
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Program {
@@ -64,10 +67,97 @@ macro_rules! id_index(
         })+
     }
 );
+pub(crate) use id_index;
 
 id_index!(InstId(u32) indexes ProgramInst in Vec<ProgramInst>, [ProgramInst]);
 id_index!(LabelId(u32) indexes LabelData in Vec<LabelData>, [LabelData]);
 
+impl Program {
+    /// Constructs a program by resolving the labels of raw instructions.
+    #[must_use]
+    pub fn new(insts: Vec<RawInst>, order: LabelOrder) -> Self {
+        let mut resolver = LabelResolver::new();
+        let insts = resolver.resolve_all(insts, order);
+        Program { insts, labels: resolver.labels }
+    }
+
+    /// Constructs a program by resolving the labels of raw instructions with
+    /// a resolver that may already have labels, such as from
+    /// [`LabelResolver::fresh`].
+    #[must_use]
+    pub fn with_resolver(
+        insts: Vec<RawInst>,
+        order: LabelOrder,
+        mut resolver: LabelResolver,
+    ) -> Self {
+        let insts = resolver.resolve_all(insts, order);
+        Program { insts, labels: resolver.labels }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn insts(&self) -> &[ProgramInst] {
+        &self.insts
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn labels(&self) -> &[LabelData] {
+        &self.labels
+    }
+
+    /// Converts an instruction back to its raw form, with its label replaced
+    /// by the canonical bits. Rebuilding a program from transformed raw
+    /// instructions resolves the labels again.
+    #[must_use]
+    pub fn raw_inst(&self, inst: &ProgramInst) -> RawInst {
+        inst.clone().map_arg(|_, arg| -> Result<_, InstError> {
+            match arg {
+                InstArg::Int(n) => Ok(InstArg::Int(n.bits().clone())),
+                InstArg::Label(l) => Ok(InstArg::Label(self.labels[l].bits.clone())),
+            }
+        })
+    }
+
+    /// Infers the subroutines of the program and the calls between them.
+    #[inline]
+    #[must_use]
+    pub fn call_graph(&self) -> CallGraph {
+        CallGraph::from_program(self)
+    }
+
+    /// Serializes the program to Whitespace tokens.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the program contains an error instruction.
+    #[must_use]
+    pub fn to_tokens(&self) -> Vec<Token> {
+        let mut toks = Vec::new();
+        for inst in &self.insts {
+            toks.extend_from_slice(inst.opcode().tokens());
+            match inst {
+                Inst::Push(n) | Inst::Copy(n) | Inst::Slide(n) => {
+                    toks.append_bits(n.bits());
+                    toks.push(Token::L);
+                }
+                Inst::Label(l) | Inst::Call(l) | Inst::Jmp(l) | Inst::Jz(l) | Inst::Jn(l) => {
+                    toks.append_bits(&self.labels[*l].bits);
+                    toks.push(Token::L);
+                }
+                _ => {}
+            }
+        }
+        toks
+    }
+}
+
+impl Display for LabelId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "label_{}", self.0)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct LabelLiteral {
     bits: BitVec,
@@ -125,6 +215,42 @@ impl LabelData {
         }
     }
 
+    #[inline]
+    #[must_use]
+    pub fn id(&self) -> LabelId {
+        self.id
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn bits(&self) -> &BitVec {
+        &self.bits
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn uint(&self) -> Option<&Integer> {
+        self.uint.as_ref()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn names(&self) -> &[(InstId, String)] {
+        &self.names
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn defs(&self) -> &[InstId] {
+        &self.defs
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn uses(&self) -> &[InstId] {
+        &self.uses
+    }
+
     #[inline]
     pub fn push_def_or_use(&mut self, inst: InstId, opcode: Opcode) {
         if opcode == Opcode::Label {
@@ -149,6 +275,8 @@ impl LabelData {
 pub struct LabelResolver {
     labels: Vec<LabelData>,
     bits_map: HashMap<BitVec, LabelId>,
+    /// The last number tried for a fresh label.
+    fresh: u32,
 }
 
 /// The ordering to use for assigning label ids when serializing.
@@ -242,6 +370,21 @@ impl LabelResolver {
         })
     }
 
+    /// Allocates a label for generated code, with the bits of the smallest
+    /// positive integer that no label in the resolver uses yet.
+    pub fn fresh(&mut self) -> BitVec {
+        loop {
+            self.fresh += 1;
+            let bits = convert::unsigned_bits_from_integer(&Integer::from(self.fresh));
+            if let Entry::Vacant(entry) = self.bits_map.entry(bits.clone()) {
+                let id = LabelId::from(self.labels.len());
+                self.labels.push(LabelData::new(id, bits.clone()));
+                entry.insert(id);
+                return bits;
+            }
+        }
+    }
+
     fn insert(&mut self, bits: BitVec, inst: InstId, opcode: Opcode) -> LabelId {
         match self.bits_map.entry(bits.clone()) {
             Entry::Occupied(entry) => {
@@ -47,7 +47,7 @@ const TUTORIAL_BITS: &[u8] = &[
     0b00001001, 0b01101111, 0b11111100,
 ];
 
-fn get_tutorial_insts() -> Vec<RawInst> {
+pub(crate) fn get_tutorial_insts() -> Vec<RawInst> {
     vec![
         Inst::Push(bitvec![0, 1]),
         Inst::Label(bitvec![0, 1, 0, 0, 0, 0, 1, 1]),